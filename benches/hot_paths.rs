@@ -0,0 +1,94 @@
+// Benchmarks of the parts of the renderer that every sample goes through. They are run with
+// "cargo bench" and compared against the previous run by Criterion, so the effect of an
+// optimization shows up as a change in percent.
+//
+// There is no acceleration structure yet. Finding the nearest hit therefore tests every
+// primitive, which is what the scene benchmark measures for the largest demo scene.
+
+#[macro_use] extern crate criterion;
+extern crate pathtracer;
+
+use std::sync::{Arc, RwLock};
+
+use criterion::{Criterion, black_box};
+
+use pathtracer::{Scene, SceneBuilder, build_demo};
+use pathtracer::geometry::*;
+use pathtracer::material::diffuse;
+use pathtracer::integrator::{render, Integrator, WorkTile, Backbuffer, GuidingGrid, ReservoirBuffer};
+use pathtracer::accel::find_scene_hit;
+
+const FRAME_WIDTH: u32 = 128;
+const FRAME_HEIGHT: u32 = 72;
+const MAX_DEPTH: u8 = 4;
+
+// Rays from the origin towards the unit circle around (0, 0, -5), so that about half of
+// them hit a unit sphere or quad placed there.
+fn fan_of_rays(num_rays: usize) -> Vec<Ray> {
+    (0..num_rays).map(|i| {
+        let angle = 2.0*std::f32::consts::PI*i as f32 / num_rays as f32;
+        let radius = 2.0*(i % 7) as f32 / 7.0;
+        let target = Vec3::new(radius*f32::cos(angle), radius*f32::sin(angle), -5.0);
+        Ray::new(Vec3::zero(), target.normalize())
+    }).collect()
+}
+
+fn bench_nearest_hit(c: &mut Criterion, name: &str, scene: &Scene, rays: &[Ray]) {
+    c.bench_function(name, |b| b.iter(|| {
+        let mut num_hits = 0;
+        for ray in rays {
+            if find_scene_hit(black_box(ray), scene).is_some() {
+                num_hits += 1;
+            }
+        }
+        num_hits
+    }));
+}
+
+fn intersection(c: &mut Criterion) {
+    let rays = fan_of_rays(1024);
+
+    let sphere = SceneBuilder::new(16, 16).sphere(Vec3::new(0.0, 0.0, -5.0), 1.0, diffuse(Vec3::one())).build();
+    bench_nearest_hit(c, "sphere intersection (1024 rays)", &sphere, &rays);
+
+    let plane = SceneBuilder::new(16, 16).quad(Vec3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0), 2.0, 2.0, diffuse(Vec3::one())).build();
+    bench_nearest_hit(c, "plane intersection (1024 rays)", &plane, &rays);
+
+    // The primary rays of the whole image, which mostly hit the small spheres.
+    let spheres = build_demo("spheres").unwrap();
+    let (width, height) = (FRAME_WIDTH, FRAME_HEIGHT);
+    let primary_rays: Vec<Ray> = (0..width*height)
+        .map(|i| spheres.camera.pixel_ray((i % width) as f32 + 0.5, (i / width) as f32 + 0.5, width, height))
+        .collect();
+    bench_nearest_hit(c, "nearest hit in the scene spheres (primary rays)", &spheres, &primary_rays);
+}
+
+// Renders one sample per pixel of the whole image, in a single tile on the calling thread.
+fn bench_frame(c: &mut Criterion, name: &str, demo_name: &str, integrator: Integrator) {
+    let mut scene = build_demo(demo_name).unwrap();
+    scene.set_resolution(FRAME_WIDTH as usize, FRAME_HEIGHT as usize);
+    let scene = Arc::new(RwLock::new(scene));
+    let backbuffer = Arc::new(Backbuffer::new(FRAME_WIDTH, FRAME_HEIGHT));
+    let guiding = GuidingGrid::new();
+    let reservoirs = ReservoirBuffer::new(FRAME_WIDTH, FRAME_HEIGHT);
+    let size = Vec2u::new(FRAME_WIDTH, FRAME_HEIGHT);
+
+    c.bench_function(name, |b| b.iter(|| {
+        let work_tile = WorkTile::new(Vec2u::new(0, 0), Vec2u::new(0, 0), size, integrator, MAX_DEPTH, 0, 1, size);
+        render(work_tile, &backbuffer, scene.clone(), &guiding, &reservoirs);
+    }));
+}
+
+fn full_frame(c: &mut Criterion) {
+    bench_frame(c, "frame of the scene cornell (path tracer)", "cornell", Integrator::PathTracer);
+    bench_frame(c, "frame of the scene materials (path tracer)", "materials", Integrator::PathTracer);
+    bench_frame(c, "frame of the scene cornell (wavefront)", "cornell", Integrator::Wavefront);
+    bench_frame(c, "frame of the scene cornell (whitted)", "cornell", Integrator::Whitted);
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = intersection, full_frame
+}
+criterion_main!(benches);
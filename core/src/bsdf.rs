@@ -0,0 +1,222 @@
+use math::{self, PI};
+use vector::{Vec2, Vec3};
+
+// The BSDFs of the renderer, the phase function of the media and the sampling of their
+// directions. The directions are given in world space unless they are sampled in the space
+// of a Basis, whose y axis is the normal.
+
+#[derive(Clone, Copy, Debug)]
+pub struct Basis(pub Vec3, pub Vec3, pub Vec3);
+
+pub fn reflect(incoming: Vec3, normal: Vec3) -> Vec3 {
+    // Bear in mind that incoming is directed at the surface!
+    incoming - 2.0*incoming.dot(normal)*normal
+}
+
+pub fn refract(incoming: Vec3, normal: Vec3, n1: f32, n2: f32) -> Vec3 {
+    // Bear in mind that incoming is directed at the surface!
+
+    let v = -incoming;
+    let n = normal;
+
+    // Pythagorean trigonometric identity: sin^2(a) + cos^2(a) = 1
+    // Since cos(a) is easy to compute, sin(a) can be computed by
+    // sin(a) = sqrt(1 - cos^2(a))
+    let cos_theta1 = v.dot(n);
+
+    // @TODO: Test whether this is really faster.
+    // @TODO: Implement a faster but worse sqrt.
+    // Look at this article: https://www.codeproject.com/Articles/69941/Best-Square-Root-Method-Algorithm-Function-Precisi
+    let sin_theta1 = math::sqrt(1.0 - cos_theta1*cos_theta1);
+
+    // Snell's law: sin(theta_1) / sin(theta_2) = n_2 / n_1
+    // where theta_1: angle between the incoming ray and normal
+    //       theta_2: angle between the outgoing ray and -normal
+    //       n1: index of refraction for the medium above the surface
+    //       n2: index of refraction for the medium below the surface
+    let sin_theta2 = (sin_theta1*n1)/n2;
+    let cos_theta2 = math::sqrt(1.0 - sin_theta2*sin_theta2);
+    
+    // Corresponds to the projection of 'incoming' onto the ground plane.
+    let p = incoming + cos_theta1*normal;
+    
+    -normal*cos_theta2 + p.normalize()*sin_theta2
+}
+
+// Wavelengths in micrometers that stand for the red, green and blue channel.
+pub const CHANNEL_WAVELENGTHS: [f32; 3] = [0.65, 0.55, 0.45];
+
+// Index of refraction of a dispersive material for one color channel. It follows Cauchy's
+// equation n = A + B/λ², where B is the dispersion in µm² and A is chosen so that the green
+// channel has the given index of refraction. Crown glass has a B of about 0.004 and flint
+// glass of about 0.01.
+pub fn cauchy_ior(ior: f32, dispersion: f32, channel: usize) -> f32 {
+    let wavelength = CHANNEL_WAVELENGTHS[channel];
+    let green = CHANNEL_WAVELENGTHS[1];
+    ior + dispersion*(1.0/(wavelength*wavelength) - 1.0/(green*green))
+}
+
+pub fn construct_coordinate_system(normal: Vec3) -> Basis {
+    const EPS: f32 = 0.9999;
+    let other = if normal.y > EPS || normal.y < -EPS {
+        Vec3::new(1.0, 0.0, 0.0)
+    } else {
+        Vec3::new(0.0, 1.0, 0.0)
+    };
+    let y = normal;
+    let x = y.cross(other).normalize();
+    let z = y.cross(x);
+    Basis(x, y, z)
+}
+
+pub fn normal_distribution_ggx(normal: Vec3, half: Vec3, alpha: f32) -> f32 {
+    // The normal distribution function computes how much the microfacets of the surface
+    // contribute to a reflection whose half vector is 'half'.
+    
+    // More in-depth explanation: The microfacet theory assumes that the relevant reflection 
+    // characteristics of every surface can be modelled by an in infinite amount of
+    // infinitesimal small mirrors where the orientation and visibility of these mirrors are 
+    // only defined statistically. The normal distribution function represents the statistical
+    // orientation of the microfacets in the sense that it returns the probability of a
+    // microfacet being oriented in the 'half' direction. Alternatively this can be seen as
+    // determining the surface area of the microfacets being oriented in the half direction
+    // relativ to the surface area of the macro surface. Therefore, when the intensity of
+    // a reflection from direction d_1 to direction d_2 has to be determined, the half vector
+    // between both directions can be determined and the normal distribution function returns
+    // the strength of the reflection as those microfacets oriented in the 'half' direction
+    // contribute to exactly that reflection.
+
+    let n = normal; // normal of the surface
+    let h = half; // half vector (normal of microfacets that contribute to the reflection)
+    let a = alpha; // alpha value that is based on the roughness of the surface (e.g. roughness^2)
+
+    let a2 = a*a;
+    let n_dot_h = f32::max(n.dot(h), 0.0);
+    let n_dot_h2 = n_dot_h*n_dot_h;
+
+    let d = n_dot_h2 * (a2 - 1.0) + 1.0;
+	
+    a2 / (PI*d*d)
+}
+
+pub fn geometry_smith(normal: Vec3, view: Vec3, light: Vec3, k: f32) -> f32 {
+    // This function computes the probability of light being blocked either by 
+    //   1. the microfacet being shadowed by another microfacet when the light is incoming or
+    //   2. the microfacet being masked by another microfacet when the light is outgoing.
+    // Another way to lock at is would be: This function reduces the amount of reflected energy
+    // by considering the proportional surface area of microfacets that is shadowed or masked
+    // by other microfacets relativ to the surface area of the macro surface.
+
+    let n = normal; // normal of the surface
+    let v = view; // direction to the viewer
+    let l = light; // direction to the light source
+
+    let geometry_schlick_ggx = |a: Vec3| -> f32 {
+        let n_dot_a = f32::max(0.0, n.dot(a));
+        let nom = n_dot_a;
+        let denom = n_dot_a * (1.0 - k) + k;
+        nom / denom
+    };
+    geometry_schlick_ggx(l)*geometry_schlick_ggx(v)
+}
+
+pub fn fresnel_schlick(cos_theta: f32, f0: Vec3) -> Vec3 {
+    f0 + (Vec3::one() - f0)*math::powf(1.0 - cos_theta, 5.0)
+}
+
+pub fn brdf_cook_torrance(view: Vec3, light: Vec3, normal: Vec3, roughness: f32, f0: Vec3) -> Vec3 {
+    // Bear in mind that 'view' as well as 'light' are pointing away from the surface!
+
+    let v = view;
+    let l = light;
+    let n = normal;
+
+    let alpha = roughness*roughness;
+    let h = (v + l).normalize();
+    let cos_theta = f32::max(0.0, h.dot(v));
+
+    // @TODO: There are multiple definitions for this. What's the best?
+    // let k = (alpha + 1.0)*(alpha + 1.0) / 8.0;
+    let k = alpha / 2.0; // Was/is used in the Unreal Engine 4 according to Brian Karis' blog.
+
+    // The normal distribution function is canceled out as it functions as the probability 
+    // density function for the monte carlo integration.
+    let num = fresnel_schlick(cos_theta, f0)*geometry_smith(n, v, l, k);
+    let denum = 4.0*f32::max(0.0, n.dot(v));
+    num / denum
+}
+
+// @TODO: Ensure that the reflection direction is not below the horizon.
+pub fn importance_sample_ggx(xi: Vec2, roughness: f32) -> Vec3 {
+    let a = roughness*roughness;
+
+    let phi = 2.0*PI*xi.x;
+    let sin_phi = math::sin(phi);
+    let cos_phi = math::cos(phi);
+
+    let cos_theta = math::sqrt((1.0 - xi.y) / (xi.y*(a*a - 1.0) + 1.0));
+    let sin_theta = math::sqrt(1.0 - cos_theta*cos_theta);
+
+    Vec3::new(sin_theta*cos_phi, cos_theta, sin_theta*sin_phi)
+}
+
+// The energy-normalized Blinn-Phong lobe of older assets, e.g. the Ks and Ns of OBJ/MTL
+// files, where the exponent is Ns. The factor (n + 8)/(8 PI) keeps the reflected energy
+// roughly constant when the exponent changes (source: Fabian Giesen, "The Blinn-Phong
+// Normalization Zoo", 2009).
+pub fn brdf_blinn_phong(view: Vec3, light: Vec3, normal: Vec3, specular: Vec3, exponent: f32) -> Vec3 {
+    let half = (view + light).normalize();
+    let cos_theta_h = f32::max(0.0, normal.dot(half));
+    specular*((exponent + 8.0)/(8.0*PI)*math::powf(cos_theta_h, exponent))
+}
+
+// Samples the half vector proportional to cos^n, with a pdf of (n + 1)/(2 PI) cos^n per
+// solid angle of the half vector.
+pub fn importance_sample_blinn_phong(xi: Vec2, exponent: f32) -> Vec3 {
+    let cos_theta = math::powf(xi.y, 1.0/(exponent + 1.0));
+    let sin_theta = math::sqrt(f32::max(0.0, 1.0 - cos_theta*cos_theta));
+    let phi = 2.0*PI*xi.x;
+    Vec3::new(sin_theta*math::cos(phi), cos_theta, sin_theta*math::sin(phi))
+}
+
+// The pdf of the light direction that is reflected about the sampled half vector.
+pub fn pdf_blinn_phong(view: Vec3, light: Vec3, normal: Vec3, exponent: f32) -> f32 {
+    let half = (view + light).normalize();
+    let cos_theta_h = f32::max(0.0, normal.dot(half));
+    let v_dot_h = view.dot(half);
+    if v_dot_h <= 0.0 {
+        return 0.0;
+    }
+    (exponent + 1.0)/(2.0*PI)*math::powf(cos_theta_h, exponent)/(4.0*v_dot_h)
+}
+
+pub fn importance_sample_cos(xi: Vec2) -> Vec3 {
+    let r = math::sqrt(xi.x);
+    let theta = 2.0*PI*xi.y;
+ 
+    let x = r * math::cos(theta);
+    let z = r * math::sin(theta);
+ 
+    Vec3::new(x, math::sqrt(f32::max(0.0, 1.0 - xi.x)), z)
+}
+
+pub fn to_basis(basis: Basis, v: Vec3) -> Vec3 {
+    let Basis(x, y, z) = basis;
+    x*v.x + y*v.y + z*v.z
+}
+
+pub fn sample_henyey_greenstein(direction: Vec3, g: f32, xi: Vec2) -> Vec3 {
+    // The phase function is sampled exactly, which is why it cancels out with its pdf
+    // and does not show up in the throughput weight.
+    let cos_theta = if math::abs(g) < 0.001 {
+        1.0 - 2.0*xi.x
+    } else {
+        let s = (1.0 - g*g) / (1.0 + g - 2.0*g*xi.x);
+        (1.0 + g*g - s*s) / (2.0*g)
+    };
+    let sin_theta = math::sqrt(f32::max(0.0, 1.0 - cos_theta*cos_theta));
+    let phi = 2.0*PI*xi.y;
+
+    let local = Vec3::new(sin_theta*math::cos(phi), cos_theta, sin_theta*math::sin(phi));
+    to_basis(construct_coordinate_system(direction), local).normalize()
+}
@@ -0,0 +1,49 @@
+use math::clampf32;
+use vector::Vec3;
+
+pub fn saturate_vec3(v: Vec3) -> Vec3 {
+    Vec3::new(
+        clampf32(0.0, 1.0, v.x),
+        clampf32(0.0, 1.0, v.y),
+        clampf32(0.0, 1.0, v.z),
+    )
+}
+
+pub fn luminance(color: Vec3) -> f32 {
+    0.2126*color.x + 0.7152*color.y + 0.0722*color.z
+}
+
+pub fn mix_vec3(a: Vec3, b: Vec3, x: f32) -> Vec3 {
+    (1.0 - x)*a + x*b
+}
+
+#[derive(Clone, Debug)]
+pub struct Pixel8(pub u8, pub u8, pub u8);
+
+// 8x8 Bayer matrix for ordered dithering. The values are the thresholds in [0, 64).
+const BAYER_8X8: [[u8; 8]; 8] = [
+    [ 0, 32,  8, 40,  2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44,  4, 36, 14, 46,  6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [ 3, 35, 11, 43,  1, 33,  9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47,  7, 39, 13, 45,  5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+impl Pixel8 {
+    // Quantizes a color in [0, 1] with an ordered dither of one step at most. This breaks up
+    // the banding in smooth gradients and dark regions without visible noise. The pattern is
+    // fixed per pixel and therefore does not flicker while the image converges.
+    pub fn from_unit_dithered(color: Vec3, x: u32, y: u32) -> Self {
+        let threshold = (BAYER_8X8[(y % 8) as usize][(x % 8) as usize] as f32 + 0.5)/64.0;
+        let quantize = |c: f32| f32::min(255.0, f32::max(0.0, c*255.0 + threshold)) as u8;
+        Pixel8(quantize(color.x), quantize(color.y), quantize(color.z))
+    }
+}
+
+pub fn is_normalized(v: Vec3) -> bool {
+    let v_len = v.length();
+    v_len > 0.99 && v_len < 1.01
+}
@@ -0,0 +1,123 @@
+use math;
+use vector::Vec3;
+use ray::{Ray, Float, FloatVec3};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transition { In, Out }
+
+// The surface the ray hits. The normal points against the ray, so for rays leaving a sphere
+// it points inwards. The renderer adds the material of the primitive to it.
+#[derive(Clone, Copy, Debug)]
+pub struct Intersection {
+    pub parameter: f32,
+    pub position: Vec3,
+    pub normal: Vec3,
+    pub transition: Transition,
+}
+
+// The intersection tests are computed with Float (see ray.rs). Every hit in front of the
+// origin is accepted, as the origins of secondary rays are moved off the surface with
+// offset_ray_origin by an offset relative to the size of the coordinates. An absolute
+// epsilon would be too large for small details and too small for large coordinates.
+pub fn intersect_sphere(origin: Vec3, radius: f32, ray: &Ray) -> Option<Intersection> {
+    let s = FloatVec3::from_vec3(origin);
+    let r = radius as Float;
+    let p = FloatVec3::from_vec3(ray.origin);
+    let d = FloatVec3::from_vec3(ray.direction);
+
+    let c = s - p;
+    let r_squared = r*r;
+
+    // The projection of the center of the sphere onto the ray. When it is negative, the
+    // center is behind the origin of the ray. The sphere can then only be hit when the
+    // origin is inside of it, e.g. for rays that were refracted into the sphere or a camera
+    // that is placed inside of an object.
+    let e_len = d.dot(c);
+    let origin_inside = c.dot(c) < r_squared;
+    if e_len < 0.0 && !origin_inside {
+        return None;
+    }
+
+    // v is the vector that is pointing from the center of the sphere to the point on the
+    // ray which is closest to the center of the sphere. When its magnitude is larger than
+    // the radius, the ray misses the sphere. Computing v directly instead of the
+    // discriminant of the quadratic equation avoids the cancellation in |c|^2 - e_len^2
+    // for spheres that are far away or large compared to the distance between the hits.
+    // Source: Haines et al., "Precision Improvements for Ray/Sphere Intersection",
+    //         Ray Tracing Gems, 2019
+    let v = d.scale(e_len) - c;
+    let v_len_squared = v.dot(v);
+    if v_len_squared > r_squared {
+        return None;
+    }
+
+    // To find the intersection points of the sphere, the distance from the nearest
+    // point on the ray to the intersection points is computed. This is done by
+    // rearanging the Pythagorean theorem. The hypothenuse is the radius of the
+    // sphere and one cathetus is the distance from the center of the sphere to
+    // the closest point on the ray to the center of the sphere.
+    let b = math::sqrt_float(r_squared - v_len_squared); // @TODO: Try this with a lower quality sqrt function
+
+    let t1 = e_len - b;
+    let t2 = e_len + b;
+
+    // The nearest root in front of the ray is the hit. The first root enters the sphere
+    // and the second one leaves it, which is the only one in front of rays starting inside
+    // of the sphere.
+    if t1 > 0.0 {
+        let position = p + d.scale(t1);
+        let normal = (position - s).to_vec3().normalize(); // @TODO: Optimize
+        Some(Intersection { parameter: t1 as f32, position: position.to_vec3(), normal: normal, transition: Transition::In })
+    } else if t2 > 0.0 {
+        let position = p + d.scale(t2);
+        let normal = (s - position).to_vec3().normalize(); // @TODO: Optimize
+        Some(Intersection { parameter: t2 as f32, position: position.to_vec3(), normal: normal, transition: Transition::Out })
+    } else {
+        None
+    }
+}
+
+// The parallelogram spanned by u and v at the origin. Its normal is given as it is stored
+// with the plane.
+pub fn intersect_plane(origin: Vec3, u: Vec3, v: Vec3, normal: Vec3, ray: &Ray) -> Option<Intersection> {
+    let n = FloatVec3::from_vec3(normal);
+    let s = FloatVec3::from_vec3(origin);
+
+    let p = FloatVec3::from_vec3(ray.origin);
+    let d = FloatVec3::from_vec3(ray.direction);
+
+    let num = n.dot(s - p);
+    let denum = n.dot(d);
+
+    if math::abs_float(denum) < 0.0000001 { // @TODO: Set a reasonable epsilon
+        return None;
+    }
+
+    let parameter = num / denum;
+    if !(parameter > 0.0) {
+        return None;
+    }
+
+    let position = p + d.scale(parameter);
+    let in_plane = position - s;
+    let u = FloatVec3::from_vec3(u);
+    let u_len = u.length();
+    let on_u = in_plane.dot(u) / u_len;
+    if on_u > u_len || on_u < 0.0 {
+        return None;
+    }
+    let v = FloatVec3::from_vec3(v);
+    let v_len = v.length();
+    let on_v = in_plane.dot(v) / v_len;
+    if on_v > v_len || on_v < 0.0 {
+        return None;
+    }
+
+    let (transition, normal) = if ray.direction.dot(normal) < 0.0 {
+        (Transition::In, normal)
+    } else {
+        (Transition::Out, -normal)
+    };
+
+    Some(Intersection { parameter: parameter as f32, position: position.to_vec3(), normal: normal, transition: transition })
+}
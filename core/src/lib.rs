@@ -0,0 +1,33 @@
+// The math of the renderer that does not need the standard library: the vectors, rays, the
+// intersection tests, the BSDFs and their sampling. It neither allocates nor keeps state in
+// statics, which is left to the renderer, e.g. the random number generator that is shared
+// by the worker threads. Without the "std" feature, the crate builds for targets without an
+// operating system and the functions of f32 are taken from libm:
+//
+//   cargo build -p pathtracer-core --no-default-features --features libm --target wasm32-unknown-unknown
+//
+// The renderer uses everything through common.rs.
+
+#![no_std]
+
+#[cfg(feature = "std")] extern crate std;
+#[cfg(not(feature = "std"))] extern crate libm;
+
+#[cfg(not(any(feature = "std", feature = "libm")))]
+compile_error!("pathtracer-core needs either the \"std\" or the \"libm\" feature for the functions of f32.");
+
+pub mod math;
+mod vector;
+mod ray;
+mod random;
+mod color;
+mod intersect;
+mod bsdf;
+
+pub use math::{PI, clampf32, saturatef32};
+pub use vector::{Vector2, Vector3, Vec2, Vec3, Vec2u};
+pub use ray::{Ray, Float, FloatVec3, RAY_EPSILON, SHADOW_EPSILON, offset_ray_origin};
+pub use random::XorShift32;
+pub use color::{Pixel8, luminance, mix_vec3, saturate_vec3, is_normalized};
+pub use intersect::{Transition, Intersection, intersect_sphere, intersect_plane};
+pub use bsdf::*;
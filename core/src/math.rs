@@ -0,0 +1,69 @@
+use ray::Float;
+
+pub const PI: f32 = ::core::f32::consts::PI;
+
+// The functions of f32 that are implemented by std, which uses the ones of the platform.
+// Without std, they are computed in software by libm. The results can differ in the last
+// bits, which is why images rendered with and without std are not identical.
+
+#[cfg(feature = "std")]
+mod platform {
+    use ray::Float;
+
+    pub fn sqrt(x: f32) -> f32 { f32::sqrt(x) }
+    pub fn sin(x: f32) -> f32 { f32::sin(x) }
+    pub fn cos(x: f32) -> f32 { f32::cos(x) }
+    pub fn tan(x: f32) -> f32 { f32::tan(x) }
+    pub fn atan2(y: f32, x: f32) -> f32 { f32::atan2(y, x) }
+    pub fn acos(x: f32) -> f32 { f32::acos(x) }
+    pub fn exp(x: f32) -> f32 { f32::exp(x) }
+    pub fn ln(x: f32) -> f32 { f32::ln(x) }
+    pub fn powf(x: f32, y: f32) -> f32 { f32::powf(x, y) }
+    pub fn floor(x: f32) -> f32 { f32::floor(x) }
+    pub fn sqrt_float(x: Float) -> Float { x.sqrt() }
+}
+
+#[cfg(not(feature = "std"))]
+mod platform {
+    use libm;
+    use ray::Float;
+
+    pub fn sqrt(x: f32) -> f32 { libm::sqrtf(x) }
+    pub fn sin(x: f32) -> f32 { libm::sinf(x) }
+    pub fn cos(x: f32) -> f32 { libm::cosf(x) }
+    pub fn tan(x: f32) -> f32 { libm::tanf(x) }
+    pub fn atan2(y: f32, x: f32) -> f32 { libm::atan2f(y, x) }
+    pub fn acos(x: f32) -> f32 { libm::acosf(x) }
+    pub fn exp(x: f32) -> f32 { libm::expf(x) }
+    pub fn ln(x: f32) -> f32 { libm::logf(x) }
+    pub fn powf(x: f32, y: f32) -> f32 { libm::powf(x, y) }
+    pub fn floor(x: f32) -> f32 { libm::floorf(x) }
+    #[cfg(not(feature = "f64"))]
+    pub fn sqrt_float(x: Float) -> Float { libm::sqrtf(x) }
+    #[cfg(feature = "f64")]
+    pub fn sqrt_float(x: Float) -> Float { libm::sqrt(x) }
+}
+
+pub use self::platform::*;
+
+pub fn abs(x: f32) -> f32 {
+    if x < 0.0 { -x } else { x }
+}
+
+pub fn abs_float(x: Float) -> Float {
+    if x < 0.0 { -x } else { x }
+}
+
+pub fn clampf32(min: f32, max: f32, x: f32) -> f32 {
+    if x > max {
+        max
+    } else if x < min {
+        min
+    } else {
+        x
+    }
+}
+
+pub fn saturatef32(x: f32) -> f32 {
+    clampf32(0.0, 1.0, x)
+}
@@ -0,0 +1,32 @@
+// Source: https://de.wikipedia.org/wiki/Xorshift
+// The state is kept by the caller. The renderer keeps one per thread that is seeded again for
+// every sample of a pixel (see common.rs).
+#[derive(Clone, Copy, Debug)]
+pub struct XorShift32 {
+    state: u32,
+}
+
+impl XorShift32 {
+    // Zero is a fixed point of xorshift and is therefore replaced.
+    pub const fn new(seed: u32) -> Self {
+        XorShift32 { state: if seed == 0 { 314159265 } else { seed } }
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+
+    // In [0, 1]
+    pub fn next_f32(&mut self) -> f32 {
+        self.next_u32() as f32 / u32::MAX as f32
+    }
+}
+
+impl Default for XorShift32 {
+    fn default() -> Self {
+        XorShift32::new(314159265)
+    }
+}
@@ -0,0 +1,119 @@
+use core::ops::{Add, Sub};
+
+use math;
+use vector::Vec3;
+
+#[derive(Clone, Debug)]
+pub struct Ray {
+    pub origin: Vec3,
+    pub direction: Vec3,
+}
+
+impl Ray {
+    pub fn new(origin: Vec3, direction: Vec3) -> Self {
+        Ray {
+            origin: origin,
+            direction: direction,
+        }
+    }
+}
+
+// Scalar of the intersection tests. In scenes that are large compared to their details,
+// e.g. a planet seen from its surface, the differences of large coordinates lose most of
+// their digits in f32 and the hits jitter from pixel to pixel. The "f64" feature computes
+// the intersection tests in double precision instead. This is not double precision tracing:
+// only the arithmetic inside the tests uses Float. The scene, Ray and Hit stay in f32, so the
+// parameter and position of every hit are rounded back to f32 and the secondary rays start
+// from f32 origins. The objects still have to be modeled around the camera for very large
+// scenes.
+#[cfg(not(feature = "f64"))]
+pub type Float = f32;
+#[cfg(feature = "f64")]
+pub type Float = f64;
+
+// A vector in the precision of Float, only with the operations of the intersection tests.
+#[derive(Clone, Copy, Debug)]
+pub struct FloatVec3 {
+    pub x: Float,
+    pub y: Float,
+    pub z: Float,
+}
+
+impl FloatVec3 {
+    pub fn new(x: Float, y: Float, z: Float) -> Self {
+        FloatVec3 { x: x, y: y, z: z }
+    }
+
+    pub fn from_vec3(v: Vec3) -> Self {
+        FloatVec3::new(v.x as Float, v.y as Float, v.z as Float)
+    }
+
+    pub fn to_vec3(self) -> Vec3 {
+        Vec3::new(self.x as f32, self.y as f32, self.z as f32)
+    }
+
+    pub fn dot(self, other: FloatVec3) -> Float {
+        self.x*other.x + self.y*other.y + self.z*other.z
+    }
+
+    pub fn length(self) -> Float {
+        math::sqrt_float(self.dot(self))
+    }
+
+    pub fn scale(self, factor: Float) -> FloatVec3 {
+        FloatVec3::new(factor*self.x, factor*self.y, factor*self.z)
+    }
+}
+
+impl Add for FloatVec3 {
+    type Output = FloatVec3;
+    fn add(self, other: FloatVec3) -> FloatVec3 {
+        FloatVec3::new(self.x + other.x, self.y + other.y, self.z + other.z)
+    }
+}
+
+impl Sub for FloatVec3 {
+    type Output = FloatVec3;
+    fn sub(self, other: FloatVec3) -> FloatVec3 {
+        FloatVec3::new(self.x - other.x, self.y - other.y, self.z - other.z)
+    }
+}
+
+// Hits of custom shapes closer to the origin of a ray than this are rejected, as their
+// intersection tests may not be precise enough for the origins that are offset with
+// offset_ray_origin. The spheres and planes only need the offset (see intersect.rs).
+pub const RAY_EPSILON: f32 = 1.0e-4;
+
+// Shadow rays end this fraction of their length before the point they are aimed at, so that
+// the surface at that point does not occlude itself.
+pub const SHADOW_EPSILON: f32 = 1.0e-3;
+
+// Moves a point on a surface along the normal far enough that rays starting there do not hit
+// the same surface again because of rounding errors (shadow acne). A fixed offset is either
+// too small for large coordinates or too large for small details. Instead, the coordinates
+// are nudged by a number of ulps proportional to the normal, which scales the offset with the
+// rounding error of the coordinate. Close to the origin, where the ulps become tiny, a small
+// fixed offset is used. Rays that enter the surface are offset with the negated normal.
+// Source: Wächter and Binder, "A Fast and Robust Method for Avoiding Self-Intersection",
+//         Ray Tracing Gems, 2019
+pub fn offset_ray_origin(position: Vec3, normal: Vec3) -> Vec3 {
+    const ORIGIN: f32 = 1.0/32.0;
+    const FLOAT_SCALE: f32 = 1.0/65536.0;
+    const INT_SCALE: f32 = 256.0;
+
+    let nudge = |p: f32, n: f32| -> f32 {
+        if math::abs(p) < ORIGIN {
+            return p + FLOAT_SCALE*n;
+        }
+        // Positive and negative floats grow in opposite directions of their bits.
+        let ulps = (INT_SCALE*n) as i32;
+        let bits = p.to_bits() as i32;
+        f32::from_bits(bits.wrapping_add(if p < 0.0 { -ulps } else { ulps }) as u32)
+    };
+
+    Vec3::new(
+        nudge(position.x, normal.x),
+        nudge(position.y, normal.y),
+        nudge(position.z, normal.z),
+    )
+}
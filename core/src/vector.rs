@@ -0,0 +1,162 @@
+use core::ops::{Add, Sub, Mul, Div, Neg};
+
+use math;
+
+// Only the operations the renderer needs. The products and quotients of two vectors are
+// taken per component, e.g. for multiplying the throughput of a path with a color.
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Vector2<T> {
+    pub x: T,
+    pub y: T,
+}
+
+impl<T> Vector2<T> {
+    pub fn new(x: T, y: T) -> Self {
+        Vector2 { x: x, y: y }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Vector3<T> {
+    pub x: T,
+    pub y: T,
+    pub z: T,
+}
+
+impl<T> Vector3<T> {
+    pub fn new(x: T, y: T, z: T) -> Self {
+        Vector3 { x: x, y: y, z: z }
+    }
+}
+
+pub type Vec2 = Vector2<f32>;
+pub type Vec3 = Vector3<f32>;
+
+pub type Vec2u = Vector2<u32>;
+
+impl Vector3<f32> {
+    pub fn zero() -> Self {
+        Vector3::new(0.0, 0.0, 0.0)
+    }
+
+    pub fn one() -> Self {
+        Vector3::new(1.0, 1.0, 1.0)
+    }
+
+    pub fn dot(self, other: Vec3) -> f32 {
+        self.x*other.x + self.y*other.y + self.z*other.z
+    }
+
+    pub fn cross(self, other: Vec3) -> Vec3 {
+        Vector3::new(
+            self.y*other.z - self.z*other.y,
+            self.z*other.x - self.x*other.z,
+            self.x*other.y - self.y*other.x,
+        )
+    }
+
+    pub fn length(self) -> f32 {
+        math::sqrt(self.dot(self))
+    }
+
+    // The zero vector becomes NaN.
+    pub fn normalize(self) -> Vec3 {
+        self*(1.0 / self.length())
+    }
+
+    pub fn as_array(self) -> [f32; 3] {
+        [self.x, self.y, self.z]
+    }
+}
+
+impl Add for Vec3 {
+    type Output = Vec3;
+    fn add(self, other: Vec3) -> Vec3 {
+        Vector3::new(self.x + other.x, self.y + other.y, self.z + other.z)
+    }
+}
+
+impl Sub for Vec3 {
+    type Output = Vec3;
+    fn sub(self, other: Vec3) -> Vec3 {
+        Vector3::new(self.x - other.x, self.y - other.y, self.z - other.z)
+    }
+}
+
+impl Neg for Vec3 {
+    type Output = Vec3;
+    fn neg(self) -> Vec3 {
+        Vector3::new(-self.x, -self.y, -self.z)
+    }
+}
+
+impl Mul for Vec3 {
+    type Output = Vec3;
+    fn mul(self, other: Vec3) -> Vec3 {
+        Vector3::new(self.x*other.x, self.y*other.y, self.z*other.z)
+    }
+}
+
+impl Mul<f32> for Vec3 {
+    type Output = Vec3;
+    fn mul(self, factor: f32) -> Vec3 {
+        Vector3::new(self.x*factor, self.y*factor, self.z*factor)
+    }
+}
+
+impl Mul<Vec3> for f32 {
+    type Output = Vec3;
+    fn mul(self, v: Vec3) -> Vec3 {
+        v*self
+    }
+}
+
+impl Div for Vec3 {
+    type Output = Vec3;
+    fn div(self, other: Vec3) -> Vec3 {
+        Vector3::new(self.x / other.x, self.y / other.y, self.z / other.z)
+    }
+}
+
+impl Div<f32> for Vec3 {
+    type Output = Vec3;
+    fn div(self, divisor: f32) -> Vec3 {
+        Vector3::new(self.x / divisor, self.y / divisor, self.z / divisor)
+    }
+}
+
+impl Add for Vec2 {
+    type Output = Vec2;
+    fn add(self, other: Vec2) -> Vec2 {
+        Vector2::new(self.x + other.x, self.y + other.y)
+    }
+}
+
+impl Sub for Vec2 {
+    type Output = Vec2;
+    fn sub(self, other: Vec2) -> Vec2 {
+        Vector2::new(self.x - other.x, self.y - other.y)
+    }
+}
+
+impl Mul for Vec2 {
+    type Output = Vec2;
+    fn mul(self, other: Vec2) -> Vec2 {
+        Vector2::new(self.x*other.x, self.y*other.y)
+    }
+}
+
+impl Mul<f32> for Vec2 {
+    type Output = Vec2;
+    fn mul(self, factor: f32) -> Vec2 {
+        Vector2::new(self.x*factor, self.y*factor)
+    }
+}
+
+impl Div for Vec2 {
+    type Output = Vec2;
+    fn div(self, other: Vec2) -> Vec2 {
+        Vector2::new(self.x / other.x, self.y / other.y)
+    }
+}
@@ -0,0 +1,111 @@
+use common::*;
+
+use scene::{Scene, ObjectId, Transform};
+
+// Keyframe animation of the camera and the objects of a scene. Every key sets the values of
+// one frame. The values in between are interpolated linearly between the neighboring keys
+// of the same track, values outside of the keys are held constant.
+//
+// There is no motion blur. Every frame is rendered as a still image of its point in time.
+
+#[derive(Clone, Debug, new)]
+pub struct CameraKey {
+    pub position: Vec3,
+    pub target: Vec3,
+    pub up: Vec3,
+}
+
+#[derive(Clone, Debug, new)]
+pub struct Keyframe {
+    pub frame: usize,
+    pub camera: Option<CameraKey>,
+    pub transforms: Vec<(ObjectId, Transform)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Animation {
+    keyframes: Vec<Keyframe>,
+}
+
+fn lerp_vec3(a: Vec3, b: Vec3, t: f32) -> Vec3 {
+    (1.0 - t)*a + t*b
+}
+
+fn lerp_f32(a: f32, b: f32, t: f32) -> f32 {
+    (1.0 - t)*a + t*b
+}
+
+// Returns the two keys around the frame and the factor between them. Both keys are the same
+// when the frame lies outside of the keys.
+fn find_keys<'a, T>(keys: &[(usize, &'a T)], frame: usize) -> Option<(&'a T, &'a T, f32)> {
+    let next = keys.iter().position(|&(key_frame, _)| key_frame >= frame);
+    match next {
+        Some(0) => Some((keys[0].1, keys[0].1, 0.0)),
+        Some(next) => {
+            let (frame0, key0) = keys[next - 1];
+            let (frame1, key1) = keys[next];
+            let t = (frame - frame0) as f32 / (frame1 - frame0) as f32;
+            Some((key0, key1, t))
+        },
+        None => keys.last().map(|&(_, key)| (key, key, 0.0)),
+    }
+}
+
+impl Animation {
+    // The keyframes are sorted by their frame.
+    pub fn new(mut keyframes: Vec<Keyframe>) -> Self {
+        keyframes.sort_by_key(|keyframe| keyframe.frame);
+        Animation {
+            keyframes: keyframes,
+        }
+    }
+
+    pub fn first_frame(&self) -> usize {
+        self.keyframes.first().map(|keyframe| keyframe.frame).unwrap_or(0)
+    }
+
+    pub fn last_frame(&self) -> usize {
+        self.keyframes.last().map(|keyframe| keyframe.frame).unwrap_or(0)
+    }
+
+    pub fn keyframes(&self) -> &[Keyframe] {
+        &self.keyframes
+    }
+
+    // Moves the camera and the objects to their state in the frame. Objects without any key
+    // keep their pose.
+    pub fn apply(&self, scene: &mut Scene, frame: usize) {
+        let camera_keys: Vec<(usize, &CameraKey)> = self.keyframes.iter()
+            .filter_map(|keyframe| keyframe.camera.as_ref().map(|camera| (keyframe.frame, camera)))
+            .collect();
+        if let Some((key0, key1, t)) = find_keys(&camera_keys, frame) {
+            let position = lerp_vec3(key0.position, key1.position, t);
+            let target = lerp_vec3(key0.target, key1.target, t);
+            let up = lerp_vec3(key0.up, key1.up, t);
+            scene.camera.look_at(position, target, up);
+        }
+
+        let mut ids: Vec<ObjectId> = self.keyframes.iter()
+            .flat_map(|keyframe| keyframe.transforms.iter().map(|&(id, _)| id))
+            .collect();
+        ids.sort();
+        ids.dedup();
+        for id in ids {
+            let transform_keys: Vec<(usize, &Transform)> = self.keyframes.iter()
+                .flat_map(|keyframe| keyframe.transforms.iter()
+                    .filter(move |&&(key_id, _)| key_id == id)
+                    .map(move |&(_, ref transform)| (keyframe.frame, transform)))
+                .collect();
+            if let Some((key0, key1, t)) = find_keys(&transform_keys, frame) {
+                let transform = Transform::new(
+                    lerp_vec3(key0.translation, key1.translation, t),
+                    lerp_vec3(key0.rotation_axis, key1.rotation_axis, t),
+                    lerp_f32(key0.rotation_angle, key1.rotation_angle, t),
+                    lerp_f32(key0.scale, key1.scale, t));
+                if !scene.set_transform(id, transform) {
+                    println!("The animated object {:?} does not exist.", id);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use common::*;
+
+use worker;
+use content;
+use scene::{self, Scene, ObjectId};
+use tracer;
+use parser;
+use export;
+use cli::{self, BakeMode};
+use progress::Progress;
+use error::Error;
+
+// Bakes the lighting of one object into a texture over its UV layout instead of rendering
+// the image of the camera, e.g. for a lightmap of the floor. Every texel is a point on the
+// surface, which is sampled like a pixel:
+//
+//   - the ambient occlusion is the same as the one of the "ao" integrator,
+//   - the irradiance is the light that arrives at the surface from the whole scene and
+//     does not depend on the material of the object.
+//
+// There are no meshes with UVs yet. The layout of a plane goes from its origin along u and
+// v. For a sphere it is the equirectangular layout of the HDRIs, with u around the y axis
+// starting at +x and v from the bottom (-y) to the top (+y). The bottom row of the texture
+// is v = 0.
+
+const DEFAULT_TEXTURE_SIZE: usize = 1024;
+
+// The position and the normal of the surface at the UV coordinates, if the object can be
+// baked.
+pub fn surface_point(scene: &Scene, object: ObjectId, uv: Vec2) -> Option<(Vec3, Vec3)> {
+    match object {
+        ObjectId::Sphere(index) => scene.spheres.get(index).map(|sphere| {
+            let phi = 2.0*PI*uv.x;
+            let theta = PI*(1.0 - uv.y);
+            let normal = Vec3::new(f32::sin(theta)*f32::cos(phi), f32::cos(theta), f32::sin(theta)*f32::sin(phi));
+            (sphere.origin + sphere.radius*normal, normal)
+        }),
+        ObjectId::Plane(index) => scene.planes.get(index).map(|plane| {
+            (plane.origin + uv.x*plane.u + uv.y*plane.v, plane.normal)
+        }),
+        _ => None,
+    }
+}
+
+// Bakes the object given with "--bake" and saves the texture. The resolution of the texture
+// is given with "--width" and "--height". The errors are returned like the ones of the
+// headless mode.
+pub fn start(options: cli::Options) -> Result<(), Error> {
+    let object = match options.bake {
+        Some(object) => object,
+        None => return Ok(()),
+    };
+    let output_path = options.output_path.clone().unwrap_or(String::from("bake.png"));
+    let num_samples = options.samples_per_pixel.unwrap_or(64);
+    let width = options.width.unwrap_or(DEFAULT_TEXTURE_SIZE) as u32;
+    let height = options.height.unwrap_or(DEFAULT_TEXTURE_SIZE) as u32;
+    let mode = options.bake_mode;
+    let max_depth = options.max_depth;
+
+    //
+    // LOADING THE SCENE
+    //
+    // The resolution of the options is the one of the texture and is not applied to the
+    // image of the scene.
+    //
+    let loaded_scene = match options.demo {
+        Some(ref name) => parser::parse_scene_text(name, parser::SceneFormat::Demo).map_err(Error::Scene),
+        None => parser::load_scene(&*options.scene_path).map(|(scene, _)| scene),
+    };
+    let mut scene = loaded_scene?;
+    if surface_point(&scene, object, Vec2::new(0.0, 0.0)).is_none() {
+        return Err(Error::InvalidOption(format!("Cannot bake {}. Only the spheres and the planes of the scene can be baked.", object)));
+    }
+
+    if let scene::Sky::HDRI(ref path, ref mut option_texture) = scene.sky {
+        let content = content::Content::new();
+        while option_texture.is_none() {
+            *option_texture = content.get_hdri_texture(path)?;
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    let scene = Arc::new(RwLock::new(scene));
+
+    //
+    // SETTING UP THE BAKING
+    //
+    // Every work item is one row of the texture. The ambient occlusion is not tone mapped,
+    // like the one of the integrator.
+    //
+    let backbuffer = Arc::new(tracer::Backbuffer::new(width, height));
+    backbuffer.set_debug_view(mode == BakeMode::AmbientOcclusion);
+    backbuffer.set_check_radiance(options.check_radiance);
+    let worker_pool = {
+        let backbuffer2 = backbuffer.clone();
+        let scene2 = scene.clone();
+        worker::WorkerPool::new(options.num_threads, Box::new(move |y: u32| {
+            let scene = scene2.read().unwrap();
+            let sample_index = unsafe { *backbuffer2.num_samples.get() };
+            for x in 0..width {
+                seed_pixel(x, y, sample_index);
+                let uv = Vec2::new((x as f32 + random32())/width as f32, (y as f32 + random32())/height as f32);
+                let (position, normal) = surface_point(&scene, object, uv).unwrap();
+                let value = match mode {
+                    BakeMode::AmbientOcclusion => tracer::ambient_occlusion(position, normal, &scene),
+                    BakeMode::Irradiance => tracer::irradiance(position, normal, &scene, max_depth),
+                };
+                tracer::accumulate_radiance(&backbuffer2, &scene.camera, x, y, value);
+            }
+        }))
+    };
+
+    //
+    // BAKING
+    //
+    println!("Baking {} into a {}x{} texture.", object, width, height);
+    let bake_time_start = precise_time_ns();
+    let mut progress = Progress::new(Some(num_samples));
+    for sample_index in 0..num_samples {
+        let sample_time_start = precise_time_ns();
+        if sample_index > 0 {
+            unsafe {
+                *backbuffer.num_samples.get() += 1;
+            }
+        }
+        for y in 0..height {
+            worker_pool.process(y);
+        }
+        worker_pool.wait();
+        progress.finish_sample(precise_time_ns() - sample_time_start, (width*height) as usize);
+        progress.print(sample_index + 1 == num_samples);
+    }
+    progress.finish();
+    println!("bake_time = {} s", (precise_time_ns() - bake_time_start) as f64 / 1_000_000_000.0);
+    let num_invalid_samples = backbuffer.take_invalid_samples();
+    if num_invalid_samples > 0 {
+        println!("Dropped {} samples with NaN, infinite or negative radiance.", num_invalid_samples);
+    }
+
+    //
+    // SAVING THE TEXTURE
+    //
+    let result = if output_path.ends_with(".exr") {
+        export::save_exr(&output_path, &backbuffer, num_samples, options.exr_precision, false, None, false, None)
+    } else {
+        let pixels = unsafe { &*backbuffer.pixels8.get() };
+        export::save_png(&output_path, width, height, pixels, None)
+    };
+    result?;
+    println!("Saved the texture to \"{}\".", output_path);
+    Ok(())
+}
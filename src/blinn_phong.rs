@@ -0,0 +1,201 @@
+use std::fs;
+
+use common::*;
+
+use registry::{Bsdf, Properties};
+
+// The energy-normalized Blinn-Phong material of older assets, whose specular color and
+// exponent assume that model, like the Kd, Ks and Ns of OBJ/MTL files. Such assets look too
+// shiny or too dull when their values are put into the physically based material. It is a
+// Lambertian diffuse lobe plus the normalized Blinn-Phong lobe (see brdf_blinn_phong) and is
+// built in as the custom material "blinn_phong" with the names of MTL:
+//
+//   material = blinn_phong { kd = (0.6, 0.6, 0.6) ks = (0.3, 0.3, 0.3) ns = 96.0 }
+//
+// The specular color is optional and black, the exponent defaults to 0. Like the other
+// custom materials, it is sampled by the path tracer, the wavefront integrator and ReSTIR,
+// and the Whitted ray tracer, the GPU and the debug views render it as diffuse with Kd.
+//
+// The materials of an MTL file are mapped with the built-in custom material "mtl", which
+// takes the material of the given name from the file. The path is relative to the working
+// directory like the sky textures:
+//
+//   material = mtl { file = assets/chair.mtl name = Wood }
+//
+// @TODO: There is no OBJ import yet, as there are no meshes. The materials of the objects
+// have to be assigned by their name.
+
+#[derive(Clone, Debug, new)]
+pub struct BlinnPhong {
+    pub diffuse: Vec3,
+    pub specular: Vec3,
+    pub exponent: f32,
+}
+
+impl BlinnPhong {
+    // Kd and Ks of MTL files often add up to more than one, which would reflect more light
+    // than arrives. Both are scaled down by the same factor then, so that the ratio between
+    // the diffuse and the specular reflection stays the same.
+    pub fn from_mtl(kd: Vec3, ks: Vec3, ns: f32) -> BlinnPhong {
+        let total = kd + ks;
+        let max_total = f32::max(total.x, f32::max(total.y, total.z));
+        let scale = if max_total > 1.0 { 1.0/max_total } else { 1.0 };
+        BlinnPhong::new(scale*kd, scale*ks, f32::max(0.0, ns))
+    }
+}
+
+pub fn create(properties: &Properties) -> Result<BlinnPhong, String> {
+    let kd = properties.vector("kd")?;
+    let ks = properties.vector_or("ks", Vec3::zero())?;
+    let ns = properties.number_or("ns", 0.0)?;
+    Ok(BlinnPhong::from_mtl(kd, ks, ns))
+}
+
+pub fn create_from_file(properties: &Properties) -> Result<BlinnPhong, String> {
+    let (path, name) = (properties.text("file")?, properties.text("name")?);
+    let text = fs::read_to_string(path).map_err(|err| format!("Could not read the MTL file \"{}\". Error: {}", path, err))?;
+    parse_mtl(&text)?.into_iter()
+        .find(|&(ref material_name, _)| material_name == name)
+        .map(|(_, material)| material)
+        .ok_or(format!("The MTL file \"{}\" has no material \"{}\".", path, name))
+}
+
+// Reads the Kd, Ks and Ns of every "newmtl" of an MTL file. The other statements, e.g. the
+// textures or the transparency, are ignored. Colors that are left out are black.
+fn parse_mtl(text: &str) -> Result<Vec<(String, BlinnPhong)>, String> {
+    let mut materials = Vec::new();
+    let mut current: Option<(String, Vec3, Vec3, f32)> = None;
+    for (line_index, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        let mut words = line.split_whitespace();
+        let statement = match words.next() {
+            Some(statement) => statement,
+            None => continue,
+        };
+        if statement == "newmtl" {
+            if let Some((name, kd, ks, ns)) = current.take() {
+                materials.push((name, BlinnPhong::from_mtl(kd, ks, ns)));
+            }
+            current = Some((words.collect::<Vec<&str>>().join(" "), Vec3::zero(), Vec3::zero(), 0.0));
+            continue;
+        }
+        let (kd, ks, ns) = match current {
+            Some((_, ref mut kd, ref mut ks, ref mut ns)) => (kd, ks, ns),
+            None => continue,
+        };
+        let numbers: Vec<f32> = words.filter_map(|word| word.parse().ok()).collect();
+        let invalid = || format!("Line {} of the MTL file is invalid: \"{}\"", line_index + 1, line);
+        match statement {
+            "Kd" | "Ks" => {
+                // A single value is the same for all channels.
+                let color = match numbers.len() {
+                    1 => Vec3::new(numbers[0], numbers[0], numbers[0]),
+                    3 => Vec3::new(numbers[0], numbers[1], numbers[2]),
+                    _ => return Err(invalid()),
+                };
+                if statement == "Kd" { *kd = color } else { *ks = color }
+            },
+            "Ns" => *ns = *numbers.first().ok_or_else(invalid)?,
+            _ => (),
+        }
+    }
+    if let Some((name, kd, ks, ns)) = current {
+        materials.push((name, BlinnPhong::from_mtl(kd, ks, ns)));
+    }
+    Ok(materials)
+}
+
+impl Bsdf for BlinnPhong {
+    // One of the lobes is chosen with the probability of its luminance. The pdf is the one of
+    // both lobes together, so that the weight does not depend on the chosen one.
+    fn sample(&self, outgoing: Vec3, normal: Vec3, xi: Vec2) -> Option<(Vec3, Vec3)> {
+        let (diffuse_luminance, specular_luminance) = (luminance(self.diffuse), luminance(self.specular));
+        if diffuse_luminance + specular_luminance <= 0.0 {
+            return None;
+        }
+        let specular_probability = specular_luminance/(diffuse_luminance + specular_luminance);
+
+        let basis = construct_coordinate_system(normal);
+        let light = if xi.x < specular_probability {
+            let xi = Vec2::new(xi.x/specular_probability, xi.y);
+            let half = to_basis(basis, importance_sample_blinn_phong(xi, self.exponent)).normalize();
+            reflect(-outgoing, half).normalize()
+        } else {
+            let xi = Vec2::new((xi.x - specular_probability)/(1.0 - specular_probability), xi.y);
+            to_basis(basis, importance_sample_cos(xi)).normalize()
+        };
+
+        let cos_theta = light.dot(normal);
+        if cos_theta <= 0.0 {
+            return None;
+        }
+        let pdf = specular_probability*pdf_blinn_phong(outgoing, light, normal, self.exponent)
+            + (1.0 - specular_probability)*cos_theta/PI;
+        if pdf <= 0.0 {
+            return None;
+        }
+        let brdf = self.diffuse/PI + brdf_blinn_phong(outgoing, light, normal, self.specular, self.exponent);
+        Some((light, brdf*(cos_theta/pdf)))
+    }
+
+    fn albedo(&self) -> Vec3 {
+        self.diffuse
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blinn_phong_reflects_about_as_much_light_as_arrives() {
+        let normal = Vec3::new(0.0, 1.0, 0.0);
+        let average_weight = |bsdf: &BlinnPhong| {
+            const N: usize = 64;
+            let mut sum = Vec3::zero();
+            for i in 0..N {
+                for j in 0..N {
+                    let xi = Vec2::new((i as f32 + 0.5)/N as f32, (j as f32 + 0.5)/N as f32);
+                    if let Some((light, weight)) = bsdf.sample(normal, normal, xi) {
+                        assert!(light.dot(normal) > 0.0);
+                        sum = sum + weight;
+                    }
+                }
+            }
+            sum/(N*N) as f32
+        };
+
+        // The cosine-distributed diffuse lobe cancels with its pdf.
+        let diffuse = average_weight(&BlinnPhong::from_mtl(Vec3::new(0.5, 0.5, 0.5), Vec3::zero(), 32.0));
+        assert!((diffuse - Vec3::new(0.5, 0.5, 0.5)).length() < 1.0e-4, "{:?}", diffuse);
+
+        // The normalization keeps the specular lobe close to one for any exponent.
+        for &exponent in [8.0, 32.0, 256.0].iter() {
+            let specular = average_weight(&BlinnPhong::from_mtl(Vec3::zero(), Vec3::one(), exponent));
+            assert!(specular.x > 0.85 && specular.x < 1.15, "{} {:?}", exponent, specular);
+        }
+
+        // Kd and Ks above one are scaled down together.
+        let bright = BlinnPhong::from_mtl(Vec3::new(0.8, 0.8, 0.8), Vec3::new(0.4, 0.4, 0.4), 10.0);
+        assert!((bright.diffuse + bright.specular - Vec3::one()).length() < 1.0e-4);
+        assert!(f32::abs(bright.diffuse.x - 2.0*bright.specular.x) < 1.0e-4);
+    }
+
+    #[test]
+    fn mtl_files_are_mapped_by_the_name_of_the_material() {
+        let text = "# Exported\nnewmtl Wood\nKd 0.6 0.4 0.2\nKs 0.1\nNs 32 # Highlight\nmap_Kd wood.png\n\nnewmtl Dark Metal\nKs 0.5 0.5 0.5\n";
+        let materials = parse_mtl(text).unwrap();
+        assert_eq!(materials.len(), 2);
+        let (ref wood_name, ref wood) = materials[0];
+        assert_eq!(wood_name, "Wood");
+        assert_eq!((wood.diffuse.x, wood.diffuse.y, wood.diffuse.z), (0.6, 0.4, 0.2));
+        assert_eq!((wood.specular.x, wood.specular.y, wood.specular.z), (0.1, 0.1, 0.1));
+        assert_eq!(wood.exponent, 32.0);
+        let (ref metal_name, ref metal) = materials[1];
+        assert_eq!(metal_name, "Dark Metal");
+        assert_eq!(metal.diffuse.x, 0.0);
+        assert_eq!(metal.exponent, 0.0);
+
+        assert!(parse_mtl("newmtl Broken\nKd 0.5 0.5\n").is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use std::sync::Arc;
+
+use common::*;
+
+use scene::{Scene, Sky, Medium, Material, Sphere, Plane, PBRParameters, ObjectId, Transform};
+use tracer::{ImageSettings, Camera, ToneMapping};
+use volume::Volume;
+use blinn_phong::BlinnPhong;
+
+// Builds scenes from code instead of a scene file, e.g. for the demo scenes or procedurally
+// generated ones. Objects are added to the innermost node that was begun and not ended yet,
+// and their geometry is relative to that node.
+//
+//   let scene = SceneBuilder::new(512, 512)
+//       .camera(Vec3::new(0.0, 2.0, 10.0), Vec3::zero(), 40.0)
+//       .sphere(Vec3::new(0.0, 1.0, 0.0), 1.0, diffuse(Vec3::new(0.8, 0.2, 0.2)))
+//       .build();
+pub struct SceneBuilder {
+    scene: Scene,
+    node_stack: Vec<usize>,
+}
+
+impl SceneBuilder {
+    pub fn new(width: usize, height: usize) -> Self {
+        let mut scene = Scene::default();
+        scene.image_settings = ImageSettings::new(width, height, true);
+        SceneBuilder {
+            scene: scene,
+            node_stack: Vec::new(),
+        }
+    }
+
+    // Places the camera with the vertical field of view in degrees. The projection plane is
+    // at a distance of one unit, nothing closer to the camera is visible.
+    pub fn camera(mut self, position: Vec3, target: Vec3, vertical_fov: f32) -> Self {
+        let aspect_ratio = self.scene.image_settings.width as f32 / self.scene.image_settings.height as f32;
+        let height = 2.0*f32::tan(0.5*vertical_fov*PI/180.0);
+        let tone_mapping = self.scene.camera.tone_mapping();
+        self.scene.camera = Camera::new(position, target, Vec3::new(0.0, 1.0, 0.0), aspect_ratio*height, height, 1.0, tone_mapping, 100.0);
+        self
+    }
+
+    pub fn full_camera(mut self, camera: Camera) -> Self {
+        self.scene.camera = camera;
+        self
+    }
+
+    pub fn tone_mapping(mut self, tone_mapping: ToneMapping) -> Self {
+        self.scene.camera.set_tone_mapping(tone_mapping);
+        self
+    }
+
+    pub fn sky(mut self, sky: Sky) -> Self {
+        self.scene.sky = sky;
+        self
+    }
+
+    pub fn medium(mut self, medium: Medium) -> Self {
+        self.scene.medium = Some(medium);
+        self
+    }
+
+    pub fn sphere(mut self, origin: Vec3, radius: f32, material: Material) -> Self {
+        let id = self.scene.add_sphere(Sphere::new(origin, radius, material));
+        self.attach(id);
+        self
+    }
+
+    pub fn plane(mut self, origin: Vec3, u: Vec3, v: Vec3, material: Material) -> Self {
+        let id = self.scene.add_plane(Plane::new(origin, u, v, material));
+        self.attach(id);
+        self
+    }
+
+    pub fn volume(mut self, volume: Volume) -> Self {
+        let id = self.scene.add_volume(volume);
+        self.attach(id);
+        self
+    }
+
+    // A rectangle centered at the position facing the direction of the normal.
+    pub fn quad(self, center: Vec3, normal: Vec3, width: f32, height: f32, material: Material) -> Self {
+        let (origin, u, v) = quad_edges(center, normal, width, height);
+        self.plane(origin, u, v, material)
+    }
+
+    pub fn begin_node(mut self, transform: Transform) -> Self {
+        let parent = self.node_stack.last().cloned();
+        let node = self.scene.add_node(parent, transform);
+        self.node_stack.push(node);
+        self
+    }
+
+    pub fn end_node(mut self) -> Self {
+        self.node_stack.pop();
+        self
+    }
+
+    pub fn build(mut self) -> Scene {
+        self.scene.update_nodes();
+        self.scene
+    }
+
+    fn attach(&mut self, id: ObjectId) {
+        if let Some(&node) = self.node_stack.last() {
+            self.scene.attach(node, id);
+        }
+    }
+}
+
+// Returns the origin and the edges of the plane of a rectangle centered at the position
+// facing the direction of the normal. The edges follow the axes of the world as far as
+// possible.
+pub fn quad_edges(center: Vec3, normal: Vec3, width: f32, height: f32) -> (Vec3, Vec3, Vec3) {
+    let normal = normal.normalize();
+    let helper = if f32::abs(normal.y) > 0.9999 { Vec3::new(0.0, 0.0, -1.0) } else { Vec3::new(0.0, 1.0, 0.0) };
+    let u = helper.cross(normal).normalize();
+    let v = normal.cross(u);
+    let (u, v) = (width*u, height*v);
+    (center - 0.5*u - 0.5*v, u, v)
+}
+
+//
+// MATERIALS
+//
+
+pub fn diffuse(color: Vec3) -> Material {
+    Material::Physically(PBRParameters {
+        reflectivity: color,
+        roughness: 1.0,
+        metalness: 0.0,
+    })
+}
+
+pub fn plastic(color: Vec3, roughness: f32) -> Material {
+    Material::Physically(PBRParameters {
+        reflectivity: color,
+        roughness: roughness,
+        metalness: 0.0,
+    })
+}
+
+pub fn metal(color: Vec3, roughness: f32) -> Material {
+    Material::Physically(PBRParameters {
+        reflectivity: color,
+        roughness: roughness,
+        metalness: 1.0,
+    })
+}
+
+pub fn glass(ior: f32) -> Material {
+    Material::Translucent(ior, 0.0, 0)
+}
+
+// Glass that splits white light into its colors, with the dispersion of cauchy_ior.
+pub fn dispersive_glass(ior: f32, dispersion: f32) -> Material {
+    Material::Translucent(ior, dispersion, 0)
+}
+
+// The legacy material of OBJ/MTL files with their Kd, Ks and Ns (see blinn_phong.rs).
+pub fn blinn_phong(kd: Vec3, ks: Vec3, ns: f32) -> Material {
+    Material::Custom(Arc::new(BlinnPhong::from_mtl(kd, ks, ns)))
+}
+
+pub fn light(radiance: Vec3) -> Material {
+    Material::Emissive(radiance)
+}
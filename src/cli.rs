@@ -0,0 +1,366 @@
+use std;
+
+use scene::{Scene, ObjectId};
+
+use clap::{App, Arg, ArgMatches};
+use log::LevelFilter;
+use env_logger;
+
+use tracer::{Integrator, ToneMapping, Stereo, StereoLayout};
+use tiles::{self, TileOrder, Region};
+use denoise::DenoiserType;
+use export::ExrPrecision;
+use postprocess::PostProcessing;
+use demo::DEMO_NAMES;
+use config;
+
+// What "--bake" bakes into the texture (see bake.rs). The mode is part of the options
+// also in the browser, which does not bake.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BakeMode {
+    AmbientOcclusion,
+    Irradiance,
+}
+
+impl BakeMode {
+    pub fn parse(name: &str) -> Option<BakeMode> {
+        match name {
+            "ao" => Some(BakeMode::AmbientOcclusion),
+            "irradiance" => Some(BakeMode::Irradiance),
+            _ => None,
+        }
+    }
+}
+
+// The settings of one run of the program. The scene file describes the image, these only
+// control how it is rendered and where the result goes. Options like the resolution
+// override the values of the scene file when they are given.
+//
+// The defaults can be changed in the config file (see config.rs). The command line takes
+// precedence over it.
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub scene_path: String,
+    pub demo: Option<String>, // Replaces the scene file
+    pub width: Option<usize>,
+    pub height: Option<usize>,
+    pub samples_per_pixel: Option<usize>, // Unlimited in the window when None
+    pub max_time: Option<f64>, // Seconds per image in the headless mode
+    pub max_depth: u8,
+    pub tile_size: u32,
+    pub output_path: Option<String>,
+    pub num_threads: usize,
+    pub seed: Option<u32>,
+    pub integrator: Integrator,
+    pub tile_order: TileOrder,
+    pub preview_scale: u32,
+    pub crop: Option<Region>, // Only this part of the image is rendered
+    pub check_radiance: bool, // Highlights pixels with NaN, infinite or negative samples
+    pub debug_pixel: Option<(u32, u32)>, // Traced with verbose logging instead of rendering
+    pub tone_mapping: Option<ToneMapping>, // Overrides the camera of the scene file
+    pub post_processing: PostProcessing,
+    pub stereo: Option<StereoLayout>, // Overrides the image of the scene file, like the distances
+    pub interocular_distance: Option<f32>,
+    pub convergence_distance: Option<f32>,
+    pub denoiser: DenoiserType,
+    pub denoise: bool, // Shows the denoised image from the start
+    pub exr_precision: ExrPrecision,
+    pub exr_aovs: bool,
+    pub exr_mattes: bool,
+    pub worker_address: Option<String>,
+    pub serve_address: Option<String>,
+    pub headless: bool,
+    pub bake: Option<ObjectId>, // Bakes the object into a texture instead of rendering the image
+    pub bake_mode: BakeMode,
+    pub animate: bool,
+    pub frame_range: Option<(usize, usize)>,
+    pub turntable: Option<usize>, // Number of frames of one orbit of the camera
+    pub fps: u32,
+    pub print_stats: bool, // Prints the statistics of the scene after loading it
+    pub memory_budget: Option<usize>, // MiB that all density grids together may take
+}
+
+const DEFAULT_TURNTABLE_FRAMES: usize = 120;
+
+fn exit_with(message: String) -> ! {
+    println!("{}", message);
+    std::process::exit(1);
+}
+
+// Without -v only warnings are logged, so that the output stays the same as without the
+// logging. RUST_LOG overrides the level, e.g. "RUST_LOG=pathtracer::network=debug".
+fn init_logging(verbosity: u64) {
+    let level = match verbosity {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(level).format_timestamp(None);
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse_filters(&filters);
+    }
+    builder.init();
+}
+
+fn parse_value<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    matches.value_of(name).map(|value| value.parse::<T>().unwrap_or_else(|_| {
+        exit_with(format!("Invalid value \"{}\" for \"--{}\".", value, name))
+    }))
+}
+
+fn check_min<T: PartialOrd + std::fmt::Display>(value: T, name: &str, min: T) -> T {
+    if value < min {
+        exit_with(format!("\"--{}\" is {} but has to be at least {}.", name, value, min));
+    }
+    value
+}
+
+fn check_range<T: PartialOrd + std::fmt::Display>(value: T, name: &str, min: T, max: T) -> T {
+    if value < min || value > max {
+        exit_with(format!("\"--{}\" is {} but has to be between {} and {}.", name, value, min, max));
+    }
+    value
+}
+
+// The defaults without a config file. The window does not limit the samples per pixel and
+// the headless mode renders 64 when they are not given.
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            scene_path: String::from("scenes/sample/sample.scene"),
+            demo: None,
+            width: None,
+            height: None,
+            samples_per_pixel: None,
+            max_time: None,
+            max_depth: 4,
+            tile_size: tiles::DEFAULT_TILE_SIZE,
+            output_path: None,
+            num_threads: 8,
+            seed: None,
+            integrator: Integrator::PathTracer,
+            tile_order: TileOrder::Spiral,
+            preview_scale: 4,
+            crop: None,
+            check_radiance: false,
+            debug_pixel: None,
+            tone_mapping: None,
+            post_processing: PostProcessing::default(),
+            stereo: None,
+            interocular_distance: None,
+            convergence_distance: None,
+            denoiser: DenoiserType::Oidn,
+            denoise: false,
+            exr_precision: ExrPrecision::Full,
+            exr_aovs: false,
+            exr_mattes: false,
+            worker_address: None,
+            serve_address: None,
+            headless: false,
+            bake: None,
+            bake_mode: BakeMode::AmbientOcclusion,
+            animate: false,
+            frame_range: None,
+            turntable: None,
+            fps: 24,
+            print_stats: false,
+            memory_budget: None,
+        }
+    }
+}
+
+impl Options {
+    // Applies the options that replace values of the scene file. Is called whenever a
+    // scene is loaded.
+    pub fn apply_to_scene(&self, scene: &mut Scene) {
+        let width = self.width.unwrap_or(scene.image_settings.width);
+        let height = self.height.unwrap_or(scene.image_settings.height);
+        scene.set_resolution(width, height);
+        if let Some(tone_mapping) = self.tone_mapping {
+            scene.camera.set_tone_mapping(tone_mapping);
+        }
+        // The distances also apply to the stereo layout of the scene file.
+        let stereo = scene.image_settings.stereo;
+        if let Some(layout) = self.stereo.or(stereo.map(|stereo| stereo.layout)) {
+            scene.image_settings.stereo = Some(Stereo::with_defaults(layout,
+                self.interocular_distance.or(stereo.map(|stereo| stereo.interocular_distance)),
+                self.convergence_distance.or(stereo.map(|stereo| stereo.convergence_distance))));
+        }
+    }
+}
+
+pub fn parse() -> Options {
+    // Values are given as "--name=value" or "--name value".
+    let matches = App::new("pathtracer")
+        .about("Progressive path tracer with an interactive window and a headless mode.")
+        .arg(Arg::with_name("config").long("config").takes_value(true).value_name("PATH")
+            .help("Config file with the default settings [default: pathtracer.toml if it exists]"))
+        .arg(Arg::with_name("scene").long("scene").alias("path").takes_value(true).value_name("PATH")
+            .help("Scene file in the native, RON or JSON format [default: scenes/sample/sample.scene]"))
+        .arg(Arg::with_name("demo").long("demo").takes_value(true).possible_values(DEMO_NAMES)
+            .help("Renders a built-in scene instead of the scene file"))
+        .arg(Arg::with_name("width").long("width").takes_value(true).help("Overrides the width of the scene file"))
+        .arg(Arg::with_name("height").long("height").takes_value(true).help("Overrides the height of the scene file"))
+        .arg(Arg::with_name("spp").long("spp").aliases(&["samples", "max-samples"]).takes_value(true)
+            .help("Samples per pixel. Unlimited in the window, 64 in the headless mode by default"))
+        .arg(Arg::with_name("max-time").long("max-time").takes_value(true).value_name("SECONDS")
+            .help("Stops the headless mode after the time per image, or at --spp if given as well"))
+        .arg(Arg::with_name("max-depth").long("max-depth").takes_value(true).help("Maximum number of bounces [default: 4]"))
+        .arg(Arg::with_name("output").long("output").takes_value(true).value_name("PATH")
+            .help("PNG, EXR or video file that is written when the rendering is finished"))
+        .arg(Arg::with_name("tile-size").long("tile-size").takes_value(true).help("Width and height of the tiles in pixels, between 4 and 512 [default: 32]"))
+        .arg(Arg::with_name("threads").long("threads").takes_value(true).help("Number of worker threads [default: 8]"))
+        .arg(Arg::with_name("seed").long("seed").takes_value(true).help("Seed of the random number generator"))
+        .arg(Arg::with_name("integrator").long("integrator").takes_value(true)
+            .possible_values(&["path", "guided", "whitted", "restir", "gpu", "wavefront", "fastgi", "normals", "depth", "ao", "heatmap"]))
+        .arg(Arg::with_name("tile-order").long("tile-order").takes_value(true).possible_values(&["scanline", "spiral", "hilbert"]))
+        .arg(Arg::with_name("crop").long("crop").takes_value(true).value_name("X,Y,WIDTH,HEIGHT")
+            .help("Renders only a region of the image, in pixels from the top left corner"))
+        .arg(Arg::with_name("check-radiance").long("check-radiance")
+            .help("Paints pixels magenta that received NaN, infinite or negative radiance"))
+        .arg(Arg::with_name("debug-pixel").long("debug-pixel").takes_value(true).number_of_values(2).value_names(&["X", "Y"])
+            .help("Traces one pixel with a log of every bounce and exits, from the top left corner. Draws its paths into --output"))
+        .arg(Arg::with_name("preview-scale").long("preview-scale").takes_value(true).possible_values(&["1", "2", "4", "8"]))
+        .arg(Arg::with_name("tone-mapping").long("tone-mapping").takes_value(true)
+            .possible_values(&["clamp", "reinhard", "aces", "exposure"]).help("Overrides the tone mapping of the camera"))
+        .arg(Arg::with_name("bloom").long("bloom").takes_value(true).value_name("STRENGTH")
+            .help("Spreads the radiance above --bloom-threshold over the neighbouring pixels, e.g. 0.5"))
+        .arg(Arg::with_name("bloom-threshold").long("bloom-threshold").takes_value(true).value_name("LUMINANCE")
+            .help("Luminance above which the radiance blooms or flares [default: 1]"))
+        .arg(Arg::with_name("lens-flare").long("lens-flare").takes_value(true).value_name("STRENGTH")
+            .help("Adds ghosts of the bright parts mirrored through the center of the image, e.g. 0.2"))
+        .arg(Arg::with_name("vignette").long("vignette").takes_value(true).value_name("STRENGTH")
+            .help("Darkens the corners of the image, from 0 to 1"))
+        .arg(Arg::with_name("stereo").long("stereo").takes_value(true).possible_values(&["side-by-side", "side_by_side", "anaglyph"])
+            .help("Renders the left and the right eye"))
+        .arg(Arg::with_name("interocular").long("interocular").takes_value(true).value_name("DISTANCE")
+            .help("Distance between the eyes in the units of the scene [default: 0.065]"))
+        .arg(Arg::with_name("convergence").long("convergence").takes_value(true).value_name("DISTANCE")
+            .help("Distance that appears at the depth of the screen [default: 30 times the interocular distance]"))
+        .arg(Arg::with_name("denoiser").long("denoiser").takes_value(true).possible_values(&["oidn", "atrous"]))
+        .arg(Arg::with_name("denoise").long("denoise").help("Shows the denoised image from the start"))
+        .arg(Arg::with_name("exr-half").long("exr-half").help("Writes EXR files with 16-bit floats"))
+        .arg(Arg::with_name("exr-aovs").long("exr-aovs").help("Adds the albedo, normal, depth and object ID to EXR files"))
+        .arg(Arg::with_name("exr-mattes").long("exr-mattes").help("Adds the coverage of the objects in every pixel to EXR files, for selecting them in compositing"))
+        .arg(Arg::with_name("worker").long("worker").takes_value(true).value_name("ADDRESS").help("Renders tiles for a master"))
+        .arg(Arg::with_name("serve").long("serve").takes_value(true).value_name("ADDRESS").help("Accepts remote workers"))
+        .arg(Arg::with_name("headless").long("headless").help("Renders without a window and exits"))
+        .arg(Arg::with_name("bake").long("bake").takes_value(true).value_name("OBJECT")
+            .help("Bakes the lighting of a sphere or a plane, e.g. \"plane:0\", into a texture of --width x --height [default: 1024]"))
+        .arg(Arg::with_name("bake-mode").long("bake-mode").takes_value(true).possible_values(&["ao", "irradiance"]))
+        .arg(Arg::with_name("animate").long("animate").help("Renders all frames of the animation in the headless mode"))
+        .arg(Arg::with_name("turntable").long("turntable").takes_value(true).min_values(0).value_name("frames=N")
+            .help("Renders the frames of one orbit of the camera around the center of the scene in the headless mode [default: frames=120]"))
+        .arg(Arg::with_name("frames").long("frames").takes_value(true).value_name("FIRST-LAST").help("Range of frames to render"))
+        .arg(Arg::with_name("fps").long("fps").takes_value(true).help("Frame rate of videos [default: 24]"))
+        .arg(Arg::with_name("memory-budget").long("memory-budget").takes_value(true).value_name("MIB")
+            .help("Lowers the resolution of the density grids that do not fit into the memory left by the others"))
+        .arg(Arg::with_name("stats").long("stats").help("Prints the primitives, their overlap and their memory after loading the scene"))
+        .arg(Arg::with_name("verbose").short("v").multiple(true).help("Logs what is loaded (-v) and the timings of every pass (-vv)"))
+        .get_matches();
+
+    init_logging(matches.occurrences_of("verbose"));
+
+    let frame_range = matches.value_of("frames").map(|range| {
+        let bounds: Vec<Option<usize>> = range.split('-').map(|bound| bound.parse::<usize>().ok()).collect();
+        match bounds.as_slice() {
+            &[Some(first), Some(last)] if first <= last => (first, last),
+            _ => exit_with(format!("Invalid frame range \"{}\". Expected \"first-last\".", range)),
+        }
+    });
+
+    let turntable = if matches.is_present("turntable") {
+        Some(match matches.value_of("turntable") {
+            Some(value) => match value.trim_start_matches("frames=").parse::<usize>() {
+                Ok(num_frames) if num_frames > 0 => num_frames,
+                _ => exit_with(format!("Invalid value \"{}\" for \"--turntable\". Expected \"frames=N\".", value)),
+            },
+            None => DEFAULT_TURNTABLE_FRAMES,
+        })
+    } else {
+        None
+    };
+
+    let crop = matches.value_of("crop").map(|region| {
+        let values: Vec<Option<u32>> = region.split(',').map(|value| value.trim().parse::<u32>().ok()).collect();
+        match values.as_slice() {
+            &[Some(x), Some(y), Some(width), Some(height)] if width > 0 && height > 0 => Region::new(x, y, width, height),
+            _ => exit_with(format!("Invalid crop region \"{}\". Expected \"x,y,width,height\".", region)),
+        }
+    });
+
+    let debug_pixel = matches.values_of("debug-pixel").map(|values| {
+        let values: Vec<&str> = values.collect();
+        match (values[0].parse::<u32>(), values[1].parse::<u32>()) {
+            (Ok(x), Ok(y)) => (x, y),
+            _ => exit_with(format!("Invalid pixel \"{} {}\" for \"--debug-pixel\". Expected \"x y\".", values[0], values[1])),
+        }
+    });
+
+    let bake = matches.value_of("bake").map(|object| {
+        ObjectId::parse(object).unwrap_or_else(|| exit_with(format!("Invalid object \"{}\" for \"--bake\". Expected e.g. \"sphere:0\".", object)))
+    });
+
+    let config = match matches.value_of("config") {
+        Some(path) => config::load(path, true),
+        None => config::load(config::DEFAULT_CONFIG_PATH, false),
+    }.unwrap_or_else(|err| exit_with(err.to_string()));
+
+    // The config file only changes the exposure when the exposure operator is selected on
+    // the command line as well.
+    let tone_mapping = matches.value_of("tone-mapping").and_then(ToneMapping::parse).map(|tone_mapping| {
+        match (tone_mapping, config.tone_mapping) {
+            (ToneMapping::Exposure(_), Some(ToneMapping::Exposure(exposure))) => ToneMapping::Exposure(exposure),
+            (tone_mapping, _) => tone_mapping,
+        }
+    }).or(config.tone_mapping);
+
+    let defaults = Options::default();
+    Options {
+        scene_path: matches.value_of("scene").map(String::from).unwrap_or(defaults.scene_path),
+        demo: matches.value_of("demo").map(String::from),
+        width: parse_value(&matches, "width"),
+        height: parse_value(&matches, "height"),
+        samples_per_pixel: parse_value(&matches, "spp").map(|spp| check_min(spp, "spp", 1)).or(config.samples_per_pixel),
+        max_time: parse_value(&matches, "max-time").map(|seconds| check_min(seconds, "max-time", 0.0)),
+        max_depth: check_min(parse_value(&matches, "max-depth").or(config.max_depth).unwrap_or(defaults.max_depth), "max-depth", 1),
+        tile_size: check_range(parse_value(&matches, "tile-size").or(config.tile_size).unwrap_or(defaults.tile_size), "tile-size", tiles::MIN_TILE_SIZE, tiles::MAX_TILE_SIZE),
+        output_path: matches.value_of("output").map(String::from),
+        num_threads: check_min(parse_value(&matches, "threads").or(config.num_threads).unwrap_or(defaults.num_threads), "threads", 1),
+        seed: parse_value(&matches, "seed"),
+        // The possible values are checked by clap.
+        integrator: matches.value_of("integrator").and_then(Integrator::parse).or(config.integrator).unwrap_or(defaults.integrator),
+        tile_order: matches.value_of("tile-order").and_then(TileOrder::parse).or(config.tile_order).unwrap_or(defaults.tile_order),
+        preview_scale: parse_value(&matches, "preview-scale").or(config.preview_scale).unwrap_or(defaults.preview_scale),
+        crop: crop,
+        check_radiance: matches.is_present("check-radiance"),
+        debug_pixel: debug_pixel,
+        tone_mapping: tone_mapping,
+        post_processing: PostProcessing::new(
+            parse_value(&matches, "bloom").map(|strength| check_min(strength, "bloom", 0.0)).unwrap_or(defaults.post_processing.bloom),
+            parse_value(&matches, "bloom-threshold").map(|threshold| check_min(threshold, "bloom-threshold", 0.0)).unwrap_or(defaults.post_processing.bloom_threshold),
+            parse_value(&matches, "vignette").map(|strength| check_min(strength, "vignette", 0.0)).unwrap_or(defaults.post_processing.vignette),
+            parse_value(&matches, "lens-flare").map(|strength| check_min(strength, "lens-flare", 0.0)).unwrap_or(defaults.post_processing.lens_flare)),
+        stereo: matches.value_of("stereo").and_then(StereoLayout::parse),
+        interocular_distance: parse_value(&matches, "interocular"),
+        convergence_distance: parse_value(&matches, "convergence"),
+        denoiser: matches.value_of("denoiser").and_then(DenoiserType::parse).or(config.denoiser).unwrap_or(defaults.denoiser),
+        denoise: matches.is_present("denoise") || config.denoise.unwrap_or(defaults.denoise),
+        exr_precision: if matches.is_present("exr-half") { ExrPrecision::Half } else { defaults.exr_precision },
+        exr_aovs: matches.is_present("exr-aovs"),
+        exr_mattes: matches.is_present("exr-mattes"),
+        worker_address: matches.value_of("worker").map(String::from),
+        serve_address: matches.value_of("serve").map(String::from),
+        headless: matches.is_present("headless"),
+        bake: bake,
+        bake_mode: matches.value_of("bake-mode").and_then(BakeMode::parse).unwrap_or(defaults.bake_mode),
+        animate: frame_range.is_some() || matches.is_present("animate"),
+        frame_range: frame_range,
+        turntable: turntable,
+        fps: parse_value(&matches, "fps").unwrap_or(defaults.fps),
+        print_stats: matches.is_present("stats"),
+        memory_budget: parse_value(&matches, "memory-budget").map(|mib| check_min(mib, "memory-budget", 1)),
+    }
+}
@@ -1,88 +1,105 @@
+// The math of the renderer lives in the pathtracer-core crate, which builds without std
+// (see core/src/lib.rs). Only the state that is shared by the threads is kept here.
 use std;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU32, Ordering};
 
-pub use hmath::*;
+#[cfg(not(target_arch = "wasm32"))]
+use time;
+#[cfg(target_arch = "wasm32")]
+use web_sys;
 
-pub const PI: f32 = std::f32::consts::PI;
+pub use pathtracer_core::*;
 
-pub type Vec2 = Vector2<f32>;
-pub type Vec3 = Vector3<f32>;
+// The seed without "--seed".
+pub const DEFAULT_SEED: u32 = 314159265;
 
-pub type Vec2u = Vector2<u32>;
+// Every thread has its own generator, which is seeded again for every sample of a pixel from
+// the indices of the pixel and of the sample (see seed_pixel). The random numbers of a pixel
+// therefore do not depend on which thread renders it or in which order, and renders with the
+// same seed are identical to the bit.
+static SEED: AtomicU32 = AtomicU32::new(DEFAULT_SEED);
 
-#[derive(Clone, Debug)]
-pub struct Ray {
-    pub origin: Vec3,
-    pub direction: Vec3,
+thread_local! {
+    static RANDOM: Cell<XorShift32> = Cell::new(XorShift32::new(DEFAULT_SEED));
 }
 
-impl Ray {
-    pub fn new(origin: Vec3, direction: Vec3) -> Self {
-        Ray {
-            origin: origin,
-            direction: direction,
-        }
-    }
+pub fn xorshift32() -> u32 {
+    RANDOM.with(|random| {
+        let mut generator = random.get();
+        let r = generator.next_u32();
+        random.set(generator);
+        r
+    })
 }
 
-// Source: https://de.wikipedia.org/wiki/Xorshift
-static mut X32: u32 = 314159265;
-pub fn xorshift32() -> u32 {
-    unsafe { 
-        X32 ^= X32 << 13;
-        X32 ^= X32 >> 17;
-        X32 ^= X32 << 5;
-        X32
-    }
+// Seeds the streams of all pixels and the generator of the calling thread, which is used
+// outside of the rendering, e.g. by the scripts.
+pub fn seed_random(seed: u32) {
+    SEED.store(seed, Ordering::Relaxed);
+    RANDOM.with(|random| random.set(XorShift32::new(seed)));
 }
 
-pub fn random32() -> f32 {
-    let r = xorshift32();
-    r as f32 / std::u32::MAX as f32
+// Source: Chris Wellons, "Prospecting for Hash Functions", 2018 (lowbias32)
+// https://nullprogram.com/blog/2018/07/31/
+pub fn hash_u32(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^= x >> 16;
+    x
 }
 
-#[allow(dead_code)]
-pub fn clampf32(min: f32, max: f32, x: f32) -> f32 {
-    if x > max {
-        max
-    } else if x < min {
-        min
-    } else {
-        x
+// Source: Austin Appleby, MurmurHash3_x86_32
+// https://github.com/aappleby/smhasher/blob/master/src/MurmurHash3.cpp
+// Cryptomatte hashes the names of the objects and of the layers with it (see matte_id).
+pub fn murmur_hash3_32(bytes: &[u8], seed: u32) -> u32 {
+    let scramble = |k: u32| k.wrapping_mul(0xcc9e2d51).rotate_left(15).wrapping_mul(0x1b873593);
+    let mut hash = seed;
+    let mut blocks = bytes.chunks_exact(4);
+    for block in &mut blocks {
+        hash ^= scramble(u32::from_le_bytes([block[0], block[1], block[2], block[3]]));
+        hash = hash.rotate_left(13).wrapping_mul(5).wrapping_add(0xe6546b64);
     }
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        let k = tail.iter().enumerate().fold(0, |k, (i, &byte)| k | (byte as u32) << (8*i));
+        hash ^= scramble(k);
+    }
+    hash ^= bytes.len() as u32;
+    hash ^= hash >> 16;
+    hash = hash.wrapping_mul(0x85ebca6b);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(0xc2b2ae35);
+    hash ^= hash >> 16;
+    hash
 }
 
-#[allow(dead_code)]
-pub fn saturatef32(x: f32) -> f32 {
-    clampf32(0.0, 1.0, x)
+// Starts the stream of random numbers of one sample of a pixel on the calling thread. The
+// hash decorrelates the streams of neighbouring pixels and samples.
+pub fn seed_pixel(x: u32, y: u32, sample_index: usize) {
+    let seed = hash_u32(SEED.load(Ordering::Relaxed) ^ hash_u32(x ^ hash_u32(y ^ hash_u32(sample_index as u32))));
+    RANDOM.with(|random| random.set(XorShift32::new(seed)));
 }
 
-#[allow(dead_code)]
-pub fn saturate_vec3(v: Vec3) -> Vec3 {
-    Vec3::new(
-        clampf32(0.0, 1.0, v.x),
-        clampf32(0.0, 1.0, v.y),
-        clampf32(0.0, 1.0, v.z),
-    )
+pub fn random32() -> f32 {
+    let r = xorshift32();
+    r as f32 / std::u32::MAX as f32
 }
 
-pub fn mix_vec3(a: Vec3, b: Vec3, x: f32) -> Vec3 {
-    (1.0 - x)*a + x*b
+// The clock for measuring durations. The browser has no monotonic clock of the system,
+// therefore the one of the page is used there, which counts in milliseconds since the
+// page was loaded.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn precise_time_ns() -> u64 {
+    time::precise_time_ns()
 }
 
-#[derive(Clone, Debug)]
-pub struct Pixel8(pub u8, pub u8, pub u8);
-
-#[derive(Clone, Debug)]
-pub struct Pixel32(pub u32, pub u32, pub u32);
-
-impl Pixel32 {
-    pub fn from_unit(color: Vec3) -> Self {
-        Pixel32((color.x*255.0) as u32, (color.y*255.0) as u32, (color.z*255.0) as u32)
+#[cfg(target_arch = "wasm32")]
+pub fn precise_time_ns() -> u64 {
+    match web_sys::window().and_then(|window| window.performance()) {
+        Some(performance) => (performance.now()*1_000_000.0) as u64,
+        None => 0,
     }
 }
-
-#[allow(dead_code)]
-pub fn is_normalized(v: Vec3) -> bool {
-    let v_len = v.length();
-    v_len > 0.99 && v_len < 1.01
-}
\ No newline at end of file
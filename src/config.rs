@@ -0,0 +1,126 @@
+use std;
+use std::fs;
+
+use toml;
+
+use tracer::{Integrator, ToneMapping};
+use tiles::{self, TileOrder};
+use denoise::DenoiserType;
+use error::Error;
+
+// Persistent settings of the renderer in a TOML file. The file is loaded at startup and its
+// values replace the defaults of the command-line options. Options given on the command
+// line still take precedence. All entries are optional:
+//
+//   spp = 256
+//   max_depth = 6
+//   tile_size = 32
+//   threads = 16
+//   integrator = "guided"
+//   tile_order = "hilbert"
+//   preview_scale = 2
+//   tone_mapping = "exposure"
+//   exposure = 1.5
+//   denoiser = "atrous"
+//   denoise = true
+
+pub const DEFAULT_CONFIG_PATH: &'static str = "pathtracer.toml";
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    spp: Option<usize>,
+    max_depth: Option<u8>,
+    tile_size: Option<u32>,
+    threads: Option<usize>,
+    integrator: Option<String>,
+    tile_order: Option<String>,
+    preview_scale: Option<u32>,
+    tone_mapping: Option<String>,
+    exposure: Option<f32>,
+    denoiser: Option<String>,
+    denoise: Option<bool>,
+}
+
+// The validated content of the file.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub samples_per_pixel: Option<usize>,
+    pub max_depth: Option<u8>,
+    pub tile_size: Option<u32>,
+    pub num_threads: Option<usize>,
+    pub integrator: Option<Integrator>,
+    pub tile_order: Option<TileOrder>,
+    pub preview_scale: Option<u32>,
+    pub tone_mapping: Option<ToneMapping>,
+    pub denoiser: Option<DenoiserType>,
+    pub denoise: Option<bool>,
+}
+
+// Loads the config file. A missing file is only an error when it was given explicitly,
+// otherwise the defaults are used.
+pub fn load(path: &str, required: bool) -> Result<Config, Error> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound && !required => {
+            debug!("There is no config file \"{}\". Using the defaults.", path);
+            return Ok(Config::default());
+        },
+        Err(err) => return Err(Error::read(path, err)),
+    };
+    let file: ConfigFile = toml::from_str(&text).map_err(|err| Error::parse(path, format!("Error: {}", err)))?;
+    let config = validate(file).map_err(|message| Error::parse(path, message))?;
+    info!("Using the config file \"{}\".", path);
+    Ok(config)
+}
+
+fn parse_name<T>(value: Option<String>, name: &str, parse: fn(&str) -> Option<T>, expected: &str) -> Result<Option<T>, String> {
+    match value {
+        Some(value) => match parse(&value) {
+            Some(parsed) => Ok(Some(parsed)),
+            None => Err(format!("Unknown {} \"{}\". Expected {}.", name, value, expected)),
+        },
+        None => Ok(None),
+    }
+}
+
+fn check_range<T: PartialOrd + std::fmt::Display + Copy>(value: Option<T>, name: &str, min: T, max: T) -> Result<Option<T>, String> {
+    match value {
+        Some(value) if value < min || value > max => Err(format!("\"{}\" is {} but has to be between {} and {}.", name, value, min, max)),
+        value => Ok(value),
+    }
+}
+
+fn validate(file: ConfigFile) -> Result<Config, String> {
+    let tone_mapping = parse_name(file.tone_mapping, "tone mapping", ToneMapping::parse, "\"clamp\", \"reinhard\", \"aces\" or \"exposure\"")?;
+    let tone_mapping = match (tone_mapping, file.exposure) {
+        (Some(ToneMapping::Exposure(_)), Some(exposure)) => {
+            if !(exposure > 0.0) {
+                return Err(format!("\"exposure\" is {} but has to be larger than 0.", exposure));
+            }
+            Some(ToneMapping::Exposure(exposure))
+        },
+        (_, Some(_)) => return Err(String::from("\"exposure\" is only used with tone_mapping = \"exposure\".")),
+        (tone_mapping, None) => tone_mapping,
+    };
+
+    let preview_scale = file.preview_scale;
+    if let Some(scale) = preview_scale {
+        if ![1, 2, 4, 8].contains(&scale) {
+            return Err(format!("\"preview_scale\" is {} but has to be 1, 2, 4 or 8.", scale));
+        }
+    }
+
+    Ok(Config {
+        samples_per_pixel: check_range(file.spp, "spp", 1, std::usize::MAX)?,
+        max_depth: check_range(file.max_depth, "max_depth", 1, std::u8::MAX)?,
+        tile_size: check_range(file.tile_size, "tile_size", tiles::MIN_TILE_SIZE, tiles::MAX_TILE_SIZE)?,
+        num_threads: check_range(file.threads, "threads", 1, 1024)?,
+        integrator: parse_name(file.integrator, "integrator", Integrator::parse, "\"path\", \"guided\", \"whitted\", \"restir\", \"gpu\", \"wavefront\", \"fastgi\", \"normals\", \"depth\", \"ao\" or \"heatmap\"")?,
+        tile_order: parse_name(file.tile_order, "tile order", TileOrder::parse, "\"scanline\", \"spiral\" or \"hilbert\"")?,
+        preview_scale: preview_scale,
+        tone_mapping: tone_mapping,
+        denoiser: parse_name(file.denoiser, "denoiser", DenoiserType::parse, "\"oidn\" or \"atrous\"")?,
+        denoise: file.denoise,
+    })
+}
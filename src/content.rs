@@ -4,30 +4,36 @@ use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
 use std::thread;
 
+use common::precise_time_ns;
+
 use scene::HDRITexture;
+use error::Error;
 
 enum AssetRequest {
     HDRITexture(String),
 }
 
-fn load_hdri_texture(path: String) -> HDRITexture {
+fn load_hdri_texture(path: &str) -> Result<HDRITexture, Error> {
     use stb_image::image;
     use stb_image::image::LoadResult;
 
-    match image::load(&*path) {
-        LoadResult::ImageU8(_) => panic!("Image \"{}\" is not an HDR image.", path),
+    let start_time = precise_time_ns();
+    match image::load(path) {
+        LoadResult::ImageU8(_) => Err(Error::texture(path, String::from("It is not an HDR image."))),
+        LoadResult::ImageF32(ref image) if image.depth != 3 => Err(Error::texture(path, format!("It has {} channels instead of 3.", image.depth))),
         LoadResult::ImageF32(image) => {
-            // @TODO: This should not be an assert.
-            assert!(image.depth == 3);
-            HDRITexture::new(image.data, image.width, image.height)
+            info!("Loaded the texture \"{}\" with {}x{} pixels in {:.1} ms.", path, image.width, image.height, (precise_time_ns() - start_time) as f64 / 1_000_000.0);
+            Ok(HDRITexture::new(image.data, image.width, image.height))
         },
-        LoadResult::Error(message) => panic!("Could not load the image \"{}\"! Error: {}", path, message),
+        LoadResult::Error(message) => Err(Error::texture(path, format!("Error: {}", message))),
     }
 }
 
+// A texture that could not be loaded keeps its error so that it is not loaded again.
 pub enum AssetState<T> {
     Loading,
     Available(T),
+    Failed(Error),
 }
 
 pub struct Content {
@@ -51,9 +57,12 @@ impl Content {
                     Ok(asset_request) => {
                         match asset_request {
                             AssetRequest::HDRITexture(path) => {
-                                let hdri_texture = load_hdri_texture(path.clone());
+                                let state = match load_hdri_texture(&path) {
+                                    Ok(hdri_texture) => AssetState::Available(Arc::new(hdri_texture)),
+                                    Err(err) => AssetState::Failed(err),
+                                };
                                 let mut hdri_textures = hdri_textures2.lock().expect("Could not aquire the lock for hdri_textures in Content.");
-                                hdri_textures.insert(path, AssetState::Available(Arc::new(hdri_texture)));
+                                hdri_textures.insert(path, state);
                             },
                         }
                     },
@@ -68,16 +77,18 @@ impl Content {
         }
     }
 
-    pub fn get_hdri_texture(&self, path: &str) -> Option<Arc<HDRITexture>> {
+    // Returns None while the texture is being loaded.
+    pub fn get_hdri_texture(&self, path: &str) -> Result<Option<Arc<HDRITexture>>, Error> {
         let mut hdri_textures = self.hdri_textures.lock().expect("Could not aquire the lock for hdri_textures in Content.");
         let (result, request_load) = match hdri_textures.get(path) {
-            Some(AssetState::Loading) => (None, false),
-            Some(AssetState::Available(hdri_texture)) => (Some(hdri_texture.clone()), false),
-            None => (None, true),
+            Some(AssetState::Loading) => (Ok(None), false),
+            Some(AssetState::Available(hdri_texture)) => (Ok(Some(hdri_texture.clone())), false),
+            Some(AssetState::Failed(err)) => (Err(err.clone()), false),
+            None => (Ok(None), true),
         };
         if request_load {
-            // @TODO: Handle the unwrap
-            self.sender.send(AssetRequest::HDRITexture(String::from(path))).unwrap();
+            self.sender.send(AssetRequest::HDRITexture(String::from(path)))
+                .map_err(|_| Error::texture(path, String::from("The thread for loading the textures has stopped.")))?;
             hdri_textures.insert(String::from(path), AssetState::Loading);
         }
         result
@@ -0,0 +1,101 @@
+use common::*;
+
+use scene::{Scene, Sky};
+use tracer::ToneMapping;
+use builder::*;
+
+// Built-in scenes for benchmarking and showing the features of the renderer without a scene
+// file. They are selected with "--demo=<name>". Remote workers build the same scene from its
+// name, therefore the random scenes use their own random number generator (XorShift32 with
+// a fixed seed) instead of the one of the renderer.
+
+pub const DEMO_NAMES: &'static [&'static str] = &["cornell", "spheres", "materials"];
+
+pub fn build(name: &str) -> Option<Scene> {
+    match name {
+        "cornell" => Some(cornell_box()),
+        "spheres" => Some(random_spheres()),
+        "materials" => Some(material_test()),
+        _ => None,
+    }
+}
+
+// The classic box with a red and a green wall, lit by an area light below the ceiling.
+fn cornell_box() -> Scene {
+    let white = diffuse(Vec3::new(0.73, 0.73, 0.73));
+    SceneBuilder::new(512, 512)
+        .camera(Vec3::new(0.0, 5.0, 15.0), Vec3::new(0.0, 5.0, 0.0), 53.0)
+        .sky(Sky::Constant(Vec3::zero()))
+        .tone_mapping(ToneMapping::Aces)
+        .quad(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 10.0, 10.0, white.clone())
+        .quad(Vec3::new(0.0, 10.0, 0.0), Vec3::new(0.0, -1.0, 0.0), 10.0, 10.0, white.clone())
+        .quad(Vec3::new(0.0, 5.0, -5.0), Vec3::new(0.0, 0.0, 1.0), 10.0, 10.0, white.clone())
+        .quad(Vec3::new(-5.0, 5.0, 0.0), Vec3::new(1.0, 0.0, 0.0), 10.0, 10.0, diffuse(Vec3::new(0.65, 0.05, 0.05)))
+        .quad(Vec3::new(5.0, 5.0, 0.0), Vec3::new(-1.0, 0.0, 0.0), 10.0, 10.0, diffuse(Vec3::new(0.12, 0.45, 0.15)))
+        .quad(Vec3::new(0.0, 9.99, 0.0), Vec3::new(0.0, -1.0, 0.0), 3.0, 3.0, light(Vec3::new(15.0, 15.0, 15.0)))
+        .sphere(Vec3::new(-2.0, 2.0, -1.5), 2.0, metal(Vec3::new(0.95, 0.93, 0.88), 0.05))
+        .sphere(Vec3::new(2.2, 1.5, 1.5), 1.5, glass(1.5))
+        .build()
+}
+
+// Source: Peter Shirley, "Ray Tracing in One Weekend", 2016
+//
+// The final scene of the book with a grid of small random spheres around three large ones.
+// As the intersection tests are not accelerated yet, this is the slowest of the demos.
+fn random_spheres() -> Scene {
+    let mut random = XorShift32::new(0x2545F491);
+
+    let mut builder = SceneBuilder::new(640, 360)
+        .camera(Vec3::new(13.0, 2.0, 3.0), Vec3::zero(), 20.0)
+        .sky(Sky::Constant(Vec3::new(0.6, 0.75, 1.0)))
+        .tone_mapping(ToneMapping::Aces)
+        .quad(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 60.0, 60.0, diffuse(Vec3::new(0.5, 0.5, 0.5)));
+
+    for a in -11..11 {
+        for b in -11..11 {
+            let center = Vec3::new(a as f32 + 0.9*random.next_f32(), 0.2, b as f32 + 0.9*random.next_f32());
+            if (center - Vec3::new(4.0, 0.2, 0.0)).length() <= 0.9 {
+                continue;
+            }
+            let choice = random.next_f32();
+            let material = if choice < 0.8 {
+                diffuse(Vec3::new(random.next_f32()*random.next_f32(), random.next_f32()*random.next_f32(), random.next_f32()*random.next_f32()))
+            } else if choice < 0.95 {
+                let color = Vec3::new(0.5 + 0.5*random.next_f32(), 0.5 + 0.5*random.next_f32(), 0.5 + 0.5*random.next_f32());
+                metal(color, 0.5*random.next_f32())
+            } else {
+                glass(1.5)
+            };
+            builder = builder.sphere(center, 0.2, material);
+        }
+    }
+
+    builder
+        .sphere(Vec3::new(0.0, 1.0, 0.0), 1.0, glass(1.5))
+        .sphere(Vec3::new(-4.0, 1.0, 0.0), 1.0, diffuse(Vec3::new(0.4, 0.2, 0.1)))
+        .sphere(Vec3::new(4.0, 1.0, 0.0), 1.0, metal(Vec3::new(0.7, 0.6, 0.5), 0.0))
+        .build()
+}
+
+// Two rows of balls with increasing roughness from left to right. The front row is a
+// dielectric, the back row a metal.
+fn material_test() -> Scene {
+    const NUM_BALLS: usize = 5;
+
+    let mut builder = SceneBuilder::new(800, 400)
+        .camera(Vec3::new(0.0, 4.0, 12.0), Vec3::new(0.0, 1.0, 0.0), 35.0)
+        .sky(Sky::Constant(Vec3::new(0.3, 0.3, 0.35)))
+        .tone_mapping(ToneMapping::Aces)
+        .quad(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 40.0, 40.0, diffuse(Vec3::new(0.5, 0.5, 0.5)))
+        .quad(Vec3::new(0.0, 8.0, 2.0), Vec3::new(0.0, -1.0, 0.0), 8.0, 3.0, light(Vec3::new(8.0, 8.0, 8.0)));
+
+    for i in 0..NUM_BALLS {
+        let roughness = i as f32 / (NUM_BALLS - 1) as f32;
+        let x = 2.5*(i as f32 - 0.5*(NUM_BALLS - 1) as f32);
+        builder = builder
+            .sphere(Vec3::new(x, 1.0, 1.5), 1.0, plastic(Vec3::new(0.8, 0.1, 0.1), roughness))
+            .sphere(Vec3::new(x, 1.0, -1.5), 1.0, metal(Vec3::new(1.0, 0.78, 0.34), roughness));
+    }
+
+    builder.build()
+}
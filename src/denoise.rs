@@ -0,0 +1,230 @@
+use std;
+
+use common::*;
+use tracer::Backbuffer;
+
+// Denoising of the progressive image. Intel Open Image Denoise is used when compiled with
+// the "denoise" feature. Otherwise, or when requested explicitly, the built-in à-trous
+// wavelet filter is used which does not need any external dependency.
+//
+// Both operate on the average linear radiance of the backbuffer together with the
+// auxiliary features. The denoised radiance is linear as well, so that it can be
+// post-processed before it is tone mapped like the backbuffer.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DenoiserType {
+    Oidn,
+    ATrous,
+}
+
+impl DenoiserType {
+    pub fn parse(name: &str) -> Option<DenoiserType> {
+        match name {
+            "oidn" => Some(DenoiserType::Oidn),
+            "atrous" => Some(DenoiserType::ATrous),
+            _ => None,
+        }
+    }
+}
+
+pub struct Denoiser {
+    oidn: Option<oidn_backend::OidnDenoiser>,
+}
+
+impl Denoiser {
+    pub fn new(denoiser_type: DenoiserType) -> Self {
+        let oidn = match denoiser_type {
+            DenoiserType::Oidn => {
+                let oidn = oidn_backend::OidnDenoiser::new();
+                if oidn.is_none() {
+                    println!("Open Image Denoise is not available. Compile with \"--features denoise\". Using the à-trous filter instead.");
+                }
+                oidn
+            },
+            DenoiserType::ATrous => None,
+        };
+        Denoiser {
+            oidn: oidn,
+        }
+    }
+
+    // Returns the denoised linear radiance of the backbuffer. Must only be called while no
+    // worker thread is rendering.
+    pub fn denoise(&mut self, backbuffer: &Backbuffer) -> Vec<Vec3> {
+        let image = match self.oidn {
+            Some(ref mut oidn) => oidn.denoise(backbuffer),
+            None => None,
+        };
+        image.unwrap_or_else(|| denoise_atrous(backbuffer))
+    }
+}
+
+//
+// À-TROUS WAVELET FILTER
+//
+// Source: Dammertz et al., "Edge-Avoiding À-Trous Wavelet Transform for fast Global
+// Illumination Filtering", 2010
+//
+// The same 5x5 B3-spline kernel is applied multiple times with an increasing distance
+// between its taps. The weights of the taps are reduced for differences in color, normal
+// and depth so that the edges in the image are preserved. The difference in color is
+// relative to the luminance of the center pixel, as the radiance is not bounded.
+//
+const NUM_ITERATIONS: usize = 5;
+const SIGMA_COLOR: f32 = 0.5;
+const SIGMA_NORMAL: f32 = 0.1;
+const SIGMA_DEPTH: f32 = 0.1; // Relative to the depth of the center pixel
+
+fn denoise_atrous(backbuffer: &Backbuffer) -> Vec<Vec3> {
+    const KERNEL: [f32; 5] = [1.0/16.0, 1.0/4.0, 3.0/8.0, 1.0/4.0, 1.0/16.0];
+
+    let (width, height) = (backbuffer.width() as i32, backbuffer.height() as i32);
+
+    let (mut colors, normals, depths) = unsafe {
+        let colors: Vec<Vec3> = (0..(width*height) as usize)
+            .map(|index| backbuffer.mean_radiance(index, *backbuffer.num_samples.get()))
+            .collect();
+        let num_samples = *backbuffer.num_samples.get() as f32;
+        let normals: Vec<Vec3> = (*backbuffer.normals.get()).iter()
+            .map(|normal| *normal/num_samples)
+            .collect();
+        let depths: Vec<f32> = (*backbuffer.depths.get()).iter()
+            .map(|depth| *depth/num_samples)
+            .collect();
+        (colors, normals, depths)
+    };
+    let mut filtered = colors.clone();
+
+    for iteration in 0..NUM_ITERATIONS {
+        let step = 1 << iteration;
+        // The color weight becomes stricter as the noise is reduced with every iteration.
+        let sigma_color = SIGMA_COLOR / (1 << iteration) as f32;
+
+        for y in 0..height {
+            for x in 0..width {
+                let index = (y*width + x) as usize;
+                let (center_color, center_normal, center_depth) = (colors[index], normals[index], depths[index]);
+
+                let mut sum = Vec3::zero();
+                let mut weight_sum = 0.0;
+                for ky in 0..5 {
+                    for kx in 0..5 {
+                        let qx = x + (kx as i32 - 2)*step;
+                        let qy = y + (ky as i32 - 2)*step;
+                        if qx < 0 || qx >= width || qy < 0 || qy >= height {
+                            continue;
+                        }
+                        let q = (qy*width + qx) as usize;
+
+                        let color_difference = (colors[q] - center_color)/(1.0 + luminance(center_color));
+                        let w_color = f32::exp(-color_difference.dot(color_difference) / (sigma_color*sigma_color));
+
+                        let normal_difference = normals[q] - center_normal;
+                        let w_normal = f32::exp(-normal_difference.dot(normal_difference) / (SIGMA_NORMAL*SIGMA_NORMAL));
+
+                        let depth_difference = (depths[q] - center_depth) / f32::max(center_depth, 0.0001);
+                        let w_depth = f32::exp(-depth_difference*depth_difference / (SIGMA_DEPTH*SIGMA_DEPTH));
+
+                        let weight = KERNEL[kx]*KERNEL[ky]*w_color*w_normal*w_depth;
+                        sum = sum + weight*colors[q];
+                        weight_sum += weight;
+                    }
+                }
+
+                // The center tap always has a weight larger than zero.
+                filtered[index] = sum/weight_sum;
+            }
+        }
+
+        std::mem::swap(&mut colors, &mut filtered);
+    }
+
+    colors
+}
+
+//
+// INTEL OPEN IMAGE DENOISE
+//
+#[cfg(feature = "denoise")]
+mod oidn_backend {
+    use oidn;
+
+    use common::*;
+    use tracer::Backbuffer;
+
+    pub struct OidnDenoiser {
+        device: oidn::Device,
+        color: Vec<f32>,
+        albedo: Vec<f32>,
+        normals: Vec<f32>,
+        output: Vec<f32>,
+    }
+
+    impl OidnDenoiser {
+        pub fn new() -> Option<Self> {
+            Some(OidnDenoiser {
+                device: oidn::Device::new(),
+                color: Vec::new(),
+                albedo: Vec::new(),
+                normals: Vec::new(),
+                output: Vec::new(),
+            })
+        }
+
+        // The radiance is not bounded, so the filter runs in HDR mode. Returns None when the
+        // filter fails.
+        pub fn denoise(&mut self, backbuffer: &Backbuffer) -> Option<Vec<Vec3>> {
+            let num_pixels = (backbuffer.width()*backbuffer.height()) as usize;
+            self.color.clear();
+            self.albedo.clear();
+            self.normals.clear();
+            self.output.resize(3*num_pixels, 0.0);
+
+            unsafe {
+                let num_samples = *backbuffer.num_samples.get() as f32;
+                for index in 0..num_pixels {
+                    let radiance = backbuffer.mean_radiance(index, *backbuffer.num_samples.get());
+                    self.color.extend_from_slice(&[radiance.x, radiance.y, radiance.z]);
+                }
+                for albedo in (*backbuffer.albedo.get()).iter() {
+                    let albedo = saturate_vec3(*albedo/num_samples);
+                    self.albedo.extend_from_slice(&[albedo.x, albedo.y, albedo.z]);
+                }
+                for normal in (*backbuffer.normals.get()).iter() {
+                    let normal = *normal/num_samples;
+                    self.normals.extend_from_slice(&[normal.x, normal.y, normal.z]);
+                }
+            }
+
+            let result = oidn::RayTracing::new(&self.device)
+                .hdr(true)
+                .image_dimensions(backbuffer.width() as usize, backbuffer.height() as usize)
+                .albedo_normal(&self.albedo, &self.normals)
+                .filter(&self.color, &mut self.output);
+            if let Err(err) = result {
+                println!("Could not denoise the image. Error: {:?}", err);
+                return None;
+            }
+
+            Some(self.output.chunks(3).map(|rgb| Vec3::new(rgb[0], rgb[1], rgb[2])).collect())
+        }
+    }
+}
+
+#[cfg(not(feature = "denoise"))]
+mod oidn_backend {
+    use common::*;
+    use tracer::Backbuffer;
+
+    pub struct OidnDenoiser;
+
+    impl OidnDenoiser {
+        pub fn new() -> Option<Self> {
+            None
+        }
+
+        pub fn denoise(&mut self, _backbuffer: &Backbuffer) -> Option<Vec<Vec3>> {
+            None
+        }
+    }
+}
@@ -0,0 +1,379 @@
+use ron;
+use serde_json;
+
+use common::*;
+
+use scene::{Scene, Sky, Medium, Material, Sphere, Plane, PBRParameters, ObjectId, Transform, Visibility};
+use tracer::{ImageSettings, Camera, DepthOfField, ToneMapping, Stereo, StereoLayout};
+use volume::{Volume, DensityGrid};
+use animation::{Animation, Keyframe, CameraKey};
+use builder;
+
+// Scene description files in RON or JSON as an alternative to the scene format of the
+// parser. They are deserialized with serde into the structs below, which mirror the blocks
+// of the native format, and then converted into a Scene. Vectors are written as tuples of
+// three numbers in RON and as arrays in JSON.
+//
+//   (
+//       image: (width: 512, height: 512),
+//       camera: (position: (0.0, 5.0, 15.0), target: (0.0, 5.0, 0.0)),
+//       spheres: [
+//           (origin: (0.0, 2.0, 0.0), radius: 2.0, material: Physically(reflectivity: (1.0, 1.0, 1.0), roughness: 0.5, metalness: 0.0)),
+//       ],
+//   )
+
+type Vec3Description = (f32, f32, f32);
+
+fn vec3(v: Vec3Description) -> Vec3 {
+    Vec3::new(v.0, v.1, v.2)
+}
+
+fn default_true() -> bool { true }
+fn default_up() -> Vec3Description { (0.0, 1.0, 0.0) }
+fn default_camera_size() -> f32 { 4.0 }
+fn default_z_near() -> f32 { 10.0 }
+fn default_iso() -> f32 { 100.0 }
+fn default_scale() -> f32 { 1.0 }
+fn default_sky() -> SkyDescription { SkyDescription::Constant { radiance: (1.0, 1.0, 1.0) } }
+fn default_tone_mapping() -> ToneMappingDescription { ToneMappingDescription::Clamp }
+fn default_visibility() -> VisibilityDescription { VisibilityDescription { camera: true, shadows: true, reflections: true } }
+fn default_transform() -> TransformDescription { TransformDescription { translation: (0.0, 0.0, 0.0), rotation_axis: default_up(), rotation_angle: 0.0, scale: 1.0 } }
+
+#[derive(Debug, Deserialize)]
+struct SceneDescription {
+    image: ImageDescription,
+    camera: CameraDescription,
+    #[serde(default = "default_sky")]
+    sky: SkyDescription,
+    #[serde(default)]
+    medium: Option<MediumDescription>,
+    #[serde(default)]
+    spheres: Vec<SphereDescription>,
+    #[serde(default)]
+    planes: Vec<PlaneDescription>,
+    #[serde(default)]
+    volumes: Vec<VolumeDescription>,
+    #[serde(default)]
+    nodes: Vec<NodeDescription>,
+    #[serde(default)]
+    animation: Vec<KeyframeDescription>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ImageDescription {
+    width: usize,
+    height: usize,
+    #[serde(default = "default_true")]
+    sub_pixel_sampling: bool,
+    #[serde(default)]
+    transparent_background: bool,
+    #[serde(default)]
+    stereo: Option<StereoDescription>,
+}
+
+#[derive(Debug, Deserialize)]
+enum StereoLayoutDescription {
+    #[serde(alias = "side_by_side", alias = "side-by-side")]
+    SideBySide,
+    Anaglyph,
+}
+
+#[derive(Debug, Deserialize)]
+struct StereoDescription {
+    layout: StereoLayoutDescription,
+    interocular: Option<f32>,
+    convergence: Option<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CameraDescription {
+    position: Vec3Description,
+    target: Vec3Description,
+    #[serde(default = "default_up")]
+    up: Vec3Description,
+    #[serde(default = "default_camera_size")]
+    width: f32,
+    #[serde(default = "default_camera_size")]
+    height: f32,
+    #[serde(default = "default_z_near")]
+    z_near: f32,
+    #[serde(default = "default_tone_mapping")]
+    tone_mapping: ToneMappingDescription,
+    #[serde(default = "default_iso")]
+    iso: f32,
+    // Pinhole without an aperture. The focus distance defaults to the distance to the target.
+    #[serde(default)]
+    aperture: Option<f32>,
+    #[serde(default)]
+    focus_distance: Option<f32>,
+}
+
+#[derive(Debug, Deserialize)]
+enum ToneMappingDescription {
+    Clamp,
+    Reinhard,
+    Aces,
+    Exposure(f32),
+}
+
+#[derive(Debug, Deserialize)]
+enum SkyDescription {
+    Constant { radiance: Vec3Description },
+    Hdri { path: String },
+}
+
+#[derive(Debug, Deserialize)]
+struct MediumDescription {
+    sigma_a: Vec3Description,
+    sigma_s: Vec3Description,
+    anisotropy: f32,
+}
+
+#[derive(Debug, Deserialize)]
+enum MaterialDescription {
+    Physically { reflectivity: Vec3Description, roughness: f32, metalness: f32 },
+    Emissive { radiance: Vec3Description },
+    Translucent { ior: f32, #[serde(default)] dispersion: f32, #[serde(default)] priority: u32 },
+    Mirror,
+    BlinnPhong { kd: Vec3Description, #[serde(default)] ks: Vec3Description, #[serde(default)] ns: f32 },
+}
+
+#[derive(Debug, Deserialize)]
+struct SphereDescription {
+    origin: Vec3Description,
+    radius: f32,
+    material: MaterialDescription,
+    #[serde(default = "default_visibility")]
+    visibility: VisibilityDescription,
+}
+
+#[derive(Debug, Deserialize)]
+struct PlaneDescription {
+    origin: Vec3Description,
+    u: Vec3Description,
+    v: Vec3Description,
+    material: MaterialDescription,
+    #[serde(default = "default_visibility")]
+    visibility: VisibilityDescription,
+}
+
+#[derive(Debug, Deserialize)]
+struct VisibilityDescription {
+    #[serde(default = "default_true")]
+    camera: bool,
+    #[serde(default = "default_true")]
+    shadows: bool,
+    #[serde(default = "default_true")]
+    reflections: bool,
+}
+
+#[derive(Debug, Deserialize)]
+enum DensityDescription {
+    Noise { resolution: usize, frequency: f32 },
+}
+
+#[derive(Debug, Deserialize)]
+struct VolumeDescription {
+    min: Vec3Description,
+    max: Vec3Description,
+    density: DensityDescription,
+    density_scale: f32,
+    albedo: Vec3Description,
+    anisotropy: f32,
+}
+
+// The objects of a node come after the ones of the scene or the parent node, and the nodes
+// are counted from the outside in.
+#[derive(Debug, Deserialize)]
+struct NodeDescription {
+    #[serde(default = "default_transform")]
+    transform: TransformDescription,
+    #[serde(default)]
+    spheres: Vec<SphereDescription>,
+    #[serde(default)]
+    planes: Vec<PlaneDescription>,
+    #[serde(default)]
+    volumes: Vec<VolumeDescription>,
+    #[serde(default)]
+    nodes: Vec<NodeDescription>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TransformDescription {
+    #[serde(default)]
+    translation: Vec3Description,
+    #[serde(default = "default_up")]
+    rotation_axis: Vec3Description,
+    #[serde(default)]
+    rotation_angle: f32,
+    #[serde(default = "default_scale")]
+    scale: f32,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyframeDescription {
+    frame: usize,
+    #[serde(default)]
+    camera: Option<CameraKeyDescription>,
+    #[serde(default)]
+    transforms: Vec<TransformKeyDescription>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CameraKeyDescription {
+    position: Vec3Description,
+    target: Vec3Description,
+    #[serde(default = "default_up")]
+    up: Vec3Description,
+}
+
+// The objects are referenced like in the native format, e.g. Sphere(0).
+#[derive(Debug, Deserialize)]
+enum ObjectIdDescription {
+    Sphere(usize),
+    Plane(usize),
+    Volume(usize),
+    EmissiveSphere(usize),
+    EmissivePlane(usize),
+    Node(usize),
+}
+
+#[derive(Debug, Deserialize)]
+struct TransformKeyDescription {
+    object: ObjectIdDescription,
+    #[serde(default)]
+    translation: Vec3Description,
+    #[serde(default = "default_up")]
+    rotation_axis: Vec3Description,
+    #[serde(default)]
+    rotation_angle: f32,
+    #[serde(default = "default_scale")]
+    scale: f32,
+}
+
+fn material(description: MaterialDescription) -> Material {
+    match description {
+        MaterialDescription::Physically { reflectivity, roughness, metalness } => Material::Physically(PBRParameters {
+            reflectivity: vec3(reflectivity),
+            roughness: roughness,
+            metalness: metalness,
+        }),
+        MaterialDescription::Emissive { radiance } => Material::Emissive(vec3(radiance)),
+        MaterialDescription::Translucent { ior, dispersion, priority } => Material::Translucent(ior, dispersion, priority),
+        MaterialDescription::Mirror => Material::Mirror,
+        MaterialDescription::BlinnPhong { kd, ks, ns } => builder::blinn_phong(vec3(kd), vec3(ks), ns),
+    }
+}
+
+fn object_id(description: ObjectIdDescription) -> ObjectId {
+    match description {
+        ObjectIdDescription::Sphere(index) => ObjectId::Sphere(index),
+        ObjectIdDescription::Plane(index) => ObjectId::Plane(index),
+        ObjectIdDescription::Volume(index) => ObjectId::Volume(index),
+        ObjectIdDescription::EmissiveSphere(index) => ObjectId::EmissiveSphere(index),
+        ObjectIdDescription::EmissivePlane(index) => ObjectId::EmissivePlane(index),
+        ObjectIdDescription::Node(index) => ObjectId::Node(index),
+    }
+}
+
+fn transform(t: TransformDescription) -> Transform {
+    Transform::new(vec3(t.translation), vec3(t.rotation_axis), t.rotation_angle, t.scale)
+}
+
+fn volume(v: VolumeDescription) -> Result<Volume, String> {
+    let grid = match v.density {
+        DensityDescription::Noise { resolution, frequency } => {
+            if resolution < 2 {
+                return Err(String::from("The resolution of the density grid has to be at least 2."));
+            }
+            DensityGrid::noise(resolution, frequency)
+        },
+    };
+    Ok(Volume::new(vec3(v.min), vec3(v.max), grid, v.density_scale, vec3(v.albedo), v.anisotropy))
+}
+
+// Adds the objects to the scene, or to the node when one is given, and continues with the
+// child nodes.
+fn add_objects(scene: &mut Scene, node: Option<usize>, spheres: Vec<SphereDescription>, planes: Vec<PlaneDescription>, volumes: Vec<VolumeDescription>, nodes: Vec<NodeDescription>) -> Result<(), String> {
+    let mut ids = Vec::new();
+    for s in spheres {
+        let visibility = Visibility { camera: s.visibility.camera, shadows: s.visibility.shadows, reflections: s.visibility.reflections };
+        ids.push(scene.add_sphere(Sphere { visibility, ..Sphere::new(vec3(s.origin), s.radius, material(s.material)) }));
+    }
+    for p in planes {
+        let visibility = Visibility { camera: p.visibility.camera, shadows: p.visibility.shadows, reflections: p.visibility.reflections };
+        ids.push(scene.add_plane(Plane { visibility, ..Plane::new(vec3(p.origin), vec3(p.u), vec3(p.v), material(p.material)) }));
+    }
+    for v in volumes {
+        ids.push(scene.add_volume(volume(v)?));
+    }
+    if let Some(node) = node {
+        for id in ids {
+            scene.attach(node, id);
+        }
+    }
+
+    for n in nodes {
+        let child = scene.add_node(node, transform(n.transform));
+        add_objects(scene, Some(child), n.spheres, n.planes, n.volumes, n.nodes)?;
+    }
+    Ok(())
+}
+
+fn build_scene(description: SceneDescription) -> Result<Scene, String> {
+    let mut image_settings = ImageSettings::new(description.image.width, description.image.height, description.image.sub_pixel_sampling);
+    image_settings.transparent_background = description.image.transparent_background;
+    image_settings.stereo = description.image.stereo.map(|s| {
+        let layout = match s.layout {
+            StereoLayoutDescription::SideBySide => StereoLayout::SideBySide,
+            StereoLayoutDescription::Anaglyph => StereoLayout::Anaglyph,
+        };
+        Stereo::with_defaults(layout, s.interocular, s.convergence)
+    });
+
+    let c = description.camera;
+    let tone_mapping = match c.tone_mapping {
+        ToneMappingDescription::Clamp => ToneMapping::Clamp,
+        ToneMappingDescription::Reinhard => ToneMapping::Reinhard,
+        ToneMappingDescription::Aces => ToneMapping::Aces,
+        ToneMappingDescription::Exposure(value) => ToneMapping::Exposure(value),
+    };
+    let mut camera = Camera::new(vec3(c.position), vec3(c.target), vec3(c.up), c.width, c.height, c.z_near, tone_mapping, c.iso);
+    if let Some(aperture) = c.aperture {
+        let focus_distance = c.focus_distance.unwrap_or((vec3(c.target) - vec3(c.position)).length());
+        camera.set_depth_of_field(Some(DepthOfField::new(aperture, focus_distance)));
+    }
+
+    let sky = match description.sky {
+        SkyDescription::Constant { radiance } => Sky::Constant(vec3(radiance)),
+        SkyDescription::Hdri { path } => Sky::HDRI(path, None),
+    };
+    let medium = description.medium.map(|m| Medium::new(vec3(m.sigma_a), vec3(m.sigma_s), m.anisotropy));
+
+    let mut scene = Scene::new(image_settings, camera, sky, medium, Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    add_objects(&mut scene, None, description.spheres, description.planes, description.volumes, description.nodes)?;
+    scene.update_nodes();
+
+    if !description.animation.is_empty() {
+        let keyframes = description.animation.into_iter().map(|key| {
+            let camera = key.camera.map(|c| CameraKey::new(vec3(c.position), vec3(c.target), vec3(c.up)));
+            let transforms = key.transforms.into_iter()
+                .map(|t| (object_id(t.object), Transform::new(vec3(t.translation), vec3(t.rotation_axis), t.rotation_angle, t.scale)))
+                .collect();
+            Keyframe::new(key.frame, camera, transforms)
+        }).collect();
+        scene.animation = Some(Animation::new(keyframes));
+    }
+
+    Ok(scene)
+}
+
+pub fn parse_ron(text: &str) -> Result<Scene, String> {
+    let description: SceneDescription = ron::de::from_str(text).map_err(|err| format!("{}", err))?;
+    build_scene(description)
+}
+
+pub fn parse_json(text: &str) -> Result<Scene, String> {
+    let description: SceneDescription = serde_json::from_str(text).map_err(|err| format!("{}", err))?;
+    build_scene(description)
+}
@@ -0,0 +1,56 @@
+use std;
+use std::fmt;
+
+// The errors that are reported to the user instead of ending the program with a panic. Each
+// of them names the file or the part of the program that failed, so that the message alone
+// tells what went wrong. The messages of the libraries are kept as strings, which keeps the
+// errors cloneable, e.g. for remembering a texture that could not be loaded.
+//
+// The parsers still report their problems as strings, which end up in the message of a
+// Error::Parse together with the path of the file. A scene without a file, like a demo
+// scene, reports them as Error::Scene.
+
+#[derive(Clone, Debug)]
+pub enum Error {
+    Read { path: String, message: String },
+    Parse { path: String, message: String },
+    Scene(String),
+    Texture { path: String, message: String },
+    Write { path: String, message: String },
+    Display(String),
+    InvalidOption(String),
+}
+
+impl Error {
+    pub fn read(path: &str, err: std::io::Error) -> Self {
+        Error::Read { path: String::from(path), message: format!("Error: {}", err) }
+    }
+
+    pub fn parse(path: &str, message: String) -> Self {
+        Error::Parse { path: String::from(path), message: message }
+    }
+
+    pub fn texture(path: &str, message: String) -> Self {
+        Error::Texture { path: String::from(path), message: message }
+    }
+
+    pub fn write(path: &str, message: String) -> Self {
+        Error::Write { path: String::from(path), message: message }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Read { ref path, ref message } => write!(f, "Could not read the file \"{}\". {}", path, message),
+            Error::Parse { ref path, ref message } => write!(f, "Could not load the file \"{}\". {}", path, message),
+            Error::Scene(ref message) => write!(f, "{}", message),
+            Error::Texture { ref path, ref message } => write!(f, "Could not load the texture \"{}\". {}", path, message),
+            Error::Write { ref path, ref message } => write!(f, "Could not write the file \"{}\". {}", path, message),
+            Error::Display(ref message) => write!(f, "{}", message),
+            Error::InvalidOption(ref message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,390 @@
+use std::io::Write;
+use std::process::{Command, Child, Stdio};
+
+use image;
+use time;
+use exr::prelude::{Image, ImageAttributes, IntegerBounds, Layer, LayerAttributes, Encoding, AnyChannels, AnyChannel, FlatSamples, WritableImage, f16};
+use exr::meta::attribute::{AttributeValue, Text};
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use serde_json;
+
+use common::*;
+use scene::Scene;
+use tracer::{Backbuffer, Camera, Integrator, Stereo, ToneMapping, MATTE_RANKS};
+use postprocess::PostProcessing;
+use error::Error;
+
+// How an image was rendered, so that it can be reproduced. It is written into the text chunks
+// of PNG files and into the header of EXR files, e.g. "spp" = "256".
+#[derive(Clone, Debug, new)]
+pub struct Metadata {
+    pub samples_per_pixel: usize,
+    pub seed: u32,
+    pub scene_hash: u64, // See scene_hash
+    pub integrator: Integrator,
+    pub max_depth: u8,
+    pub render_time: f64, // Seconds since the accumulation started
+    pub width: u32, // Of the backbuffer, which may differ from the scene file
+    pub height: u32,
+    pub stereo: Option<Stereo>,
+    pub tone_mapping: ToneMapping,
+    pub post_processing: Option<PostProcessing>, // None when the image is not post-processed
+    pub tile_size: u32,
+}
+
+impl Metadata {
+    fn entries(&self) -> Vec<(&'static str, String)> {
+        let stereo = match self.stereo {
+            Some(stereo) => format!("{:?} interocular_distance={} convergence_distance={}", stereo.layout, stereo.interocular_distance, stereo.convergence_distance),
+            None => "off".to_string(),
+        };
+        let post_processing = match self.post_processing {
+            Some(settings) => format!("bloom={} bloom_threshold={} lens_flare={} vignette={}", settings.bloom, settings.bloom_threshold, settings.lens_flare, settings.vignette),
+            None => "off".to_string(),
+        };
+        vec![
+            ("Software", format!("pathtracer {}", env!("CARGO_PKG_VERSION"))),
+            ("spp", format!("{}", self.samples_per_pixel)),
+            ("seed", format!("{}", self.seed)),
+            ("scene_hash", format!("{:016x}", self.scene_hash)),
+            ("integrator", format!("{:?}", self.integrator)),
+            ("max_depth", format!("{}", self.max_depth)),
+            ("render_time", format!("{:.3}", self.render_time)),
+            ("resolution", format!("{}x{}", self.width, self.height)),
+            ("stereo", stereo),
+            ("tone_mapping", format!("{:?}", self.tone_mapping)),
+            ("post_processing", post_processing),
+            ("tile_size", format!("{}", self.tile_size)),
+        ]
+    }
+
+    // The radiance in EXR files and PNGs with alpha is saved without the post-processing.
+    fn without_post_processing(&self) -> Metadata {
+        Metadata { post_processing: None, ..self.clone() }
+    }
+}
+
+// FNV-1a of the source of the scene, i.e. the text of the scene file or the name of a demo
+// scene. In contrast to the hasher of the standard library it does not change between
+// versions of Rust.
+pub fn scene_hash(source: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in source.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Source: PNG specification, annex D
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// The image crate cannot write text chunks, so they are inserted into the encoded file right
+// after the header chunk, which is the first one after the 8 bytes of the signature.
+fn write_png(path: &str, bytes: &[u8], width: u32, height: u32, color: image::ColorType, metadata: Option<&Metadata>) -> Result<(), Error> {
+    const HEADER_END: usize = 8 + 4 + 4 + 13 + 4;
+    let mut png = Vec::new();
+    image::png::PNGEncoder::new(&mut png).encode(bytes, width, height, color)
+        .map_err(|err| Error::write(path, format!("Error: {}", err)))?;
+    if let Some(metadata) = metadata {
+        let mut chunks = Vec::new();
+        for (keyword, text) in metadata.entries() {
+            let mut chunk = Vec::from(&b"tEXt"[..]);
+            chunk.extend_from_slice(keyword.as_bytes());
+            chunk.push(0);
+            chunk.extend_from_slice(text.as_bytes());
+            chunks.extend_from_slice(&((chunk.len() - 4) as u32).to_be_bytes());
+            chunks.extend_from_slice(&chunk);
+            chunks.extend_from_slice(&crc32(&chunk).to_be_bytes());
+        }
+        png.splice(HEADER_END..HEADER_END, chunks);
+    }
+    File::create(path).and_then(|mut file| file.write_all(&png))
+        .map_err(|err| Error::write(path, format!("Error: {}", err)))
+}
+
+// The rows of the backbuffer are stored from the bottom to the top like the rows of OpenGL
+// textures. Image files are stored from the top to the bottom.
+fn flipped_rgb8(width: u32, height: u32, pixels: &[Pixel8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity((3*width*height) as usize);
+    for y in (0..height).rev() {
+        for x in 0..width {
+            let ref pixel = pixels[(y*width + x) as usize];
+            bytes.extend_from_slice(&[pixel.0, pixel.1, pixel.2]);
+        }
+    }
+    bytes
+}
+
+pub fn save_png(path: &str, width: u32, height: u32, pixels: &[Pixel8], metadata: Option<&Metadata>) -> Result<(), Error> {
+    let bytes = flipped_rgb8(width, height, pixels);
+    write_png(path, &bytes, width, height, image::RGB(8), metadata)
+}
+
+// Saves the image with the coverage in the alpha channel. The radiance of a transparent
+// background is premultiplied with the coverage, so it is divided by the alpha before the
+// tone mapping as PNG expects straight alpha. The denoiser is not applied. The radiance is
+// averaged over the given number of samples, see save_exr.
+pub fn save_png_rgba(path: &str, backbuffer: &Backbuffer, camera: &Camera, num_samples: usize, metadata: Option<&Metadata>) -> Result<(), Error> {
+    let (width, height) = (backbuffer.width(), backbuffer.height());
+    let num_samples = usize::max(num_samples, 1) as f32;
+    let alphas = unsafe { &*backbuffer.alphas.get() };
+
+    let mut bytes = Vec::with_capacity((4*width*height) as usize);
+    for y in (0..height).rev() {
+        for x in 0..width {
+            let index = (y*width + x) as usize;
+            let alpha = saturatef32(alphas[index]/num_samples);
+            let straight = if alpha > 0.0 { backbuffer.mean_radiance(index, num_samples as usize)/alpha } else { Vec3::zero() };
+            let pixel = Pixel8::from_unit_dithered(backbuffer.display_color(straight, camera), x, y);
+            bytes.extend_from_slice(&[pixel.0, pixel.1, pixel.2, (alpha*255.0 + 0.5) as u8]);
+        }
+    }
+    write_png(path, &bytes, width, height, image::RGBA(8), metadata.map(|metadata| metadata.without_post_processing()).as_ref())
+}
+
+// E.g. "render_2018-08-14_20-15-03.png"
+pub fn timestamped_filename(prefix: &str, extension: &str) -> String {
+    let timestamp = time::strftime("%Y-%m-%d_%H-%M-%S", &time::now()).unwrap_or(String::from("unknown"));
+    format!("{}_{}.{}", prefix, timestamp, extension)
+}
+
+// E.g. "frame.png" becomes "frame_0001.png" for the first frame.
+pub fn numbered_filename(path: &str, number: usize) -> String {
+    match path.rfind('.') {
+        Some(index) if !path[index..].contains('/') => format!("{}_{:04}{}", &path[..index], number, &path[index..]),
+        _ => format!("{}_{:04}", path, number),
+    }
+}
+
+//
+// VIDEO
+//
+// Animations are encoded by piping the raw frames into ffmpeg which has to be installed and
+// available on the PATH. The container and the codec are chosen by ffmpeg from the
+// extension of the path, e.g. ".mp4" or ".gif".
+//
+#[cfg(not(target_arch = "wasm32"))]
+pub fn is_video_path(path: &str) -> bool {
+    [".mp4", ".mkv", ".webm", ".gif"].iter().any(|extension| path.ends_with(extension))
+}
+
+pub struct VideoEncoder {
+    path: String,
+    width: u32,
+    height: u32,
+    ffmpeg: Child,
+}
+
+impl VideoEncoder {
+    pub fn new(path: &str, width: u32, height: u32, fps: u32) -> Result<Self, Error> {
+        let mut command = Command::new("ffmpeg");
+        command
+            .args(&["-y", "-loglevel", "error"])
+            .args(&["-f", "rawvideo", "-pix_fmt", "rgb24"])
+            .args(&["-s", &format!("{}x{}", width, height), "-r", &fps.to_string()])
+            .args(&["-i", "-"]);
+        // Most players only support H.264 with 4:2:0 chroma subsampling. As it halves the
+        // resolution of the colors, the width and the height must be even, so a column or a
+        // row at the border is cropped from odd sizes.
+        if path.ends_with(".mp4") || path.ends_with(".mkv") {
+            command.args(&["-pix_fmt", "yuv420p"]);
+            if width % 2 != 0 || height % 2 != 0 {
+                warn!("The video is cropped to {}x{} as the size has to be even.", width - width % 2, height - height % 2);
+                command.args(&["-vf", "crop=trunc(iw/2)*2:trunc(ih/2)*2:0:0"]);
+            }
+        }
+        let ffmpeg = command
+            .arg(path)
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|err| Error::write(path, format!("Could not start ffmpeg. {}", err)))?;
+        Ok(VideoEncoder {
+            path: String::from(path),
+            width: width,
+            height: height,
+            ffmpeg: ffmpeg,
+        })
+    }
+
+    pub fn write_frame(&mut self, pixels: &[Pixel8]) -> Result<(), Error> {
+        let bytes = flipped_rgb8(self.width, self.height, pixels);
+        let path = &self.path;
+        match self.ffmpeg.stdin {
+            Some(ref mut stdin) => stdin.write_all(&bytes)
+                .map_err(|err| Error::write(path, format!("Could not write the frame. {}", err))),
+            None => Err(Error::write(path, String::from("The input of ffmpeg is closed."))),
+        }
+    }
+
+    // Closes the input so that ffmpeg writes the file and waits until it is done.
+    pub fn finish(mut self) -> Result<(), Error> {
+        drop(self.ffmpeg.stdin.take());
+        let status = self.ffmpeg.wait()
+            .map_err(|err| Error::write(&self.path, format!("Could not wait for ffmpeg. {}", err)))?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(Error::write(&self.path, format!("ffmpeg could not encode the video ({}).", status)))
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExrPrecision {
+    Half,
+    Full,
+}
+
+// Builds the channels of one layer. The values are flipped vertically like the PNG.
+fn exr_channels(width: u32, height: u32, names: &[&str], values: &Fn(usize) -> Vec<f32>, precision: ExrPrecision) -> AnyChannels<FlatSamples> {
+    let mut samples = vec![Vec::with_capacity((width*height) as usize); names.len()];
+    for y in (0..height).rev() {
+        for x in 0..width {
+            let pixel = values((y*width + x) as usize);
+            for (channel, value) in pixel.into_iter().enumerate() {
+                samples[channel].push(value);
+            }
+        }
+    }
+    let channels: Vec<_> = names.iter().zip(samples.into_iter()).map(|(name, samples)| {
+        let samples = match precision {
+            ExrPrecision::Half => FlatSamples::F16(samples.into_iter().map(f16::from_f32).collect()),
+            ExrPrecision::Full => FlatSamples::F32(samples),
+        };
+        AnyChannel::new(*name, samples)
+    }).collect();
+    AnyChannels::sort(channels.into())
+}
+
+// Writes the mean linear radiance of every pixel without any tone mapping or exposure. With
+// alpha, the coverage is written as the alpha channel which only makes sense for a
+// transparent background as the colors are then premultiplied. With aovs, the albedo, the
+// normal, the depth and the ID of the object with the most coverage are written as
+// additional layers. With the scene for the mattes, the objects of every pixel are written
+// with their coverage in the layout of Cryptomatte: two ranks per layer with the ID and the
+// coverage each, the IDs as the bits of floats, always with full precision. The header gets
+// the attributes of Cryptomatte with the manifest of the scene (see save_matte_manifest).
+// Like for the PNG, tiles that are being rendered while saving can be from the previous
+// frame.
+//
+// The number of samples is passed explicitly as the backbuffer of the window already counts
+// the frame that is being rendered. The coverage is divided by it like the radiance.
+pub fn save_exr(path: &str, backbuffer: &Backbuffer, num_samples: usize, precision: ExrPrecision, aovs: bool, mattes: Option<&Scene>, alpha: bool, metadata: Option<&Metadata>) -> Result<(), Error> {
+    let (width, height) = (backbuffer.width(), backbuffer.height());
+    let mean_radiance = |index: usize| backbuffer.mean_radiance(index, num_samples);
+    let num_samples = usize::max(num_samples, 1) as f32;
+    let (albedo, normals, depths, alphas, pixel_mattes) = unsafe {
+        (&*backbuffer.albedo.get(), &*backbuffer.normals.get(), &*backbuffer.depths.get(), &*backbuffer.alphas.get(), &*backbuffer.mattes.get())
+    };
+
+    let layer = |name: &str, channels: AnyChannels<FlatSamples>| {
+        Layer::new((width as usize, height as usize), LayerAttributes::named(name), Encoding::FAST_LOSSLESS, channels)
+    };
+
+    let mut layers = vec![
+        if alpha {
+            layer("beauty", exr_channels(width, height, &["R", "G", "B", "A"], &|i| {
+                let v = mean_radiance(i);
+                vec![v.x, v.y, v.z, alphas[i]/num_samples]
+            }, precision))
+        } else {
+            layer("beauty", exr_channels(width, height, &["R", "G", "B"], &|i| {
+                let v = mean_radiance(i);
+                vec![v.x, v.y, v.z]
+            }, precision))
+        },
+    ];
+    if aovs {
+        layers.push(layer("albedo", exr_channels(width, height, &["R", "G", "B"], &|i| {
+            let v = albedo[i]/num_samples;
+            vec![v.x, v.y, v.z]
+        }, precision)));
+        layers.push(layer("normal", exr_channels(width, height, &["X", "Y", "Z"], &|i| {
+            let v = normals[i]/num_samples;
+            vec![v.x, v.y, v.z]
+        }, precision)));
+        layers.push(layer("depth", exr_channels(width, height, &["Z"], &|i| {
+            vec![depths[i]/num_samples]
+        }, precision)));
+        let mut ids = Vec::with_capacity((width*height) as usize);
+        for y in (0..height).rev() {
+            for x in 0..width {
+                ids.push(pixel_mattes[(y*width + x) as usize][0].0);
+            }
+        }
+        layers.push(layer("id", AnyChannels::sort(vec![AnyChannel::new("id", FlatSamples::U32(ids))].into())));
+    }
+    if mattes.is_some() {
+        for pair in 0..MATTE_RANKS/2 {
+            layers.push(layer(&format!("{}{:02}", MATTE_LAYER, pair), exr_channels(width, height, &["R", "G", "B", "A"], &|i| {
+                let (first, second) = (pixel_mattes[i][2*pair], pixel_mattes[i][2*pair + 1]);
+                vec![f32::from_bits(first.0), first.1/num_samples, f32::from_bits(second.0), second.1/num_samples]
+            }, ExrPrecision::Full)));
+        }
+    }
+
+    // The metadata is stored as text attributes of the file. Values that EXR cannot store as
+    // text are left out.
+    let mut attributes = ImageAttributes::new(IntegerBounds::from_dimensions((width as usize, height as usize)));
+    for (name, value) in metadata.map(|metadata| metadata.without_post_processing().entries()).unwrap_or(Vec::new()) {
+        if let (Some(name), Some(value)) = (Text::new_or_none(name), Text::new_or_none(value)) {
+            attributes.other.insert(name, AttributeValue::Text(value));
+        }
+    }
+    if let Some(scene) = mattes {
+        let manifest = serde_json::to_string(&matte_manifest(scene)).map_err(|err| Error::write(path, format!("Error: {}", err)))?;
+        for (name, value) in cryptomatte_attributes(manifest) {
+            if let (Some(name), Some(value)) = (Text::new_or_none(name), Text::new_or_none(value)) {
+                attributes.other.insert(name, AttributeValue::Text(value));
+            }
+        }
+    }
+    let image = Image::from_layers(attributes, layers);
+    image.write().to_file(path)
+        .map_err(|err| Error::write(path, format!("Error: {}", err)))
+}
+
+// The layers of the mattes are "matte00" to "matte02". Cryptomatte calls this the type name.
+const MATTE_LAYER: &str = "matte";
+
+// The names of the objects with their matte IDs as hexadecimal numbers, in the format of
+// the manifests of Cryptomatte, {"sphere 0": "3f2a98c1", ...}.
+fn matte_manifest(scene: &Scene) -> BTreeMap<String, String> {
+    scene.primitives()
+        .map(|(id, _)| (format!("{}", id), format!("{:08x}", id.matte_id())))
+        .collect()
+}
+
+// The header attributes of Cryptomatte for the mattes, which let compositors find the layers
+// and the names of the objects without the manifest next to the file. The key of the
+// attributes is made of the first seven hexadecimal digits of the hash of the type name.
+fn cryptomatte_attributes(manifest: String) -> Vec<(String, String)> {
+    let key = &format!("{:08x}", murmur_hash3_32(MATTE_LAYER.as_bytes(), 0))[..7];
+    vec![
+        (format!("cryptomatte/{}/name", key), String::from(MATTE_LAYER)),
+        (format!("cryptomatte/{}/hash", key), String::from("MurmurHash3_32")),
+        (format!("cryptomatte/{}/conversion", key), String::from("uint32_to_float32")),
+        (format!("cryptomatte/{}/manifest", key), manifest),
+    ]
+}
+
+// Writes the manifest of the mattes next to the EXR file as well, e.g. "render.exr" gets
+// "render.mattes.json".
+pub fn save_matte_manifest(exr_path: &str, scene: &Scene) -> Result<(), Error> {
+    let path = format!("{}.mattes.json", exr_path.trim_end_matches(".exr"));
+    let manifest = matte_manifest(scene);
+    let file = File::create(&path).map_err(|err| Error::write(&path, format!("Error: {}", err)))?;
+    serde_json::to_writer_pretty(file, &manifest).map_err(|err| Error::write(&path, format!("Error: {}", err)))
+}
@@ -0,0 +1,273 @@
+// Optional GPU backend that runs the path tracing in a WGSL compute shader (see
+// shaders/pathtracer.wgsl). It is only compiled with the "gpu" feature. Without the
+// feature, or when no suitable adapter is found, GpuRenderer::new returns None and the
+// CPU integrators are used.
+
+#[cfg(feature = "gpu")]
+pub use self::backend::GpuRenderer;
+
+#[cfg(not(feature = "gpu"))]
+pub use self::fallback::GpuRenderer;
+
+#[cfg(feature = "gpu")]
+mod backend {
+    use std::borrow::Cow;
+
+    use wgpu;
+    use wgpu::util::DeviceExt;
+    use pollster;
+
+    use common::*;
+    use scene::{Scene, Sky, Material};
+    use tracer::{self, Backbuffer};
+
+    const SHADER: &'static str = include_str!("shaders/pathtracer.wgsl");
+    const WORKGROUP_SIZE: u32 = 8;
+
+    struct FrameBuffers {
+        width: u32,
+        height: u32,
+        radiance: wgpu::Buffer,
+        staging: wgpu::Buffer,
+    }
+
+    pub struct GpuRenderer {
+        device: wgpu::Device,
+        queue: wgpu::Queue,
+        pipeline: wgpu::ComputePipeline,
+        frame_buffers: Option<FrameBuffers>,
+        frame_index: u32,
+        warned_about_priorities: bool,
+    }
+
+    fn push_f32s(bytes: &mut Vec<u8>, values: &[f32]) {
+        for value in values {
+            bytes.extend_from_slice(&value.to_bits().to_le_bytes());
+        }
+    }
+
+    fn push_u32s(bytes: &mut Vec<u8>, values: &[u32]) {
+        for value in values {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn push_material(bytes: &mut Vec<u8>, material: &Material) {
+        match material {
+            &Material::None => push_f32s(bytes, &[0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]),
+            &Material::Emissive(radiance) => push_f32s(bytes, &[radiance.x, radiance.y, radiance.z, 1.0, 0.0, 0.0, 0.0, 0.0]),
+            &Material::Mirror => push_f32s(bytes, &[1.0, 1.0, 1.0, 2.0, 0.0, 0.0, 0.0, 0.0]),
+            &Material::Translucent(ior, _, _) => push_f32s(bytes, &[1.0, 1.0, 1.0, 3.0, 0.0, 0.0, ior, 0.0]),
+            &Material::Physically(ref p) => push_f32s(bytes, &[p.reflectivity.x, p.reflectivity.y, p.reflectivity.z, 4.0, p.roughness, p.metalness, 0.0, 0.0]),
+            // Custom BSDFs are rendered as rough diffuse surfaces with their albedo.
+            &Material::Custom(ref bsdf) => {
+                let albedo = bsdf.albedo();
+                push_f32s(bytes, &[albedo.x, albedo.y, albedo.z, 4.0, 1.0, 0.0, 0.0, 0.0])
+            },
+        }
+    }
+
+    impl GpuRenderer {
+        pub fn new() -> Option<Self> {
+            let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+            let adapter = pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                compatible_surface: None,
+                force_fallback_adapter: false,
+            }))?;
+            let (device, queue) = pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor {
+                label: Some("pathtracer"),
+                required_features: wgpu::Features::empty(),
+                required_limits: wgpu::Limits::default(),
+            }, None)).ok()?;
+
+            let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some("pathtracer.wgsl"),
+                source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(SHADER)),
+            });
+            let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("pathtracer"),
+                layout: None,
+                module: &shader,
+                entry_point: "main",
+            });
+
+            println!("Using the GPU adapter \"{}\".", adapter.get_info().name);
+
+            Some(GpuRenderer {
+                device: device,
+                queue: queue,
+                pipeline: pipeline,
+                frame_buffers: None,
+                frame_index: 0,
+                warned_about_priorities: false,
+            })
+        }
+
+        // The radiance buffers are kept between frames and only recreated when the resolution
+        // changes.
+        fn update_frame_buffers(&mut self, width: u32, height: u32) {
+            let outdated = match self.frame_buffers {
+                Some(ref buffers) => buffers.width != width || buffers.height != height,
+                None => true,
+            };
+            if outdated {
+                let size = (width*height) as u64*16;
+                let radiance = self.device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("radiance"),
+                    size: size,
+                    usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+                    mapped_at_creation: false,
+                });
+                let staging = self.device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("staging"),
+                    size: size,
+                    usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                });
+                self.frame_buffers = Some(FrameBuffers {
+                    width: width,
+                    height: height,
+                    radiance: radiance,
+                    staging: staging,
+                });
+            }
+        }
+
+        // Renders one sample per pixel and accumulates it into the backbuffer. Must only be
+        // called while no worker thread is rendering.
+        pub fn render_frame(&mut self, scene: &Scene, backbuffer: &Backbuffer, max_depth: u8) {
+            let (width, height) = (backbuffer.width(), backbuffer.height());
+            self.frame_index = self.frame_index.wrapping_add(1);
+            self.update_frame_buffers(width, height);
+
+            // The shader does not track the nested dielectrics and refracts every surface
+            // against air.
+            let has_priorities = scene.spheres.iter().map(|sphere| &sphere.material).chain(scene.planes.iter().map(|plane| &plane.material))
+                .any(|material| match *material { Material::Translucent(_, _, priority) => priority != 0, _ => false });
+            if has_priorities && !self.warned_about_priorities {
+                warn!("The GPU ignores the priorities of the dielectrics and refracts every surface against air.");
+                self.warned_about_priorities = true;
+            }
+
+            // UPLOADING THE SCENE
+            //
+            // The arrays must not be empty as storage buffers without any content cannot be
+            // bound. The number of primitives is passed separately.
+            let mut spheres = Vec::new();
+            let num_spheres = scene.spheres.len() + scene.emissive_spheres.len();
+            for sphere in scene.spheres.iter().chain(scene.emissive_spheres.iter()) {
+                push_f32s(&mut spheres, &[sphere.origin.x, sphere.origin.y, sphere.origin.z, sphere.radius]);
+                push_material(&mut spheres, &sphere.material);
+            }
+            if num_spheres == 0 {
+                push_f32s(&mut spheres, &[0.0; 12]);
+            }
+
+            let mut planes = Vec::new();
+            let num_planes = scene.planes.len() + scene.emissive_planes.len();
+            for plane in scene.planes.iter().chain(scene.emissive_planes.iter()) {
+                push_f32s(&mut planes, &[plane.origin.x, plane.origin.y, plane.origin.z, 0.0]);
+                push_f32s(&mut planes, &[plane.u.x, plane.u.y, plane.u.z, 0.0]);
+                push_f32s(&mut planes, &[plane.v.x, plane.v.y, plane.v.z, 0.0]);
+                push_f32s(&mut planes, &[plane.normal.x, plane.normal.y, plane.normal.z, 0.0]);
+                push_material(&mut planes, &plane.material);
+            }
+            if num_planes == 0 {
+                push_f32s(&mut planes, &[0.0; 24]);
+            }
+
+            // HDRI skies are not supported on the GPU.
+            let sky = match scene.sky {
+                Sky::Constant(radiance) => radiance,
+                Sky::HDRI(_, _) => Vec3::one(),
+            };
+
+            let (plane_origin, plane_u, plane_v) = scene.camera.projection_plane();
+            let camera_position = scene.camera.position();
+            let mut params = Vec::new();
+            for v in &[plane_origin, plane_u, plane_v, camera_position, sky] {
+                push_f32s(&mut params, &[v.x, v.y, v.z, 0.0]);
+            }
+            push_u32s(&mut params, &[width, height, num_spheres as u32, num_planes as u32]);
+            let sub_pixel_sampling = if scene.image_settings.sub_pixel_sampling { 1 } else { 0 };
+            push_u32s(&mut params, &[self.frame_index, max_depth as u32, sub_pixel_sampling, 0]);
+
+            let create_buffer = |label: &str, contents: &[u8], usage: wgpu::BufferUsages| {
+                self.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some(label),
+                    contents: contents,
+                    usage: usage,
+                })
+            };
+            let params_buffer = create_buffer("params", &params, wgpu::BufferUsages::UNIFORM);
+            let spheres_buffer = create_buffer("spheres", &spheres, wgpu::BufferUsages::STORAGE);
+            let planes_buffer = create_buffer("planes", &planes, wgpu::BufferUsages::STORAGE);
+
+            // DISPATCHING THE COMPUTE SHADER
+            let device = &self.device;
+            let queue = &self.queue;
+            let pipeline = &self.pipeline;
+            let frame_buffers = self.frame_buffers.as_ref().unwrap();
+            let size = (width*height) as u64*16;
+
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("pathtracer"),
+                layout: &pipeline.get_bind_group_layout(0),
+                entries: &[
+                    wgpu::BindGroupEntry { binding: 0, resource: params_buffer.as_entire_binding() },
+                    wgpu::BindGroupEntry { binding: 1, resource: spheres_buffer.as_entire_binding() },
+                    wgpu::BindGroupEntry { binding: 2, resource: planes_buffer.as_entire_binding() },
+                    wgpu::BindGroupEntry { binding: 3, resource: frame_buffers.radiance.as_entire_binding() },
+                ],
+            });
+
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: Some("pathtracer") });
+            {
+                let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                    label: Some("pathtracer"),
+                    timestamp_writes: None,
+                });
+                pass.set_pipeline(pipeline);
+                pass.set_bind_group(0, &bind_group, &[]);
+                pass.dispatch_workgroups((width + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE, (height + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE, 1);
+            }
+            encoder.copy_buffer_to_buffer(&frame_buffers.radiance, 0, &frame_buffers.staging, 0, size);
+            queue.submit(Some(encoder.finish()));
+
+            // READING BACK THE RADIANCE
+            let slice = frame_buffers.staging.slice(..);
+            slice.map_async(wgpu::MapMode::Read, |_| ());
+            let _ = device.poll(wgpu::Maintain::Wait);
+            {
+                let data = slice.get_mapped_range();
+                let read_f32 = |offset: usize| f32::from_bits(u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]]));
+                for y in 0..height {
+                    for x in 0..width {
+                        let offset = ((y*width + x) as usize)*16;
+                        let hdr_radiance = Vec3::new(read_f32(offset), read_f32(offset + 4), read_f32(offset + 8));
+                        tracer::accumulate_radiance(backbuffer, &scene.camera, x, y, hdr_radiance);
+                    }
+                }
+            }
+            frame_buffers.staging.unmap();
+        }
+    }
+}
+
+#[cfg(not(feature = "gpu"))]
+mod fallback {
+    use scene::Scene;
+    use tracer::Backbuffer;
+
+    pub struct GpuRenderer;
+
+    impl GpuRenderer {
+        pub fn new() -> Option<Self> {
+            None
+        }
+
+        #[allow(dead_code)]
+        pub fn render_frame(&mut self, _scene: &Scene, _backbuffer: &Backbuffer, _max_depth: u8) {}
+    }
+}
@@ -0,0 +1,172 @@
+use std;
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use common::*;
+
+use scene::Scene;
+
+// The scene bounds are subdivided into a regular grid of cells. Every cell stores a
+// histogram over the sphere of directions which is learned from the radiance that the
+// paths bring back to the surfaces within the cell. The directions are mapped to the
+// histogram with the equal-area cylindrical projection (cos(theta), phi) so that every
+// bin covers the same solid angle.
+const GRID_RESOLUTION: usize = 16;
+const NUM_CELLS: usize = GRID_RESOLUTION*GRID_RESOLUTION*GRID_RESOLUTION;
+const DIRECTIONAL_RESOLUTION: usize = 8;
+const NUM_BINS: usize = DIRECTIONAL_RESOLUTION*DIRECTIONAL_RESOLUTION;
+
+// The training data is accumulated by all worker threads at the same time. Therefore the
+// luminance is stored as a fixed point number in atomics.
+const FIXED_POINT_SCALE: f32 = 256.0;
+const MAX_RECORDED_LUMINANCE: f32 = 1000.0; // Keeps fireflies from dominating a cell.
+
+// Part of the uniform distribution that is always mixed in so that no direction gets a
+// probability of zero.
+const UNIFORM_FRACTION: f32 = 0.1;
+
+#[derive(Debug)]
+struct Distribution {
+    min: Vec3,
+    cell_size: Vec3,
+    cdfs: Vec<f32>,
+    trained: Vec<bool>,
+}
+
+#[derive(Debug)]
+pub struct GuidingGrid {
+    training: Vec<AtomicUsize>,
+    // The sampling distribution is only built from the training data by the main thread
+    // while all worker threads are waiting for work. Just as with the Backbuffer, the
+    // workers read it without synchronization.
+    distribution: UnsafeCell<Distribution>,
+}
+unsafe impl Sync for GuidingGrid {}
+
+impl GuidingGrid {
+    pub fn new() -> Self {
+        GuidingGrid {
+            training: (0..NUM_CELLS*NUM_BINS).map(|_| AtomicUsize::new(0)).collect(),
+            distribution: UnsafeCell::new(Distribution {
+                min: Vec3::zero(),
+                cell_size: Vec3::one(),
+                cdfs: vec![0.0; NUM_CELLS*NUM_BINS],
+                trained: vec![false; NUM_CELLS],
+            }),
+        }
+    }
+
+    // Must only be called while no worker thread is rendering.
+    pub fn reset(&self, scene: &Scene) {
+        let (min, max) = scene_bounds(scene);
+        let extent = max - min;
+        // Flat scenes would produce cells without volume.
+        let extent = Vec3::new(f32::max(extent.x, 0.001), f32::max(extent.y, 0.001), f32::max(extent.z, 0.001));
+
+        for value in &self.training {
+            value.store(0, Ordering::Relaxed);
+        }
+
+        let distribution = unsafe { &mut *self.distribution.get() };
+        distribution.min = min;
+        distribution.cell_size = extent / GRID_RESOLUTION as f32;
+        for trained in distribution.trained.iter_mut() {
+            *trained = false;
+        }
+    }
+
+    // Rebuilds the sampling distribution from the training data that was collected so far.
+    // Must only be called while no worker thread is rendering.
+    pub fn update(&self) {
+        let distribution = unsafe { &mut *self.distribution.get() };
+        for cell in 0..NUM_CELLS {
+            let bins = &self.training[cell*NUM_BINS..(cell + 1)*NUM_BINS];
+            let sum = bins.iter().map(|bin| bin.load(Ordering::Relaxed)).sum::<usize>() as f32;
+            if sum <= 0.0 {
+                continue;
+            }
+
+            let mut cumulative = 0.0;
+            for (i, bin) in bins.iter().enumerate() {
+                let learned = bin.load(Ordering::Relaxed) as f32 / sum;
+                cumulative += (1.0 - UNIFORM_FRACTION)*learned + UNIFORM_FRACTION / NUM_BINS as f32;
+                distribution.cdfs[cell*NUM_BINS + i] = cumulative;
+            }
+            distribution.trained[cell] = true;
+        }
+    }
+
+    fn distribution(&self) -> &Distribution {
+        unsafe { &*self.distribution.get() }
+    }
+
+    fn cell_index(&self, position: Vec3) -> usize {
+        let distribution = self.distribution();
+        let local = (position - distribution.min) / distribution.cell_size;
+        let index = |x: f32| usize::min(GRID_RESOLUTION - 1, f32::max(0.0, x) as usize);
+        (index(local.z)*GRID_RESOLUTION + index(local.y))*GRID_RESOLUTION + index(local.x)
+    }
+
+    pub fn is_trained(&self, position: Vec3) -> bool {
+        self.distribution().trained[self.cell_index(position)]
+    }
+
+    pub fn record(&self, position: Vec3, direction: Vec3, luminance: f32) {
+        if !(luminance > 0.0) {
+            return; // Also discards NaNs
+        }
+        let fixed_point = (f32::min(luminance, MAX_RECORDED_LUMINANCE)*FIXED_POINT_SCALE) as usize;
+        let index = self.cell_index(position)*NUM_BINS + bin_index(direction);
+        self.training[index].fetch_add(fixed_point, Ordering::Relaxed);
+    }
+
+    // Expects a trained cell at the position.
+    pub fn sample(&self, position: Vec3, xi: Vec2) -> Vec3 {
+        let cdf = &self.distribution().cdfs[self.cell_index(position)*NUM_BINS..][..NUM_BINS];
+
+        // Selecting the bin with xi.x and reusing the remainder to place the direction
+        // within the bin.
+        let u = xi.x*cdf[NUM_BINS - 1];
+        let bin = cdf.iter().position(|c| u < *c).unwrap_or(NUM_BINS - 1);
+        let lower = if bin == 0 { 0.0 } else { cdf[bin - 1] };
+        let r1 = clampf32(0.0, 0.9999, (u - lower) / (cdf[bin] - lower));
+        let r2 = xi.y;
+
+        let (theta_bin, phi_bin) = (bin / DIRECTIONAL_RESOLUTION, bin % DIRECTIONAL_RESOLUTION);
+        let cos_theta = -1.0 + 2.0*(theta_bin as f32 + r1) / DIRECTIONAL_RESOLUTION as f32;
+        let phi = 2.0*PI*(phi_bin as f32 + r2) / DIRECTIONAL_RESOLUTION as f32;
+        let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - cos_theta*cos_theta));
+
+        Vec3::new(sin_theta*f32::cos(phi), cos_theta, sin_theta*f32::sin(phi))
+    }
+
+    // Expects a trained cell at the position.
+    pub fn pdf(&self, position: Vec3, direction: Vec3) -> f32 {
+        let cdf = &self.distribution().cdfs[self.cell_index(position)*NUM_BINS..][..NUM_BINS];
+        let bin = bin_index(direction);
+        let lower = if bin == 0 { 0.0 } else { cdf[bin - 1] };
+        let probability = (cdf[bin] - lower) / cdf[NUM_BINS - 1];
+        probability*NUM_BINS as f32 / (4.0*PI)
+    }
+}
+
+fn bin_index(direction: Vec3) -> usize {
+    let mut phi = f32::atan2(direction.z, direction.x);
+    if phi < 0.0 {
+        phi += 2.0*PI;
+    }
+    let index = |x: f32| usize::min(DIRECTIONAL_RESOLUTION - 1, f32::max(0.0, x*DIRECTIONAL_RESOLUTION as f32) as usize);
+    let theta_bin = index((direction.y + 1.0) / 2.0);
+    let phi_bin = index(phi / (2.0*PI));
+    theta_bin*DIRECTIONAL_RESOLUTION + phi_bin
+}
+
+fn scene_bounds(scene: &Scene) -> (Vec3, Vec3) {
+    let bounds = scene.bounds();
+    if bounds.is_empty() {
+        // An empty scene
+        (Vec3::zero(), Vec3::one())
+    } else {
+        (bounds.min, bounds.max)
+    }
+}
@@ -0,0 +1,273 @@
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use common::{PI, DEFAULT_SEED, precise_time_ns};
+
+use worker;
+use content;
+use scene;
+use tracer;
+use parser;
+use guiding;
+use restir;
+use tiles;
+use export;
+use postprocess;
+use rayview;
+use gpu;
+use cli;
+use stats;
+use progress::Progress;
+use error::Error;
+
+// Renders the scene with a fixed number of samples without creating a window, saves the
+// image and exits. Paths ending with ".exr" are saved with the linear radiance.
+//
+// With "--animate" every frame of the animation of the scene is rendered and saved with its
+// number appended to the output path. "--frames=first-last" restricts the range. When the
+// output path is a video, the frames are encoded into it with ffmpeg instead.
+//
+// "--turntable=frames=N" renders the frames the same way without an animation. The camera
+// orbits around the center of the bounds of the scene at its distance and height in the
+// scene file, starting at its position.
+//
+// With "--max-time", every image or frame is rendered until the time is up, or until the
+// samples per pixel are reached if they are given as well. The last sample is always
+// finished, so that all pixels have the same number of samples.
+//
+// The post-processing is applied to the PNGs and the videos. EXR files and PNGs with a
+// transparent background keep the radiance as it was rendered.
+//
+// Everything that keeps the image from being saved is returned as an error, which the
+// binary reports before it exits with a failure.
+pub fn start(options: cli::Options) -> Result<(), Error> {
+    let scene_file_path = options.scene_path.clone();
+    let output_path = options.output_path.clone().unwrap_or(String::from(if options.animate || options.turntable.is_some() { "frame.png" } else { "render.png" }));
+    let max_time_ns = options.max_time.map(|seconds| (seconds*1_000_000_000.0) as u64);
+    let num_samples = if max_time_ns.is_some() { options.samples_per_pixel } else { Some(options.samples_per_pixel.unwrap_or(64)) };
+    let integrator = options.integrator;
+    let exr_precision = options.exr_precision;
+    let exr_aovs = options.exr_aovs;
+    let exr_mattes = options.exr_mattes;
+    let post_processing = options.post_processing;
+    let mut post_processed_pixels = Vec::new();
+    if export::is_video_path(&output_path) && !options.animate && options.turntable.is_none() {
+        return Err(Error::InvalidOption(String::from("Videos can only be rendered from animations. Add \"--animate\" or \"--turntable\".")));
+    }
+    if options.animate && options.turntable.is_some() {
+        return Err(Error::InvalidOption(String::from("The turntable replaces the animation. Remove either \"--animate\" or \"--turntable\".")));
+    }
+
+    //
+    // LOADING THE SCENE
+    //
+    // In contrast to the window, a scene that cannot be loaded is an error as the result
+    // would not be what was asked for. The source is hashed for the metadata of the images.
+    //
+    let loaded_scene = match options.demo {
+        Some(ref name) => parser::parse_scene_text(name, parser::SceneFormat::Demo).map_err(Error::Scene).map(|scene| (scene, name.clone())),
+        None => parser::load_scene(&*scene_file_path),
+    };
+    let (mut scene, scene_hash) = loaded_scene.map(|(scene, source)| (scene, export::scene_hash(&source)))?;
+    options.apply_to_scene(&mut scene);
+    if options.print_stats {
+        stats::print_scene_stats(&scene);
+    }
+
+    if let scene::Sky::HDRI(ref path, ref mut option_texture) = scene.sky {
+        let content = content::Content::new();
+        while option_texture.is_none() {
+            *option_texture = content.get_hdri_texture(path)?;
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Only the pixel is traced, e.g. to find out where invalid radiance comes from. With an
+    // output path, its paths are also drawn over a top and side view of the scene.
+    if let Some((x, y)) = options.debug_pixel {
+        tracer::trace_debug_pixel(&scene, integrator, options.max_depth, x, y);
+        if let Some(ref path) = options.output_path {
+            let (width, height) = (scene.image_settings.width as u32, scene.image_settings.height as u32);
+            let segments = tracer::record_pixel_paths(&scene, options.max_depth, x, y);
+            let pixels = rayview::draw(&scene, &segments, width, height);
+            match export::save_png(path, width, height, &pixels, None) {
+                Ok(()) => println!("Saved the paths of the pixel to \"{}\".", path),
+                Err(err) => println!("{}", err),
+            }
+        }
+        return Ok(());
+    }
+
+    let turntable = options.turntable.map(|num_frames| (num_frames, scene.camera.position(), scene.bounds_with_volumes().center()));
+    let frames: Vec<Option<usize>> = if let Some(num_frames) = options.turntable {
+        (0..num_frames).map(|frame| Some(frame)).collect()
+    } else if options.animate {
+        let (first_frame, last_frame) = match scene.animation {
+            Some(ref animation) => options.frame_range.unwrap_or((animation.first_frame(), animation.last_frame())),
+            None => return Err(Error::InvalidOption(format!("The scene \"{}\" has no animation.", scene_file_path))),
+        };
+        (first_frame..last_frame + 1).map(|frame| Some(frame)).collect()
+    } else {
+        vec![None]
+    };
+
+    let width = scene.image_settings.width as u32;
+    let height = scene.image_settings.height as u32;
+    let scene = Arc::new(RwLock::new(scene));
+
+    //
+    // SETTING UP THE RENDERER
+    //
+    let backbuffer = Arc::new(tracer::Backbuffer::new(width, height));
+    backbuffer.set_debug_view(integrator.is_debug_view());
+    backbuffer.set_check_radiance(options.check_radiance);
+    let guiding = Arc::new(guiding::GuidingGrid::new());
+    let reservoirs = Arc::new(restir::ReservoirBuffer::new(width, height));
+    let mut gpu_renderer = if integrator == tracer::Integrator::Gpu {
+        match gpu::GpuRenderer::new() {
+            Some(gpu_renderer) => Some(gpu_renderer),
+            None => return Err(Error::InvalidOption(String::from("The GPU backend is not available."))),
+        }
+    } else {
+        None
+    };
+
+    let worker_pool = {
+        let backbuffer2 = backbuffer.clone();
+        let scene2 = scene.clone();
+        let guiding2 = guiding.clone();
+        let reservoirs2 = reservoirs.clone();
+        worker::WorkerPool::new(options.num_threads, Box::new(move |work_tile: tracer::WorkTile| {
+            let tile_time_start = precise_time_ns();
+            tracer::render(work_tile, &backbuffer2, scene2.clone(), &guiding2, &reservoirs2);
+            stats::flush_tile(precise_time_ns() - tile_time_start);
+        }))
+    };
+
+    let mut video_encoder = if export::is_video_path(&output_path) {
+        Some(export::VideoEncoder::new(&output_path, width, height, options.fps)?)
+    } else {
+        None
+    };
+
+    for frame in frames {
+        //
+        // ADVANCING THE ANIMATION
+        //
+        // Everything that was learned from the previous frame is thrown away as the
+        // geometry has changed.
+        //
+        if let Some(frame) = frame {
+            let mut scene = scene.write().unwrap();
+            if let Some((num_frames, position, center)) = turntable {
+                scene.camera.orbit(position, center, 2.0*PI*frame as f32 / num_frames as f32);
+            } else {
+                let animation = scene.animation.take();
+                if let Some(ref animation) = animation {
+                    animation.apply(&mut scene, frame);
+                }
+                scene.animation = animation;
+            }
+            println!("frame {}", frame);
+        }
+        backbuffer.clear();
+        guiding.reset(&scene.read().unwrap());
+        reservoirs.clear();
+
+        //
+        // RENDERING
+        //
+        let render_time_start = precise_time_ns();
+        let mut progress = Progress::new(num_samples);
+        stats::take_frame_stats();
+        let mut sample_index = 0;
+        while num_samples.map(|num_samples| sample_index < num_samples).unwrap_or(true) {
+            let sample_time_start = precise_time_ns();
+            if sample_index > 0 {
+                unsafe {
+                    *backbuffer.num_samples.get() += 1;
+                }
+            }
+
+            match gpu_renderer {
+                Some(ref mut gpu_renderer) => gpu_renderer.render_frame(&scene.read().unwrap(), &backbuffer, options.max_depth),
+                None => {
+                    for work_tile in tiles::work_tiles(tiles::TileOrder::Scanline, width, height, options.crop, options.tile_size, integrator, options.max_depth, 0, unsafe { *backbuffer.num_samples.get() }, 1, exr_aovs || exr_mattes) {
+                        worker_pool.process(work_tile);
+                    }
+                    worker_pool.wait();
+                },
+            }
+
+            let render_time_end = precise_time_ns();
+            if integrator == tracer::Integrator::GuidedPathTracer {
+                guiding.update();
+            }
+            if integrator == tracer::Integrator::ReSTIR {
+                reservoirs.swap();
+            }
+            let sample_time = precise_time_ns() - sample_time_start;
+            let rays = stats::take_frame_stats().rays;
+            debug!("Sample {}: {:.1} ms rendering, {:.1} ms updating, {} rays",
+                sample_index + 1, (render_time_end - sample_time_start) as f64 / 1_000_000.0,
+                (sample_time - (render_time_end - sample_time_start)) as f64 / 1_000_000.0, rays);
+            progress.finish_sample(sample_time, rays);
+            sample_index += 1;
+            let out_of_time = max_time_ns.map(|max_time_ns| precise_time_ns() - render_time_start >= max_time_ns).unwrap_or(false);
+            progress.print(out_of_time || num_samples == Some(sample_index));
+            if out_of_time {
+                break;
+            }
+        }
+        progress.finish();
+        if num_samples != Some(sample_index) {
+            println!("Stopped after {} samples per pixel as the time limit was reached.", sample_index);
+        }
+        let render_time = precise_time_ns() - render_time_start;
+        println!("render_time = {} s", render_time as f64 / 1_000_000_000.0);
+        let num_invalid_samples = backbuffer.take_invalid_samples();
+        if num_invalid_samples > 0 {
+            println!("Dropped {} samples with NaN, infinite or negative radiance.", num_invalid_samples);
+        }
+
+        //
+        // SAVING THE IMAGE
+        //
+        // The alpha channel is dropped for videos.
+        if post_processing.is_enabled() {
+            postprocess::apply(&backbuffer, &scene.read().unwrap().camera, &post_processing, unsafe { *backbuffer.num_samples.get() }, &mut post_processed_pixels);
+        }
+        let pixels = if post_processing.is_enabled() { &post_processed_pixels } else { unsafe { &*backbuffer.pixels8.get() } };
+        if let Some(ref mut video_encoder) = video_encoder {
+            video_encoder.write_frame(pixels)?;
+            continue;
+        }
+
+        let path = match frame {
+            Some(frame) => export::numbered_filename(&output_path, frame),
+            None => output_path.clone(),
+        };
+        let scene = scene.read().unwrap();
+        let transparent_background = scene.image_settings.transparent_background;
+        let metadata = export::Metadata::new(unsafe { *backbuffer.num_samples.get() }, options.seed.unwrap_or(DEFAULT_SEED), scene_hash,
+            integrator, options.max_depth, render_time as f64 / 1_000_000_000.0, width, height, scene.image_settings.stereo, scene.camera.tone_mapping(),
+            if post_processing.is_enabled() { Some(post_processing) } else { None }, options.tile_size);
+        let result = if path.ends_with(".exr") {
+            export::save_exr(&path, &backbuffer, metadata.samples_per_pixel, exr_precision, exr_aovs, if exr_mattes { Some(&*scene) } else { None }, transparent_background, Some(&metadata))
+                .and_then(|()| if exr_mattes { export::save_matte_manifest(&path, &scene) } else { Ok(()) })
+        } else if transparent_background {
+            export::save_png_rgba(&path, &backbuffer, &scene.camera, metadata.samples_per_pixel, Some(&metadata))
+        } else {
+            export::save_png(&path, width, height, pixels, Some(&metadata))
+        };
+        result?;
+        println!("Saved the image to \"{}\".", path);
+    }
+
+    if let Some(video_encoder) = video_encoder {
+        video_encoder.finish()?;
+        println!("Saved the video to \"{}\".", output_path);
+    }
+    Ok(())
+}
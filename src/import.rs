@@ -0,0 +1,142 @@
+use common::*;
+
+// Imports the camera and the lights of a glTF file (.gltf or .glb), e.g. one exported from
+// Blender, so that the view and the lighting of the scene are reproduced. The geometry is not
+// imported. Only supported with the "import" feature.
+//
+// The first camera of the default scene is used. Only perspective cameras are supported.
+// glTF cameras look along their negative z-axis with the y-axis up.
+//
+// Point and spot lights of the KHR_lights_punctual extension become small emissive spheres
+// as the renderer has no point lights. The spheres emit the intensity of the light (in
+// candela) which gives them the radiance intensity/(pi*r^2). Spot lights lose their cone and
+// directional lights are skipped.
+
+#[derive(Clone, Debug)]
+pub struct ImportedCamera {
+    pub position: Vec3,
+    pub target: Vec3,
+    pub up: Vec3,
+    // In radians
+    pub vertical_fov: f32,
+    pub z_near: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct ImportedLight {
+    pub position: Vec3,
+    pub color: Vec3,
+    // In candela
+    pub intensity: f32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Imported {
+    pub camera: Option<ImportedCamera>,
+    pub lights: Vec<ImportedLight>,
+}
+
+#[cfg(feature = "import")]
+pub use self::backend::load;
+
+#[cfg(not(feature = "import"))]
+pub use self::fallback::load;
+
+#[cfg(feature = "import")]
+mod backend {
+    use gltf::{self, Gltf, Node};
+    use gltf::camera::Projection;
+    use gltf::khr_lights_punctual::Kind;
+
+    use common::*;
+    use super::{Imported, ImportedCamera, ImportedLight};
+
+    // Column-major like glTF
+    type Matrix = [[f32; 4]; 4];
+
+    fn multiply(a: &Matrix, b: &Matrix) -> Matrix {
+        let mut result = [[0.0; 4]; 4];
+        for column in 0..4 {
+            for row in 0..4 {
+                result[column][row] = (0..4).map(|k| a[k][row]*b[column][k]).sum();
+            }
+        }
+        result
+    }
+
+    fn transform(m: &Matrix, x: f32, y: f32, z: f32, w: f32) -> Vec3 {
+        Vec3::new(
+            m[0][0]*x + m[1][0]*y + m[2][0]*z + m[3][0]*w,
+            m[0][1]*x + m[1][1]*y + m[2][1]*z + m[3][1]*w,
+            m[0][2]*x + m[1][2]*y + m[2][2]*z + m[3][2]*w,
+        )
+    }
+
+    fn visit(node: &Node, parent: &Matrix, imported: &mut Imported) -> Result<(), String> {
+        let world = multiply(parent, &node.transform().matrix());
+        let position = transform(&world, 0.0, 0.0, 0.0, 1.0);
+
+        if let Some(camera) = node.camera() {
+            if imported.camera.is_none() {
+                match camera.projection() {
+                    Projection::Perspective(perspective) => {
+                        imported.camera = Some(ImportedCamera {
+                            position: position,
+                            target: position + transform(&world, 0.0, 0.0, -1.0, 0.0),
+                            up: transform(&world, 0.0, 1.0, 0.0, 0.0),
+                            vertical_fov: perspective.yfov(),
+                            z_near: perspective.znear(),
+                        });
+                    },
+                    Projection::Orthographic(_) => return Err(format!("The camera \"{}\" is orthographic, which is not supported.", camera.name().unwrap_or(""))),
+                }
+            }
+        }
+
+        if let Some(light) = node.light() {
+            let color = light.color();
+            let imported_light = ImportedLight {
+                position: position,
+                color: Vec3::new(color[0], color[1], color[2]),
+                intensity: light.intensity(),
+            };
+            match light.kind() {
+                Kind::Point => imported.lights.push(imported_light),
+                Kind::Spot { .. } => {
+                    warn!("The spot light \"{}\" is imported as a point light.", light.name().unwrap_or(""));
+                    imported.lights.push(imported_light);
+                },
+                Kind::Directional => warn!("The directional light \"{}\" is skipped.", light.name().unwrap_or("")),
+            }
+        }
+
+        for child in node.children() {
+            visit(&child, &world, imported)?;
+        }
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<Imported, String> {
+        let document = Gltf::open(path).map_err(|err: gltf::Error| format!("Could not load the glTF file \"{}\". Error: {}", path, err))?;
+        let scene = match document.default_scene().or_else(|| document.scenes().next()) {
+            Some(scene) => scene,
+            None => return Err(format!("The glTF file \"{}\" does not contain a scene.", path)),
+        };
+
+        let identity = [[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0]];
+        let mut imported = Imported::default();
+        for node in scene.nodes() {
+            visit(&node, &identity, &mut imported)?;
+        }
+        Ok(imported)
+    }
+}
+
+#[cfg(not(feature = "import"))]
+mod fallback {
+    use super::Imported;
+
+    pub fn load(_path: &str) -> Result<Imported, String> {
+        Err(String::from("Importing glTF files requires the \"import\" feature."))
+    }
+}
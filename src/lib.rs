@@ -0,0 +1,157 @@
+// The renderer as a library, so that it can be embedded into other programs and tested
+// without the window. The program in main.rs only parses the command line and starts the
+// viewer, the headless mode or a remote worker. Built for wasm32, the library renders into
+// a canvas of the page instead (see web.rs) and has neither the window, the headless mode
+// nor the distributed rendering.
+//
+// The implementation is spread over the private modules below. The public modules at the
+// end group what is needed from the outside by topic:
+//
+//   geometry    rays, primitives and their intersection
+//   material    materials, the sky and participating media
+//   camera      the camera and the tone mapping
+//   integrator  the integrators and what they render into
+//   accel       finding the nearest hit (no acceleration structure yet)
+//   output      saving images and videos and denoising
+//   viewer      the window, the headless mode, baking and distributed rendering
+//   plugin      registering custom materials and primitives for the scene files
+//
+// The vectors, the intersection tests and the BSDFs are the pathtracer-core crate in core/,
+// which builds without std.
+//
+// Renderer::builder() configures and runs the window or the headless mode. Scenes are
+// loaded with load_scene or built with SceneBuilder. Loading, saving and opening
+// the window report an Error with a readable message.
+
+#[macro_use] extern crate derive_new;
+
+extern crate winit;
+#[cfg(not(feature = "overlay"))] extern crate softbuffer;
+extern crate pathtracer_core;
+extern crate rand;
+extern crate time;
+#[cfg(not(target_arch = "wasm32"))] extern crate notify;
+extern crate stb_image;
+extern crate image;
+extern crate exr;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate ron;
+extern crate serde_json;
+extern crate clap;
+extern crate toml;
+#[macro_use] extern crate log;
+extern crate env_logger;
+#[cfg(feature = "gpu")] extern crate wgpu;
+#[cfg(feature = "gpu")] extern crate pollster;
+#[cfg(feature = "denoise")] extern crate oidn;
+#[cfg(feature = "scripting")] extern crate rhai;
+#[cfg(feature = "import")] extern crate gltf;
+#[cfg(feature = "overlay")] #[macro_use] extern crate glium;
+#[cfg(feature = "overlay")] extern crate imgui;
+#[cfg(feature = "overlay")] extern crate imgui_glium_renderer;
+#[cfg(feature = "overlay")] extern crate glutin_winit;
+#[cfg(feature = "overlay")] extern crate raw_window_handle;
+#[cfg(target_arch = "wasm32")] extern crate wasm_bindgen;
+#[cfg(target_arch = "wasm32")] extern crate web_sys;
+
+#[cfg(all(target_arch = "wasm32", feature = "overlay"))]
+compile_error!("The overlay needs OpenGL and is not available in the browser.");
+
+mod common;
+mod cli;
+mod config;
+#[cfg(not(target_arch = "wasm32"))] mod window;
+mod overlay;
+mod presenter;
+#[cfg(not(target_arch = "wasm32"))] mod headless;
+#[cfg(not(target_arch = "wasm32"))] mod bake;
+#[cfg(target_arch = "wasm32")] mod web;
+mod scene;
+mod animation;
+mod volume;
+mod guiding;
+mod restir;
+mod worker;
+mod tracer;
+mod stats;
+mod progress;
+mod tiles;
+mod gpu;
+mod denoise;
+mod postprocess;
+#[cfg(not(target_arch = "wasm32"))] mod rayview;
+#[cfg(not(target_arch = "wasm32"))] mod network;
+mod parser;
+mod description;
+mod builder;
+mod demo;
+mod script;
+mod import;
+mod content;
+mod export;
+mod validation;
+mod error;
+mod renderer;
+mod registry;
+mod blinn_phong;
+
+pub use scene::Scene;
+pub use builder::SceneBuilder;
+pub use parser::{load_scene, parse_scene_text, SceneFormat, ParseError};
+pub use demo::{DEMO_NAMES, build as build_demo};
+pub use common::seed_random;
+pub use error::Error;
+pub use renderer::{Renderer, RendererBuilder};
+
+pub mod geometry {
+    pub use common::{Ray, Vector2, Vector3, Vec2, Vec3, Vec2u, Float, RAY_EPSILON, offset_ray_origin};
+    pub use common::{Transition, Intersection};
+    pub use scene::{Sphere, Plane, Primitive, Bounds, ObjectId, Transform, Node, CustomPrimitive, Visibility, RayKind};
+    pub use tracer::Hit;
+    pub use volume::{Volume, DensityGrid};
+}
+
+pub mod material {
+    pub use scene::{Material, PBRParameters, Medium, Sky};
+    pub use builder::{diffuse, plastic, metal, glass, dispersive_glass, light, blinn_phong};
+    pub use blinn_phong::BlinnPhong;
+}
+
+pub mod camera {
+    pub use tracer::{Camera, DepthOfField, ColorGrading, ToneMapping, ImageSettings, Stereo, StereoLayout, tone_map};
+}
+
+pub mod integrator {
+    pub use tracer::{Integrator, WorkTile, FinishedTile, Backbuffer, Features, render, trace_debug_pixel, record_pixel_paths, RaySegment, SegmentKind};
+    pub use guiding::GuidingGrid;
+    pub use restir::ReservoirBuffer;
+    pub use tiles::{TileOrder, Region, work_tiles, DEFAULT_TILE_SIZE};
+    pub use worker::WorkerPool;
+}
+
+pub mod accel {
+    // @TODO: Replace the linear search over all primitives with a BVH.
+    pub use scene::{find_scene_hit, find_object_hit, find_visible_hit, find_visible_object_hit};
+}
+
+pub mod output {
+    pub use export::{save_png, save_png_rgba, save_exr, save_matte_manifest, Metadata, scene_hash, ExrPrecision, VideoEncoder, timestamped_filename, numbered_filename};
+    pub use denoise::{Denoiser, DenoiserType};
+    pub use postprocess::{PostProcessing, apply as post_process, apply_to_image as post_process_image};
+    pub use progress::Progress;
+}
+
+pub mod viewer {
+    pub use cli::{Options, parse as parse_options};
+    #[cfg(not(target_arch = "wasm32"))] pub use window::start as start_window;
+    #[cfg(not(target_arch = "wasm32"))] pub use headless::start as start_headless;
+    #[cfg(not(target_arch = "wasm32"))] pub use bake::start as start_bake;
+    pub use cli::BakeMode;
+    #[cfg(not(target_arch = "wasm32"))] pub use network::{serve, run_worker, Server};
+    #[cfg(target_arch = "wasm32")] pub use web::{start as start_canvas, DEFAULT_CANVAS_ID};
+}
+
+pub mod plugin {
+    pub use registry::{Bsdf, Shape, Properties, Value, MaterialFactory, ShapeFactory, register_material, register_shape};
+}
@@ -1,21 +1,15 @@
-#[macro_use] extern crate derive_new;
+extern crate pathtracer;
 
-extern crate glium;
-extern crate glutin;
-extern crate hmath;
-extern crate rand;
-extern crate time;
-extern crate notify;
-extern crate stb_image;
-
-mod common;
-mod window;
-mod scene;
-mod worker;
-mod tracer;
-mod parser;
-mod content;
+use pathtracer::Renderer;
 
 fn main() {
-    window::start();
-}
\ No newline at end of file
+    // The errors of the options and of the rendering itself, e.g. a scene that cannot be
+    // loaded in the headless mode, end the program with a failure.
+    match Renderer::builder().command_line().build().and_then(|renderer| renderer.run()) {
+        Ok(()) => {},
+        Err(err) => {
+            println!("{}", err);
+            std::process::exit(1);
+        },
+    }
+}
@@ -0,0 +1,523 @@
+use std;
+use std::io;
+use std::io::{Read, Write, BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+use common::*;
+use worker::WorkerPool;
+use scene::{Scene, Sky};
+use tracer::{self, Backbuffer, WorkTile, FinishedTile, Integrator, Features, CameraView, DepthOfField};
+use guiding::GuidingGrid;
+use restir::ReservoirBuffer;
+use parser::{self, SceneFormat};
+use content;
+
+// Distributed rendering over TCP. The master serves the tiles of its worker pool to remote
+// workers which render them and send the radiance of the samples back. The master merges them
+// into its backbuffer just like the results of its own worker threads.
+//
+// Every remote worker thread has its own connection. The protocol is strictly request and
+// response, initiated by the remote worker:
+//
+//   worker: REQUEST_TILE
+//   master: [SCENE format source] TILE work_tile camera   or   NO_WORK
+//   worker: TILE_RESULT work_tile pixels
+//
+// The scene is only sent when the worker does not have the current version yet. The camera
+// is sent with every tile as it changes without a new version, e.g. with the overlay or when
+// focusing. All numbers are little endian.
+
+const REQUEST_TILE: u8 = 0;
+const TILE_RESULT: u8 = 1;
+const SCENE: u8 = 2;
+const TILE: u8 = 3;
+const NO_WORK: u8 = 4;
+
+// Longest string that is accepted from the other side, which is far more than any scene
+// file needs. Larger lengths are treated as a broken connection.
+const MAX_STRING_LENGTH: usize = 64*1024*1024;
+
+// The source of the scene file that is sent to the remote workers. The version is
+// incremented whenever the scene is reloaded.
+#[derive(Debug, new)]
+pub struct SceneSource {
+    pub version: usize,
+    pub format: SceneFormat,
+    pub text: String,
+}
+
+fn scene_format_code(format: SceneFormat) -> u8 {
+    match format {
+        SceneFormat::Native => 0,
+        SceneFormat::Ron => 1,
+        SceneFormat::Json => 2,
+        SceneFormat::Demo => 3,
+    }
+}
+
+fn scene_format_from_code(code: u8) -> io::Result<SceneFormat> {
+    match code {
+        0 => Ok(SceneFormat::Native),
+        1 => Ok(SceneFormat::Ron),
+        2 => Ok(SceneFormat::Json),
+        3 => Ok(SceneFormat::Demo),
+        _ => Err(invalid_data("Unknown scene format.")),
+    }
+}
+
+// Only the integrators that do not depend on state shared between the tiles, like the
+// guiding grid or the reservoirs, can be rendered remotely.
+fn is_remote_integrator(integrator: Integrator) -> bool {
+    match integrator {
+        Integrator::PathTracer | Integrator::Whitted | Integrator::Wavefront | Integrator::FastGI => true,
+        Integrator::Normals | Integrator::Depth | Integrator::AmbientOcclusion | Integrator::Heatmap => true,
+        Integrator::GuidedPathTracer | Integrator::ReSTIR | Integrator::Gpu => false,
+    }
+}
+
+//
+// ENCODING
+//
+fn write_u8<W: Write>(writer: &mut W, value: u8) -> io::Result<()> {
+    writer.write_all(&[value])
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_u64<W: Write>(writer: &mut W, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_f32<W: Write>(writer: &mut W, value: f32) -> io::Result<()> {
+    write_u32(writer, value.to_bits())
+}
+
+fn write_vec3<W: Write>(writer: &mut W, value: Vec3) -> io::Result<()> {
+    write_f32(writer, value.x)?;
+    write_f32(writer, value.y)?;
+    write_f32(writer, value.z)
+}
+
+fn write_string<W: Write>(writer: &mut W, value: &str) -> io::Result<()> {
+    write_u64(writer, value.len() as u64)?;
+    writer.write_all(value.as_bytes())
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_f32<R: Read>(reader: &mut R) -> io::Result<f32> {
+    Ok(f32::from_bits(read_u32(reader)?))
+}
+
+fn read_vec3<R: Read>(reader: &mut R) -> io::Result<Vec3> {
+    let x = read_f32(reader)?;
+    let y = read_f32(reader)?;
+    let z = read_f32(reader)?;
+    Ok(Vec3::new(x, y, z))
+}
+
+fn read_string<R: Read>(reader: &mut R) -> io::Result<String> {
+    let len = read_u64(reader)?;
+    if len > MAX_STRING_LENGTH as u64 {
+        return Err(invalid_data("The scene is too large."));
+    }
+    let mut bytes = vec![0; len as usize];
+    reader.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| invalid_data("The scene is not valid UTF-8."))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_camera_view<W: Write>(writer: &mut W, view: &CameraView) -> io::Result<()> {
+    write_vec3(writer, view.position)?;
+    write_vec3(writer, view.plane_origin)?;
+    write_vec3(writer, view.plane_u)?;
+    write_vec3(writer, view.plane_v)?;
+    write_f32(writer, view.width)?;
+    write_f32(writer, view.height)?;
+    write_f32(writer, view.z_near)?;
+    match view.depth_of_field {
+        Some(depth_of_field) => {
+            write_u8(writer, 1)?;
+            write_f32(writer, depth_of_field.aperture)?;
+            write_f32(writer, depth_of_field.focus_distance)
+        },
+        None => write_u8(writer, 0),
+    }
+}
+
+fn read_camera_view<R: Read>(reader: &mut R) -> io::Result<CameraView> {
+    let position = read_vec3(reader)?;
+    let plane_origin = read_vec3(reader)?;
+    let plane_u = read_vec3(reader)?;
+    let plane_v = read_vec3(reader)?;
+    let (width, height, z_near) = (read_f32(reader)?, read_f32(reader)?, read_f32(reader)?);
+    let depth_of_field = match read_u8(reader)? {
+        0 => None,
+        1 => Some(DepthOfField::new(read_f32(reader)?, read_f32(reader)?)),
+        _ => return Err(invalid_data("Unknown depth of field.")),
+    };
+    Ok(CameraView::new(position, plane_origin, plane_u, plane_v, width, height, z_near, depth_of_field))
+}
+
+fn is_same_tile(a: &WorkTile, b: &WorkTile) -> bool {
+    a.position.x == b.position.x && a.position.y == b.position.y && a.size.x == b.size.x && a.size.y == b.size.y &&
+        a.image_size.x == b.image_size.x && a.image_size.y == b.image_size.y && a.resolution_scale == b.resolution_scale &&
+        a.generation == b.generation && a.sample_index == b.sample_index && a.integrator == b.integrator && a.features == b.features
+}
+
+fn write_work_tile<W: Write>(writer: &mut W, work_tile: &WorkTile) -> io::Result<()> {
+    write_u32(writer, work_tile.tile_index.x)?;
+    write_u32(writer, work_tile.tile_index.y)?;
+    write_u32(writer, work_tile.position.x)?;
+    write_u32(writer, work_tile.position.y)?;
+    write_u32(writer, work_tile.size.x)?;
+    write_u32(writer, work_tile.size.y)?;
+    write_u8(writer, match work_tile.integrator {
+        Integrator::PathTracer => 0,
+        Integrator::Whitted => 1,
+        Integrator::Wavefront => 2,
+        Integrator::Normals => 3,
+        Integrator::Depth => 4,
+        Integrator::AmbientOcclusion => 5,
+        Integrator::Heatmap => 6,
+        Integrator::FastGI => 7,
+        _ => return Err(invalid_data("The integrator cannot be rendered remotely.")),
+    })?;
+    write_u8(writer, work_tile.max_depth)?;
+    write_u64(writer, work_tile.generation as u64)?;
+    write_u64(writer, work_tile.sample_index as u64)?;
+    write_u32(writer, work_tile.resolution_scale)?;
+    write_u32(writer, work_tile.image_size.x)?;
+    write_u32(writer, work_tile.image_size.y)?;
+    write_u8(writer, work_tile.features as u8)
+}
+
+fn read_work_tile<R: Read>(reader: &mut R) -> io::Result<WorkTile> {
+    let tile_index = Vec2u::new(read_u32(reader)?, read_u32(reader)?);
+    let position = Vec2u::new(read_u32(reader)?, read_u32(reader)?);
+    let size = Vec2u::new(read_u32(reader)?, read_u32(reader)?);
+    let integrator = match read_u8(reader)? {
+        0 => Integrator::PathTracer,
+        1 => Integrator::Whitted,
+        2 => Integrator::Wavefront,
+        3 => Integrator::Normals,
+        4 => Integrator::Depth,
+        5 => Integrator::AmbientOcclusion,
+        6 => Integrator::Heatmap,
+        7 => Integrator::FastGI,
+        _ => return Err(invalid_data("Unknown integrator.")),
+    };
+    let max_depth = read_u8(reader)?;
+    let generation = read_u64(reader)? as usize;
+    let sample_index = read_u64(reader)? as usize;
+    let resolution_scale = read_u32(reader)?;
+    let image_size = Vec2u::new(read_u32(reader)?, read_u32(reader)?);
+    let features = read_u8(reader)? != 0;
+    Ok(WorkTile::new(tile_index, position, size, integrator, max_depth, generation, sample_index, resolution_scale, image_size, features))
+}
+
+// The pixels of a tile are clipped against the image in case a tile does not fit.
+fn tile_pixels(work_tile: &WorkTile, width: u32, height: u32) -> Vec<Vec2u> {
+    let x1 = u32::min(work_tile.position.x + work_tile.size.x, width);
+    let y1 = u32::min(work_tile.position.y + work_tile.size.y, height);
+    let mut pixels = Vec::new();
+    for y in work_tile.position.y..y1 {
+        for x in work_tile.position.x..x1 {
+            pixels.push(Vec2u::new(x, y));
+        }
+    }
+    pixels
+}
+
+//
+// MASTER
+//
+pub struct Server {
+    pub worker_pool: Arc<WorkerPool<WorkTile>>,
+    pub tile_sender: Sender<FinishedTile>,
+    pub backbuffer: Arc<Backbuffer>,
+    pub scene: Arc<RwLock<Scene>>,
+    pub scene_source: Arc<RwLock<SceneSource>>,
+    pub generation: Arc<AtomicUsize>,
+}
+
+// Starts accepting remote workers in the background.
+pub fn serve(address: &str, server: Server) {
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(err) => {
+            println!("Could not listen for remote workers on \"{}\". Error: {}", address, err);
+            return;
+        },
+    };
+    println!("Listening for remote workers on \"{}\".", address);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            let peer = stream.peer_addr().map(|address| address.to_string()).unwrap_or(String::from("unknown"));
+            println!("Remote worker {} connected.", peer);
+
+            let connection = Server {
+                worker_pool: server.worker_pool.clone(),
+                tile_sender: server.tile_sender.clone(),
+                backbuffer: server.backbuffer.clone(),
+                scene: server.scene.clone(),
+                scene_source: server.scene_source.clone(),
+                generation: server.generation.clone(),
+            };
+            thread::spawn(move || {
+                let mut tile_in_flight = None;
+                if let Err(err) = handle_connection(stream, &connection, &mut tile_in_flight) {
+                    println!("Remote worker {} disconnected. Error: {}", peer, err);
+                }
+                // The tile that the worker did not finish is rendered by someone else.
+                if let Some(work_tile) = tile_in_flight {
+                    connection.worker_pool.process(work_tile);
+                }
+            });
+        }
+    });
+}
+
+fn handle_connection(stream: TcpStream, server: &Server, tile_in_flight: &mut Option<WorkTile>) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut scene_version = None;
+
+    loop {
+        match read_u8(&mut reader)? {
+            REQUEST_TILE => {
+                match server.worker_pool.take_matching(|work_tile| is_remote_integrator(work_tile.integrator)) {
+                    Some(work_tile) => {
+                        *tile_in_flight = Some(work_tile.clone());
+                        {
+                            let scene_source = server.scene_source.read().expect("Could not get reading access to the scene source.");
+                            if scene_version != Some(scene_source.version) {
+                                write_u8(&mut writer, SCENE)?;
+                                write_u8(&mut writer, scene_format_code(scene_source.format))?;
+                                write_string(&mut writer, &scene_source.text)?;
+                                scene_version = Some(scene_source.version);
+                                debug!("Sent version {} of the scene to a remote worker.", scene_source.version);
+                            }
+                        }
+                        write_u8(&mut writer, TILE)?;
+                        write_work_tile(&mut writer, &work_tile)?;
+                        let view = server.scene.read().expect("Could not get reading access to the scene for sending the camera.").camera.view();
+                        write_camera_view(&mut writer, &view)?;
+                    },
+                    None => write_u8(&mut writer, NO_WORK)?,
+                }
+                writer.flush()?;
+            },
+            TILE_RESULT => {
+                let work_tile = read_work_tile(&mut reader)?;
+                // Only the tile that was sent to the worker is accepted. Otherwise, the worker
+                // could write anywhere into the backbuffer.
+                match *tile_in_flight {
+                    Some(ref sent_tile) if is_same_tile(sent_tile, &work_tile) => {},
+                    _ => return Err(invalid_data("The remote worker returned a tile that was not sent to it.")),
+                }
+                let mut samples = Vec::new();
+                // The size of the tile is used as the image may have been resized since.
+                for _ in tile_pixels(&work_tile, work_tile.image_size.x, work_tile.image_size.y) {
+                    let radiance = read_vec3(&mut reader)?;
+                    let features = Features::new(read_vec3(&mut reader)?, read_vec3(&mut reader)?, read_f32(&mut reader)?, read_f32(&mut reader)?, read_u32(&mut reader)?);
+                    samples.push((radiance, features));
+                }
+                *tile_in_flight = None;
+
+                // Like on the worker threads, the result of an outdated tile is dropped.
+                let cancelled = work_tile.generation != server.generation.load(Ordering::SeqCst);
+                trace!("Received a tile with {} pixels from a remote worker{}.", samples.len(), if cancelled { ", which is outdated" } else { "" });
+                if !cancelled {
+                    let scene = server.scene.read().expect("Could not get reading access to the scene for merging a remote tile.");
+                    let pixels = tile_pixels(&work_tile, server.backbuffer.width(), server.backbuffer.height());
+                    for (pixel, (radiance, features)) in pixels.into_iter().zip(samples.into_iter()) {
+                        tracer::accumulate_radiance(&server.backbuffer, &scene.camera, pixel.x, pixel.y, radiance);
+                        if work_tile.features {
+                            tracer::accumulate_features(&server.backbuffer, pixel.x, pixel.y, &features);
+                        }
+                    }
+                }
+                server.tile_sender.send(FinishedTile::new(work_tile, cancelled)).expect("Could not send the finished tile to the main thread.");
+            },
+            _ => return Err(invalid_data("Unknown message from the remote worker.")),
+        }
+    }
+}
+
+//
+// REMOTE WORKER
+//
+struct RemoteScene {
+    scene: Arc<RwLock<Scene>>,
+    backbuffer: Arc<Backbuffer>,
+    // Not used by the remote integrators but required for rendering.
+    guiding: GuidingGrid,
+    reservoirs: ReservoirBuffer,
+}
+
+impl RemoteScene {
+    fn new(text: &str, format: SceneFormat, content: &content::Content) -> Self {
+        let mut scene = parser::parse_scene_text(text, format).unwrap_or_else(|err| {
+            println!("Could not parse the scene of the master. Error: \"{}\". Using the default scene.", err);
+            Scene::default()
+        });
+
+        // The sky texture is loaded from the same path as on the master.
+        let mut failed = false;
+        if let Sky::HDRI(ref path, ref mut option_texture) = scene.sky {
+            while option_texture.is_none() && !failed {
+                match content.get_hdri_texture(path) {
+                    Ok(hdri_texture) => *option_texture = hdri_texture,
+                    Err(err) => {
+                        println!("{} Using a black sky.", err);
+                        failed = true;
+                    },
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        if failed {
+            scene.sky = Sky::Constant(Vec3::zero());
+        }
+
+        let width = scene.image_settings.width as u32;
+        let height = scene.image_settings.height as u32;
+        RemoteScene {
+            scene: Arc::new(RwLock::new(scene)),
+            // The number of samples stays at one so that the tiles are overwritten instead
+            // of accumulated.
+            backbuffer: Arc::new(Backbuffer::new(width, height)),
+            guiding: GuidingGrid::new(),
+            reservoirs: ReservoirBuffer::new(width, height),
+        }
+    }
+}
+
+// Connects to the master with one connection per thread and renders tiles until the
+// process is terminated. Lost connections are reestablished.
+pub fn run_worker(address: &str, num_threads: usize) {
+    let threads: Vec<_> = (0..num_threads).map(|_| {
+        let address = String::from(address);
+        thread::spawn(move || {
+            let content = content::Content::new();
+            loop {
+                if let Err(err) = render_remotely(&address, &content) {
+                    println!("Lost the connection to the master \"{}\". Error: {}", address, err);
+                }
+                thread::sleep(Duration::from_secs(1));
+            }
+        })
+    }).collect();
+
+    for thread in threads {
+        let _ = thread.join();
+    }
+}
+
+fn render_remotely(address: &str, content: &content::Content) -> io::Result<()> {
+    let stream = TcpStream::connect(address)?;
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut remote_scene: Option<RemoteScene> = None;
+
+    loop {
+        write_u8(&mut writer, REQUEST_TILE)?;
+        writer.flush()?;
+
+        let work_tile = loop {
+            match read_u8(&mut reader)? {
+                SCENE => {
+                    let format = scene_format_from_code(read_u8(&mut reader)?)?;
+                    let text = read_string(&mut reader)?;
+                    remote_scene = Some(RemoteScene::new(&text, format, content));
+                },
+                TILE => {
+                    let work_tile = read_work_tile(&mut reader)?;
+                    break Some((work_tile, read_camera_view(&mut reader)?));
+                },
+                NO_WORK => break None,
+                _ => return Err(invalid_data("Unknown message from the master.")),
+            }
+        };
+
+        let (work_tile, view) = match work_tile {
+            Some(work_tile_and_view) => work_tile_and_view,
+            None => {
+                thread::sleep(Duration::from_millis(20));
+                continue;
+            },
+        };
+        let remote_scene = match remote_scene {
+            Some(ref remote_scene) => remote_scene,
+            None => return Err(invalid_data("The master sent a tile without a scene.")),
+        };
+
+        // The image of the master may have been resized, e.g. with the window.
+        let (width, height) = (work_tile.image_size.x, work_tile.image_size.y);
+        if width != remote_scene.backbuffer.width() || height != remote_scene.backbuffer.height() {
+            remote_scene.backbuffer.resize(width, height);
+            remote_scene.reservoirs.resize(width, height);
+            remote_scene.scene.write().expect("Could not get writing access to the remote scene.").set_resolution(width as usize, height as usize);
+        }
+        // Replaces the camera of the scene file, also after the resize above. The view contains the
+        // depth of field, so focusing and framing in the window of the master reach the workers.
+        remote_scene.scene.write().expect("Could not get writing access to the remote scene.").camera.set_view(view);
+
+        tracer::render(work_tile.clone(), &remote_scene.backbuffer, remote_scene.scene.clone(), &remote_scene.guiding, &remote_scene.reservoirs);
+
+        write_u8(&mut writer, TILE_RESULT)?;
+        write_work_tile(&mut writer, &work_tile)?;
+        let backbuffer = &remote_scene.backbuffer;
+        for pixel in tile_pixels(&work_tile, backbuffer.width(), backbuffer.height()) {
+            let index = (pixel.y*backbuffer.width() + pixel.x) as usize;
+            // A remote worker renders one sample per tile, so that the first rank of the matte
+            // is the object of that sample.
+            let (radiance, albedo, normal, depth, alpha, matte_id) = unsafe {
+                ((*backbuffer.radiance.get())[index], (*backbuffer.albedo.get())[index], (*backbuffer.normals.get())[index], (*backbuffer.depths.get())[index], (*backbuffer.alphas.get())[index], (*backbuffer.mattes.get())[index][0].0)
+            };
+            // A dropped sample is sent as NaN so that the master drops it, too.
+            let radiance = if backbuffer.valid_samples(index, 1) == 0 { Vec3::new(std::f32::NAN, std::f32::NAN, std::f32::NAN) } else { radiance };
+            write_vec3(&mut writer, radiance)?;
+            write_vec3(&mut writer, albedo)?;
+            write_vec3(&mut writer, normal)?;
+            write_f32(&mut writer, depth)?;
+            write_f32(&mut writer, alpha)?;
+            write_u32(&mut writer, matte_id)?;
+        }
+        writer.flush()?;
+    }
+}
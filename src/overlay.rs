@@ -0,0 +1,234 @@
+use scene::Material;
+use tracer::ColorGrading;
+
+// A window with the parameters of the renderer on top of the image, drawn with Dear ImGui
+// (https://github.com/ocornut/imgui). It is shown and hidden with Tab and only available with
+// the "overlay" feature. Every edit restarts the accumulation, except for the exposure and the
+// color grading which only tone map the image again.
+//
+// The material is the one of the object that was picked last with a left click.
+
+// The values shown by the overlay. The window copies them from the renderer before drawing
+// and applies the ones that changed afterwards.
+#[derive(Clone, Debug)]
+pub struct Parameters {
+    pub vertical_fov: f32, // In degrees
+    pub exposure: Option<f32>, // Only with the exposure tone mapping
+    pub color_grading: ColorGrading,
+    pub max_depth: u8,
+    pub samples_per_pixel: Option<usize>,
+    pub material: Option<(String, Material)>, // Name and material of the picked object
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Changes {
+    pub camera: bool,
+    pub exposure: bool,
+    pub color_grading: bool,
+    pub max_depth: bool,
+    pub samples_per_pixel: bool,
+    pub material: bool,
+}
+
+#[cfg(feature = "overlay")]
+pub use self::backend::Overlay;
+
+#[cfg(not(feature = "overlay"))]
+pub use self::fallback::Overlay;
+
+#[cfg(feature = "overlay")]
+mod backend {
+    use imgui::{Context, Condition, SliderFlags, Ui};
+    use imgui_glium_renderer::Renderer;
+
+    use glium;
+    use glium::backend::Facade;
+    use winit::event::{WindowEvent, ElementState, MouseButton, MouseScrollDelta};
+    use glium::Surface;
+
+    use common::*;
+    use scene::Material;
+    use tracer;
+    use super::{Parameters, Changes};
+
+    const MAX_SAMPLES_PER_PIXEL: i32 = 65536;
+
+    pub struct Overlay {
+        imgui: Context,
+        renderer: Renderer,
+        mouse_position: (f32, f32), // Physical pixels
+        mouse_down: [bool; 5],
+        mouse_wheel: f32,
+        last_frame_time: u64,
+    }
+
+    impl Overlay {
+        pub fn new<F: Facade>(display: &F) -> Option<Self> {
+            let mut imgui = Context::create();
+            imgui.set_ini_filename(None);
+            match Renderer::new(&mut imgui, display) {
+                Ok(renderer) => Some(Overlay {
+                    imgui: imgui,
+                    renderer: renderer,
+                    mouse_position: (0.0, 0.0),
+                    mouse_down: [false; 5],
+                    mouse_wheel: 0.0,
+                    last_frame_time: precise_time_ns(),
+                }),
+                Err(err) => {
+                    println!("Could not create the overlay. Error: {:?}", err);
+                    None
+                },
+            }
+        }
+
+        // Returns whether the overlay uses the mouse, in which case the event should not
+        // reach the scene, e.g. for picking.
+        pub fn handle_event(&mut self, event: &WindowEvent) -> bool {
+            match *event {
+                WindowEvent::CursorMoved { position, .. } => {
+                    self.mouse_position = (position.x as f32, position.y as f32);
+                },
+                WindowEvent::MouseInput { state, button, .. } => {
+                    let pressed = state == ElementState::Pressed;
+                    match button {
+                        MouseButton::Left => self.mouse_down[0] = pressed,
+                        MouseButton::Right => self.mouse_down[1] = pressed,
+                        MouseButton::Middle => self.mouse_down[2] = pressed,
+                        _ => (),
+                    }
+                },
+                WindowEvent::MouseWheel { delta, .. } => {
+                    self.mouse_wheel = match delta {
+                        MouseScrollDelta::LineDelta(_, y) => y,
+                        MouseScrollDelta::PixelDelta(position) => position.y as f32 / 20.0,
+                    };
+                },
+                _ => (),
+            }
+            self.imgui.io().want_capture_mouse
+        }
+
+        // Draws the overlay on top of the image and returns which of the parameters were
+        // edited. ImGui works in logical pixels, which are the physical pixels divided by the
+        // scale factor of the window.
+        pub fn draw(&mut self, target: &mut glium::Frame, scale_factor: f64, parameters: &mut Parameters) -> Changes {
+            let now = precise_time_ns();
+            let delta_time = (now - self.last_frame_time) as f32 / 1_000_000_000.0;
+            self.last_frame_time = now;
+
+            let scale = scale_factor as f32;
+            let (width, height) = target.get_dimensions();
+            {
+                let io = self.imgui.io_mut();
+                io.display_size = [width as f32 / scale, height as f32 / scale];
+                io.display_framebuffer_scale = [scale, scale];
+                io.delta_time = f32::max(delta_time, 1.0e-6);
+                io.mouse_pos = [self.mouse_position.0 / scale, self.mouse_position.1 / scale];
+                io.mouse_down = self.mouse_down;
+                io.mouse_wheel = self.mouse_wheel;
+            }
+            self.mouse_wheel = 0.0;
+
+            let mut changes = Changes::default();
+            let ui = self.imgui.new_frame();
+            ui.window("Parameters").position([10.0, 10.0], Condition::FirstUseEver).size([320.0, 300.0], Condition::FirstUseEver).build(|| {
+                // CAMERA
+                changes.camera = ui.slider("field of view", 1.0, 170.0, &mut parameters.vertical_fov);
+                if let Some(ref mut exposure) = parameters.exposure {
+                    changes.exposure = ui.slider_config("exposure", 0.01, 16.0).flags(SliderFlags::LOGARITHMIC).build(exposure);
+                }
+
+                // COLOR GRADING
+                let ref mut grading = parameters.color_grading;
+                changes.color_grading = ui.slider("exposure (EV)", -8.0, 8.0, &mut grading.exposure);
+                changes.color_grading |= ui.slider("white balance (K)", tracer::MIN_WHITE_BALANCE, tracer::MAX_WHITE_BALANCE, &mut grading.white_balance);
+                changes.color_grading |= ui.slider("saturation", 0.0, 2.0, &mut grading.saturation);
+
+                // SAMPLING
+                let mut max_depth = parameters.max_depth as i32;
+                if ui.slider("max depth", 1, 32, &mut max_depth) {
+                    parameters.max_depth = max_depth as u8;
+                    changes.max_depth = true;
+                }
+                let mut limited = parameters.samples_per_pixel.is_some();
+                let mut samples_per_pixel = parameters.samples_per_pixel.unwrap_or(256) as i32;
+                let limit_changed = ui.checkbox("limit spp", &mut limited);
+                let spp_changed = limited && ui.slider("spp", 1, MAX_SAMPLES_PER_PIXEL, &mut samples_per_pixel);
+                if limit_changed || spp_changed {
+                    parameters.samples_per_pixel = if limited { Some(samples_per_pixel as usize) } else { None };
+                    changes.samples_per_pixel = true;
+                }
+
+                // MATERIAL OF THE PICKED OBJECT
+                ui.separator();
+                match parameters.material {
+                    Some((ref name, ref mut material)) => {
+                        ui.text(format!("Material of {}", name));
+                        changes.material = edit_material(ui, material);
+                    },
+                    None => ui.text("Click on an object to edit its material."),
+                }
+            });
+            let draw_data = self.imgui.render();
+            if let Err(err) = self.renderer.render(target, draw_data) {
+                println!("Could not draw the overlay. Error: {:?}", err);
+            }
+            changes
+        }
+    }
+
+    fn edit_color(ui: &Ui, label: &str, color: &mut Vec3) -> bool {
+        let mut values = [color.x, color.y, color.z];
+        let changed = ui.color_edit3(label, &mut values);
+        *color = Vec3::new(values[0], values[1], values[2]);
+        changed
+    }
+
+    fn edit_material(ui: &Ui, material: &mut Material) -> bool {
+        match *material {
+            Material::Physically(ref mut parameters) => {
+                let mut changed = edit_color(ui, "reflectivity", &mut parameters.reflectivity);
+                changed |= ui.slider("roughness", 0.0, 1.0, &mut parameters.roughness);
+                changed |= ui.slider("metalness", 0.0, 1.0, &mut parameters.metalness);
+                changed
+            },
+            Material::Translucent(ref mut ior, ref mut dispersion, _) => {
+                let mut changed = ui.slider("ior", 1.0, 3.0, ior);
+                changed |= ui.slider("dispersion", 0.0, 0.05, dispersion);
+                changed
+            },
+            Material::Emissive(ref mut radiance) => {
+                // The color is edited separately from the intensity as the radiance is
+                // usually much larger than one.
+                let mut intensity = f32::max(radiance.x, f32::max(radiance.y, radiance.z));
+                let mut color = if intensity > 0.0 { *radiance/intensity } else { Vec3::one() };
+                let mut changed = edit_color(ui, "color", &mut color);
+                changed |= ui.slider_config("intensity", 0.0, 100.0).flags(SliderFlags::LOGARITHMIC).build(&mut intensity);
+                if changed {
+                    *radiance = intensity*color;
+                }
+                changed
+            },
+            Material::Mirror | Material::None | Material::Custom(_) => {
+                ui.text("The material has no parameters.");
+                false
+            },
+        }
+    }
+}
+
+// Without the feature, the presenter does not create an overlay.
+#[cfg(not(feature = "overlay"))]
+mod fallback {
+    use winit::event::WindowEvent;
+
+    #[allow(dead_code)]
+    pub struct Overlay;
+
+    impl Overlay {
+        pub fn handle_event(&mut self, _event: &WindowEvent) -> bool {
+            false
+        }
+    }
+}
@@ -1,7 +1,92 @@
+use std::fs;
+use std::fmt;
+
+
 use common::*;
 
-use scene::{Scene, Sky, Material, Sphere, Plane, PBRParameters};
-use tracer::{ImageSettings, Camera, ToneMapping};
+use scene::{Scene, Sky, Medium, Material, Sphere, Plane, PBRParameters, ObjectId, Transform, CustomPrimitive, Visibility};
+use tracer::{ImageSettings, Camera, DepthOfField, ToneMapping, Stereo, StereoLayout};
+use volume::{self, Volume, DensityGrid};
+use animation::{Animation, Keyframe, CameraKey};
+use description;
+use demo;
+use script::{self, ScriptObject};
+use validation::{self, Subject};
+use import;
+use registry::{self, Properties, Value};
+use error::Error;
+
+// Besides the native format below, scenes can be described in RON or JSON (see
+// description.rs). The format is chosen by the extension of the file. The text of the
+// built-in demo scenes is only their name.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SceneFormat {
+    Native,
+    Ron,
+    Json,
+    Demo,
+}
+
+impl SceneFormat {
+    pub fn from_path(path: &str) -> SceneFormat {
+        if path.ends_with(".ron") {
+            SceneFormat::Ron
+        } else if path.ends_with(".json") {
+            SceneFormat::Json
+        } else {
+            SceneFormat::Native
+        }
+    }
+}
+
+// The scene is validated after parsing (see validation.rs) so that invalid values are
+// reported while loading instead of breaking the render. Problems in the native format are
+// reported with their position in the file.
+pub fn parse_scene_text(text: &str, format: SceneFormat) -> Result<Scene, String> {
+    let start_time = precise_time_ns();
+    // The grids of the scene that this one replaces are still alive, e.g. while reloading.
+    volume::reset_memory_budget();
+    let (scene, positions) = match format {
+        SceneFormat::Native => parse_scene(text).map_err(|err| format!("{}", err))?,
+        SceneFormat::Ron => (description::parse_ron(text)?, SourcePositions::default()),
+        SceneFormat::Json => (description::parse_json(text)?, SourcePositions::default()),
+        SceneFormat::Demo => (demo::build(text).ok_or(format!("Unknown demo scene \"{}\".", text))?, SourcePositions::default()),
+    };
+
+    let problems = validation::validate(&scene);
+    let describe = |problem: &validation::Problem| match positions.find(problem.subject) {
+        Some(position) => format!("{}: {}", position, problem.message),
+        None => format!("{}: {}", problem.subject, problem.message),
+    };
+    if problems.is_empty() {
+        for warning in validation::warnings(&scene) {
+            warn!("{}", describe(&warning));
+        }
+        info!("Parsed the {:?} scene in {:.1} ms: {} spheres, {} planes, {} volumes, {} nodes and {} lights, {}x{} pixels",
+            format, (precise_time_ns() - start_time) as f64 / 1_000_000.0,
+            scene.spheres.len(), scene.planes.len(), scene.volumes.len(), scene.nodes.len(), scene.num_lights(),
+            scene.image_settings.width, scene.image_settings.height);
+        let bounds = scene.bounds_with_volumes();
+        if !bounds.is_empty() {
+            let (center, radius) = bounds.bounding_sphere();
+            info!("The scene spans from {:?} to {:?}, within {} around {:?}.", bounds.min, bounds.max, radius, center);
+            if !scene.camera.sees_sphere(center, radius) {
+                warn!("The scene is outside the view of the camera. It is framed with F in the window or Scene::auto_frame.");
+            }
+        }
+        return Ok(scene);
+    }
+    let lines: Vec<String> = problems.iter().map(|problem| format!("  {}", describe(problem))).collect();
+    Err(format!("The scene is invalid:\n{}", lines.join("\n")))
+}
+
+// Returns the source of the scene file as well so that it can be sent to remote workers.
+pub fn load_scene(filename: &str) -> Result<(Scene, String), Error> {
+    let content = fs::read_to_string(filename).map_err(|err| Error::read(filename, err))?;
+    info!("Loading the scene \"{}\" ({} bytes).", filename, content.len());
+    let scene = parse_scene_text(&*content, SceneFormat::from_path(filename)).map_err(|message| Error::parse(filename, message))?;
+    Ok((scene, content))
+}
 
 #[derive(Clone, Debug, new)]
 pub struct ParseError {
@@ -9,6 +94,12 @@ pub struct ParseError {
     position: TextPosition,
 }
 
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.position, self.message)
+    }
+}
+
 type Text<'a> = &'a str;
 
 type ParseSuccess<'a, T> = (T, ParseContext<'a>);
@@ -20,6 +111,12 @@ pub struct TextPosition {
     column: usize,
 }
 
+impl fmt::Display for TextPosition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
 impl TextPosition {
     fn advanced_column(&mut self) {
         self.column += 1;
@@ -47,68 +144,356 @@ fn error<'a, T>(message: String, context_before_error: &ParseContext) -> ParseRe
     Err(ParseError::new(message, context_before_error.position))
 }
 
-pub fn parse_scene(content: &str) -> Result<Scene, ParseError> {
+// Where the parts of the scene start in the file, to point to them when the scene is invalid.
+#[derive(Clone, Debug, Default)]
+struct SourcePositions {
+    image: Option<TextPosition>,
+    camera: Option<TextPosition>,
+    medium: Option<TextPosition>,
+    animation: Option<TextPosition>,
+    objects: Vec<(ObjectId, TextPosition)>,
+}
+
+impl SourcePositions {
+    fn find(&self, subject: Subject) -> Option<TextPosition> {
+        match subject {
+            Subject::Image => self.image,
+            Subject::Camera => self.camera,
+            Subject::Medium => self.medium,
+            Subject::Animation => self.animation,
+            Subject::Object(id) => self.objects.iter().find(|&&(object, _)| object == id).map(|&(_, position)| position),
+        }
+    }
+}
+
+fn parse_scene(content: &str) -> Result<(Scene, SourcePositions), ParseError> {
     let context = ParseContext::new(content, TextPosition::new(1, 1));
 
     let mut running_context = context.clone();
 
     let mut image_settings = ImageSettings::new(256, 256, true);
-    let mut spheres = Vec::new();
-    let mut planes = Vec::new();
-    let mut emissive_spheres = Vec::new();
-    let mut emissive_planes = Vec::new();
+    let mut items = Vec::new();
     let mut sky = Sky::Constant(Vec3::new(1.0, 1.0, 1.0));
+    let mut medium = None;
+    let mut animation = None;
     let mut camera = Camera::new(Vec3::new(0.0, 2.0, 20.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 4.0, 4.0, 10.0, ToneMapping::Clamp, 100.0);
+    let mut gltf = None;
+    let mut positions = SourcePositions::default();
 
-    // @TODO: This structure is needed more often. There should be a function doing this.
     loop {
         let (_, context) = parse_free(&running_context)?;
         if context.text.len() == 0 {
             break;
         }
+        let position = context.position;
+
+        // The block is chosen by its keyword so that an error inside of the block is reported
+        // instead of the list of the expected blocks.
+        let keyword = parse_name(&context).map(|(keyword, _)| keyword).unwrap_or("");
+        running_context = match keyword {
+            "image" => {
+                let (parsed_image_settings, context) = parse_free_and_image_settings(&context)?;
+                image_settings = parsed_image_settings;
+                positions.image = Some(position);
+                context
+            },
+            "sphere" | "plane" | "volume" | "node" | "script" => {
+                let (item, context) = parse_free_and_scene_item(&context)?;
+                items.push((position, item));
+                context
+            },
+            "sky" => {
+                let (parsed_sky, context) = parse_free_and_sky(&context)?;
+                sky = parsed_sky;
+                context
+            },
+            "camera" => {
+                let (parsed_camera, context) = parse_free_and_camera(&context)?;
+                camera = parsed_camera;
+                positions.camera = Some(position);
+                context
+            },
+            "medium" => {
+                let (parsed_medium, context) = parse_free_and_medium(&context)?;
+                medium = Some(parsed_medium);
+                positions.medium = Some(position);
+                context
+            },
+            "animation" => {
+                let (parsed_animation, context) = parse_free_and_animation(&context)?;
+                animation = Some(parsed_animation);
+                positions.animation = Some(position);
+                context
+            },
+            "gltf" => {
+                let (parsed_gltf, context) = parse_free_and_gltf(&context)?;
+                gltf = Some((position, parsed_gltf));
+                context
+            },
+            _ if is_custom_primitive(&context) => {
+                let (item, context) = parse_free_and_scene_item(&context)?;
+                items.push((position, item));
+                context
+            },
+            _ => return Err(ParseError::new(String::from("Expected \"image\", \"camera\", \"sphere\", \"plane\", \"volume\", \"node\", \"script\", \"sky\", \"medium\", \"animation\", \"gltf\" or a registered primitive."), context.position)),
+        };
+    }
 
-        if let Ok((parsed_image_settings, context)) = parse_free_and_image_settings(&context) {
-            image_settings = parsed_image_settings;
-            running_context = context;
-            continue;
+    // The imported camera replaces the one of the file but keeps its tone mapping, ISO and
+    // depth of field. The lights are added after all other objects so that the indices of
+    // those do not change.
+    if let Some((position, gltf)) = gltf {
+        let imported = import::load(&gltf.path).map_err(|message| ParseError::new(message, position))?;
+        if let Some(imported_camera) = imported.camera {
+            let aspect_ratio = image_settings.width as f32 / image_settings.height as f32;
+            let height = 2.0*imported_camera.z_near*f32::tan(0.5*imported_camera.vertical_fov);
+            let depth_of_field = camera.depth_of_field();
+            camera = Camera::new(imported_camera.position, imported_camera.target, imported_camera.up, aspect_ratio*height, height, imported_camera.z_near, camera.tone_mapping(), camera.iso());
+            camera.set_depth_of_field(depth_of_field);
+            positions.camera = Some(position);
+        }
+        for light in imported.lights {
+            let area = PI*gltf.light_radius*gltf.light_radius;
+            let radiance = (gltf.light_scale*light.intensity/area)*light.color;
+            items.push((position, SceneItem::Sphere(Sphere::new(light.position, gltf.light_radius, Material::Emissive(radiance)))));
         }
+    }
 
-        if let Ok((sphere, context)) = parse_free_and_sphere(&context) {
-            if let Material::Emissive(_) = sphere.material {
-                emissive_spheres.push(sphere);
-            } else {
-                spheres.push(sphere);
+    // The objects are added in the order of the file so that their indices match the ones
+    // used by the animation.
+    let mut scene = Scene::new(image_settings, camera, sky, medium, Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    add_scene_items(&mut scene, items, None, &mut positions);
+    scene.update_nodes();
+    scene.animation = animation;
+    Ok((scene, positions))
+}
+
+enum SceneItem {
+    Sphere(Sphere),
+    Plane(Plane),
+    Volume(Volume),
+    Node(Transform, Vec<(TextPosition, SceneItem)>),
+    Script(Vec<ScriptObject>),
+    Custom(CustomPrimitive),
+}
+
+fn add_scene_items(scene: &mut Scene, items: Vec<(TextPosition, SceneItem)>, node: Option<usize>, positions: &mut SourcePositions) {
+    for (position, item) in items {
+        let ids = match item {
+            SceneItem::Sphere(sphere) => vec![scene.add_sphere(sphere)],
+            SceneItem::Plane(plane) => vec![scene.add_plane(plane)],
+            SceneItem::Volume(volume) => vec![scene.add_volume(volume)],
+            SceneItem::Custom(custom) => vec![scene.add_custom(custom)],
+            SceneItem::Node(transform, children) => {
+                let child = scene.add_node(node, transform);
+                positions.objects.push((ObjectId::Node(child), position));
+                add_scene_items(scene, children, Some(child), positions);
+                continue;
+            },
+            SceneItem::Script(objects) => objects.into_iter().map(|object| match object {
+                ScriptObject::Sphere(sphere) => scene.add_sphere(sphere),
+                ScriptObject::Plane(plane) => scene.add_plane(plane),
+            }).collect(),
+        };
+        for id in ids {
+            positions.objects.push((id, position));
+            if let Some(node) = node {
+                scene.attach(node, id);
             }
-            running_context = context;
-            continue;
         }
+    }
+}
 
-        if let Ok((plane, context)) = parse_free_and_plane(&context) {
-            if let Material::Emissive(_) = plane.material {
-                emissive_planes.push(plane);
-            } else {
-                planes.push(plane);
+fn parse_free_and_scene_item<'a>(context: &ParseContext<'a>) -> ParseResult<'a, SceneItem> {
+    let (_, context) = parse_free(&context)?;
+    let keyword = parse_name(&context).map(|(keyword, _)| keyword).unwrap_or("");
+    match keyword {
+        "sphere" => parse_free_and_sphere(&context).map(|(sphere, context)| (SceneItem::Sphere(sphere), context)),
+        "plane" => parse_free_and_plane(&context).map(|(plane, context)| (SceneItem::Plane(plane), context)),
+        "volume" => parse_free_and_volume(&context).map(|(volume, context)| (SceneItem::Volume(volume), context)),
+        "node" => parse_free_and_node(&context),
+        "script" => parse_free_and_script(&context),
+        _ if is_custom_primitive(&context) => parse_free_and_custom_primitive(&context).map(|(custom, context)| (SceneItem::Custom(custom), context)),
+        _ => error(String::from("Expected \"sphere\", \"plane\", \"volume\", \"node\", \"script\" or a registered primitive."), &context),
+    }
+}
+
+fn is_custom_primitive(context: &ParseContext) -> bool {
+    parse_name(context).map(|(name, _)| registry::is_shape(name)).unwrap_or(false)
+}
+
+struct GltfImport {
+    path: String,
+    light_radius: f32,
+    light_scale: f32,
+}
+
+// Takes the camera and the lights from a glTF file (see import.rs). The lights become spheres
+// with the radius, which has to be larger than zero, and their intensity is multiplied by the
+// scale. Both are optional.
+//
+//   gltf {
+//       path = blender/export.gltf
+//       light_radius = 0.1
+//       light_scale = 1.0
+//   }
+fn parse_free_and_gltf<'a>(context: &ParseContext<'a>) -> ParseResult<'a, GltfImport> {
+    let (_   , context) = parse_free_and_string(&context, "gltf")?;
+    let (_   , context) = parse_free_and_string(&context, "{")?;
+    let (_   , context) = parse_free_and_string(&context, "path")?;
+    let (_   , context) = parse_free_and_string(&context, "=")?;
+    let (path, context) = parse_free_and_path(&context)?;
+
+    let mut gltf = GltfImport {
+        path: path,
+        light_radius: 0.1,
+        light_scale: 1.0,
+    };
+
+    // Optional
+    let context = match parse_free_and_named_f32(&context, "light_radius") {
+        Ok((light_radius, new_context)) => {
+            if !(light_radius > 0.0) {
+                return error(format!("The light radius is {} but has to be larger than 0.", light_radius), &context);
             }
-            running_context = context;
-            continue;
-        }
+            gltf.light_radius = light_radius;
+            new_context
+        },
+        Err(_) => context,
+    };
+    let context = match parse_free_and_named_f32(&context, "light_scale") {
+        Ok((light_scale, context)) => {
+            gltf.light_scale = light_scale;
+            context
+        },
+        Err(_) => context,
+    };
 
-        if let Ok((parsed_sky, context)) = parse_free_and_sky(&context) {
-            sky = parsed_sky;
-            running_context = context;
-            continue;
-        }
+    let (_   , context) = parse_free_and_string(&context, "}")?;
+    success(gltf, context)
+}
 
-        if let Ok((parsed_camera, context)) = parse_free_and_camera(&context) {
-            camera = parsed_camera;
-            running_context = context;
-            continue;
+fn parse_free_and_named_f32<'a>(context: &ParseContext<'a>, name: &str) -> ParseResult<'a, f32> {
+    let (_    , context) = parse_free_and_string(&context, name)?;
+    let (_    , context) = parse_free_and_string(&context, "=")?;
+    let (value, context) = parse_free_and_f32(&context)?;
+    success(value, context)
+}
+
+fn parse_free_and_named_bool<'a>(context: &ParseContext<'a>, name: &str) -> ParseResult<'a, bool> {
+    let (_    , context) = parse_free_and_string(&context, name)?;
+    let (_    , context) = parse_free_and_string(&context, "=")?;
+    let (value, context) = parse_free_and_bool(&context)?;
+    success(value, context)
+}
+
+// The visibility of spheres and planes is optional and comes after the material, e.g.
+// "visibility = { camera = false }". The flags that are left out are true.
+fn parse_free_and_optional_visibility<'a>(context: &ParseContext<'a>) -> ParseResult<'a, Visibility> {
+    let context = match parse_free_and_string(&context, "visibility") {
+        Ok((_, context)) => context,
+        Err(_) => return success(Visibility::default(), context.clone()),
+    };
+    let (_, context) = parse_free_and_string(&context, "=")?;
+    let (_, context) = parse_free_and_string(&context, "{")?;
+    let mut visibility = Visibility::default();
+    let context = match parse_free_and_named_bool(&context, "camera") {
+        Ok((value, context)) => { visibility.camera = value; context },
+        Err(_) => context,
+    };
+    let context = match parse_free_and_named_bool(&context, "shadows") {
+        Ok((value, context)) => { visibility.shadows = value; context },
+        Err(_) => context,
+    };
+    let context = match parse_free_and_named_bool(&context, "reflections") {
+        Ok((value, context)) => { visibility.reflections = value; context },
+        Err(_) => context,
+    };
+    let (_, context) = parse_free_and_string(&context, "}")?;
+    success(visibility, context)
+}
+
+// The script is everything between the braces and is run while parsing (see script.rs).
+// Braces in strings and comments of the script are skipped while looking for the end.
+fn parse_free_and_script<'a>(context: &ParseContext<'a>) -> ParseResult<'a, SceneItem> {
+    let (_, context) = parse_free_and_string(&context, "script")?;
+    let (_, context) = parse_free_and_string(&context, "{")?;
+    let script_position = context.position;
+
+    let mut depth = 1;
+    let mut in_string = false;
+    let mut in_comment = false;
+    let mut previous = ' ';
+    let mut position = context.position;
+    let mut end = None;
+    for (index, c) in context.text.char_indices() {
+        if in_comment {
+            in_comment = c != '\n';
+        } else if in_string {
+            in_string = !(c == '"' && previous != '\\');
+        } else if c == '/' && previous == '/' {
+            in_comment = true;
+        } else if c == '"' {
+            in_string = true;
+        } else if c == '{' {
+            depth += 1;
+        } else if c == '}' {
+            depth -= 1;
+            if depth == 0 {
+                end = Some(index);
+                break;
+            }
         }
+        if c == '\n' {
+            position.advanced_line();
+        } else {
+            position.advanced_column();
+        }
+        previous = c;
+    }
+
+    let end = match end {
+        Some(end) => end,
+        None => return error(String::from("The script is missing its closing brace."), &context),
+    };
+    let (source, rest) = context.text.split_at(end);
+    position.advanced_column();
+    let context_after_script = ParseContext::new(&rest[1..], position);
 
-        return Err(ParseError::new(String::from("Expected \"camera\", \"sphere\", \"plane\" or \"sky\"."), context.position));
+    match script::run(source) {
+        Ok(objects) => success(SceneItem::Script(objects), context_after_script),
+        Err(message) => Err(ParseError::new(format!("Error in the script: {}", message), script_position)),
     }
+}
 
-    Ok(Scene::new(image_settings, camera, sky, spheres, planes, emissive_spheres, emissive_planes))
+// A node groups objects and other nodes. Their geometry is given relative to the node, which
+// is placed by the transform relative to the parent node.
+//
+//   node {
+//       transform { translation = (0.0, 0.0, 0.0) rotation_axis = (0.0, 1.0, 0.0) rotation_angle = 0.0 scale = 1.0 }
+//       sphere { ... }
+//       node { ... }
+//   }
+fn parse_free_and_node<'a>(context: &ParseContext<'a>) -> ParseResult<'a, SceneItem> {
+    let (_        , context) = parse_free_and_string(&context, "node")?;
+    let (_        , context) = parse_free_and_string(&context, "{")?;
+    let (_        , context) = parse_free_and_string(&context, "transform")?;
+    let (_        , context) = parse_free_and_string(&context, "{")?;
+    let (transform, context) = parse_free_and_transform_fields(&context)?;
+    let (_        , context) = parse_free_and_string(&context, "}")?;
+
+    let mut children = Vec::new();
+    let mut running_context = context;
+    loop {
+        if let Ok((_, context)) = parse_free_and_string(&running_context, "}") {
+            return success(SceneItem::Node(transform, children), context);
+        }
+        let (_    , context) = parse_free(&running_context)?;
+        let position = context.position;
+        let (child, context) = parse_free_and_scene_item(&context)?;
+        children.push((position, child));
+        running_context = context;
+    }
 }
 
 fn parse_free_and_image_settings<'a>(context: &ParseContext<'a>) -> ParseResult<'a, ImageSettings> {
@@ -129,9 +514,58 @@ fn parse_free_and_image_settings<'a>(context: &ParseContext<'a>) -> ParseResult<
     let (_                 , context) = parse_free_and_string(&context, "=")?;
     let (sub_pixel_sampling, context) = parse_free_and_bool(&context)?;
 
+    let mut image_settings = ImageSettings::new(width as usize, height as usize, sub_pixel_sampling);
+
+    // Optional
+    let context = match parse_free_and_transparent_background(&context) {
+        Ok((transparent_background, context)) => {
+            image_settings.transparent_background = transparent_background;
+            context
+        },
+        Err(_) => context,
+    };
+    let context = match parse_free_and_stereo(&context) {
+        Ok((stereo, context)) => {
+            image_settings.stereo = Some(stereo);
+            context
+        },
+        Err(_) => context,
+    };
+
     let (_                 , context) = parse_free_and_string(&context, "}")?;
 
-    success(ImageSettings::new(width as usize, height as usize, sub_pixel_sampling), context)
+    success(image_settings, context)
+}
+
+// stereo = side_by_side or anaglyph, optionally followed by the interocular and the
+// convergence distance. Like on the command line, side-by-side is accepted as well.
+fn parse_free_and_stereo<'a>(context: &ParseContext<'a>) -> ParseResult<'a, Stereo> {
+    let (_     , context) = parse_free_and_string(&context, "stereo")?;
+    let (_     , context) = parse_free_and_string(&context, "=")?;
+    let side_by_side = parse_free_and_string(&context, "side_by_side").or_else(|_| parse_free_and_string(&context, "side-by-side"));
+    let (layout, context) = match side_by_side {
+        Ok((_, context)) => (StereoLayout::SideBySide, context),
+        Err(_) => {
+            let (_, context) = parse_free_and_string(&context, "anaglyph")?;
+            (StereoLayout::Anaglyph, context)
+        },
+    };
+    let (interocular_distance, context) = match parse_free_and_named_f32(&context, "interocular") {
+        Ok((value, context)) => (Some(value), context),
+        Err(_) => (None, context),
+    };
+    let (convergence_distance, context) = match parse_free_and_named_f32(&context, "convergence") {
+        Ok((value, context)) => (Some(value), context),
+        Err(_) => (None, context),
+    };
+    success(Stereo::with_defaults(layout, interocular_distance, convergence_distance), context)
+}
+
+fn parse_free_and_transparent_background<'a>(context: &ParseContext<'a>) -> ParseResult<'a, bool> {
+    let (_    , context) = parse_free_and_string(&context, "transparent_background")?;
+    let (_    , context) = parse_free_and_string(&context, "=")?;
+    let (value, context) = parse_free_and_bool(&context)?;
+    success(value, context)
 }
 
 fn parse_free_and_camera<'a>(context: &ParseContext<'a>) -> ParseResult<'a, Camera> {
@@ -172,9 +606,20 @@ fn parse_free_and_camera<'a>(context: &ParseContext<'a>) -> ParseResult<'a, Came
     let (_           , context) = parse_free_and_string(&context, "=")?;
     let (iso         , context) = parse_free_and_f32(&context)?;
 
+    // The depth of field is optional and focuses on the target by default.
+    let (depth_of_field, context) = match parse_free_and_named_f32(&context, "aperture") {
+        Ok((aperture, context)) => match parse_free_and_named_f32(&context, "focus_distance") {
+            Ok((focus_distance, context)) => (Some(DepthOfField::new(aperture, focus_distance)), context),
+            Err(_) => (Some(DepthOfField::new(aperture, (target - position).length())), context),
+        },
+        Err(_) => (None, context),
+    };
+
     let (_           , context) = parse_free_and_string(&context, "}")?;
 
-    success(Camera::new(position, target, up, width, height, z_near, tone_mapping, iso), context)
+    let mut camera = Camera::new(position, target, up, width, height, z_near, tone_mapping, iso);
+    camera.set_depth_of_field(depth_of_field);
+    success(camera, context)
 }
 
 fn parse_free_and_tone_mapping<'a>(context: &ParseContext<'a>) -> ParseResult<'a, ToneMapping> {
@@ -188,11 +633,15 @@ fn parse_free_and_tone_mapping<'a>(context: &ParseContext<'a>) -> ParseResult<'a
         return success(ToneMapping::Reinhard, context);
     }
 
+    if let Ok((_, context)) = parse_free_and_string(&context, "aces") {
+        return success(ToneMapping::Aces, context);
+    }
+
     if let Ok((tone_mapping, context)) = parse_free_and_exposure_tone_mapping(&context) {
         return success(tone_mapping, context);
     }
 
-    error(String::from("Unknown sky type."), &context)
+    error(String::from("Unknown tone mapping."), &context)
 }
 
 fn parse_free_and_exposure_tone_mapping<'a>(context: &ParseContext<'a>) -> ParseResult<'a, ToneMapping> {
@@ -256,6 +705,185 @@ fn parse_free_and_sky<'a>(context: &ParseContext<'a>) -> ParseResult<'a, Sky> {
     success(sky, context)
 }
 
+fn parse_free_and_medium<'a>(context: &ParseContext<'a>) -> ParseResult<'a, Medium> {
+    let (_         , context) = parse_free_and_string(&context, "medium")?;
+    let (_         , context) = parse_free_and_string(&context, "{")?;
+
+    let (_         , context) = parse_free_and_string(&context, "sigma_a")?;
+    let (_         , context) = parse_free_and_string(&context, "=")?;
+    let (sigma_a   , context) = parse_free_and_vec3(&context)?;
+
+    let (_         , context) = parse_free_and_string(&context, "sigma_s")?;
+    let (_         , context) = parse_free_and_string(&context, "=")?;
+    let (sigma_s   , context) = parse_free_and_vec3(&context)?;
+
+    let (_         , context) = parse_free_and_string(&context, "anisotropy")?;
+    let (_         , context) = parse_free_and_string(&context, "=")?;
+    let (anisotropy, context) = parse_free_and_anisotropy(&context)?;
+
+    let (_         , context) = parse_free_and_string(&context, "}")?;
+
+    success(Medium::new(sigma_a, sigma_s, anisotropy), context)
+}
+
+// Parses the anisotropy of the Henyey-Greenstein phase function. At -1 or 1 it becomes a delta
+// distribution that cannot be sampled.
+fn parse_free_and_anisotropy<'a>(context: &ParseContext<'a>) -> ParseResult<'a, f32> {
+    let (anisotropy, context_after) = parse_free_and_f32(&context)?;
+    if anisotropy.is_nan() || anisotropy <= -1.0 || anisotropy >= 1.0 {
+        return error(format!("The anisotropy {} has to be between -1 and 1 (exclusive).", anisotropy), &context);
+    }
+    success(anisotropy, context_after)
+}
+
+fn parse_free_and_animation<'a>(context: &ParseContext<'a>) -> ParseResult<'a, Animation> {
+    let (_, context) = parse_free_and_string(&context, "animation")?;
+    let (_, context) = parse_free_and_string(&context, "{")?;
+
+    let mut keyframes = Vec::new();
+    let mut running_context = context;
+    while let Ok((keyframe, context)) = parse_free_and_keyframe(&running_context) {
+        keyframes.push(keyframe);
+        running_context = context;
+    }
+
+    let (_, context) = parse_free_and_string(&running_context, "}")?;
+
+    if keyframes.is_empty() {
+        return error(String::from("The animation needs at least one key."), &context);
+    }
+
+    success(Animation::new(keyframes), context)
+}
+
+fn parse_free_and_keyframe<'a>(context: &ParseContext<'a>) -> ParseResult<'a, Keyframe> {
+    let (_     , context) = parse_free_and_string(&context, "key")?;
+    let (_     , context) = parse_free_and_string(&context, "{")?;
+
+    let (_     , context) = parse_free_and_string(&context, "frame")?;
+    let (_     , context) = parse_free_and_string(&context, "=")?;
+    let (frame , context) = parse_free_and_i32(&context)?;
+
+    if frame < 0 {
+        return error(String::from("The frame of a key must not be negative."), &context);
+    }
+
+    // The camera is optional and has to come before the transforms.
+    let (camera, context) = match parse_free_and_camera_key(&context) {
+        Ok((camera, context)) => (Some(camera), context),
+        Err(_) => (None, context),
+    };
+
+    let mut transforms = Vec::new();
+    let mut running_context = context;
+    while let Ok((transform, context)) = parse_free_and_transform_key(&running_context) {
+        transforms.push(transform);
+        running_context = context;
+    }
+
+    let (_     , context) = parse_free_and_string(&running_context, "}")?;
+
+    success(Keyframe::new(frame as usize, camera, transforms), context)
+}
+
+fn parse_free_and_camera_key<'a>(context: &ParseContext<'a>) -> ParseResult<'a, CameraKey> {
+    let (_       , context) = parse_free_and_string(&context, "camera")?;
+    let (_       , context) = parse_free_and_string(&context, "{")?;
+
+    let (_       , context) = parse_free_and_string(&context, "position")?;
+    let (_       , context) = parse_free_and_string(&context, "=")?;
+    let (position, context) = parse_free_and_vec3(&context)?;
+
+    let (_       , context) = parse_free_and_string(&context, "target")?;
+    let (_       , context) = parse_free_and_string(&context, "=")?;
+    let (target  , context) = parse_free_and_vec3(&context)?;
+
+    let (_       , context) = parse_free_and_string(&context, "up")?;
+    let (_       , context) = parse_free_and_string(&context, "=")?;
+    let (up      , context) = parse_free_and_vec3(&context)?;
+
+    let (_       , context) = parse_free_and_string(&context, "}")?;
+
+    success(CameraKey::new(position, target, up), context)
+}
+
+fn parse_free_and_transform_key<'a>(context: &ParseContext<'a>) -> ParseResult<'a, (ObjectId, Transform)> {
+    let (_             , context) = parse_free_and_string(&context, "transform")?;
+    let (_             , context) = parse_free_and_string(&context, "{")?;
+
+    let (_             , context) = parse_free_and_string(&context, "object")?;
+    let (_             , context) = parse_free_and_string(&context, "=")?;
+    let (id            , context) = parse_free_and_object_id(&context)?;
+    let (transform     , context) = parse_free_and_transform_fields(&context)?;
+    let (_             , context) = parse_free_and_string(&context, "}")?;
+
+    success((id, transform), context)
+}
+
+fn parse_free_and_transform_fields<'a>(context: &ParseContext<'a>) -> ParseResult<'a, Transform> {
+    let (_             , context) = parse_free_and_string(&context, "translation")?;
+    let (_             , context) = parse_free_and_string(&context, "=")?;
+    let (translation   , context) = parse_free_and_vec3(&context)?;
+
+    let (_             , context) = parse_free_and_string(&context, "rotation_axis")?;
+    let (_             , context) = parse_free_and_string(&context, "=")?;
+    let (rotation_axis , context) = parse_free_and_vec3(&context)?;
+
+    let (_             , context) = parse_free_and_string(&context, "rotation_angle")?;
+    let (_             , context) = parse_free_and_string(&context, "=")?;
+    let (rotation_angle, context) = parse_free_and_f32(&context)?;
+
+    let (_             , context) = parse_free_and_string(&context, "scale")?;
+    let (_             , context) = parse_free_and_string(&context, "=")?;
+    let (scale         , context) = parse_free_and_f32(&context)?;
+
+    success(Transform::new(translation, rotation_axis, rotation_angle, scale), context)
+}
+
+// Objects are referenced by their kind and their index in the order of the scene file, e.g.
+// "sphere 0". Lights are counted separately. Nodes are referenced in the same way, e.g.
+// "node 0", and are counted from the outside in.
+fn parse_free_and_object_id<'a>(context: &ParseContext<'a>) -> ParseResult<'a, ObjectId> {
+    let (kind , context) = parse_free_and_object_kind(&context)?;
+    let (index, context) = parse_free_and_i32(&context)?;
+
+    if index < 0 {
+        return error(String::from("The index of an object must not be negative."), &context);
+    }
+
+    success(kind(index as usize), context)
+}
+
+fn parse_free_and_object_kind<'a>(context: &ParseContext<'a>) -> ParseResult<'a, fn(usize) -> ObjectId> {
+    let (_, context) = parse_free(&context)?;
+
+    if let Ok((_, context)) = parse_free_and_string(&context, "emissive_sphere") {
+        return success(ObjectId::EmissiveSphere as fn(usize) -> ObjectId, context);
+    }
+
+    if let Ok((_, context)) = parse_free_and_string(&context, "emissive_plane") {
+        return success(ObjectId::EmissivePlane as fn(usize) -> ObjectId, context);
+    }
+
+    if let Ok((_, context)) = parse_free_and_string(&context, "sphere") {
+        return success(ObjectId::Sphere as fn(usize) -> ObjectId, context);
+    }
+
+    if let Ok((_, context)) = parse_free_and_string(&context, "plane") {
+        return success(ObjectId::Plane as fn(usize) -> ObjectId, context);
+    }
+
+    if let Ok((_, context)) = parse_free_and_string(&context, "volume") {
+        return success(ObjectId::Volume as fn(usize) -> ObjectId, context);
+    }
+
+    if let Ok((_, context)) = parse_free_and_string(&context, "node") {
+        return success(ObjectId::Node as fn(usize) -> ObjectId, context);
+    }
+
+    error(String::from("Unknown object kind."), &context)
+}
+
 fn parse_free_and_vec3<'a>(context: &ParseContext<'a>) -> ParseResult<'a, Vec3> {
     let (_, context) = parse_free_and_string(&context, "(")?;
 
@@ -272,8 +900,8 @@ fn parse_free_and_vec3<'a>(context: &ParseContext<'a>) -> ParseResult<'a, Vec3>
     success(Vec3::new(x, y, z), context)
 }
 
-enum MaterialType { Physically, Emissive, Translucent }
-fn parse_free_and_material_type<'a>(context: &ParseContext<'a>) -> ParseResult<'a, MaterialType> {
+enum MaterialType<'a> { Physically, Emissive, Translucent, Custom(Text<'a>) }
+fn parse_free_and_material_type<'a>(context: &ParseContext<'a>) -> ParseResult<'a, MaterialType<'a>> {
     if let Ok((_, context)) = parse_free_and_string(&context, "physically") {
         return success(MaterialType::Physically, context);
     }
@@ -286,6 +914,13 @@ fn parse_free_and_material_type<'a>(context: &ParseContext<'a>) -> ParseResult<'
         return success(MaterialType::Translucent, context);
     }
 
+    let (_, name_context) = parse_free(&context)?;
+    if let Ok((name, context)) = parse_name(&name_context) {
+        if registry::is_material(name) {
+            return success(MaterialType::Custom(name), context);
+        }
+    }
+
     // @TODO: Add a default material for quickly setting up a scene.
 
     error(String::from("Unknown material type."), context)
@@ -341,9 +976,29 @@ fn parse_free_and_translucent_material<'a>(context: &ParseContext<'a>) -> ParseR
     let (_  , context) = parse_free_and_string(&context, "=")?;
     let (ior, context) = parse_free_and_f32(&context)?;
 
+    // Without the dispersion, all colors are refracted alike.
+    let (dispersion, context) = match parse_free_and_string(&context, "dispersion") {
+        Ok((_, context)) => {
+            let (_, context) = parse_free_and_string(&context, "=")?;
+            parse_free_and_f32(&context)?
+        },
+        Err(_) => (0.0, context),
+    };
+
+    // Overlapping dielectrics take the index of refraction of the one with the higher
+    // priority (see the nested dielectrics in tracer.rs).
+    let (priority, context) = match parse_free_and_string(&context, "priority") {
+        Ok((_, context)) => {
+            let (_, context) = parse_free_and_string(&context, "=")?;
+            let (_, context) = parse_free(&context)?;
+            parse_u32(&context)?
+        },
+        Err(_) => (0, context),
+    };
+
     let (_  , context) = parse_free_and_string(&context, "}")?;
 
-    success(Material::Translucent(ior), context)
+    success(Material::Translucent(ior, dispersion, priority), context)
 }
 
 fn parse_free_and_material<'a>(context: &ParseContext<'a>) -> ParseResult<'a, Material> {
@@ -353,6 +1008,83 @@ fn parse_free_and_material<'a>(context: &ParseContext<'a>) -> ParseResult<'a, Ma
         MaterialType::Physically => parse_free_and_physically_material(&context),
         MaterialType::Emissive => parse_free_and_emissive_material(&context),
         MaterialType::Translucent => parse_free_and_translucent_material(&context),
+        MaterialType::Custom(name) => parse_free_and_custom_material(&context, name),
+    }
+}
+
+// The fields of a registered type (see registry.rs) can be given in any order. Every value is
+// a vector, a number or a word. The material of a primitive is parsed like the ones of the
+// built-in primitives.
+//
+//   velvet { color = (0.5, 0.1, 0.1) sheen = 0.8 }
+//   torus { major_radius = 2.0 minor_radius = 0.5 material = velvet { ... } }
+fn parse_free_and_properties<'a>(context: &ParseContext<'a>, type_name: &str) -> ParseResult<'a, (Properties, Option<Material>)> {
+    let (_, context) = parse_free_and_string(&context, "{")?;
+
+    let mut properties = Properties::new(type_name);
+    let mut material = None;
+    let mut running_context = context;
+    loop {
+        if let Ok((_, context)) = parse_free_and_string(&running_context, "}") {
+            return success((properties, material), context);
+        }
+        let (_   , context) = parse_free(&running_context)?;
+        let (name, context) = parse_name(&context)?;
+        let (_   , context) = parse_free_and_string(&context, "=")?;
+        running_context = if name == "material" {
+            let (parsed_material, context) = parse_free_and_material(&context)?;
+            material = Some(parsed_material);
+            context
+        } else {
+            let (value, context) = parse_free_and_value(&context)?;
+            properties.push(name, value);
+            context
+        };
+    }
+}
+
+fn parse_free_and_value<'a>(context: &ParseContext<'a>) -> ParseResult<'a, Value> {
+    if let Ok((vector, context)) = parse_free_and_vec3(&context) {
+        return success(Value::Vector(vector), context);
+    }
+    if let Ok((number, context)) = parse_free_and_f32(&context) {
+        return success(Value::Number(number), context);
+    }
+    if let Ok((number, context)) = parse_free_and_i32(&context) {
+        return success(Value::Number(number as f32), context);
+    }
+    if let Ok((text, context)) = parse_free_and_path(&context) {
+        return success(Value::Text(text), context);
+    }
+    error(String::from("Expected a vector, a number or a word."), &context)
+}
+
+// The errors of the factories are reported at the start of the block.
+fn parse_free_and_custom_material<'a>(context: &ParseContext<'a>, name: &str) -> ParseResult<'a, Material> {
+    let ((properties, material), context_after) = parse_free_and_properties(&context, name)?;
+    if material.is_some() {
+        return error(format!("The material \"{}\" cannot have a material.", name), &context);
+    }
+    match registry::create_material(name, &properties) {
+        Ok(material) => success(material, context_after),
+        Err(message) => error(message, &context),
+    }
+}
+
+fn parse_free_and_custom_primitive<'a>(context: &ParseContext<'a>) -> ParseResult<'a, CustomPrimitive> {
+    let (_   , context) = parse_free(&context)?;
+    let (name, context) = parse_name(&context)?;
+    let ((properties, material), context_after) = parse_free_and_properties(&context, name)?;
+    let material = match material {
+        Some(material) => material,
+        None => return error(format!("The primitive \"{}\" needs a material.", name), &context),
+    };
+    if let Material::Emissive(_) = material {
+        return error(format!("The primitive \"{}\" cannot be emissive.", name), &context);
+    }
+    match registry::create_shape(name, &properties) {
+        Ok(shape) => success(CustomPrimitive::new(shape, material), context_after),
+        Err(message) => error(message, &context),
     }
 }
 
@@ -388,9 +1120,10 @@ fn parse_free_and_sphere<'a>(context: &ParseContext<'a>) -> ParseResult<'a, Sphe
     let (radius  , context) = radius(context)?;
     let (_       , context) = parse_free_and_newline(&context)?;
     let (material, context) = material(context)?;
+    let (visibility, context) = parse_free_and_optional_visibility(&context)?;
     let (_       , context) = parse_free_and_string(&context, "}")?;
 
-    success(Sphere::new(origin, radius, material), context)
+    success(Sphere { visibility, ..Sphere::new(origin, radius, material) }, context)
 }
 
 fn parse_free_and_plane<'a>(context: &ParseContext<'a>) -> ParseResult<'a, Plane> {
@@ -420,9 +1153,82 @@ fn parse_free_and_plane<'a>(context: &ParseContext<'a>) -> ParseResult<'a, Plane
     let (_       , context) = parse_free_and_string(&context, "=")?;
     let (material, context) = parse_free_and_material(&context)?;
 
+    let (visibility, context) = parse_free_and_optional_visibility(&context)?;
+
     let (_       , context) = parse_free_and_string(&context, "}")?;
 
-    success(Plane::new(origin, u, v, material), context)
+    success(Plane { visibility, ..Plane::new(origin, u, v, material) }, context)
+}
+
+enum DensityType { Noise }
+
+fn parse_free_and_density_type<'a>(context: &ParseContext<'a>) -> ParseResult<'a, DensityType> {
+    if let Ok((_, context)) = parse_free_and_string(&context, "noise") {
+        return success(DensityType::Noise, context);
+    }
+
+    error(String::from("Unknown density type."), context)
+}
+
+fn parse_free_and_noise_density<'a>(context: &ParseContext<'a>) -> ParseResult<'a, DensityGrid> {
+    let (_         , context) = parse_free_and_string(&context, "{")?;
+
+    let (_         , context) = parse_free_and_string(&context, "resolution")?;
+    let (_         , context) = parse_free_and_string(&context, "=")?;
+    let (resolution, context) = parse_free_and_i32(&context)?;
+
+    let (_         , context) = parse_free_and_string(&context, "frequency")?;
+    let (_         , context) = parse_free_and_string(&context, "=")?;
+    let (frequency , context) = parse_free_and_f32(&context)?;
+
+    let (_         , context) = parse_free_and_string(&context, "}")?;
+
+    if resolution < 2 {
+        return error(String::from("The resolution of the density grid has to be at least 2."), &context);
+    }
+
+    success(DensityGrid::noise(resolution as usize, frequency), context)
+}
+
+fn parse_free_and_density<'a>(context: &ParseContext<'a>) -> ParseResult<'a, DensityGrid> {
+    let (density_type, context) = parse_free_and_density_type(&context)?;
+
+    match density_type {
+        DensityType::Noise => parse_free_and_noise_density(&context),
+    }
+}
+
+fn parse_free_and_volume<'a>(context: &ParseContext<'a>) -> ParseResult<'a, Volume> {
+    let (_            , context) = parse_free_and_string(&context, "volume")?;
+    let (_            , context) = parse_free_and_string(&context, "{")?;
+
+    let (_            , context) = parse_free_and_string(&context, "min")?;
+    let (_            , context) = parse_free_and_string(&context, "=")?;
+    let (min          , context) = parse_free_and_vec3(&context)?;
+
+    let (_            , context) = parse_free_and_string(&context, "max")?;
+    let (_            , context) = parse_free_and_string(&context, "=")?;
+    let (max          , context) = parse_free_and_vec3(&context)?;
+
+    let (_            , context) = parse_free_and_string(&context, "density")?;
+    let (_            , context) = parse_free_and_string(&context, "=")?;
+    let (grid         , context) = parse_free_and_density(&context)?;
+
+    let (_            , context) = parse_free_and_string(&context, "density_scale")?;
+    let (_            , context) = parse_free_and_string(&context, "=")?;
+    let (density_scale, context) = parse_free_and_f32(&context)?;
+
+    let (_            , context) = parse_free_and_string(&context, "albedo")?;
+    let (_            , context) = parse_free_and_string(&context, "=")?;
+    let (albedo       , context) = parse_free_and_vec3(&context)?;
+
+    let (_            , context) = parse_free_and_string(&context, "anisotropy")?;
+    let (_            , context) = parse_free_and_string(&context, "=")?;
+    let (anisotropy   , context) = parse_free_and_anisotropy(&context)?;
+
+    let (_            , context) = parse_free_and_string(&context, "}")?;
+
+    success(Volume::new(min, max, grid, density_scale, albedo, anisotropy), context)
 }
 
 fn parse_free_and_path<'a>(context: &ParseContext<'a>) -> ParseResult<'a, String> {
@@ -449,7 +1255,6 @@ fn parse_free_and_ident<'a>(context: &ParseContext<'a>) -> ParseResult<'a, Text<
     success(ident, context)
 }
 
-#[allow(dead_code)]
 fn parse_alphabetic<'a>(context: &ParseContext<'a>) -> ParseResult<'a, Text<'a>> {
     let ident = context.text.chars().take_while(|c| c.is_alphabetic());
     let count = ident.count();
@@ -464,6 +1269,19 @@ fn parse_alphabetic<'a>(context: &ParseContext<'a>) -> ParseResult<'a, Text<'a>>
     }
 }
 
+// The names of the fields and of the registered types, e.g. "major_radius".
+fn parse_name<'a>(context: &ParseContext<'a>) -> ParseResult<'a, Text<'a>> {
+    let mut chars = context.text.chars();
+    if !chars.next().map(|c| c.is_alphabetic()).unwrap_or(false) {
+        return error(String::from("Name expected."), context);
+    }
+    let count = 1 + chars.take_while(|c| c.is_alphanumeric() || *c == '_').count();
+    let (name, rest) = context.text.split_at(count);
+    let mut position = context.position;
+    position.advanced_column_n(count);
+    success(name, ParseContext::new(rest, position))
+}
+
 fn parse_until_newline<'a>(context: &ParseContext<'a>) -> ParseResult<'a, &'a str> {
     let mut comment = context.text.chars().take_while(|c| *c != '\n');
     comment.next();
@@ -541,7 +1359,7 @@ fn parse_string<'a>(context: &ParseContext<'a>, string: &str) -> ParseResult<'a,
         let new_context = ParseContext::new(rest, position);
         success((), new_context)
     } else {
-        error(format!("Could not parse the string \"{}\".", string), context)
+        error(format!("Expected \"{}\".", string), context)
     }
 }
 
@@ -589,7 +1407,7 @@ fn parse_free_and_bool<'a>(context: &ParseContext<'a>) -> ParseResult<'a, bool>
     }
 
     if let Ok((_, context)) = parse_free_and_string(&context, "false") {
-        return success(true, context);
+        return success(false, context);
     }
 
     error(String::from("Expecting boolean."), &context)
@@ -646,4 +1464,36 @@ fn parse_free_and_newline<'a>(context: &ParseContext<'a>) -> ParseResult<'a, ()>
     } else {
         success((), new_context)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_bool(text: &str) -> bool {
+        parse_free_and_bool(&ParseContext::new(text, TextPosition::new(1, 1))).unwrap().0
+    }
+
+    #[test]
+    fn booleans_are_parsed_as_written() {
+        assert_eq!(parse_bool("true"), true);
+        assert_eq!(parse_bool("false"), false);
+        assert_eq!(parse_bool("  false"), false);
+    }
+
+    #[test]
+    fn stereo_is_side_by_side_in_both_spellings() {
+        let parse = |text: &str| parse_free_and_stereo(&ParseContext::new(text, TextPosition::new(1, 1))).map(|(stereo, _)| stereo.layout);
+        assert_eq!(parse("stereo = side_by_side").ok(), Some(StereoLayout::SideBySide));
+        assert_eq!(parse("stereo = side-by-side interocular = 0.2").ok(), Some(StereoLayout::SideBySide));
+        assert_eq!(parse("stereo = anaglyph").ok(), Some(StereoLayout::Anaglyph));
+    }
+
+    #[test]
+    fn gltf_lights_need_a_positive_radius() {
+        let parse = |text: &str| parse_free_and_gltf(&ParseContext::new(text, TextPosition::new(1, 1))).map(|(gltf, _)| gltf.light_radius);
+        assert_eq!(parse("gltf { path = export.gltf light_radius = 0.5 }").ok(), Some(0.5));
+        assert!(parse("gltf { path = export.gltf light_radius = 0.0 }").is_err());
+        assert!(parse("gltf { path = export.gltf light_radius = -1.0 }").is_err());
+    }
+}
@@ -0,0 +1,151 @@
+use common::*;
+
+use tracer::{Backbuffer, Camera};
+
+// Effects of a camera lens that are applied to the average radiance of the backbuffer before
+// it is tone mapped, for nicer beauty shots. They work on the whole image and are therefore
+// computed from the backbuffer after a sample instead of for every pixel like the tone
+// mapping (see tracer::tone_map):
+//
+//   - the bloom spreads the radiance above the threshold over the neighbouring pixels, as
+//     the light that is scattered in the lens and makes bright lights glow,
+//   - the lens flare adds ghosts of the bright parts that are mirrored through the center of
+//     the image, like the reflections between the elements of the lens,
+//   - the vignette darkens the image towards its corners.
+//
+// The strengths are zero when disabled. The radiance in EXR files stays unprocessed.
+
+#[derive(Clone, Copy, Debug, PartialEq, new)]
+pub struct PostProcessing {
+    pub bloom: f32, // Fraction of the bright radiance that is spread
+    pub bloom_threshold: f32, // Luminance above which the radiance blooms
+    pub vignette: f32, // Darkening of the corners, one is black
+    pub lens_flare: f32, // Fraction of the bright radiance in the ghosts
+}
+
+impl Default for PostProcessing {
+    fn default() -> Self {
+        PostProcessing::new(0.0, 1.0, 0.0, 0.0)
+    }
+}
+
+impl PostProcessing {
+    pub fn is_enabled(&self) -> bool {
+        self.bloom > 0.0 || self.vignette > 0.0 || self.lens_flare > 0.0
+    }
+}
+
+// Radii of the blurs of the bloom in parts of the height of the image. Summing a narrow and
+// a wide one gives the sharp core and the broad halo of real lenses.
+const BLOOM_RADII: [f32; 3] = [0.005, 0.02, 0.06];
+
+// Positions of the ghosts of the lens flare along the line from the pixel through the center
+// of the image, where -1 mirrors the pixel through the center, with their tints.
+const GHOSTS: [(f32, (f32, f32, f32)); 3] = [
+    (-1.0, (1.0, 0.8, 0.6)),
+    (-0.4, (0.6, 0.8, 1.0)),
+    (0.6, (0.7, 1.0, 0.7)),
+];
+
+// Averages the pixels within the radius in both directions, which is repeated three times to
+// approximate a gaussian blur. The pixels outside of the image are left out of the average.
+fn blur(image: &Vec<Vec3>, width: usize, height: usize, radius: usize) -> Vec<Vec3> {
+    let mut result = image.clone();
+    let mut line = Vec::new();
+    for _ in 0..3 {
+        for &(length, count, stride, step) in [(width, height, width, 1), (height, width, 1, width)].iter() {
+            for i in 0..count {
+                line.clear();
+                line.extend((0..length).map(|j| result[i*stride + j*step]));
+                let mut sum = Vec3::zero();
+                for j in 0..usize::min(radius, length) {
+                    sum = sum + line[j];
+                }
+                for j in 0..length {
+                    if j + radius < length {
+                        sum = sum + line[j + radius];
+                    }
+                    if j > radius {
+                        sum = sum - line[j - radius - 1];
+                    }
+                    let num_pixels = usize::min(j + radius, length - 1) + 1 - j.saturating_sub(radius);
+                    result[i*stride + j*step] = sum/num_pixels as f32;
+                }
+            }
+        }
+    }
+    result
+}
+
+// Writes the post-processed and tone mapped image into the pixels, which are resized to the
+// backbuffer. The radiance is averaged over the given number of samples. Must only be called
+// while no worker thread is rendering.
+pub fn apply(backbuffer: &Backbuffer, camera: &Camera, settings: &PostProcessing, num_samples: usize, pixels: &mut Vec<Pixel8>) {
+    let (width, height) = (backbuffer.width() as usize, backbuffer.height() as usize);
+    let image: Vec<Vec3> = (0..width*height).map(|index| backbuffer.mean_radiance(index, num_samples)).collect();
+    apply_to_image(backbuffer, camera, settings, image, pixels);
+}
+
+// Like apply but for a linear image in the size of the backbuffer, e.g. the denoised one.
+pub fn apply_to_image(backbuffer: &Backbuffer, camera: &Camera, settings: &PostProcessing, mut image: Vec<Vec3>, pixels: &mut Vec<Pixel8>) {
+    let (width, height) = (backbuffer.width() as usize, backbuffer.height() as usize);
+    assert_eq!(image.len(), width*height);
+
+    // BLOOM AND LENS FLARE
+    if settings.bloom > 0.0 || settings.lens_flare > 0.0 {
+        let bright: Vec<Vec3> = image.iter().map(|&radiance| {
+            let l = luminance(radiance);
+            if l > settings.bloom_threshold { ((l - settings.bloom_threshold)/l)*radiance } else { Vec3::zero() }
+        }).collect();
+        let blurred: Vec<Vec<Vec3>> = BLOOM_RADII.iter()
+            .map(|&radius| blur(&bright, width, height, usize::max(1, (radius*height as f32) as usize)))
+            .collect();
+
+        let (center_x, center_y) = (0.5*width as f32, 0.5*height as f32);
+        let max_distance = f32::sqrt(center_x*center_x + center_y*center_y);
+        for y in 0..height {
+            for x in 0..width {
+                let index = y*width + x;
+                let mut bloom = Vec3::zero();
+                for layer in blurred.iter() {
+                    bloom = bloom + layer[index];
+                }
+                image[index] = image[index] + (settings.bloom/blurred.len() as f32)*bloom;
+
+                // The ghosts are sampled from the widest blur, so that they are soft, and fade
+                // out towards the border of the image.
+                if settings.lens_flare > 0.0 {
+                    let (dx, dy) = (x as f32 + 0.5 - center_x, y as f32 + 0.5 - center_y);
+                    for &(position, (r, g, b)) in GHOSTS.iter() {
+                        let (sample_x, sample_y) = (center_x + position*dx, center_y + position*dy);
+                        if sample_x < 0.0 || sample_y < 0.0 || sample_x >= width as f32 || sample_y >= height as f32 {
+                            continue;
+                        }
+                        let falloff = saturatef32(1.0 - f32::abs(position)*f32::sqrt(dx*dx + dy*dy)/max_distance);
+                        let ghost = blurred[blurred.len() - 1][sample_y as usize*width + sample_x as usize];
+                        image[index] = image[index] + (settings.lens_flare*falloff*falloff)*(Vec3::new(r, g, b)*ghost);
+                    }
+                }
+            }
+        }
+    }
+
+    // VIGNETTE
+    //
+    // The factor falls off with the squared distance from the center and reaches one minus
+    // the strength in the corners.
+    if settings.vignette > 0.0 {
+        let (center_x, center_y) = (0.5*width as f32, 0.5*height as f32);
+        let max_distance_squared = center_x*center_x + center_y*center_y;
+        for y in 0..height {
+            for x in 0..width {
+                let (dx, dy) = (x as f32 + 0.5 - center_x, y as f32 + 0.5 - center_y);
+                let factor = saturatef32(1.0 - settings.vignette*(dx*dx + dy*dy)/max_distance_squared);
+                image[y*width + x] = factor*image[y*width + x];
+            }
+        }
+    }
+
+    // TONE MAPPING
+    backbuffer.tone_map_image(&image, camera, pixels);
+}
@@ -0,0 +1,371 @@
+// Opens the window and draws the image into it. The quad of the image can be placed
+// anywhere, also partly outside of the window, for the zoom and the pan of the view.
+// Magnified pixels are not filtered so that single pixels can be inspected.
+//
+// By default, the pixels are copied into the window with softbuffer, which needs neither
+// OpenGL nor a GPU and works the same on X11, Wayland, macOS and Windows. Dear ImGui needs a
+// GPU renderer, so with the "overlay" feature the image is drawn with OpenGL via glium
+// instead.
+//
+// The pixels are already encoded in sRGB and are shown as they are.
+//
+// In the browser, softbuffer draws into a canvas of the page instead of a window.
+
+#[cfg(not(feature = "overlay"))]
+pub use self::software::Presenter;
+
+#[cfg(feature = "overlay")]
+pub use self::opengl::Presenter;
+
+#[cfg(not(feature = "overlay"))]
+mod software {
+    use std::num::NonZeroU32;
+    use std::rc::Rc;
+
+    use softbuffer::{Context, Surface};
+    use winit::dpi::PhysicalSize;
+    use winit::event_loop::EventLoop;
+    use winit::window::{Window, WindowBuilder};
+    #[cfg(target_arch = "wasm32")]
+    use winit::platform::web::WindowBuilderExtWebSys;
+    #[cfg(target_arch = "wasm32")]
+    use web_sys::HtmlCanvasElement;
+
+    use common::*;
+    use error::Error;
+    use overlay::{Overlay, Parameters, Changes};
+
+    // Maps the pixels of the window to the image. Returns the column of the image for
+    // every column of the window and the row for every row, both counted from the upper
+    // left corner, or None outside of the image.
+    fn nearest_pixels(window_size: (u32, u32), image_size: (u32, u32), top_left: (f64, f64), bottom_right: (f64, f64)) -> (Vec<Option<u32>>, Vec<Option<u32>>) {
+        let lookup = |window_size: u32, image_size: u32, start: f64, end: f64| -> Vec<Option<u32>> {
+            (0..window_size).map(|i| {
+                let t = (i as f64 + 0.5 - start)/(end - start);
+                if t >= 0.0 && t < 1.0 { Some(u32::min((t*image_size as f64) as u32, image_size - 1)) } else { None }
+            }).collect()
+        };
+        (lookup(window_size.0, image_size.0, top_left.0, bottom_right.0), lookup(window_size.1, image_size.1, top_left.1, bottom_right.1))
+    }
+
+    // The context is only needed for creating the surface, which keeps the connection to the
+    // display on its own.
+    pub struct Presenter {
+        window: Rc<Window>,
+        surface: Surface<Rc<Window>, Rc<Window>>,
+        size: (u32, u32), // Of the surface in physical pixels
+    }
+
+    impl Presenter {
+        // Opens the window with the size in physical pixels.
+        pub fn open(event_loop: &EventLoop<()>, width: u32, height: u32) -> Result<(Rc<Window>, Self), Error> {
+            let window = WindowBuilder::new()
+                .with_title("Pathtracer")
+                .with_inner_size(PhysicalSize::new(width, height))
+                .build(event_loop)
+                .map_err(|err| Error::Display(format!("Could not open the window. Error: {}", err)))?;
+            Presenter::with_window(window)
+        }
+
+        // Draws into the canvas of the page, which is resized to the size in physical pixels.
+        #[cfg(target_arch = "wasm32")]
+        pub fn open_canvas(event_loop: &EventLoop<()>, canvas: HtmlCanvasElement, width: u32, height: u32) -> Result<(Rc<Window>, Self), Error> {
+            let window = WindowBuilder::new()
+                .with_canvas(Some(canvas))
+                .with_inner_size(PhysicalSize::new(width, height))
+                .build(event_loop)
+                .map_err(|err| Error::Display(format!("Could not draw into the canvas. Error: {}", err)))?;
+            Presenter::with_window(window)
+        }
+
+        fn with_window(window: Window) -> Result<(Rc<Window>, Self), Error> {
+            let window = Rc::new(window);
+            let context = Context::new(window.clone())
+                .map_err(|err| Error::Display(format!("Could not connect to the display. Error: {}", err)))?;
+            let surface = Surface::new(&context, window.clone())
+                .map_err(|err| Error::Display(format!("Could not create the surface of the window. Error: {}", err)))?;
+            let size = window.inner_size();
+            let mut presenter = Presenter {
+                window: window.clone(),
+                surface: surface,
+                size: (0, 0),
+            };
+            presenter.resize(size.width, size.height);
+            Ok((window, presenter))
+        }
+
+        pub fn resize(&mut self, width: u32, height: u32) {
+            if let (Some(non_zero_width), Some(non_zero_height)) = (NonZeroU32::new(width), NonZeroU32::new(height)) {
+                match self.surface.resize(non_zero_width, non_zero_height) {
+                    Ok(()) => self.size = (width, height),
+                    Err(err) => println!("Could not resize the surface of the window. Error: {}", err),
+                }
+            }
+        }
+
+        // Dear ImGui needs the OpenGL presenter.
+        pub fn create_overlay(&self) -> Option<Overlay> {
+            None
+        }
+
+        // Draws the pixels, which start with the bottom row, between the two corners and
+        // shows the frame. The corners are given in pixels from the upper left corner of the
+        // window.
+        pub fn present(&mut self, pixels: &[Pixel8], width: u32, height: u32, top_left: (f64, f64), bottom_right: (f64, f64), _overlay: Option<(&mut Overlay, &mut Parameters)>) -> Result<Changes, Error> {
+            let (window_width, window_height) = self.size;
+            if window_width == 0 || window_height == 0 {
+                return Ok(Changes::default());
+            }
+
+            let mut buffer = self.surface.buffer_mut()
+                .map_err(|err| Error::Display(format!("Could not get the buffer of the window. Error: {}", err)))?;
+            if pixels.len() == (width*height) as usize && !pixels.is_empty() {
+                let (columns, rows) = nearest_pixels(self.size, (width, height), top_left, bottom_right);
+                for (y, row) in rows.iter().enumerate() {
+                    let line = &mut buffer[y*window_width as usize..(y + 1)*window_width as usize];
+                    match *row {
+                        Some(row) => {
+                            let start = ((height - 1 - row)*width) as usize;
+                            let image_line = &pixels[start..start + width as usize];
+                            for (value, column) in line.iter_mut().zip(columns.iter()) {
+                                *value = match *column {
+                                    Some(column) => {
+                                        let ref pixel = image_line[column as usize];
+                                        (pixel.0 as u32) << 16 | (pixel.1 as u32) << 8 | pixel.2 as u32
+                                    },
+                                    None => 0,
+                                };
+                            }
+                        },
+                        None => {
+                            for value in line.iter_mut() {
+                                *value = 0;
+                            }
+                        },
+                    }
+                }
+            } else {
+                for value in buffer.iter_mut() {
+                    *value = 0;
+                }
+            }
+            self.window.pre_present_notify();
+            buffer.present()
+                .map_err(|err| Error::Display(format!("Could not show the frame. Error: {}", err)))?;
+            Ok(Changes::default())
+        }
+    }
+}
+
+#[cfg(feature = "overlay")]
+mod opengl {
+    use std::borrow::Cow;
+    use std::num::NonZeroU32;
+    use std::rc::Rc;
+
+    use glium;
+    use glium::{Surface, Program, VertexBuffer, Display};
+    use glium::glutin::config::ConfigTemplateBuilder;
+    use glium::glutin::context::{ContextAttributesBuilder, NotCurrentGlContext};
+    use glium::glutin::display::{GetGlDisplay, GlDisplay};
+    use glium::glutin::surface::{WindowSurface, SurfaceAttributesBuilder};
+    use glium::index::{NoIndices, PrimitiveType};
+    use glium::program::ProgramCreationInput;
+    use glium::texture::{Texture2d, RawImage2d, ClientFormat, MipmapsOption};
+    use glium::uniforms::{MagnifySamplerFilter, MinifySamplerFilter};
+    use glium::Rect;
+    use glutin_winit::DisplayBuilder;
+    use raw_window_handle::HasRawWindowHandle;
+    use winit::dpi::PhysicalSize;
+    use winit::event_loop::EventLoop;
+    use winit::window::{Window, WindowBuilder};
+
+    use common::*;
+    use error::Error;
+    use overlay::{Overlay, Parameters, Changes};
+
+    // The pixels are uploaded to a texture on every frame, which is only recreated when the
+    // size of the image changes. The program tells glium that it outputs sRGB, which keeps
+    // the framebuffer from converting the pixels again.
+
+    #[derive(Clone, Copy, Debug)]
+    struct Vertex {
+        position: [f32; 2],
+    }
+    implement_vertex!(Vertex, position);
+
+    const VERTEX_SHADER: &str = "
+        #version 110
+
+        uniform vec4 rect; // Left, bottom, right and top in normalized device coordinates
+        attribute vec2 position;
+        varying vec2 tex_coords;
+
+        void main() {
+            tex_coords = position;
+            gl_Position = vec4(mix(rect.xy, rect.zw, position), 0.0, 1.0);
+        }
+    ";
+
+    const FRAGMENT_SHADER: &str = "
+        #version 110
+
+        uniform sampler2D image;
+        varying vec2 tex_coords;
+
+        void main() {
+            gl_FragColor = texture2D(image, tex_coords);
+        }
+    ";
+
+    pub struct Presenter {
+        window: Rc<Window>,
+        display: Display<WindowSurface>,
+        program: Program,
+        vertices: VertexBuffer<Vertex>,
+        texture: Option<Texture2d>,
+    }
+
+    impl Presenter {
+        // Opens the window with the size in physical pixels.
+        pub fn open(event_loop: &EventLoop<()>, width: u32, height: u32) -> Result<(Rc<Window>, Self), Error> {
+            let (window, display) = open_window(event_loop, width, height)?;
+            let program = Program::new(&display, ProgramCreationInput::SourceCode {
+                vertex_shader: VERTEX_SHADER,
+                tessellation_control_shader: None,
+                tessellation_evaluation_shader: None,
+                geometry_shader: None,
+                fragment_shader: FRAGMENT_SHADER,
+                transform_feedback_varyings: None,
+                outputs_srgb: true,
+                uses_point_size: false,
+            }).map_err(|err| Error::Display(format!("Could not compile the shaders for displaying the image. Error: {:?}", err)))?;
+
+            let corners = [
+                Vertex { position: [0.0, 0.0] },
+                Vertex { position: [1.0, 0.0] },
+                Vertex { position: [0.0, 1.0] },
+                Vertex { position: [1.0, 1.0] },
+            ];
+            let vertices = VertexBuffer::new(&display, &corners)
+                .map_err(|err| Error::Display(format!("Could not create the vertex buffer for displaying the image. Error: {:?}", err)))?;
+
+            let window = Rc::new(window);
+            Ok((window.clone(), Presenter {
+                window: window,
+                display: display,
+                program: program,
+                vertices: vertices,
+                texture: None,
+            }))
+        }
+
+        pub fn resize(&mut self, width: u32, height: u32) {
+            self.display.resize((width, height));
+        }
+
+        pub fn create_overlay(&self) -> Option<Overlay> {
+            Overlay::new(&self.display)
+        }
+
+        // Draws the pixels, which start with the bottom row, between the two corners, then
+        // the overlay on top and shows the frame. The corners are given in pixels from the
+        // upper left corner of the window. Returns the parameters that were edited in the
+        // overlay.
+        pub fn present(&mut self, pixels: &[Pixel8], width: u32, height: u32, top_left: (f64, f64), bottom_right: (f64, f64), overlay: Option<(&mut Overlay, &mut Parameters)>) -> Result<Changes, Error> {
+            let mut target = self.display.draw();
+            target.clear_color(0.0, 0.0, 0.0, 1.0);
+            let result = self.draw_image(&mut target, pixels, width, height, top_left, bottom_right);
+            let changes = match overlay {
+                Some((overlay, parameters)) => overlay.draw(&mut target, self.window.scale_factor(), parameters),
+                None => Changes::default(),
+            };
+            target.finish()
+                .map_err(|err| Error::Display(format!("Could not show the frame. Error: {:?}", err)))?;
+            result.map(|()| changes)
+        }
+
+        fn draw_image(&mut self, target: &mut glium::Frame, pixels: &[Pixel8], width: u32, height: u32, top_left: (f64, f64), bottom_right: (f64, f64)) -> Result<(), Error> {
+            if pixels.len() != (width*height) as usize || pixels.is_empty() {
+                return Ok(());
+            }
+
+            // UPLOADING THE PIXELS
+            let mut data = Vec::with_capacity(3*pixels.len());
+            for pixel in pixels {
+                data.extend_from_slice(&[pixel.0, pixel.1, pixel.2]);
+            }
+            let image = RawImage2d {
+                data: Cow::Owned(data),
+                width: width,
+                height: height,
+                format: ClientFormat::U8U8U8,
+            };
+            let size_changed = match self.texture {
+                Some(ref texture) => (texture.width(), texture.height()) != (width, height),
+                None => true,
+            };
+            if size_changed {
+                let texture = Texture2d::with_mipmaps(&self.display, image, MipmapsOption::NoMipmap)
+                    .map_err(|err| Error::Display(format!("Could not create the texture for displaying the image. Error: {:?}", err)))?;
+                self.texture = Some(texture);
+            } else if let Some(ref texture) = self.texture {
+                texture.write(Rect { left: 0, bottom: 0, width: width, height: height }, image);
+            }
+
+            // DRAWING THE QUAD
+            let texture = match self.texture {
+                Some(ref texture) => texture,
+                None => return Ok(()),
+            };
+            let (window_width, window_height) = target.get_dimensions();
+            let to_ndc = |(x, y): (f64, f64)| [(2.0*x/window_width as f64 - 1.0) as f32, (1.0 - 2.0*y/window_height as f64) as f32];
+            let (bottom_left, top_right) = (to_ndc((top_left.0, bottom_right.1)), to_ndc((bottom_right.0, top_left.1)));
+            let uniforms = uniform! {
+                rect: [bottom_left[0], bottom_left[1], top_right[0], top_right[1]],
+                image: texture.sampled()
+                    .magnify_filter(MagnifySamplerFilter::Nearest)
+                    .minify_filter(MinifySamplerFilter::Linear),
+            };
+            target.draw(&self.vertices, &NoIndices(PrimitiveType::TriangleStrip), &self.program, &uniforms, &Default::default())
+                .map_err(|err| Error::Display(format!("Could not draw the image. Error: {:?}", err)))
+        }
+    }
+
+    // The same steps as the SimpleWindowBuilder of glium, which panics when there is no
+    // display or no suitable OpenGL context, e.g. over SSH without X forwarding. Here every
+    // step returns its error instead. The first configuration of glutin is taken, as the
+    // image is drawn without multisampling or depth. glutin reports an error instead of
+    // calling the picker when there is no configuration at all.
+    fn open_window(event_loop: &EventLoop<()>, width: u32, height: u32) -> Result<(Window, Display<WindowSurface>), Error> {
+        let window_builder = WindowBuilder::new()
+            .with_title("Pathtracer")
+            .with_inner_size(PhysicalSize::new(width, height));
+        let (window, config) = DisplayBuilder::new()
+            .with_window_builder(Some(window_builder))
+            .build(event_loop, ConfigTemplateBuilder::new(), |mut configs| configs.next().expect("glutin returned no configurations."))
+            .map_err(|err| Error::Display(format!("Could not open the window. Error: {}", err)))?;
+        let window = window.ok_or(Error::Display(String::from("Could not open the window.")))?;
+
+        let size = window.inner_size();
+        let surface_attributes = SurfaceAttributesBuilder::<WindowSurface>::new().build(
+            window.raw_window_handle(),
+            NonZeroU32::new(u32::max(size.width, 1)).unwrap(),
+            NonZeroU32::new(u32::max(size.height, 1)).unwrap());
+        let context_attributes = ContextAttributesBuilder::new().build(Some(window.raw_window_handle()));
+        // The window outlives the surface and the context, which are owned by the display of
+        // the presenter that is dropped together with it.
+        let (surface, context) = unsafe {
+            let surface = config.display().create_window_surface(&config, &surface_attributes)
+                .map_err(|err| Error::Display(format!("Could not create the surface of the window. Error: {}", err)))?;
+            let context = config.display().create_context(&config, &context_attributes)
+                .map_err(|err| Error::Display(format!("Could not create the OpenGL context. Error: {}", err)))?;
+            (surface, context)
+        };
+        let context = context.make_current(&surface)
+            .map_err(|err| Error::Display(format!("Could not make the OpenGL context current. Error: {}", err)))?;
+        let display = Display::from_context_surface(context, surface)
+            .map_err(|err| Error::Display(format!("Could not connect glium to the OpenGL context. Error: {}", err)))?;
+        Ok((window, display))
+    }
+}
@@ -0,0 +1,130 @@
+use std;
+use std::io::Write;
+
+use common::precise_time_ns;
+
+// Progress of the accumulation since the last restart. The window shows it in its title and
+// both the window and the headless mode print it as a progress bar to the terminal, which is
+// redrawn in place. Within a frame, the progress is updated from the finished tiles.
+//
+// Only the frames in full resolution are counted. The time of the preview frames and the
+// time while paused are therefore not included in the elapsed time. The remaining time is
+// estimated from the average time per sample and only known when the samples per pixel are
+// limited.
+
+const BAR_WIDTH: usize = 30;
+const PRINT_INTERVAL_NS: u64 = 100_000_000;
+
+#[derive(Clone, Debug)]
+pub struct Progress {
+    target_samples: Option<usize>,
+    num_samples: usize, // Finished samples per pixel
+    render_time_ns: u64, // Summed over the finished samples
+    rays: usize,
+    frame_tiles: (usize, usize), // Finished and total tiles of the current frame
+    last_print_ns: u64,
+}
+
+impl Progress {
+    pub fn new(target_samples: Option<usize>) -> Self {
+        Progress {
+            target_samples: target_samples,
+            num_samples: 0,
+            render_time_ns: 0,
+            rays: 0,
+            frame_tiles: (0, 0),
+            last_print_ns: 0,
+        }
+    }
+
+    pub fn restart(&mut self) {
+        *self = Progress::new(self.target_samples);
+    }
+
+    pub fn set_target_samples(&mut self, target_samples: Option<usize>) {
+        self.target_samples = target_samples;
+    }
+
+    pub fn set_frame_tiles(&mut self, num_finished: usize, num_tiles: usize) {
+        self.frame_tiles = (num_finished, num_tiles);
+    }
+
+    pub fn finish_sample(&mut self, render_time_ns: u64, rays: usize) {
+        self.num_samples += 1;
+        self.render_time_ns += render_time_ns;
+        self.rays += rays;
+        self.frame_tiles = (0, 0);
+    }
+
+    // Finished samples plus the finished part of the current frame
+    fn fractional_samples(&self) -> f64 {
+        let (num_finished, num_tiles) = self.frame_tiles;
+        let frame = if num_tiles > 0 { num_finished as f64 / num_tiles as f64 } else { 0.0 };
+        self.num_samples as f64 + frame
+    }
+
+    fn remaining_seconds(&self) -> Option<f64> {
+        match self.target_samples {
+            Some(target_samples) if self.num_samples > 0 => {
+                let seconds_per_sample = self.render_time_ns as f64 / 1_000_000_000.0 / self.num_samples as f64;
+                Some(f64::max(0.0, target_samples as f64 - self.fractional_samples())*seconds_per_sample)
+            },
+            _ => None,
+        }
+    }
+
+    // E.g. "1m 23s | 64/256 spp | ETA 4m 10s | 12.34 Mrays/s"
+    pub fn summary(&self) -> String {
+        let seconds = self.render_time_ns as f64 / 1_000_000_000.0;
+        let samples = match self.target_samples {
+            Some(target_samples) => format!("{}/{} spp", self.num_samples, target_samples),
+            None => format!("{} spp", self.num_samples),
+        };
+        let remaining = match self.remaining_seconds() {
+            Some(remaining) => format_duration(remaining),
+            None => String::from("-"),
+        };
+        let mrays_per_second = if seconds > 0.0 { self.rays as f64 / seconds / 1_000_000.0 } else { 0.0 };
+        format!("{} | {} | ETA {} | {:.2} Mrays/s", format_duration(seconds), samples, remaining, mrays_per_second)
+    }
+
+    // Redraws the progress bar in the terminal. Without a limit of the samples per pixel,
+    // only the summary is shown. Unless forced, the bar is redrawn at most ten times per
+    // second.
+    pub fn print(&mut self, force: bool) {
+        let now = precise_time_ns();
+        if !force && now - self.last_print_ns < PRINT_INTERVAL_NS {
+            return;
+        }
+        self.last_print_ns = now;
+
+        let line = match self.target_samples {
+            Some(target_samples) => {
+                let fraction = f64::min(1.0, self.fractional_samples() / target_samples as f64);
+                let filled = (fraction*BAR_WIDTH as f64) as usize;
+                format!("[{}{}] {:3.0}% | {}", "#".repeat(filled), "-".repeat(BAR_WIDTH - filled), 100.0*fraction, self.summary())
+            },
+            None => self.summary(),
+        };
+        // The trailing spaces overwrite the rest of a longer previous line.
+        print!("\r{:<100}", line);
+        std::io::stdout().flush().ok();
+    }
+
+    // Moves the cursor below the bar so that the next output starts on a new line.
+    pub fn finish(&self) {
+        println!();
+    }
+}
+
+// E.g. "12.3s", "4m 05s" or "1h 02m"
+fn format_duration(seconds: f64) -> String {
+    let whole_seconds = seconds as u64;
+    if whole_seconds < 60 {
+        format!("{:.1}s", seconds)
+    } else if whole_seconds < 3600 {
+        format!("{}m {:02}s", whole_seconds / 60, whole_seconds % 60)
+    } else {
+        format!("{}h {:02}m", whole_seconds / 3600, (whole_seconds % 3600) / 60)
+    }
+}
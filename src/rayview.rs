@@ -0,0 +1,221 @@
+use std;
+
+use common::*;
+
+use scene::{Scene, Bounds, Primitive};
+use tracer::{RaySegment, SegmentKind};
+
+// Draws the recorded paths of a pixel (see tracer::record_pixel_paths) as lines over a
+// wireframe of the scene, to see where the rays of a pixel that looks wrong go. The left
+// half of the image looks at the scene from the top with x to the right and -z up, the right
+// half from the side with -z to the right and y up. Both are orthographic and fitted to the
+// scene, the camera and the paths:
+//
+//   - the spheres are circles, the planes their edges and the custom primitives and volumes
+//     their bounding boxes,
+//   - the camera is white with lines to the corners of its projection plane,
+//   - the primary rays are yellow, the bounces orange and the rays into the sky blue,
+//   - the shadow rays are green when they reach the light and red when they are blocked.
+
+const BACKGROUND: Pixel8 = Pixel8(24, 24, 24);
+const SEPARATOR: Pixel8 = Pixel8(64, 64, 64);
+const WIREFRAME: Pixel8 = Pixel8(128, 128, 128);
+const VOLUME: Pixel8 = Pixel8(96, 96, 160);
+const CAMERA: Pixel8 = Pixel8(255, 255, 255);
+const PRIMARY: Pixel8 = Pixel8(255, 220, 0);
+const BOUNCE: Pixel8 = Pixel8(255, 128, 0);
+const SKY: Pixel8 = Pixel8(100, 180, 255);
+const SHADOW_VISIBLE: Pixel8 = Pixel8(0, 200, 0);
+const SHADOW_BLOCKED: Pixel8 = Pixel8(220, 0, 0);
+
+// Segments of the circles of the spheres.
+const NUM_CIRCLE_SEGMENTS: usize = 64;
+
+// One half of the image with the axes of the scene that are horizontal and vertical in it.
+struct View {
+    project: fn(Vec3) -> (f32, f32),
+    x0: usize,
+    width: usize,
+    center: (f32, f32),
+    scale: f32,
+}
+
+fn project_top(p: Vec3) -> (f32, f32) {
+    (p.x, -p.z)
+}
+
+fn project_side(p: Vec3) -> (f32, f32) {
+    (-p.z, p.y)
+}
+
+struct Canvas {
+    pixels: Vec<Pixel8>,
+    width: usize,
+    height: usize,
+}
+
+impl Canvas {
+    fn to_pixel(&self, view: &View, p: Vec3) -> (f32, f32) {
+        let (u, v) = (view.project)(p);
+        (view.x0 as f32 + 0.5*view.width as f32 + (u - view.center.0)*view.scale,
+         0.5*self.height as f32 + (v - view.center.1)*view.scale)
+    }
+
+    // Only the pixels inside of the view are drawn, so that the lines do not cross over
+    // into the other one.
+    fn plot(&mut self, view: &View, x: f32, y: f32, color: &Pixel8) {
+        if x < view.x0 as f32 || x >= (view.x0 + view.width) as f32 || y < 0.0 || y >= self.height as f32 {
+            return;
+        }
+        self.pixels[y as usize*self.width + x as usize] = color.clone();
+    }
+
+    fn line_2d(&mut self, view: &View, a: (f32, f32), b: (f32, f32), color: &Pixel8) {
+        let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+        // The steps are limited for lines that are almost infinitely long, e.g. when a ray
+        // leaves the scene.
+        let max_steps = 4*(self.width + self.height);
+        let num_steps = usize::min(f32::max(f32::abs(dx), f32::abs(dy)).ceil() as usize, max_steps);
+        for step in 0..num_steps + 1 {
+            let t = if num_steps == 0 { 0.0 } else { step as f32 / num_steps as f32 };
+            self.plot(view, a.0 + t*dx, a.1 + t*dy, color);
+        }
+    }
+
+    fn line(&mut self, view: &View, a: Vec3, b: Vec3, color: &Pixel8) {
+        let (a, b) = (self.to_pixel(view, a), self.to_pixel(view, b));
+        self.line_2d(view, a, b, color);
+    }
+
+    fn dot(&mut self, view: &View, p: Vec3, color: &Pixel8) {
+        let (x, y) = self.to_pixel(view, p);
+        for dy in -1..2 {
+            for dx in -1..2 {
+                self.plot(view, x + dx as f32, y + dy as f32, color);
+            }
+        }
+    }
+
+    fn circle(&mut self, view: &View, center: Vec3, radius: f32, color: &Pixel8) {
+        let (x, y) = self.to_pixel(view, center);
+        let radius = radius*view.scale;
+        let point = |i: usize| {
+            let phi = 2.0*PI*i as f32 / NUM_CIRCLE_SEGMENTS as f32;
+            (x + radius*f32::cos(phi), y + radius*f32::sin(phi))
+        };
+        for i in 0..NUM_CIRCLE_SEGMENTS {
+            self.line_2d(view, point(i), point(i + 1), color);
+        }
+    }
+
+    fn bounding_box(&mut self, view: &View, bounds: &Bounds, color: &Pixel8) {
+        let corner = |i: usize| Vec3::new(
+            if i & 1 == 0 { bounds.min.x } else { bounds.max.x },
+            if i & 2 == 0 { bounds.min.y } else { bounds.max.y },
+            if i & 4 == 0 { bounds.min.z } else { bounds.max.z });
+        for i in 0..8 {
+            for &bit in [1, 2, 4].iter() {
+                if i & bit == 0 {
+                    self.line(view, corner(i), corner(i | bit), color);
+                }
+            }
+        }
+    }
+}
+
+// Fits the bounds into the view with a margin around them.
+fn fit_view(project: fn(Vec3) -> (f32, f32), bounds: &Bounds, x0: usize, width: usize, height: usize) -> View {
+    let (mut min, mut max) = ((std::f32::MAX, std::f32::MAX), (std::f32::MIN, std::f32::MIN));
+    for &x in [bounds.min.x, bounds.max.x].iter() {
+        for &y in [bounds.min.y, bounds.max.y].iter() {
+            for &z in [bounds.min.z, bounds.max.z].iter() {
+                let (u, v) = project(Vec3::new(x, y, z));
+                min = (f32::min(min.0, u), f32::min(min.1, v));
+                max = (f32::max(max.0, u), f32::max(max.1, v));
+            }
+        }
+    }
+    const MARGIN: f32 = 0.9;
+    let extent = (f32::max(max.0 - min.0, 1.0e-3), f32::max(max.1 - min.1, 1.0e-3));
+    let scale = MARGIN*f32::min(width as f32 / extent.0, height as f32 / extent.1);
+    View { project, x0, width, center: (0.5*(min.0 + max.0), 0.5*(min.1 + max.1)), scale }
+}
+
+// Returns the image in the layout of the backbuffer, starting with the bottom row.
+pub fn draw(scene: &Scene, segments: &[RaySegment], width: u32, height: u32) -> Vec<Pixel8> {
+    let (width, height) = (width as usize, height as usize);
+    let mut canvas = Canvas { pixels: vec![BACKGROUND; width*height], width, height };
+
+    // FITTING THE VIEWS
+    //
+    // The rays into the sky are recorded with a length of one and are extended to the size
+    // of the scene, which leaves them out of the bounds.
+    let mut bounds = scene.bounds_with_volumes();
+    bounds.extend(scene.camera.position());
+    for segment in segments.iter().filter(|segment| segment.kind != SegmentKind::Sky) {
+        bounds.extend(segment.start);
+        bounds.extend(segment.end);
+    }
+    let sky_length = f32::max(2.0*bounds.bounding_sphere().1, 1.0);
+    let views = [
+        fit_view(project_top, &bounds, 0, width/2, height),
+        fit_view(project_side, &bounds, width/2, width - width/2, height),
+    ];
+
+    for view in views.iter() {
+        // WIREFRAME
+        for (_, primitive) in scene.primitives() {
+            match primitive {
+                Primitive::Sphere(sphere) => canvas.circle(view, sphere.origin, sphere.radius, &WIREFRAME),
+                Primitive::Plane(plane) => {
+                    let corners = [plane.origin, plane.origin + plane.u, plane.origin + plane.u + plane.v, plane.origin + plane.v];
+                    for i in 0..4 {
+                        canvas.line(view, corners[i], corners[(i + 1) % 4], &WIREFRAME);
+                    }
+                },
+                Primitive::Custom(_) => canvas.bounding_box(view, &primitive.bounds(), &WIREFRAME),
+            }
+        }
+        for volume in scene.volumes.iter() {
+            canvas.bounding_box(view, &Bounds { min: volume.min, max: volume.max }, &VOLUME);
+        }
+
+        // CAMERA
+        let position = scene.camera.position();
+        let (plane_origin, plane_u, plane_v) = scene.camera.projection_plane();
+        let corners = [plane_origin, plane_origin + plane_u, plane_origin + plane_u + plane_v, plane_origin + plane_v];
+        for i in 0..4 {
+            canvas.line(view, position, corners[i], &CAMERA);
+            canvas.line(view, corners[i], corners[(i + 1) % 4], &CAMERA);
+        }
+        canvas.dot(view, position, &CAMERA);
+
+        // PATHS
+        //
+        // The shadow rays are drawn last, so that it is visible which lights are blocked.
+        for segment in segments.iter().filter(|segment| segment.kind != SegmentKind::Sky) {
+            if let SegmentKind::Path = segment.kind {
+                let color = if segment.bounce == 0 { PRIMARY } else { BOUNCE };
+                canvas.line(view, segment.start, segment.end, &color);
+                canvas.dot(view, segment.end, &color);
+            }
+        }
+        for segment in segments.iter().filter(|segment| segment.kind == SegmentKind::Sky) {
+            let end = segment.start + sky_length*(segment.end - segment.start);
+            canvas.line(view, segment.start, end, &SKY);
+        }
+        for segment in segments.iter() {
+            if let SegmentKind::Shadow(is_visible) = segment.kind {
+                canvas.line(view, segment.start, segment.end, if is_visible { &SHADOW_VISIBLE } else { &SHADOW_BLOCKED });
+            }
+        }
+    }
+
+    // The views are separated by a vertical line.
+    if width > 1 {
+        for y in 0..height {
+            canvas.pixels[y*width + width/2] = SEPARATOR.clone();
+        }
+    }
+    canvas.pixels
+}
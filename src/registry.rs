@@ -0,0 +1,178 @@
+use std::fmt;
+use std::collections::BTreeMap;
+use std::sync::{Arc, RwLock, OnceLock};
+
+use common::*;
+use scene::{Material, Bounds};
+use blinn_phong;
+
+// Materials and primitives that are added by the programs using the library. They are
+// registered under a type name before the scene is loaded, which makes the name usable in
+// native scene files like the built-in types:
+//
+//   pathtracer::plugin::register_material("velvet", |properties| {
+//       Ok(Arc::new(Velvet { color: properties.vector("color")?, sheen: properties.number_or("sheen", 0.5)? }))
+//   });
+//
+//   sphere {
+//       origin = (0.0, 1.0, 0.0)
+//       radius = 1.0
+//       material = velvet { color = (0.5, 0.1, 0.1) sheen = 0.8 }
+//   }
+//
+// Primitives are given as blocks on their own, with the material as one of the properties:
+//
+//   torus { major_radius = 2.0 minor_radius = 0.5 material = physically { ... } }
+//
+// The path tracer, the wavefront integrator and ReSTIR sample the BSDF. The Whitted ray
+// tracer, the GPU and the debug views treat custom materials as diffuse with their albedo.
+// Custom primitives are not sampled as lights and cannot be animated. Remote workers have to
+// register the same types to parse the scene of the master. The materials "blinn_phong" and
+// "mtl" are registered from the start (see blinn_phong.rs).
+//
+// @TODO: Support the registered types in RON and JSON scene files.
+
+pub trait Bsdf: Send + Sync + fmt::Debug {
+    // Samples the direction towards the incoming light for the direction towards the viewer.
+    // Both point away from the surface and the normal faces the viewer. Returns the
+    // direction and its weight, which is the BSDF times the cosine divided by the pdf, or
+    // None when the path is absorbed.
+    fn sample(&self, outgoing: Vec3, normal: Vec3, xi: Vec2) -> Option<(Vec3, Vec3)>;
+
+    // The diffuse color for the denoiser and for the integrators that do not sample the BSDF.
+    fn albedo(&self) -> Vec3;
+}
+
+pub trait Shape: Send + Sync + fmt::Debug {
+    // Returns the nearest distance along the ray and the normal there, which points
+    // outwards. Hits closer than RAY_EPSILON are ignored by the caller.
+    fn intersect(&self, ray: &Ray) -> Option<(f32, Vec3)>;
+
+    fn bounds(&self) -> Bounds;
+}
+
+#[derive(Clone, Debug)]
+pub enum Value {
+    Number(f32),
+    Vector(Vec3),
+    Text(String), // Words and paths
+}
+
+// The fields of a block in the scene file in the order in which they were given.
+#[derive(Clone, Debug, Default)]
+pub struct Properties {
+    type_name: String,
+    values: Vec<(String, Value)>,
+}
+
+impl Properties {
+    pub fn new(type_name: &str) -> Self {
+        Properties {
+            type_name: String::from(type_name),
+            values: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, name: &str, value: Value) {
+        self.values.push((String::from(name), value));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.values.iter().find(|&&(ref value_name, _)| value_name == name).map(|&(_, ref value)| value)
+    }
+
+    fn missing(&self, name: &str, expected: &str) -> String {
+        format!("\"{}\" needs \"{}\" as {}.", self.type_name, name, expected)
+    }
+
+    pub fn number(&self, name: &str) -> Result<f32, String> {
+        match self.get(name) {
+            Some(&Value::Number(number)) => Ok(number),
+            _ => Err(self.missing(name, "a number")),
+        }
+    }
+
+    pub fn vector(&self, name: &str) -> Result<Vec3, String> {
+        match self.get(name) {
+            Some(&Value::Vector(vector)) => Ok(vector),
+            _ => Err(self.missing(name, "a vector")),
+        }
+    }
+
+    pub fn text(&self, name: &str) -> Result<&str, String> {
+        match self.get(name) {
+            Some(&Value::Text(ref text)) => Ok(text),
+            _ => Err(self.missing(name, "a word or a path")),
+        }
+    }
+
+    // Only fails when the field is given with the wrong type.
+    pub fn number_or(&self, name: &str, default: f32) -> Result<f32, String> {
+        match self.get(name) {
+            None => Ok(default),
+            Some(_) => self.number(name),
+        }
+    }
+
+    pub fn vector_or(&self, name: &str, default: Vec3) -> Result<Vec3, String> {
+        match self.get(name) {
+            None => Ok(default),
+            Some(_) => self.vector(name),
+        }
+    }
+}
+
+pub type MaterialFactory = Box<Fn(&Properties) -> Result<Arc<Bsdf>, String> + Send + Sync>;
+pub type ShapeFactory = Box<Fn(&Properties) -> Result<Arc<Shape>, String> + Send + Sync>;
+
+#[derive(Default)]
+struct Registry {
+    materials: BTreeMap<String, MaterialFactory>,
+    shapes: BTreeMap<String, ShapeFactory>,
+}
+
+fn registry() -> &'static RwLock<Registry> {
+    static REGISTRY: OnceLock<RwLock<Registry>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let mut registry = Registry::default();
+        let factory: MaterialFactory = Box::new(|properties: &Properties| blinn_phong::create(properties).map(|bsdf| Arc::new(bsdf) as Arc<Bsdf>));
+        registry.materials.insert(String::from("blinn_phong"), factory);
+        let factory: MaterialFactory = Box::new(|properties: &Properties| blinn_phong::create_from_file(properties).map(|bsdf| Arc::new(bsdf) as Arc<Bsdf>));
+        registry.materials.insert(String::from("mtl"), factory);
+        RwLock::new(registry)
+    })
+}
+
+// A type that is registered again replaces the previous one. The names of the built-in
+// types are parsed as those.
+pub fn register_material<F>(name: &str, factory: F) where F: Fn(&Properties) -> Result<Arc<Bsdf>, String> + Send + Sync + 'static {
+    registry().write().expect("Could not get writing access to the registry.").materials.insert(String::from(name), Box::new(factory));
+}
+
+pub fn register_shape<F>(name: &str, factory: F) where F: Fn(&Properties) -> Result<Arc<Shape>, String> + Send + Sync + 'static {
+    registry().write().expect("Could not get writing access to the registry.").shapes.insert(String::from(name), Box::new(factory));
+}
+
+pub fn is_material(name: &str) -> bool {
+    registry().read().expect("Could not get reading access to the registry.").materials.contains_key(name)
+}
+
+pub fn is_shape(name: &str) -> bool {
+    registry().read().expect("Could not get reading access to the registry.").shapes.contains_key(name)
+}
+
+pub fn create_material(name: &str, properties: &Properties) -> Result<Material, String> {
+    let registry = registry().read().expect("Could not get reading access to the registry.");
+    match registry.materials.get(name) {
+        Some(factory) => factory(properties).map(Material::Custom),
+        None => Err(format!("Unknown material type \"{}\".", name)),
+    }
+}
+
+pub fn create_shape(name: &str, properties: &Properties) -> Result<Arc<Shape>, String> {
+    let registry = registry().read().expect("Could not get reading access to the registry.");
+    match registry.shapes.get(name) {
+        Some(factory) => factory(properties),
+        None => Err(format!("Unknown primitive type \"{}\".", name)),
+    }
+}
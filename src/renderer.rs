@@ -0,0 +1,262 @@
+use cli::{self, Options, BakeMode};
+use tracer::{Integrator, ToneMapping, StereoLayout};
+use postprocess::PostProcessing;
+use scene::ObjectId;
+use demo::DEMO_NAMES;
+#[cfg(not(target_arch = "wasm32"))] use window;
+#[cfg(not(target_arch = "wasm32"))] use headless;
+#[cfg(not(target_arch = "wasm32"))] use bake;
+#[cfg(not(target_arch = "wasm32"))] use network;
+#[cfg(target_arch = "wasm32")] use web;
+use common;
+use volume;
+use error::Error;
+
+// The single entry point for running the renderer, with the window, in the headless mode or
+// as a remote worker. The builder starts from the defaults or from the command line and
+// every setter overrides one option, e.g. for rendering a demo scene from another program:
+//
+//   Renderer::builder()
+//       .demo("cornell")
+//       .resolution(640, 360)
+//       .spp(256)
+//       .max_depth(8)
+//       .integrator(Integrator::PathTracer)
+//       .headless(true)
+//       .build()?
+//       .run()?;
+//
+// The options that are not covered by a setter can be given as a whole with options().
+pub struct Renderer {
+    options: Options,
+}
+
+impl Renderer {
+    pub fn builder() -> RendererBuilder {
+        RendererBuilder {
+            options: Options::default(),
+        }
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    // Renders until the window is closed, the image is saved in the headless mode or the
+    // master disconnects. The errors are left to the caller, e.g. the binary exits with them.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn run(self) -> Result<(), Error> {
+        let options = self.options;
+        if let Some(seed) = options.seed {
+            common::seed_random(seed);
+        }
+        if let Some(memory_budget) = options.memory_budget {
+            volume::set_memory_budget(memory_budget.saturating_mul(1024*1024));
+        }
+
+        // A remote worker renders the tiles of a master on another machine and does not open a
+        // window. The scene is sent by the master.
+        if let Some(ref address) = options.worker_address {
+            println!("Rendering tiles for the master \"{}\".", address);
+            network::run_worker(address, options.num_threads);
+            return Ok(());
+        }
+
+        // Baking and tracing a single pixel do not need a window either.
+        if options.bake.is_some() {
+            bake::start(options)
+        } else if options.headless || options.debug_pixel.is_some() {
+            headless::start(options)
+        } else {
+            window::start(options);
+            Ok(())
+        }
+    }
+
+    // In the browser, the image is rendered into the canvas with the id DEFAULT_CANVAS_ID
+    // and the page stays responsive while rendering. Errors are shown on the console and
+    // returned.
+    #[cfg(target_arch = "wasm32")]
+    pub fn run(self) -> Result<(), Error> {
+        let options = self.options;
+        if let Some(seed) = options.seed {
+            common::seed_random(seed);
+        }
+        if let Some(memory_budget) = options.memory_budget {
+            volume::set_memory_budget(memory_budget.saturating_mul(1024*1024));
+        }
+        web::start(options, web::DEFAULT_CANVAS_ID).map_err(|err| {
+            web::log(&format!("{}", err));
+            err
+        })
+    }
+}
+
+pub struct RendererBuilder {
+    options: Options,
+}
+
+impl RendererBuilder {
+    // Replaces all options with the ones of the command line and the config file. Exits
+    // with a message when they are invalid.
+    pub fn command_line(mut self) -> Self {
+        self.options = cli::parse();
+        self
+    }
+
+    pub fn options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn scene(mut self, path: &str) -> Self {
+        self.options.scene_path = String::from(path);
+        self.options.demo = None;
+        self
+    }
+
+    pub fn demo(mut self, name: &str) -> Self {
+        self.options.demo = Some(String::from(name));
+        self
+    }
+
+    // Overrides the resolution of the scene.
+    pub fn resolution(mut self, width: usize, height: usize) -> Self {
+        self.options.width = Some(width);
+        self.options.height = Some(height);
+        self
+    }
+
+    pub fn spp(mut self, samples_per_pixel: usize) -> Self {
+        self.options.samples_per_pixel = Some(samples_per_pixel);
+        self
+    }
+
+    // Stops the headless mode after the time per image, or at the samples per pixel if they
+    // are given as well.
+    pub fn max_time(mut self, seconds: f64) -> Self {
+        self.options.max_time = Some(seconds);
+        self
+    }
+
+    pub fn max_depth(mut self, max_depth: u8) -> Self {
+        self.options.max_depth = max_depth;
+        self
+    }
+
+    pub fn integrator(mut self, integrator: Integrator) -> Self {
+        self.options.integrator = integrator;
+        self
+    }
+
+    pub fn tone_mapping(mut self, tone_mapping: ToneMapping) -> Self {
+        self.options.tone_mapping = Some(tone_mapping);
+        self
+    }
+
+    // Bloom, lens flare and vignette before the tone mapping of the displayed image.
+    pub fn post_processing(mut self, post_processing: PostProcessing) -> Self {
+        self.options.post_processing = post_processing;
+        self
+    }
+
+    // Renders both eyes with the distance between them in the units of the scene.
+    pub fn stereo(mut self, layout: StereoLayout, interocular_distance: f32) -> Self {
+        self.options.stereo = Some(layout);
+        self.options.interocular_distance = Some(interocular_distance);
+        self
+    }
+
+    pub fn tile_size(mut self, tile_size: u32) -> Self {
+        self.options.tile_size = tile_size;
+        self
+    }
+
+    pub fn threads(mut self, num_threads: usize) -> Self {
+        self.options.num_threads = num_threads;
+        self
+    }
+
+    pub fn seed(mut self, seed: u32) -> Self {
+        self.options.seed = Some(seed);
+        self
+    }
+
+    // Limits the memory of every density grid, in MiB.
+    pub fn memory_budget(mut self, mib: usize) -> Self {
+        self.options.memory_budget = Some(mib);
+        self
+    }
+
+    pub fn output(mut self, path: &str) -> Self {
+        self.options.output_path = Some(String::from(path));
+        self
+    }
+
+    pub fn headless(mut self, headless: bool) -> Self {
+        self.options.headless = headless;
+        self
+    }
+
+    // Renders all frames of the animation, or only the given range, in the headless mode.
+    pub fn animate(mut self, frame_range: Option<(usize, usize)>) -> Self {
+        self.options.animate = true;
+        self.options.frame_range = frame_range;
+        self
+    }
+
+    // Renders the frames of one orbit of the camera around the center of the scene in the
+    // headless mode, without an animation.
+    pub fn turntable(mut self, num_frames: usize) -> Self {
+        self.options.turntable = Some(num_frames);
+        self
+    }
+
+    // Bakes the lighting of a sphere or a plane into a texture instead of rendering the
+    // image. The resolution is the one of the texture.
+    pub fn bake(mut self, object: ObjectId, mode: BakeMode) -> Self {
+        self.options.bake = Some(object);
+        self.options.bake_mode = mode;
+        self
+    }
+
+    // Accepts remote workers on the address, e.g. "0.0.0.0:7878".
+    pub fn serve(mut self, address: &str) -> Self {
+        self.options.serve_address = Some(String::from(address));
+        self
+    }
+
+    // Checks the same limits as the command line.
+    pub fn build(self) -> Result<Renderer, Error> {
+        let options = self.options;
+        let invalid = |message: String| Err(Error::InvalidOption(message));
+        if options.samples_per_pixel == Some(0) {
+            return invalid(String::from("The samples per pixel have to be at least 1."));
+        }
+        if options.max_depth < 1 {
+            return invalid(String::from("The maximum depth has to be at least 1."));
+        }
+        if options.tile_size < 4 {
+            return invalid(format!("The tile size is {} but has to be at least 4.", options.tile_size));
+        }
+        if options.num_threads < 1 {
+            return invalid(String::from("At least one worker thread is needed."));
+        }
+        if options.width == Some(0) || options.height == Some(0) {
+            return invalid(String::from("The width and the height have to be at least 1."));
+        }
+        if let Some(ref name) = options.demo {
+            if !DEMO_NAMES.contains(&name.as_str()) {
+                return invalid(format!("Unknown demo scene \"{}\". Expected one of {}.", name, DEMO_NAMES.join(", ")));
+            }
+        }
+        if let Some((first, last)) = options.frame_range {
+            if first > last {
+                return invalid(format!("The first frame {} is after the last frame {}.", first, last));
+            }
+        }
+        Ok(Renderer {
+            options: options,
+        })
+    }
+}
@@ -0,0 +1,142 @@
+use std;
+use std::cell::UnsafeCell;
+
+use common::*;
+
+#[derive(Clone, Copy, Debug, new)]
+pub struct LightSample {
+    pub position: Vec3,
+    pub normal: Vec3,
+    pub radiance: Vec3,
+}
+
+// A reservoir holds one light sample that was selected from a stream of candidates with
+// weighted reservoir sampling. Reservoirs can be merged which allows to reuse the light
+// samples of neighboring pixels and of the previous frame.
+#[derive(Clone, Copy, Debug)]
+pub struct Reservoir {
+    pub sample: LightSample,
+    pub weight_sum: f32,
+    pub num_candidates: f32,
+    pub weight: f32, // Unbiased contribution weight W of the selected sample
+    pub surface_normal: Vec3, // Normal of the surface the reservoir was created for
+}
+
+impl Reservoir {
+    pub fn empty() -> Self {
+        Reservoir {
+            sample: LightSample::new(Vec3::zero(), Vec3::zero(), Vec3::zero()),
+            weight_sum: 0.0,
+            num_candidates: 0.0,
+            weight: 0.0,
+            surface_normal: Vec3::zero(),
+        }
+    }
+
+    pub fn add(&mut self, sample: LightSample, weight: f32, num_candidates: f32) {
+        self.weight_sum += weight;
+        self.num_candidates += num_candidates;
+        if weight > 0.0 && random32()*self.weight_sum < weight {
+            self.sample = sample;
+        }
+    }
+
+    // Merges another reservoir whose sample is evaluated with the target function of the
+    // pixel into which it is merged.
+    pub fn merge(&mut self, other: &Reservoir, target: f32) {
+        let weight = target*other.weight*other.num_candidates;
+        self.add(other.sample, weight, other.num_candidates);
+    }
+
+    // Computes the contribution weight after all candidates have been added.
+    pub fn finalize(&mut self, target: f32) {
+        self.weight = if target > 0.0 && self.num_candidates > 0.0 {
+            self.weight_sum / (self.num_candidates*target)
+        } else {
+            0.0
+        };
+    }
+
+    // Limits the influence of old reservoirs so that the result can still adapt to changes.
+    pub fn clamped(&self, max_candidates: f32) -> Reservoir {
+        let mut reservoir = *self;
+        if reservoir.num_candidates > max_candidates {
+            reservoir.weight_sum *= max_candidates / reservoir.num_candidates;
+            reservoir.num_candidates = max_candidates;
+        }
+        reservoir
+    }
+}
+
+pub struct ReservoirBuffer {
+    size: UnsafeCell<(u32, u32)>,
+    // Every pixel writes its reservoir to the current buffer while the reservoirs of the
+    // neighbors are read from the previous buffer. Therefore the worker threads never read
+    // what other threads are writing in the same frame and the same reasoning as for the
+    // Backbuffer applies.
+    previous: UnsafeCell<Vec<Reservoir>>,
+    current: UnsafeCell<Vec<Reservoir>>,
+}
+unsafe impl Sync for ReservoirBuffer {}
+
+impl ReservoirBuffer {
+    pub fn new(width: u32, height: u32) -> Self {
+        let num_pixels = (width*height) as usize;
+        ReservoirBuffer {
+            size: UnsafeCell::new((width, height)),
+            previous: UnsafeCell::new(vec![Reservoir::empty(); num_pixels]),
+            current: UnsafeCell::new(vec![Reservoir::empty(); num_pixels]),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        unsafe { (*self.size.get()).0 }
+    }
+
+    pub fn height(&self) -> u32 {
+        unsafe { (*self.size.get()).1 }
+    }
+
+    // Reallocates the reservoirs for the new size, which clears them. Must only be called
+    // while no worker thread is rendering.
+    pub fn resize(&self, width: u32, height: u32) {
+        let num_pixels = (width*height) as usize;
+        unsafe {
+            *self.size.get() = (width, height);
+            *self.previous.get() = vec![Reservoir::empty(); num_pixels];
+            *self.current.get() = vec![Reservoir::empty(); num_pixels];
+        }
+    }
+
+    // Must only be called while no worker thread is rendering.
+    pub fn clear(&self) {
+        unsafe {
+            for reservoir in (*self.previous.get()).iter_mut() {
+                *reservoir = Reservoir::empty();
+            }
+            for reservoir in (*self.current.get()).iter_mut() {
+                *reservoir = Reservoir::empty();
+            }
+        }
+    }
+
+    // Makes the reservoirs of this frame available for reuse in the next one. Must only be
+    // called while no worker thread is rendering.
+    pub fn swap(&self) {
+        unsafe {
+            std::mem::swap(&mut *self.previous.get(), &mut *self.current.get());
+        }
+    }
+
+    pub fn previous(&self, x: u32, y: u32) -> Reservoir {
+        let index = (y*self.width() + x) as usize;
+        unsafe { (*self.previous.get())[index] }
+    }
+
+    pub fn store_unsafe(&self, x: u32, y: u32, reservoir: Reservoir) {
+        let index = (y*self.width() + x) as usize;
+        unsafe {
+            (*self.current.get())[index] = reservoir;
+        }
+    }
+}
@@ -1,8 +1,15 @@
 use common::*;
+use pathtracer_core;
 
-use tracer::{ImageSettings, Hit, Transition, Camera, ToneMapping};
+use tracer::{ImageSettings, Hit, Camera, ToneMapping};
+use volume::Volume;
+use animation::Animation;
+use stats;
+use registry::{Bsdf, Shape};
 
+use std::fmt;
 use std::sync::Arc;
+use std::collections::BTreeMap;
 
 #[derive(Clone, Debug)]
 pub struct HDRITexture {
@@ -74,6 +81,33 @@ pub enum Sky {
     HDRI(String, Option<Arc<HDRITexture>>),
 }
 
+// A homogeneous participating medium that fills the whole scene. The coefficients
+// are given per color channel in units of 1/distance.
+#[derive(Clone, Debug, new)]
+pub struct Medium {
+    pub sigma_a: Vec3, // absorption coefficient
+    pub sigma_s: Vec3, // scattering coefficient
+    pub anisotropy: f32, // g parameter of the Henyey-Greenstein phase function in (-1, 1)
+}
+
+impl Medium {
+    pub fn sigma_t(&self) -> Vec3 {
+        self.sigma_a + self.sigma_s
+    }
+
+    pub fn transmittance(&self, distance: f32) -> Vec3 {
+        // Channels without extinction must not be evaluated as exp(-0*inf) = NaN when
+        // the distance is infinite (e.g. a ray escaping to the sky).
+        let transmittance = |sigma_t: f32| if sigma_t > 0.0 { f32::exp(-sigma_t*distance) } else { 1.0 };
+        let sigma_t = self.sigma_t();
+        Vec3::new(
+            transmittance(sigma_t.x),
+            transmittance(sigma_t.y),
+            transmittance(sigma_t.z),
+        )
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct PBRParameters {
     pub reflectivity: Vec3,
@@ -87,8 +121,55 @@ pub enum Material {
     None,
     Emissive(Vec3),
     Mirror,
-    Translucent(f32),
+    Translucent(f32, f32, u32), // Index of refraction, the dispersion (see cauchy_ior) and the priority of nested dielectrics
     Physically(PBRParameters),
+    Custom(Arc<Bsdf>), // See registry.rs
+}
+
+// A primitive of a type that was registered by the program using the library (see
+// registry.rs). It cannot be a light and is not transformed by animations or nodes.
+#[derive(Clone, Debug, new)]
+pub struct CustomPrimitive {
+    pub shape: Arc<Shape>,
+    pub material: Material,
+    #[new(default)]
+    pub visibility: Visibility,
+}
+
+// Which rays hit an object, like the ray visibility of production renderers. A light that is
+// hidden from the camera still lights the scene and a floor that is hidden from the
+// reflections is not seen in mirrors and glass. The reflections are all the rays after the
+// first bounce, so such a floor does not reflect any light onto the other objects either.
+// The lights are always hit by shadow rays, which are connected to them. The debug views
+// and the GPU backend show every object.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Visibility {
+    pub camera: bool,
+    pub shadows: bool, // Casts shadows
+    pub reflections: bool,
+}
+
+impl Default for Visibility {
+    fn default() -> Self {
+        Visibility { camera: true, shadows: true, reflections: true }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RayKind {
+    Camera,
+    Shadow,
+    Bounce,
+}
+
+impl Visibility {
+    pub fn is_visible_to(&self, kind: RayKind) -> bool {
+        match kind {
+            RayKind::Camera => self.camera,
+            RayKind::Shadow => self.shadows,
+            RayKind::Bounce => self.reflections,
+        }
+    }
 }
 
 // @TODO: Distinguish finite and infinite planes
@@ -99,6 +180,7 @@ pub struct Plane {
     pub v: Vec3,
     pub material: Material,
     pub normal: Vec3,
+    pub visibility: Visibility,
 }
 
 impl Plane {
@@ -110,6 +192,7 @@ impl Plane {
             v: v,
             material: material,
             normal: normal,
+            visibility: Visibility::default(),
         }
     }
 
@@ -130,6 +213,8 @@ pub struct Sphere {
     pub origin: Vec3,
     pub radius: f32,
     pub material: Material,
+    #[new(default)]
+    pub visibility: Visibility,
 }
 
 impl Sphere {
@@ -148,150 +233,949 @@ impl Sphere {
     }
 }
 
+// Axis-aligned bounding box. An empty box has its minimum above its maximum so that
+// extending it with the first point makes it contain only that point.
+#[derive(Clone, Copy, Debug)]
+pub struct Bounds {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Bounds {
+    pub fn empty() -> Self {
+        Bounds {
+            min: Vec3::new(std::f32::MAX, std::f32::MAX, std::f32::MAX),
+            max: Vec3::new(std::f32::MIN, std::f32::MIN, std::f32::MIN),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x
+    }
+
+    pub fn extend(&mut self, p: Vec3) {
+        self.min = Vec3::new(f32::min(self.min.x, p.x), f32::min(self.min.y, p.y), f32::min(self.min.z, p.z));
+        self.max = Vec3::new(f32::max(self.max.x, p.x), f32::max(self.max.y, p.y), f32::max(self.max.z, p.z));
+    }
+
+    pub fn center(&self) -> Vec3 {
+        0.5*(self.min + self.max)
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let extent = self.max - self.min;
+        2.0*(extent.x*extent.y + extent.y*extent.z + extent.z*extent.x)
+    }
+
+    // Boxes that only touch overlap as well.
+    pub fn overlaps(&self, other: &Bounds) -> bool {
+        self.min.x <= other.max.x && other.min.x <= self.max.x &&
+        self.min.y <= other.max.y && other.min.y <= self.max.y &&
+        self.min.z <= other.max.z && other.min.z <= self.max.z
+    }
+
+    // The sphere around the box, with the center and the radius.
+    pub fn bounding_sphere(&self) -> (Vec3, f32) {
+        (self.center(), 0.5*(self.max - self.min).length())
+    }
+
+    pub fn union(&self, other: &Bounds) -> Bounds {
+        let mut bounds = *self;
+        if !other.is_empty() {
+            bounds.extend(other.min);
+            bounds.extend(other.max);
+        }
+        bounds
+    }
+}
+
+// A primitive of the scene, borrowed from the Vec of its kind. Everything that treats all
+// kinds of primitives alike goes through this enum, e.g. finding the nearest hit, the
+// bounds and sampling the lights. A new kind of primitive only needs a variant here and
+// its Vecs in the scene.
+#[derive(Clone, Copy, Debug)]
+pub enum Primitive<'a> {
+    Sphere(&'a Sphere),
+    Plane(&'a Plane),
+    Custom(&'a CustomPrimitive),
+}
+
+impl<'a> Primitive<'a> {
+    pub fn material(self) -> &'a Material {
+        match self {
+            Primitive::Sphere(sphere) => &sphere.material,
+            Primitive::Plane(plane) => &plane.material,
+            Primitive::Custom(custom) => &custom.material,
+        }
+    }
+
+    pub fn visibility(self) -> Visibility {
+        match self {
+            Primitive::Sphere(sphere) => sphere.visibility,
+            Primitive::Plane(plane) => plane.visibility,
+            Primitive::Custom(custom) => custom.visibility,
+        }
+    }
+
+    // Planes are only hit from the side their normal is pointing to.
+    pub fn intersect(self, ray: &Ray) -> Option<Hit<'a>> {
+        match self {
+            Primitive::Sphere(sphere) => intersect_sphere(sphere, ray),
+            Primitive::Plane(plane) => intersect_plane(plane, ray).filter(|hit| hit.transition == Transition::In),
+            Primitive::Custom(custom) => intersect_custom(custom, ray),
+        }
+    }
+
+    pub fn bounds(self) -> Bounds {
+        let mut bounds = Bounds::empty();
+        match self {
+            Primitive::Sphere(sphere) => {
+                let r = Vec3::new(sphere.radius, sphere.radius, sphere.radius);
+                bounds.extend(sphere.origin - r);
+                bounds.extend(sphere.origin + r);
+            },
+            Primitive::Plane(plane) => {
+                bounds.extend(plane.origin);
+                bounds.extend(plane.origin + plane.u);
+                bounds.extend(plane.origin + plane.v);
+                bounds.extend(plane.origin + plane.u + plane.v);
+            },
+            Primitive::Custom(custom) => bounds = custom.shape.bounds(),
+        }
+        bounds
+    }
+
+    // A point on the surface for connecting the position to it, e.g. for sampling lights.
+    pub fn sample(self, position: Vec3) -> Option<Vec3> {
+        match self {
+            Primitive::Sphere(sphere) => sphere.sample(position),
+            Primitive::Plane(plane) => plane.sample(position),
+            Primitive::Custom(_) => None, // Custom primitives are no lights
+        }
+    }
+}
+
+// Identifies an object by its index in the respective Vec of the scene. Nodes are no objects
+// but are transformed in the same way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ObjectId {
+    Sphere(usize),
+    Plane(usize),
+    Volume(usize),
+    EmissiveSphere(usize),
+    EmissivePlane(usize),
+    Node(usize),
+    Custom(usize),
+}
+
+impl ObjectId {
+    // Reads the names of Display back, e.g. "sphere 0", which can also be written as
+    // "sphere:0" on the command line.
+    pub fn parse(text: &str) -> Option<ObjectId> {
+        let mut parts = text.trim().splitn(2, |c: char| c == ':' || c.is_whitespace());
+        let kind = parts.next()?;
+        let index = parts.next()?.trim().parse::<usize>().ok()?;
+        match kind {
+            "sphere" => Some(ObjectId::Sphere(index)),
+            "plane" => Some(ObjectId::Plane(index)),
+            "volume" => Some(ObjectId::Volume(index)),
+            "emissive_sphere" => Some(ObjectId::EmissiveSphere(index)),
+            "emissive_plane" => Some(ObjectId::EmissivePlane(index)),
+            "node" => Some(ObjectId::Node(index)),
+            "custom" => Some(ObjectId::Custom(index)),
+            _ => None,
+        }
+    }
+
+    // Identifies the object in the ID buffer and the mattes. Like in Cryptomatte, it is the
+    // MurmurHash3 of the name, so that it stays the same between renders as long as the
+    // object keeps its index, and compositors can compute it from the manifest. The IDs are
+    // stored as the bits of floats and the exponent is changed where they would be denormal,
+    // infinite or NaN. Zero is left for the sky.
+    pub fn matte_id(&self) -> u32 {
+        let mut hash = murmur_hash3_32(format!("{}", self).as_bytes(), 0);
+        let exponent = (hash >> 23) & 0xff;
+        if exponent == 0 || exponent == 0xff {
+            hash ^= 1 << 23;
+        }
+        hash
+    }
+}
+
+// Names the object like the animation of the scene file, e.g. "sphere 0".
+impl fmt::Display for ObjectId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ObjectId::Sphere(index) => write!(f, "sphere {}", index),
+            ObjectId::Plane(index) => write!(f, "plane {}", index),
+            ObjectId::Volume(index) => write!(f, "volume {}", index),
+            ObjectId::EmissiveSphere(index) => write!(f, "emissive_sphere {}", index),
+            ObjectId::EmissivePlane(index) => write!(f, "emissive_plane {}", index),
+            ObjectId::Node(index) => write!(f, "node {}", index),
+            ObjectId::Custom(index) => write!(f, "custom {}", index),
+        }
+    }
+}
+
+// Rotation around the axis by the angle (in radians) followed by a uniform scale and the
+// translation. The rotation is applied around the origin of the object.
+#[derive(Clone, Copy, Debug, new)]
+pub struct Transform {
+    pub translation: Vec3,
+    pub rotation_axis: Vec3,
+    pub rotation_angle: f32,
+    pub scale: f32,
+}
+
+impl Transform {
+    pub fn identity() -> Self {
+        Transform::new(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 0.0, 1.0)
+    }
+
+    // Source: https://en.wikipedia.org/wiki/Rodrigues%27_rotation_formula
+    fn rotate(&self, v: Vec3) -> Vec3 {
+        let k = self.rotation_axis.normalize();
+        let (sin, cos) = (f32::sin(self.rotation_angle), f32::cos(self.rotation_angle));
+        cos*v + sin*k.cross(v) + ((1.0 - cos)*k.dot(v))*k
+    }
+
+    // Transforms a vector spanning the object, e.g. the edges of a plane.
+    fn apply_vector(&self, v: Vec3) -> Vec3 {
+        self.scale*self.rotate(v)
+    }
+
+    // Transforms a point given relative to the origin of the transform, e.g. the origin of
+    // an object in the space of its node.
+    fn apply_point(&self, p: Vec3) -> Vec3 {
+        self.translation + self.apply_vector(p)
+    }
+
+    fn quaternion(&self) -> (f32, Vec3) {
+        if self.rotation_angle == 0.0 {
+            return (1.0, Vec3::zero());
+        }
+        let half_angle = 0.5*self.rotation_angle;
+        (f32::cos(half_angle), f32::sin(half_angle)*self.rotation_axis.normalize())
+    }
+
+    // Returns the transform that applies inner first and then self. The rotations are
+    // combined as quaternions and converted back into an axis and an angle.
+    pub fn compose(&self, inner: &Transform) -> Transform {
+        let ((w1, v1), (w2, v2)) = (self.quaternion(), inner.quaternion());
+        let w = clampf32(-1.0, 1.0, w1*w2 - v1.dot(v2));
+        let v = w1*v2 + w2*v1 + v1.cross(v2);
+
+        let sin_half_angle = f32::sqrt(1.0 - w*w);
+        let (rotation_axis, rotation_angle) = if sin_half_angle < 0.000001 {
+            (Vec3::new(0.0, 1.0, 0.0), 0.0)
+        } else {
+            (v/sin_half_angle, 2.0*f32::acos(w))
+        };
+
+        Transform::new(self.apply_point(inner.translation), rotation_axis, rotation_angle, self.scale*inner.scale)
+    }
+}
+
+// A group of objects that are transformed together, e.g. a planet with its orbiting moons.
+// The transform of a node is relative to its parent and the geometry of its objects is given
+// relative to the node. The objects stay in the flat Vecs of the scene which are used for
+// rendering. Their geometry is updated whenever a transform of the hierarchy changes.
+#[derive(Clone, Debug, new)]
+pub struct Node {
+    pub parent: Option<usize>, // Always comes before the node in the Vec of the scene
+    pub transform: Transform,
+    #[new(value = "Transform::identity()")]
+    pub pose: Transform, // Set by the animation, applied before the transform
+    #[new(default)]
+    pub objects: Vec<ObjectId>,
+}
+
+// The geometry of an object as it was loaded. Transforms are always applied to the rest
+// pose so that setting them repeatedly does not accumulate errors.
+#[derive(Clone, Debug)]
+enum RestPose {
+    Sphere(Vec3, f32),
+    Plane(Vec3, Vec3, Vec3),
+    Volume(Vec3, Vec3),
+}
+
+impl RestPose {
+    // Rotates and scales the object around its own origin and translates it afterwards.
+    // Volumes are axis-aligned and therefore ignore the rotation.
+    fn posed(&self, transform: &Transform) -> RestPose {
+        match *self {
+            RestPose::Sphere(origin, radius) => RestPose::Sphere(origin + transform.translation, transform.scale*radius),
+            RestPose::Plane(origin, u, v) => RestPose::Plane(origin + transform.translation, transform.apply_vector(u), transform.apply_vector(v)),
+            RestPose::Volume(min, max) => {
+                let center = 0.5*(min + max) + transform.translation;
+                let half_extent = (0.5*transform.scale)*(max - min);
+                RestPose::Volume(center - half_extent, center + half_extent)
+            },
+        }
+    }
+
+    // Moves the object from the space of a node into the space of the scene. In contrast to
+    // posed, the rotation also moves the origin of the object.
+    fn placed(&self, transform: &Transform) -> RestPose {
+        match *self {
+            RestPose::Sphere(origin, radius) => RestPose::Sphere(transform.apply_point(origin), transform.scale*radius),
+            RestPose::Plane(origin, u, v) => RestPose::Plane(transform.apply_point(origin), transform.apply_vector(u), transform.apply_vector(v)),
+            RestPose::Volume(min, max) => {
+                let center = transform.apply_point(0.5*(min + max));
+                let half_extent = (0.5*transform.scale)*(max - min);
+                RestPose::Volume(center - half_extent, center + half_extent)
+            },
+        }
+    }
+}
+
 #[derive(Debug, new)]
 pub struct Scene {
     pub image_settings: ImageSettings,
     pub camera: Camera,
     pub sky: Sky,
+    pub medium: Option<Medium>,
     pub spheres: Vec<Sphere>,
     pub planes: Vec<Plane>,
+    pub volumes: Vec<Volume>,
     pub emissive_spheres: Vec<Sphere>,
     pub emissive_planes: Vec<Plane>,
+    #[new(default)]
+    pub animation: Option<Animation>,
+    #[new(default)]
+    pub nodes: Vec<Node>,
+    #[new(default)]
+    rest_poses: BTreeMap<ObjectId, RestPose>,
+    #[new(default)]
+    object_transforms: BTreeMap<ObjectId, Transform>, // Of the objects in nodes
+    #[new(default)]
+    pub custom_primitives: Vec<CustomPrimitive>,
+}
+
+// The objects of the scene can be changed in place, e.g. for animations, instead of
+// building a new scene. Everything that is derived from the geometry, like the bounds of the
+// guiding grid, has to be updated by the caller together with restarting the accumulation.
+impl Scene {
+    fn rest_pose(&mut self, id: ObjectId) -> Option<RestPose> {
+        if let Some(rest_pose) = self.rest_poses.get(&id) {
+            return Some(rest_pose.clone());
+        }
+        let rest_pose = match id {
+            ObjectId::Sphere(index) => self.spheres.get(index).map(|s| RestPose::Sphere(s.origin, s.radius)),
+            ObjectId::EmissiveSphere(index) => self.emissive_spheres.get(index).map(|s| RestPose::Sphere(s.origin, s.radius)),
+            ObjectId::Plane(index) => self.planes.get(index).map(|p| RestPose::Plane(p.origin, p.u, p.v)),
+            ObjectId::EmissivePlane(index) => self.emissive_planes.get(index).map(|p| RestPose::Plane(p.origin, p.u, p.v)),
+            ObjectId::Volume(index) => self.volumes.get(index).map(|v| RestPose::Volume(v.min, v.max)),
+            ObjectId::Node(_) | ObjectId::Custom(_) => None,
+        };
+        if let Some(ref rest_pose) = rest_pose {
+            self.rest_poses.insert(id, rest_pose.clone());
+        }
+        rest_pose
+    }
+
+    fn write_pose(&mut self, id: ObjectId, pose: RestPose) -> bool {
+        match (id, pose) {
+            (ObjectId::Sphere(index), RestPose::Sphere(origin, radius)) |
+            (ObjectId::EmissiveSphere(index), RestPose::Sphere(origin, radius)) => {
+                let sphere = match id {
+                    ObjectId::Sphere(_) => &mut self.spheres[index],
+                    _ => &mut self.emissive_spheres[index],
+                };
+                sphere.origin = origin;
+                sphere.radius = radius;
+            },
+            (ObjectId::Plane(index), RestPose::Plane(origin, u, v)) |
+            (ObjectId::EmissivePlane(index), RestPose::Plane(origin, u, v)) => {
+                let plane = match id {
+                    ObjectId::Plane(_) => &mut self.planes[index],
+                    _ => &mut self.emissive_planes[index],
+                };
+                plane.origin = origin;
+                plane.u = u;
+                plane.v = v;
+                plane.normal = u.cross(v).normalize();
+            },
+            (ObjectId::Volume(index), RestPose::Volume(min, max)) => {
+                let ref mut volume = self.volumes[index];
+                volume.min = min;
+                volume.max = max;
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    // Places the object with the transform relative to the pose it was loaded with. Returns
+    // false when there is no object with the id. Volumes are axis-aligned and therefore
+    // ignore the rotation. The transform of a node is applied in the space of the node
+    // before its own transform and moves all objects below it.
+    pub fn set_transform(&mut self, id: ObjectId, transform: Transform) -> bool {
+        if let ObjectId::Node(index) = id {
+            if index >= self.nodes.len() {
+                return false;
+            }
+            self.nodes[index].pose = transform;
+            self.update_nodes();
+            return true;
+        }
+
+        let rest_pose = match self.rest_pose(id) {
+            Some(rest_pose) => rest_pose,
+            None => return false,
+        };
+        let pose = rest_pose.posed(&transform);
+        match self.nodes.iter().position(|node| node.objects.contains(&id)) {
+            Some(node) => {
+                self.object_transforms.insert(id, transform);
+                let world_transform = self.world_transform(node);
+                self.write_pose(id, pose.placed(&world_transform))
+            },
+            None => self.write_pose(id, pose),
+        }
+    }
+
+    //
+    // HIERARCHY
+    //
+
+    // Adds a node below the parent, which has to exist already, and returns its index.
+    pub fn add_node(&mut self, parent: Option<usize>, transform: Transform) -> usize {
+        debug_assert!(parent.map(|parent| parent < self.nodes.len()).unwrap_or(true));
+        self.nodes.push(Node::new(parent, transform));
+        self.nodes.len() - 1
+    }
+
+    // Adds the object to the node. Its current geometry is kept as the geometry relative to
+    // the node. update_nodes has to be called after all objects were attached.
+    pub fn attach(&mut self, node: usize, id: ObjectId) -> bool {
+        if node >= self.nodes.len() || self.rest_pose(id).is_none() {
+            return false;
+        }
+        self.nodes[node].objects.push(id);
+        true
+    }
+
+    // The transform from the space of the node into the space of the scene.
+    pub fn world_transform(&self, node: usize) -> Transform {
+        let ref node = self.nodes[node];
+        let local_transform = node.transform.compose(&node.pose);
+        match node.parent {
+            Some(parent) => self.world_transform(parent).compose(&local_transform),
+            None => local_transform,
+        }
+    }
+
+    pub fn material(&self, id: ObjectId) -> Option<&Material> {
+        match id {
+            ObjectId::Sphere(index) => self.spheres.get(index).map(|sphere| &sphere.material),
+            ObjectId::EmissiveSphere(index) => self.emissive_spheres.get(index).map(|sphere| &sphere.material),
+            ObjectId::Plane(index) => self.planes.get(index).map(|plane| &plane.material),
+            ObjectId::EmissivePlane(index) => self.emissive_planes.get(index).map(|plane| &plane.material),
+            ObjectId::Custom(index) => self.custom_primitives.get(index).map(|custom| &custom.material),
+            ObjectId::Volume(_) | ObjectId::Node(_) => None,
+        }
+    }
+
+    // Replaces the material of an object. Returns false when there is no object with the id
+    // or when it would become emissive or stop being emissive, as the lights are kept
+    // separately.
+    pub fn set_material(&mut self, id: ObjectId, material: Material) -> bool {
+        let is_emissive = match material {
+            Material::Emissive(_) => true,
+            _ => false,
+        };
+        let target = match id {
+            ObjectId::Sphere(index) if !is_emissive => self.spheres.get_mut(index).map(|sphere| &mut sphere.material),
+            ObjectId::EmissiveSphere(index) if is_emissive => self.emissive_spheres.get_mut(index).map(|sphere| &mut sphere.material),
+            ObjectId::Plane(index) if !is_emissive => self.planes.get_mut(index).map(|plane| &mut plane.material),
+            ObjectId::EmissivePlane(index) if is_emissive => self.emissive_planes.get_mut(index).map(|plane| &mut plane.material),
+            ObjectId::Custom(index) if !is_emissive => self.custom_primitives.get_mut(index).map(|custom| &mut custom.material),
+            _ => None,
+        };
+        match target {
+            Some(target) => {
+                *target = material;
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Returns false when there is no object with the id.
+    pub fn set_visibility(&mut self, id: ObjectId, visibility: Visibility) -> bool {
+        let target = match id {
+            ObjectId::Sphere(index) => self.spheres.get_mut(index).map(|sphere| &mut sphere.visibility),
+            ObjectId::EmissiveSphere(index) => self.emissive_spheres.get_mut(index).map(|sphere| &mut sphere.visibility),
+            ObjectId::Plane(index) => self.planes.get_mut(index).map(|plane| &mut plane.visibility),
+            ObjectId::EmissivePlane(index) => self.emissive_planes.get_mut(index).map(|plane| &mut plane.visibility),
+            ObjectId::Custom(index) => self.custom_primitives.get_mut(index).map(|custom| &mut custom.visibility),
+            ObjectId::Volume(_) | ObjectId::Node(_) => None,
+        };
+        match target {
+            Some(target) => {
+                *target = visibility;
+                true
+            },
+            None => false,
+        }
+    }
+
+    //
+    // PRIMITIVES
+    //
+    // All surfaces with their ids, lights included. Volumes have no surface and are not
+    // part of it.
+    pub fn primitives<'a>(&'a self) -> impl Iterator<Item = (ObjectId, Primitive<'a>)> + 'a {
+        let spheres = self.spheres.iter().enumerate().map(|(index, sphere)| (ObjectId::Sphere(index), Primitive::Sphere(sphere)));
+        let emissive_spheres = self.emissive_spheres.iter().enumerate().map(|(index, sphere)| (ObjectId::EmissiveSphere(index), Primitive::Sphere(sphere)));
+        let planes = self.planes.iter().enumerate().map(|(index, plane)| (ObjectId::Plane(index), Primitive::Plane(plane)));
+        let emissive_planes = self.emissive_planes.iter().enumerate().map(|(index, plane)| (ObjectId::EmissivePlane(index), Primitive::Plane(plane)));
+        let custom_primitives = self.custom_primitives.iter().enumerate().map(|(index, custom)| (ObjectId::Custom(index), Primitive::Custom(custom)));
+        spheres.chain(emissive_spheres).chain(planes).chain(emissive_planes).chain(custom_primitives)
+    }
+
+    pub fn num_primitives(&self) -> usize {
+        self.spheres.len() + self.emissive_spheres.len() + self.planes.len() + self.emissive_planes.len() + self.custom_primitives.len()
+    }
+
+    pub fn num_lights(&self) -> usize {
+        self.emissive_spheres.len() + self.emissive_planes.len()
+    }
+
+    // The lights are numbered from the emissive spheres to the emissive planes.
+    pub fn light(&self, index: usize) -> Option<Primitive> {
+        if index < self.emissive_spheres.len() {
+            Some(Primitive::Sphere(&self.emissive_spheres[index]))
+        } else {
+            self.emissive_planes.get(index - self.emissive_spheres.len()).map(Primitive::Plane)
+        }
+    }
+
+    pub fn bounds(&self) -> Bounds {
+        self.primitives().fold(Bounds::empty(), |bounds, (_, primitive)| bounds.union(&primitive.bounds()))
+    }
+
+    // The bounds of the primitives together with the boxes of the volumes, i.e. of everything
+    // that can be seen, e.g. for framing and orbiting the camera.
+    pub fn bounds_with_volumes(&self) -> Bounds {
+        let mut bounds = self.bounds();
+        for volume in self.volumes.iter() {
+            bounds.extend(volume.min);
+            bounds.extend(volume.max);
+        }
+        bounds
+    }
+
+    // Moves the camera so that the whole scene, including the volumes, is in view (see
+    // Camera::auto_frame).
+    pub fn auto_frame(&mut self) {
+        let bounds = self.bounds_with_volumes();
+        self.camera.auto_frame(&bounds);
+    }
+
+    // Changes the size of the image. The camera is widened or narrowed by the change of the
+    // aspect ratio so that the image is not stretched. The result only depends on the size
+    // of the scene file and the new one, which is how remote workers arrive at the same
+    // camera.
+    pub fn set_resolution(&mut self, width: usize, height: usize) {
+        let old_aspect_ratio = self.image_settings.width as f32 / self.image_settings.height as f32;
+        let new_aspect_ratio = width as f32 / height as f32;
+        let factor = new_aspect_ratio / old_aspect_ratio;
+        if factor.is_finite() && factor > 0.0 && factor != 1.0 {
+            self.camera.scale_width(factor);
+        }
+        self.image_settings.width = width;
+        self.image_settings.height = height;
+    }
+
+    // Updates the geometry of all objects in nodes from the transforms of the hierarchy.
+    pub fn update_nodes(&mut self) {
+        for node in 0..self.nodes.len() {
+            let world_transform = self.world_transform(node);
+            for id in self.nodes[node].objects.clone() {
+                let transform = self.object_transforms.get(&id).cloned().unwrap_or(Transform::identity());
+                if let Some(rest_pose) = self.rest_pose(id) {
+                    self.write_pose(id, rest_pose.posed(&transform).placed(&world_transform));
+                }
+            }
+        }
+    }
+
+    //
+    // ADDING OBJECTS
+    //
+    // Lights are kept in separate Vecs so that they can be sampled directly. Therefore the
+    // id depends on the material.
+    //
+
+    pub fn add_sphere(&mut self, sphere: Sphere) -> ObjectId {
+        if let Material::Emissive(_) = sphere.material {
+            self.emissive_spheres.push(sphere);
+            ObjectId::EmissiveSphere(self.emissive_spheres.len() - 1)
+        } else {
+            self.spheres.push(sphere);
+            ObjectId::Sphere(self.spheres.len() - 1)
+        }
+    }
+
+    pub fn add_plane(&mut self, plane: Plane) -> ObjectId {
+        if let Material::Emissive(_) = plane.material {
+            self.emissive_planes.push(plane);
+            ObjectId::EmissivePlane(self.emissive_planes.len() - 1)
+        } else {
+            self.planes.push(plane);
+            ObjectId::Plane(self.planes.len() - 1)
+        }
+    }
+
+    pub fn add_volume(&mut self, volume: Volume) -> ObjectId {
+        self.volumes.push(volume);
+        ObjectId::Volume(self.volumes.len() - 1)
+    }
+
+    pub fn add_custom(&mut self, custom: CustomPrimitive) -> ObjectId {
+        self.custom_primitives.push(custom);
+        ObjectId::Custom(self.custom_primitives.len() - 1)
+    }
 }
 
 impl Default for Scene {
     fn default() -> Self {
         let camera = Camera::new(Vec3::new(0.0, 2.0, 20.0), Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 4.0, 4.0, 10.0, ToneMapping::Exposure(1.0), 100.0);
-        Scene::new(ImageSettings::new(256, 256, true), camera, Sky::Constant(Vec3::new(1.0, 1.0, 1.0)), Vec::new(), Vec::new(), Vec::new(), Vec::new())
+        Scene::new(ImageSettings::new(256, 256, true), camera, Sky::Constant(Vec3::new(1.0, 1.0, 1.0)), None, Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new())
     }
 }
 
-fn intersect_sphere<'a>(sphere: &'a Sphere, ray: &Ray) -> Option<Hit<'a>> {
-    let s = sphere.origin;
-    let r = sphere.radius;
-    let p = ray.origin;
-    let d = ray.direction;
-
-    let c = s - p;
-
-    let e_len = d.dot(c);
-    // When the projection is negative, the ray is pointing away from the sphere
-    // but the origin of the ray might be inside the sphere. However, a projection
-    // less than -r means that the origin of the sphere is at least r behind the
-    // origin of the ray and therefore cannot possibly intersect.
-    if e_len < -r {
+// The shape reports the normal on the outside. The hit is flipped to the side of the ray
+// like the ones of the spheres.
+fn intersect_custom<'a>(custom: &'a CustomPrimitive, ray: &Ray) -> Option<Hit<'a>> {
+    let (parameter, normal) = custom.shape.intersect(ray)?;
+    if parameter < RAY_EPSILON || !parameter.is_finite() {
         return None;
     }
-    let e = e_len*d;
-
-    let v = e - c;
-    let v_len = v.length(); // @TODO: Can be squared
-    // v is the vector that is pointing from the center of the sphere to the point 
-    // on the ray which is closest to the center of the sphere. This point might be 
-    // inside of the sphere or outside. However, when the magnitude is less than 
-    // the radius of the sphere, the point cannot be inside the sphere.
-    if v_len > r {
-        return None;
+    let position = ray.origin + parameter*ray.direction;
+    if normal.dot(ray.direction) < 0.0 {
+        Some(Hit::new(parameter, position, normal, &custom.material, Transition::In))
+    } else {
+        Some(Hit::new(parameter, position, -normal, &custom.material, Transition::Out))
     }
+}
+
+// The intersection tests are part of the core crate and only get the material here.
+fn intersect_sphere<'a>(sphere: &'a Sphere, ray: &Ray) -> Option<Hit<'a>> {
+    pathtracer_core::intersect_sphere(sphere.origin, sphere.radius, ray).map(|i| Hit::new(i.parameter, i.position, i.normal, &sphere.material, i.transition))
+}
+
+fn intersect_plane<'a>(plane: &'a Plane, ray: &Ray) -> Option<Hit<'a>> {
+    pathtracer_core::intersect_plane(plane.origin, plane.u, plane.v, plane.normal, ray).map(|i| Hit::new(i.parameter, i.position, i.normal, &plane.material, i.transition))
+}
+
+pub fn find_scene_hit<'a>(ray: &Ray, scene: &'a Scene) -> Option<Hit<'a>> {
+    find_object_hit(ray, scene).map(|(_, hit)| hit)
+}
 
-    // To find the intersection points of the sphere, the distance from the nearest
-    // point on the ray to the intersection points is computed. This is done by
-    // rearanging the Pythagorean theorem. The hypothenuse is the radius of the
-    // sphere and one cathetus is the distance from the center of the sphere to
-    // the closest point on the ray to the center of the sphere.
-    let b = f32::sqrt(r*r - v_len*v_len); // @TODO: Try this with a lower quality sqrt function
+// Like find_scene_hit but also returns which of the objects was hit, e.g. for picking them in
+// the window. Volumes are not hit as they have no surface. Of hits at the same distance, the
+// first primitive wins.
+pub fn find_object_hit<'a>(ray: &Ray, scene: &'a Scene) -> Option<(ObjectId, Hit<'a>)> {
+    find_nearest_hit(ray, scene, None)
+}
 
-    let t1 = e_len - b;
-    let t2 = e_len + b;
+// Like find_scene_hit but skips the objects that are hidden from the kind of ray (see
+// Visibility).
+pub fn find_visible_hit<'a>(ray: &Ray, scene: &'a Scene, kind: RayKind) -> Option<Hit<'a>> {
+    find_nearest_hit(ray, scene, Some(kind)).map(|(_, hit)| hit)
+}
 
-    let material = &sphere.material;
+pub fn find_visible_object_hit<'a>(ray: &Ray, scene: &'a Scene, kind: RayKind) -> Option<(ObjectId, Hit<'a>)> {
+    find_nearest_hit(ray, scene, Some(kind))
+}
 
-    // Find the closest of both hit points
-    if t1 >= 0.0 {
-        let position = p + t1*d;
-        let normal = (position - sphere.origin).normalize(); // @TODO: Optimize
-        Some(Hit::new(t1, position, normal, material, Transition::In))
-    } else if t2 >= 0.0 { 
-        let position = p + t2*d;
-        let normal = (sphere.origin - position).normalize();// @TODO: Optimize
-        Some(Hit::new(t2, position, normal, material, Transition::Out))
-    } else {
-        None
+fn find_nearest_hit<'a>(ray: &Ray, scene: &'a Scene, kind: Option<RayKind>) -> Option<(ObjectId, Hit<'a>)> {
+    stats::count_ray(scene.num_primitives());
+
+    let mut nearest_hit: Option<(ObjectId, Hit)> = None;
+    for (id, primitive) in scene.primitives() {
+        if let Some(kind) = kind {
+            if !primitive.visibility().is_visible_to(kind) {
+                continue;
+            }
+        }
+        if let Some(hit) = primitive.intersect(ray) {
+            let is_nearer = match nearest_hit {
+                Some((_, ref nearest)) => hit.parameter < nearest.parameter,
+                None => true,
+            };
+            if is_nearer {
+                nearest_hit = Some((id, hit));
+            }
+        }
     }
+    nearest_hit
 }
 
-fn intersect_plane<'a>(plane: &'a Plane, ray: &Ray) -> Option<Hit<'a>> {
-    let n = plane.normal;
-    let s = plane.origin;
-    
-    let p = ray.origin;
-    let d = ray.direction;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let num = n.x*(s.x - p.x) + n.y*(s.y - p.y) + n.z*(s.z - p.z);
-    let denum = n.x*d.x + n.y*d.y + n.z*d.z;
+    fn assert_close(a: Vec3, b: Vec3) {
+        assert!((a - b).length() < 1.0e-4, "{:?} != {:?}", a, b);
+    }
 
-    if f32::abs(denum) < 0.0000001 { // @TODO: Set a reasonable epsilon
-        return None;
+    fn unit_sphere() -> Sphere {
+        Sphere::new(Vec3::zero(), 1.0, Material::None)
     }
 
-    let parameter = num / denum;
-    if parameter < 0.0 {
-        return None;
+    // The square from (-1, 0, -1) to (1, 0, 1) that faces upwards.
+    fn floor() -> Plane {
+        Plane::new(Vec3::new(-1.0, 0.0, -1.0), Vec3::new(0.0, 0.0, 2.0), Vec3::new(2.0, 0.0, 0.0), Material::None)
     }
 
-    let position = p + parameter*d;
-    let in_plane = position - s;
-    let on_u = in_plane.dot(plane.u.normalize());
-    if on_u > plane.u.length() || on_u < 0.0 {
-        return None;
+    #[test]
+    fn sphere_is_entered_from_outside() {
+        let sphere = unit_sphere();
+        let hit = intersect_sphere(&sphere, &Ray::new(Vec3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0))).unwrap();
+        assert!(f32::abs(hit.parameter - 4.0) < 1.0e-4);
+        assert_eq!(hit.transition, Transition::In);
+        assert_close(hit.position, Vec3::new(0.0, 0.0, -1.0));
+        assert_close(hit.normal, Vec3::new(0.0, 0.0, -1.0));
     }
-    let on_v = in_plane.dot(plane.v.normalize());
-    if on_v > plane.v.length() || on_v < 0.0 {
-        return None;
+
+    #[test]
+    fn sphere_is_left_from_inside() {
+        let sphere = unit_sphere();
+        let hit = intersect_sphere(&sphere, &Ray::new(Vec3::zero(), Vec3::new(0.0, 0.0, 1.0))).unwrap();
+        assert!(f32::abs(hit.parameter - 1.0) < 1.0e-4);
+        assert_eq!(hit.transition, Transition::Out);
+        // The normal points against the ray, towards the inside.
+        assert_close(hit.normal, Vec3::new(0.0, 0.0, -1.0));
+
+        // The center of the sphere is behind the origin of the ray.
+        let hit = intersect_sphere(&sphere, &Ray::new(Vec3::new(0.0, 0.0, 0.5), Vec3::new(0.0, 0.0, 1.0))).unwrap();
+        assert!(f32::abs(hit.parameter - 0.5) < 1.0e-4);
+        assert_eq!(hit.transition, Transition::Out);
     }
 
-    let material = &plane.material;
-    let (transition, normal) = if ray.direction.dot(n) < 0.0 { 
-        (Transition::In, n)
-    } else { 
-        (Transition::Out, -n)
-    };
+    #[test]
+    fn sphere_behind_or_beside_the_ray_is_missed() {
+        let sphere = unit_sphere();
+        assert!(intersect_sphere(&sphere, &Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, 1.0))).is_none());
+        assert!(intersect_sphere(&sphere, &Ray::new(Vec3::new(0.0, 1.001, -5.0), Vec3::new(0.0, 0.0, 1.0))).is_none());
+    }
 
-    Some(Hit::new(parameter, position, normal, material, transition))
-}
+    #[test]
+    fn sphere_is_hit_at_a_grazing_angle() {
+        let sphere = unit_sphere();
+        let hit = intersect_sphere(&sphere, &Ray::new(Vec3::new(0.0, 0.999, -5.0), Vec3::new(0.0, 0.0, 1.0))).unwrap();
+        assert_eq!(hit.transition, Transition::In);
+        assert!(f32::abs(hit.position.length() - 1.0) < 1.0e-3);
+    }
 
-pub fn find_scene_hit<'a>(ray: &Ray, scene: &'a Scene) -> Option<Hit<'a>> {
-    let mut nearest_hit: Option<Hit> = None;
-
-    for sphere in scene.spheres.iter().chain(scene.emissive_spheres.iter()) {
-        if let Some(hit) = intersect_sphere(sphere, &ray) {
-            nearest_hit = if let Some(nearest_hit) = nearest_hit {
-                if hit.parameter < nearest_hit.parameter {
-                    Some(hit)
-                } else {
-                    Some(nearest_hit)
-                }
-            } else {
-                Some(hit)
-            }
+    #[test]
+    fn ray_leaving_the_surface_of_a_sphere_does_not_hit_it_again() {
+        let sphere = unit_sphere();
+        assert!(intersect_sphere(&sphere, &Ray::new(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, 1.0))).is_none());
+
+        // A ray refracted into the sphere only hits the opposite side.
+        let hit = intersect_sphere(&sphere, &Ray::new(Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 0.0, 1.0))).unwrap();
+        assert!(f32::abs(hit.parameter - 2.0) < 1.0e-4);
+        assert_eq!(hit.transition, Transition::Out);
+    }
+
+    #[test]
+    fn offset_origins_on_far_away_surfaces_do_not_hit_them_again() {
+        let sphere = Sphere::new(Vec3::new(0.0, 0.0, 10000.0), 100.0, Material::None);
+        let hit = intersect_sphere(&sphere, &Ray::new(Vec3::zero(), Vec3::new(0.0, 0.0, 1.0))).unwrap();
+        let reflected = Vec3::new(1.0, 0.0, -0.01).normalize();
+        assert!(intersect_sphere(&sphere, &Ray::new(offset_ray_origin(hit.position, hit.normal), reflected)).is_none());
+    }
+
+    #[test]
+    fn distant_sphere_is_hit_precisely() {
+        let sphere = Sphere::new(Vec3::new(0.0, 0.0, 10000.0), 1.0, Material::None);
+        let hit = intersect_sphere(&sphere, &Ray::new(Vec3::zero(), Vec3::new(0.0, 0.0, 1.0))).unwrap();
+        assert!(f32::abs(hit.parameter - 9999.0) < 1.0e-2);
+    }
+
+    #[test]
+    fn plane_is_hit_from_its_front_and_back() {
+        let plane = floor();
+        assert_close(plane.normal, Vec3::new(0.0, 1.0, 0.0));
+
+        let hit = intersect_plane(&plane, &Ray::new(Vec3::new(0.5, 1.0, 0.5), Vec3::new(0.0, -1.0, 0.0))).unwrap();
+        assert!(f32::abs(hit.parameter - 1.0) < 1.0e-4);
+        assert_eq!(hit.transition, Transition::In);
+        assert_close(hit.position, Vec3::new(0.5, 0.0, 0.5));
+        assert_close(hit.normal, Vec3::new(0.0, 1.0, 0.0));
+
+        let hit = intersect_plane(&plane, &Ray::new(Vec3::new(0.5, -1.0, 0.5), Vec3::new(0.0, 1.0, 0.0))).unwrap();
+        assert_eq!(hit.transition, Transition::Out);
+        assert_close(hit.normal, Vec3::new(0.0, -1.0, 0.0));
+    }
+
+    #[test]
+    fn plane_is_missed_outside_of_its_edges_and_behind_the_ray() {
+        let plane = floor();
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        assert!(intersect_plane(&plane, &Ray::new(Vec3::new(1.5, 1.0, 0.0), down)).is_none());
+        assert!(intersect_plane(&plane, &Ray::new(Vec3::new(0.0, 1.0, -1.5), down)).is_none());
+        assert!(intersect_plane(&plane, &Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 1.0, 0.0))).is_none());
+        assert!(intersect_plane(&plane, &Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(1.0, 0.0, 0.0))).is_none());
+        assert!(intersect_plane(&plane, &Ray::new(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0))).is_none());
+    }
+
+    #[test]
+    fn transforms_are_applied_to_the_rest_pose() {
+        let mut scene = Scene::default();
+        scene.spheres.push(Sphere::new(Vec3::new(1.0, 0.0, 0.0), 1.0, Material::None));
+        scene.planes.push(Plane::new(Vec3::zero(), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0), Material::None));
+
+        let translation = Transform::new(Vec3::new(0.0, 2.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 0.0, 2.0);
+        for _ in 0..2 {
+            assert!(scene.set_transform(ObjectId::Sphere(0), translation));
         }
+        assert_close(scene.spheres[0].origin, Vec3::new(1.0, 2.0, 0.0));
+        assert!(f32::abs(scene.spheres[0].radius - 2.0) < 1.0e-4);
+
+        // A quarter turn around y rotates the edges of the plane and its normal.
+        let rotation = Transform::new(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), 0.5*PI, 1.0);
+        assert!(scene.set_transform(ObjectId::Plane(0), rotation));
+        assert_close(scene.planes[0].u, Vec3::new(0.0, 0.0, -1.0));
+        assert_close(scene.planes[0].normal, Vec3::new(0.0, 1.0, 0.0));
+        assert!(scene.set_transform(ObjectId::Plane(0), Transform::identity()));
+        assert_close(scene.planes[0].u, Vec3::new(1.0, 0.0, 0.0));
+
+        assert!(!scene.set_transform(ObjectId::Volume(0), translation));
+        assert!(!scene.set_transform(ObjectId::EmissiveSphere(0), translation));
     }
 
-    for plane in scene.planes.iter().chain(scene.emissive_planes.iter()) {
-        if let Some(hit) = intersect_plane(plane, &ray) {
-            if hit.transition == Transition::In {
-                nearest_hit = if let Some(nearest_hit) = nearest_hit {
-                    if hit.parameter < nearest_hit.parameter {
-                        Some(hit)
-                    } else {
-                        Some(nearest_hit)
-                    }
-                } else {
-                    Some(hit)
-                }
-            }
+    #[test]
+    fn lights_keep_their_material() {
+        let mut scene = Scene::default();
+        scene.spheres.push(Sphere::new(Vec3::zero(), 1.0, Material::None));
+        scene.emissive_planes.push(Plane::new(Vec3::zero(), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 0.0, -1.0), Material::Emissive(Vec3::one())));
+
+        assert!(scene.set_material(ObjectId::Sphere(0), Material::Mirror));
+        assert!(!scene.set_material(ObjectId::Sphere(0), Material::Emissive(Vec3::one())));
+        assert!(!scene.set_material(ObjectId::EmissivePlane(0), Material::Mirror));
+        assert!(!scene.set_material(ObjectId::Plane(3), Material::Mirror));
+        match scene.spheres[0].material {
+            Material::Mirror => (),
+            ref material => panic!("{:?}", material),
         }
     }
 
-    nearest_hit
+    // The infinite plane y = 0 with the outside above it.
+    #[derive(Debug)]
+    struct Ground;
+
+    impl Shape for Ground {
+        fn intersect(&self, ray: &Ray) -> Option<(f32, Vec3)> {
+            Some((-ray.origin.y / ray.direction.y, Vec3::new(0.0, 1.0, 0.0)))
+        }
+
+        fn bounds(&self) -> Bounds {
+            Bounds::empty()
+        }
+    }
+
+    #[test]
+    fn custom_primitive_is_hit_from_both_sides() {
+        let custom = CustomPrimitive::new(Arc::new(Ground), Material::None);
+
+        let hit = intersect_custom(&custom, &Ray::new(Vec3::new(0.0, 2.0, 0.0), Vec3::new(0.0, -1.0, 0.0))).unwrap();
+        assert!(f32::abs(hit.parameter - 2.0) < 1.0e-4);
+        assert_eq!(hit.transition, Transition::In);
+        assert_close(hit.normal, Vec3::new(0.0, 1.0, 0.0));
+
+        // The normal is flipped towards the ray.
+        let hit = intersect_custom(&custom, &Ray::new(Vec3::new(0.0, -2.0, 0.0), Vec3::new(0.0, 1.0, 0.0))).unwrap();
+        assert_eq!(hit.transition, Transition::Out);
+        assert_close(hit.normal, Vec3::new(0.0, -1.0, 0.0));
+
+        // Hits behind the ray and parallel rays are ignored.
+        assert!(intersect_custom(&custom, &Ray::new(Vec3::new(0.0, 2.0, 0.0), Vec3::new(0.0, 1.0, 0.0))).is_none());
+        assert!(intersect_custom(&custom, &Ray::new(Vec3::new(0.0, 2.0, 0.0), Vec3::new(1.0, 0.0, 0.0))).is_none());
+    }
+
+    #[test]
+    fn object_id_is_parsed_from_its_name() {
+        for id in &[ObjectId::Sphere(0), ObjectId::EmissivePlane(3), ObjectId::Custom(12)] {
+            assert_eq!(ObjectId::parse(&format!("{}", id)), Some(*id));
+        }
+        assert_eq!(ObjectId::parse("plane:2"), Some(ObjectId::Plane(2)));
+        assert_eq!(ObjectId::parse("plane"), None);
+        assert_eq!(ObjectId::parse("cube 1"), None);
+    }
+
+    #[test]
+    fn matte_ids_are_distinct_normal_floats() {
+        let ids: Vec<u32> = (0..100).flat_map(|index| vec![ObjectId::Sphere(index), ObjectId::Plane(index), ObjectId::Custom(index)])
+            .map(|id| id.matte_id()).collect();
+        for (i, &id) in ids.iter().enumerate() {
+            assert!(f32::from_bits(id).is_normal(), "{:x}", id);
+            assert!(!ids[i + 1..].contains(&id), "{:x}", id);
+        }
+        assert_eq!(ObjectId::Sphere(3).matte_id(), ObjectId::Sphere(3).matte_id());
+    }
+
+    #[test]
+    fn murmur_hash_matches_the_reference() {
+        assert_eq!(murmur_hash3_32(b"", 0), 0);
+        assert_eq!(murmur_hash3_32(b"test", 0), 0xba6bd213);
+        assert_eq!(murmur_hash3_32(b"The quick brown fox jumps over the lazy dog", 0), 0x2e4ff723);
+    }
+
+    #[test]
+    fn bounds_of_touching_primitives_overlap() {
+        let sphere = unit_sphere();
+        let floor = floor();
+        let (sphere_bounds, floor_bounds) = (Primitive::Sphere(&sphere).bounds(), Primitive::Plane(&floor).bounds());
+        assert!(f32::abs(sphere_bounds.surface_area() - 24.0) < 1.0e-4);
+        assert!(f32::abs(floor_bounds.surface_area() - 8.0) < 1.0e-4);
+        assert!(sphere_bounds.overlaps(&floor_bounds));
+        let far = Sphere::new(Vec3::new(5.0, 0.0, 0.0), 1.0, Material::None);
+        assert!(!Primitive::Sphere(&far).bounds().overlaps(&sphere_bounds));
+        assert_eq!(Bounds::empty().surface_area(), 0.0);
+    }
+
+    #[test]
+    fn auto_frame_includes_the_volumes() {
+        let mut scene = Scene::default();
+        scene.add_sphere(unit_sphere());
+        let grid = ::volume::DensityGrid::new(2, vec![1.0; 8]);
+        scene.volumes.push(Volume::new(Vec3::new(30.0, -2.0, -2.0), Vec3::new(34.0, 2.0, 2.0), grid, 1.0, Vec3::one(), 0.0));
+        scene.auto_frame();
+        let (center, radius) = scene.bounds_with_volumes().bounding_sphere();
+        assert!(scene.camera.sees_sphere(center, radius));
+        assert!(scene.camera.sees_sphere(Vec3::new(32.0, 0.0, 0.0), 0.1));
+    }
+
+    #[test]
+    fn hidden_objects_are_skipped_by_their_kind_of_ray() {
+        let mut scene = Scene::default();
+        let sphere = scene.add_sphere(Sphere::new(Vec3::new(0.0, 1.0, 0.0), 0.5, Material::None));
+        scene.add_plane(floor());
+        scene.add_sphere(Sphere { visibility: Visibility { camera: false, shadows: false, reflections: false }, ..Sphere::new(Vec3::new(0.0, 3.0, 0.0), 0.5, Material::Emissive(Vec3::one())) });
+        assert!(scene.set_visibility(sphere, Visibility { camera: false, ..Visibility::default() }));
+        assert!(!scene.set_visibility(ObjectId::Volume(0), Visibility::default()));
+
+        // The sphere is only hidden from the camera and the picking sees every object.
+        let down = Ray::new(Vec3::new(0.0, 2.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        assert_close(find_visible_hit(&down, &scene, RayKind::Camera).unwrap().position, Vec3::zero());
+        assert_close(find_visible_hit(&down, &scene, RayKind::Bounce).unwrap().position, Vec3::new(0.0, 0.5, 0.0));
+        assert_close(find_visible_hit(&down, &scene, RayKind::Shadow).unwrap().position, Vec3::new(0.0, 0.5, 0.0));
+        assert_eq!(find_object_hit(&down, &scene).unwrap().0, sphere);
+
+        // The hidden light is passed by every kind of ray, the shadow rays that are connected
+        // to it treat the miss as the light being visible.
+        let up = Ray::new(Vec3::new(0.0, 2.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        assert!(find_visible_hit(&up, &scene, RayKind::Camera).is_none());
+        assert!(find_visible_hit(&up, &scene, RayKind::Bounce).is_none());
+        assert!(find_visible_hit(&up, &scene, RayKind::Shadow).is_none());
+    }
 }
@@ -0,0 +1,122 @@
+use scene::{Sphere, Plane};
+
+// Scripts in scene files that generate geometry procedurally when the scene is loaded. They
+// are written in Rhai (https://rhai.rs) and only supported with the "scripting" feature.
+//
+//   script {
+//       let material = diffuse(vec3(0.8, 0.8, 0.8));
+//       for i in 0..100 {
+//           let angle = 0.1*i;
+//           sphere(vec3(5.0*angle.cos(), 0.05*i, 5.0*angle.sin()), 0.2, material);
+//       }
+//   }
+//
+// Besides the language itself (loops, functions, math on floats), the script can use:
+//
+//   vec3(x, y, z) with +, - and * by a float as well as the fields x, y and z
+//   diffuse(color), plastic(color, roughness), metal(color, roughness), glass(ior),
+//   glass(ior, dispersion), light(radiance) and mirror() to create materials
+//   sphere(origin, radius, material), plane(origin, u, v, material) and
+//   quad(center, normal, width, height, material) to add objects
+//   random() for a number in [0, 1] which is the same on every run and seed(n) to change it
+//
+// Numbers have to be written as floats, e.g. 1.0 instead of 1, as the functions only take
+// floats. The objects are added in the order in which they are created.
+
+#[derive(Clone, Debug)]
+pub enum ScriptObject {
+    Sphere(Sphere),
+    Plane(Plane),
+}
+
+#[cfg(feature = "scripting")]
+pub use self::backend::run;
+
+#[cfg(not(feature = "scripting"))]
+pub use self::fallback::run;
+
+#[cfg(feature = "scripting")]
+mod backend {
+    use std::rc::Rc;
+    use std::cell::RefCell;
+
+    use rhai::{Engine, FLOAT, INT};
+
+    use common::*;
+    use scene::{Material, Sphere, Plane};
+    use builder;
+    use super::ScriptObject;
+
+    // Limits the scripts so that a mistake like an endless loop does not hang the renderer.
+    const MAX_OPERATIONS: u64 = 100_000_000;
+
+    fn scaled(v: Vec3, s: FLOAT) -> Vec3 {
+        (s as f32)*v
+    }
+
+    pub fn run(source: &str) -> Result<Vec<ScriptObject>, String> {
+        let objects = Rc::new(RefCell::new(Vec::new()));
+        let random = Rc::new(RefCell::new(XorShift32::new(1)));
+
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+
+        // VECTORS
+        engine.register_type_with_name::<Vec3>("Vec3");
+        engine.register_fn("vec3", |x: FLOAT, y: FLOAT, z: FLOAT| Vec3::new(x as f32, y as f32, z as f32));
+        engine.register_fn("+", |a: Vec3, b: Vec3| a + b);
+        engine.register_fn("-", |a: Vec3, b: Vec3| a - b);
+        engine.register_fn("*", |v: Vec3, s: FLOAT| scaled(v, s));
+        engine.register_fn("*", |s: FLOAT, v: Vec3| scaled(v, s));
+        engine.register_get("x", |v: &mut Vec3| v.x as FLOAT);
+        engine.register_get("y", |v: &mut Vec3| v.y as FLOAT);
+        engine.register_get("z", |v: &mut Vec3| v.z as FLOAT);
+
+        // MATERIALS
+        engine.register_type_with_name::<Material>("Material");
+        engine.register_fn("diffuse", |color: Vec3| builder::diffuse(color));
+        engine.register_fn("plastic", |color: Vec3, roughness: FLOAT| builder::plastic(color, roughness as f32));
+        engine.register_fn("metal", |color: Vec3, roughness: FLOAT| builder::metal(color, roughness as f32));
+        engine.register_fn("glass", |ior: FLOAT| builder::glass(ior as f32));
+        engine.register_fn("glass", |ior: FLOAT, dispersion: FLOAT| builder::dispersive_glass(ior as f32, dispersion as f32));
+        engine.register_fn("light", |radiance: Vec3| builder::light(radiance));
+        engine.register_fn("mirror", || Material::Mirror);
+
+        // OBJECTS
+        let target = objects.clone();
+        engine.register_fn("sphere", move |origin: Vec3, radius: FLOAT, material: Material| {
+            target.borrow_mut().push(ScriptObject::Sphere(Sphere::new(origin, radius as f32, material)));
+        });
+        let target = objects.clone();
+        engine.register_fn("plane", move |origin: Vec3, u: Vec3, v: Vec3, material: Material| {
+            target.borrow_mut().push(ScriptObject::Plane(Plane::new(origin, u, v, material)));
+        });
+        let target = objects.clone();
+        engine.register_fn("quad", move |center: Vec3, normal: Vec3, width: FLOAT, height: FLOAT, material: Material| {
+            let (origin, u, v) = builder::quad_edges(center, normal, width as f32, height as f32);
+            target.borrow_mut().push(ScriptObject::Plane(Plane::new(origin, u, v, material)));
+        });
+
+        // RANDOM NUMBERS
+        let generator = random.clone();
+        engine.register_fn("random", move || generator.borrow_mut().next_f32() as FLOAT);
+        let generator = random.clone();
+        engine.register_fn("seed", move |seed: INT| {
+            *generator.borrow_mut() = XorShift32::new(seed as u32);
+        });
+
+        engine.run(source).map_err(|err| format!("{}", err))?;
+
+        let objects = objects.replace(Vec::new());
+        Ok(objects)
+    }
+}
+
+#[cfg(not(feature = "scripting"))]
+mod fallback {
+    use super::ScriptObject;
+
+    pub fn run(_source: &str) -> Result<Vec<ScriptObject>, String> {
+        Err(String::from("Scripts in scene files require the \"scripting\" feature."))
+    }
+}
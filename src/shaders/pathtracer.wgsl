@@ -0,0 +1,248 @@
+// Path tracer running as a compute shader with one invocation per pixel. The scene is
+// uploaded by gpu.rs as flat arrays of vec4s. Every invocation writes the radiance of one
+// sample which is accumulated into the backbuffer on the CPU.
+//
+// Materials (w component of the material vec4):
+//   0 = none, 1 = emissive, 2 = mirror, 3 = translucent, 4 = physically based
+
+struct Params {
+    plane_origin: vec4<f32>,
+    plane_u: vec4<f32>,
+    plane_v: vec4<f32>,
+    camera_position: vec4<f32>,
+    sky: vec4<f32>,
+    size: vec4<u32>,    // width, height, number of spheres, number of planes
+    options: vec4<u32>, // frame index, maximum depth, sub-pixel sampling, unused
+};
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read> spheres: array<vec4<f32>>;
+@group(0) @binding(2) var<storage, read> planes: array<vec4<f32>>;
+@group(0) @binding(3) var<storage, read_write> radiance: array<vec4<f32>>;
+
+const PI: f32 = 3.14159265;
+const SPHERE_STRIDE: u32 = 3u;
+const PLANE_STRIDE: u32 = 6u;
+const NO_HIT: f32 = 1e30;
+const RAY_EPSILON: f32 = 0.0001;
+
+var<private> rng_state: u32;
+
+// Source: https://www.reedbeta.com/blog/hash-functions-for-gpu-rendering/
+fn random() -> f32 {
+    rng_state = rng_state * 747796405u + 2891336453u;
+    var word = ((rng_state >> ((rng_state >> 28u) + 4u)) ^ rng_state) * 277803737u;
+    word = (word >> 22u) ^ word;
+    return f32(word) / 4294967295.0;
+}
+
+// Same as offset_ray_origin in common.rs
+fn offset_ray_origin(p: vec3<f32>, n: vec3<f32>) -> vec3<f32> {
+    let ulps = vec3<i32>(256.0 * n);
+    let nudged = bitcast<vec3<f32>>(bitcast<vec3<i32>>(p) + select(ulps, -ulps, p < vec3<f32>(0.0)));
+    return select(nudged, p + n / 65536.0, abs(p) < vec3<f32>(1.0 / 32.0));
+}
+
+struct Hit {
+    t: f32,
+    position: vec3<f32>,
+    normal: vec3<f32>,
+    inside: bool,
+    material: vec4<f32>,   // color in xyz, type in w
+    parameters: vec4<f32>, // roughness, metalness, index of refraction
+};
+
+// All primitives are tested one after another, just as on the CPU.
+fn intersect_scene(origin: vec3<f32>, direction: vec3<f32>) -> Hit {
+    var hit: Hit;
+    hit.t = NO_HIT;
+
+    for (var i = 0u; i < params.size.z; i = i + 1u) {
+        let base = i * SPHERE_STRIDE;
+        let center = spheres[base].xyz;
+        let r = spheres[base].w;
+
+        let oc = origin - center;
+        let b = dot(oc, direction);
+        let c = dot(oc, oc) - r * r;
+        let discriminant = b * b - c;
+        if (discriminant < 0.0) {
+            continue;
+        }
+
+        let sq = sqrt(discriminant);
+        var t = -b - sq;
+        var inside = false;
+        if (t < RAY_EPSILON) {
+            t = -b + sq;
+            inside = true;
+        }
+        if (t < RAY_EPSILON || t >= hit.t) {
+            continue;
+        }
+
+        hit.t = t;
+        hit.position = origin + t * direction;
+        let outward = (hit.position - center) / r;
+        hit.normal = select(outward, -outward, inside);
+        hit.inside = inside;
+        hit.material = spheres[base + 1u];
+        hit.parameters = spheres[base + 2u];
+    }
+
+    for (var i = 0u; i < params.size.w; i = i + 1u) {
+        let base = i * PLANE_STRIDE;
+        let plane_origin = planes[base].xyz;
+        let u = planes[base + 1u].xyz;
+        let v = planes[base + 2u].xyz;
+        let n = planes[base + 3u].xyz;
+
+        // Planes are only visible from the side their normal is pointing to.
+        let denom = dot(n, direction);
+        if (denom > -0.0000001) {
+            continue;
+        }
+        let t = dot(n, plane_origin - origin) / denom;
+        if (t < RAY_EPSILON || t >= hit.t) {
+            continue;
+        }
+
+        let p = origin + t * direction;
+        let in_plane = p - plane_origin;
+        let on_u = dot(in_plane, normalize(u));
+        let on_v = dot(in_plane, normalize(v));
+        if (on_u < 0.0 || on_u > length(u) || on_v < 0.0 || on_v > length(v)) {
+            continue;
+        }
+
+        hit.t = t;
+        hit.position = p;
+        hit.normal = n;
+        hit.inside = false;
+        hit.material = planes[base + 4u];
+        hit.parameters = planes[base + 5u];
+    }
+
+    return hit;
+}
+
+fn to_world(normal: vec3<f32>, v: vec3<f32>) -> vec3<f32> {
+    var other = vec3<f32>(0.0, 1.0, 0.0);
+    if (abs(normal.y) > 0.9999) {
+        other = vec3<f32>(1.0, 0.0, 0.0);
+    }
+    let x = normalize(cross(normal, other));
+    let z = cross(normal, x);
+    return x * v.x + normal * v.y + z * v.z;
+}
+
+fn fresnel_schlick(cos_theta: f32, f0: vec3<f32>) -> vec3<f32> {
+    return f0 + (vec3<f32>(1.0) - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+// The normal distribution function is canceled out by the importance sampling.
+fn brdf_cook_torrance(view: vec3<f32>, light: vec3<f32>, normal: vec3<f32>, roughness: f32, f0: vec3<f32>) -> vec3<f32> {
+    let alpha = roughness * roughness;
+    let h = normalize(view + light);
+    let k = alpha / 2.0;
+    let n_dot_v = max(0.0, dot(normal, view));
+    let n_dot_l = max(0.0, dot(normal, light));
+    let g = (n_dot_l / max(n_dot_l * (1.0 - k) + k, 0.000001)) * (n_dot_v / max(n_dot_v * (1.0 - k) + k, 0.000001));
+    return fresnel_schlick(max(0.0, dot(h, view)), f0) * g / max(4.0 * n_dot_v, 0.0001);
+}
+
+@compute @workgroup_size(8, 8)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let width = params.size.x;
+    let height = params.size.y;
+    if (id.x >= width || id.y >= height) {
+        return;
+    }
+
+    rng_state = (id.y * width + id.x) * 9781u + params.options.x * 6271u + 1u;
+    random();
+
+    var offset = vec2<f32>(f32(id.x), f32(id.y));
+    if (params.options.z != 0u) {
+        offset = offset + vec2<f32>(random(), random());
+    }
+    let du = params.plane_u.xyz / f32(width);
+    let dv = params.plane_v.xyz / f32(height);
+    var origin = params.plane_origin.xyz + offset.x * du + offset.y * dv;
+    var direction = normalize(origin - params.camera_position.xyz);
+
+    var throughput = vec3<f32>(1.0);
+    var result = vec3<f32>(0.0);
+
+    for (var depth = 0u; depth <= params.options.y; depth = depth + 1u) {
+        let hit = intersect_scene(origin, direction);
+        if (hit.t >= NO_HIT) {
+            result += throughput * params.sky.xyz;
+            break;
+        }
+
+        let material_type = u32(hit.material.w);
+        let color = hit.material.xyz;
+
+        if (material_type == 0u) {
+            result += throughput;
+            break;
+        }
+
+        if (material_type == 1u) {
+            result += throughput * color;
+            break;
+        }
+
+        if (material_type == 2u) {
+            direction = reflect(direction, hit.normal);
+            origin = offset_ray_origin(hit.position, hit.normal);
+            continue;
+        }
+
+        if (material_type == 3u) {
+            // Instead of tracing both the reflection and the refraction as on the CPU, one
+            // of them is chosen according to the Fresnel term.
+            let ior = hit.parameters.z;
+            let eta = select(1.0 / ior, ior, hit.inside);
+            let cos_theta = max(0.0, dot(hit.normal, -direction));
+            let fresnel = fresnel_schlick(cos_theta, vec3<f32>(0.04)).x;
+            let refracted = refract(direction, hit.normal, eta);
+            if (random() < fresnel || dot(refracted, refracted) == 0.0) {
+                direction = reflect(direction, hit.normal);
+                origin = offset_ray_origin(hit.position, hit.normal);
+            } else {
+                direction = normalize(refracted);
+                origin = offset_ray_origin(hit.position, -hit.normal);
+            }
+            continue;
+        }
+
+        // Physically based material
+        let roughness = hit.parameters.x;
+        let metalness = hit.parameters.y;
+        let f0 = mix(vec3<f32>(0.04), color, metalness);
+        let xi = vec2<f32>(random(), random());
+        origin = offset_ray_origin(hit.position, hit.normal);
+
+        if (random() < (f0.x + f0.y + f0.z) / 3.0) {
+            let a = roughness * roughness;
+            let phi = 2.0 * PI * xi.x;
+            let cos_theta = sqrt((1.0 - xi.y) / (xi.y * (a * a - 1.0) + 1.0));
+            let sin_theta = sqrt(max(0.0, 1.0 - cos_theta * cos_theta));
+            let h = to_world(hit.normal, vec3<f32>(sin_theta * cos(phi), cos_theta, sin_theta * sin(phi)));
+            let light = normalize(reflect(direction, h));
+            throughput *= PI * brdf_cook_torrance(-direction, light, hit.normal, roughness, f0);
+            direction = light;
+        } else {
+            let r = sqrt(xi.x);
+            let theta = 2.0 * PI * xi.y;
+            let local = vec3<f32>(r * cos(theta), sqrt(max(0.0, 1.0 - xi.x)), r * sin(theta));
+            let light = normalize(to_world(hit.normal, local));
+            throughput *= color * dot(light, hit.normal);
+            direction = light;
+        }
+    }
+
+    radiance[id.y * width + id.x] = vec4<f32>(result, 1.0);
+}
@@ -0,0 +1,137 @@
+use std::cell::Cell;
+#[cfg(not(target_arch = "wasm32"))] use std;
+#[cfg(not(target_arch = "wasm32"))] use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(not(target_arch = "wasm32"))] use scene::{Scene, Sphere, Plane, CustomPrimitive, Bounds};
+#[cfg(not(target_arch = "wasm32"))] use volume::Volume;
+
+// Statistics for measuring the performance of the renderer. The counters are incremented
+// per thread without any synchronization and only added to the global counters when a tile
+// is finished, as atomic increments for every ray would slow down the worker threads
+// considerably.
+//
+// There is no acceleration structure yet. Therefore every ray is tested against every
+// primitive and the number of intersection tests is the number of rays times the number of
+// primitives.
+//
+// Only the counters of the calling thread are used in the browser, e.g. for the heatmap.
+// The global counters and the scene statistics are printed by the window and the headless
+// mode.
+
+thread_local! {
+    static LOCAL_RAYS: Cell<usize> = Cell::new(0);
+    static LOCAL_INTERSECTION_TESTS: Cell<usize> = Cell::new(0);
+}
+
+#[cfg(not(target_arch = "wasm32"))] static RAYS: AtomicUsize = AtomicUsize::new(0);
+#[cfg(not(target_arch = "wasm32"))] static INTERSECTION_TESTS: AtomicUsize = AtomicUsize::new(0);
+#[cfg(not(target_arch = "wasm32"))] static TILES: AtomicUsize = AtomicUsize::new(0);
+#[cfg(not(target_arch = "wasm32"))] static TILE_TIME_NS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn count_ray(num_intersection_tests: usize) {
+    LOCAL_RAYS.with(|rays| rays.set(rays.get() + 1));
+    LOCAL_INTERSECTION_TESTS.with(|tests| tests.set(tests.get() + num_intersection_tests));
+}
+
+// Number of rays the calling thread traced in the current tile, e.g. for the heatmap.
+pub fn local_rays() -> usize {
+    LOCAL_RAYS.with(|rays| rays.get())
+}
+
+// Adds the counters of the calling thread to the global ones. Is called after every tile.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn flush_tile(tile_time_ns: u64) {
+    RAYS.fetch_add(LOCAL_RAYS.with(|rays| rays.replace(0)), Ordering::Relaxed);
+    INTERSECTION_TESTS.fetch_add(LOCAL_INTERSECTION_TESTS.with(|tests| tests.replace(0)), Ordering::Relaxed);
+    TILES.fetch_add(1, Ordering::Relaxed);
+    TILE_TIME_NS.fetch_add(tile_time_ns as usize, Ordering::Relaxed);
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Clone, Debug, Default)]
+pub struct FrameStats {
+    pub rays: usize,
+    pub intersection_tests: usize,
+    pub tiles: usize,
+    pub tile_time_ns: usize, // Summed over all worker threads
+}
+
+// Returns the statistics since the last call and resets them.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn take_frame_stats() -> FrameStats {
+    FrameStats {
+        rays: RAYS.swap(0, Ordering::Relaxed),
+        intersection_tests: INTERSECTION_TESTS.swap(0, Ordering::Relaxed),
+        tiles: TILES.swap(0, Ordering::Relaxed),
+        tile_time_ns: TILE_TIME_NS.swap(0, Ordering::Relaxed),
+    }
+}
+
+//
+// SCENE STATISTICS
+//
+// Printed with "--stats" after loading the scene, e.g. to find out why a scene renders
+// slowly. As long as there is no acceleration structure, the scene is a single leaf with all
+// primitives and there is no depth histogram. The overlap is the summed surface area of the
+// bounds of the primitives relative to the one of the bounds of the scene. It is the number
+// of bounds that a random ray through the scene passes, and therefore the cost of the leaf
+// for the surface area heuristic. The memory leaves out what the custom primitives and
+// materials allocate themselves.
+//
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Clone, Debug, Default)]
+pub struct SceneStats {
+    pub spheres: usize,
+    pub planes: usize,
+    pub custom_primitives: usize,
+    pub lights: usize,
+    pub volumes: usize,
+    pub nodes: usize,
+    pub overlapping_pairs: usize, // Pairs of primitives whose bounds overlap
+    pub overlap: f32,
+    pub primitive_bytes: usize,
+    pub volume_bytes: usize,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn scene_stats(scene: &Scene) -> SceneStats {
+    let bounds: Vec<Bounds> = scene.primitives().map(|(_, primitive)| primitive.bounds()).collect();
+    let mut overlapping_pairs = 0;
+    for (i, a) in bounds.iter().enumerate() {
+        overlapping_pairs += bounds[i + 1..].iter().filter(|b| a.overlaps(b)).count();
+    }
+    let scene_area = scene.bounds().surface_area();
+    let overlap = if scene_area > 0.0 {
+        bounds.iter().map(|bounds| bounds.surface_area()).sum::<f32>() / scene_area
+    } else {
+        0.0
+    };
+
+    let spheres = scene.spheres.len() + scene.emissive_spheres.len();
+    let planes = scene.planes.len() + scene.emissive_planes.len();
+    SceneStats {
+        spheres: spheres,
+        planes: planes,
+        custom_primitives: scene.custom_primitives.len(),
+        lights: scene.num_lights(),
+        volumes: scene.volumes.len(),
+        nodes: scene.nodes.len(),
+        overlapping_pairs: overlapping_pairs,
+        overlap: overlap,
+        primitive_bytes: spheres*std::mem::size_of::<Sphere>() + planes*std::mem::size_of::<Plane>()
+            + scene.custom_primitives.len()*std::mem::size_of::<CustomPrimitive>(),
+        volume_bytes: scene.volumes.iter().map(|volume| std::mem::size_of::<Volume>() + volume.grid.values.len()*std::mem::size_of::<f32>()).sum(),
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn print_scene_stats(scene: &Scene) {
+    let stats = scene_stats(scene);
+    let num_primitives = stats.spheres + stats.planes + stats.custom_primitives;
+    println!("Scene statistics:");
+    println!("  primitives = {} ({} spheres, {} planes, {} custom), lights = {}, volumes = {}, nodes = {}",
+        num_primitives, stats.spheres, stats.planes, stats.custom_primitives, stats.lights, stats.volumes, stats.nodes);
+    println!("  acceleration structure = none, 1 leaf with {} primitives, {} intersection tests per ray", num_primitives, num_primitives);
+    println!("  overlap = {:.2}, overlapping pairs = {} of {}", stats.overlap, stats.overlapping_pairs, num_primitives*num_primitives.saturating_sub(1)/2);
+    println!("  memory = {:.1} KiB primitives, {:.1} KiB volumes", stats.primitive_bytes as f64 / 1024.0, stats.volume_bytes as f64 / 1024.0);
+}
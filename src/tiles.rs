@@ -0,0 +1,173 @@
+use std;
+
+use common::*;
+use tracer::{WorkTile, Integrator};
+
+pub const DEFAULT_TILE_SIZE: u32 = 32;
+// Accepted from the command line and the config file.
+pub const MIN_TILE_SIZE: u32 = 4;
+pub const MAX_TILE_SIZE: u32 = 512;
+
+// The order in which the tiles are queued for the worker threads. As the tiles become
+// visible as soon as they are finished, the order determines which part of the image
+// appears first during progressive rendering.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileOrder {
+    Scanline,
+    Spiral, // Starting in the center of the image
+    Hilbert,
+}
+
+impl TileOrder {
+    pub fn parse(name: &str) -> Option<TileOrder> {
+        match name {
+            "scanline" => Some(TileOrder::Scanline),
+            "spiral" => Some(TileOrder::Spiral),
+            "hilbert" => Some(TileOrder::Hilbert),
+            _ => None,
+        }
+    }
+
+    pub fn next(self) -> TileOrder {
+        match self {
+            TileOrder::Scanline => TileOrder::Spiral,
+            TileOrder::Spiral => TileOrder::Hilbert,
+            TileOrder::Hilbert => TileOrder::Scanline,
+        }
+    }
+}
+
+// A rectangle of the image in pixels. Unlike the backbuffer, it is measured from the top left
+// corner like the positions in the window and in image editors.
+#[derive(Clone, Copy, Debug, PartialEq, new)]
+pub struct Region {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Region {
+    // The region between two corners, e.g. of a rectangle that was dragged with the mouse
+    pub fn from_corners(x0: u32, y0: u32, x1: u32, y1: u32) -> Region {
+        Region::new(u32::min(x0, x1), u32::min(y0, y1), u32::max(x0, x1) - u32::min(x0, x1), u32::max(y0, y1) - u32::min(y0, y1))
+    }
+
+    // The region that shows the same part of the scene after the image was resized. The camera
+    // keeps its vertical field of view and is widened or narrowed around its center (see
+    // Scene::set_resolution), so the region is scaled with the height and stays at the same
+    // distance from the center. The parts left or right of the image are cut off.
+    pub fn resized(&self, old_width: u32, old_height: u32, new_width: u32, new_height: u32) -> Region {
+        let scale = new_height as f32 / u32::max(old_height, 1) as f32;
+        let to_new_x = |x: u32| f32::max(0.5*new_width as f32 + (x as f32 - 0.5*old_width as f32)*scale, 0.0).round() as u32;
+        let to_new_y = |y: u32| (y as f32*scale).round() as u32;
+        Region::from_corners(to_new_x(self.x), to_new_y(self.y), to_new_x(self.x.saturating_add(self.width)), to_new_y(self.y.saturating_add(self.height)))
+    }
+
+    // Returns the part of the region inside of the image in the coordinates of the
+    // backbuffer, i.e. the first and the last pixel plus one with y pointing up. None when
+    // the region lies completely outside.
+    fn clip(&self, width: u32, height: u32) -> Option<(Vec2u, Vec2u)> {
+        let (x0, x1) = (u32::min(self.x, width), u32::min(self.x.saturating_add(self.width), width));
+        let (y0, y1) = (u32::min(self.y, height), u32::min(self.y.saturating_add(self.height), height));
+        if x0 < x1 && y0 < y1 {
+            Some((Vec2u::new(x0, height - y1), Vec2u::new(x1, height - y0)))
+        } else {
+            None
+        }
+    }
+}
+
+// Returns the indices of all tiles in the given order.
+pub fn ordered_tiles(order: TileOrder, num_tiles_x: u32, num_tiles_y: u32) -> Vec<Vec2u> {
+    let mut tiles = Vec::with_capacity((num_tiles_x*num_tiles_y) as usize);
+    for y in 0..num_tiles_y {
+        for x in 0..num_tiles_x {
+            tiles.push(Vec2u::new(x, y));
+        }
+    }
+
+    match order {
+        TileOrder::Scanline => (),
+        TileOrder::Spiral => {
+            // The tiles are sorted by the ring around the center they are in and within the
+            // ring by their angle. This results in a square spiral.
+            let center_x = num_tiles_x as f32 / 2.0;
+            let center_y = num_tiles_y as f32 / 2.0;
+            let key = |tile: &Vec2u| {
+                let dx = tile.x as f32 + 0.5 - center_x;
+                let dy = tile.y as f32 + 0.5 - center_y;
+                let ring = f32::max(f32::abs(dx), f32::abs(dy)).floor();
+                (ring, f32::atan2(dy, dx))
+            };
+            tiles.sort_by(|a, b| key(a).partial_cmp(&key(b)).unwrap());
+        },
+        TileOrder::Hilbert => {
+            let mut n = 1;
+            while n < num_tiles_x || n < num_tiles_y {
+                n *= 2;
+            }
+            tiles.sort_by_key(|tile| hilbert_index(n, tile.x, tile.y));
+        },
+    }
+
+    tiles
+}
+
+// Creates the work items for one frame with square tiles covering the whole image, or only
+// the crop region when one is given. A crop region outside of the image is ignored. The tiles
+// at the right and the top border are cut off so that no tile extends beyond the image.
+pub fn work_tiles(order: TileOrder, width: u32, height: u32, crop: Option<Region>, tile_size: u32, integrator: Integrator, max_depth: u8, generation: usize, sample_index: usize, resolution_scale: u32, features: bool) -> Vec<WorkTile> {
+    let (start, end) = crop.and_then(|crop| crop.clip(width, height)).unwrap_or((Vec2u::new(0, 0), Vec2u::new(width, height)));
+    let num_tiles_x = (end.x - start.x + tile_size - 1) / tile_size;
+    let num_tiles_y = (end.y - start.y + tile_size - 1) / tile_size;
+    ordered_tiles(order, num_tiles_x, num_tiles_y).into_iter().map(|tile_index| {
+        let tile_position = Vec2u::new(start.x + tile_index.x*tile_size, start.y + tile_index.y*tile_size);
+        let tile_size = Vec2u::new(u32::min(tile_size, end.x - tile_position.x), u32::min(tile_size, end.y - tile_position.y));
+        WorkTile::new(tile_index, tile_position, tile_size, integrator, max_depth, generation, sample_index, resolution_scale, Vec2u::new(width, height), features)
+    }).collect()
+}
+
+// Maps a position on a n*n grid (n is a power of two) to its index along the Hilbert curve.
+// Source: https://en.wikipedia.org/wiki/Hilbert_curve
+fn hilbert_index(n: u32, x: u32, y: u32) -> u32 {
+    let (mut x, mut y) = (x, y);
+    let mut d = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = if (x & s) > 0 { 1 } else { 0 };
+        let ry = if (y & s) > 0 { 1 } else { 0 };
+        d += s*s*((3*rx) ^ ry);
+
+        // Rotating the quadrant
+        if ry == 0 {
+            if rx == 1 {
+                x = n - 1 - x;
+                y = n - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    d
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regions_beyond_the_range_of_the_pixels_are_clipped() {
+        let region = Region::new(4, 2, std::u32::MAX, std::u32::MAX);
+        assert_eq!(region.clip(16, 8).map(|(start, end)| (start.x, start.y, end.x, end.y)), Some((4, 0, 16, 6)));
+    }
+
+    #[test]
+    fn resized_regions_show_the_same_part_of_the_view() {
+        let region = Region::new(40, 20, 20, 10);
+        // Twice the size
+        assert_eq!(region.resized(100, 50, 200, 100), Region::new(80, 40, 40, 20));
+        // Only wider, the view is extended on both sides.
+        assert_eq!(region.resized(100, 50, 200, 50), Region::new(90, 20, 20, 10));
+    }
+}
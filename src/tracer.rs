@@ -1,15 +1,107 @@
 use common::*;
 
-use scene::{Scene, Sky, PBRParameters, Material, find_scene_hit};
+use scene::{Scene, Sky, Medium, PBRParameters, Material, Primitive, ObjectId, Bounds, RayKind, find_scene_hit, find_visible_hit, find_visible_object_hit};
+use volume;
+use stats;
+use guiding::GuidingGrid;
+use restir::{LightSample, Reservoir, ReservoirBuffer};
 
+use std;
 use std::sync::{Arc, RwLock};
-use std::cell::UnsafeCell;
+use std::cell::{Cell, RefCell, UnsafeCell};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 #[derive(Clone, Debug, new)]
 pub struct ImageSettings {
     pub width: usize,
     pub height: usize,
     pub sub_pixel_sampling: bool,
+    // Renders the directly visible sky as black so that the image can be composited over
+    // another background with the alpha of the backbuffer. The sky still lights the scene.
+    #[new(default)]
+    pub transparent_background: bool,
+    #[new(default)]
+    pub stereo: Option<Stereo>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StereoLayout {
+    SideBySide, // The left eye in the left half of the image, both squeezed to half the width
+    Anaglyph,   // The red channel from the left eye, green and blue from the right one
+}
+
+impl StereoLayout {
+    pub fn parse(name: &str) -> Option<StereoLayout> {
+        match name {
+            "side-by-side" | "side_by_side" => Some(StereoLayout::SideBySide),
+            "anaglyph" => Some(StereoLayout::Anaglyph),
+            _ => None,
+        }
+    }
+}
+
+// The eyes are shifted from the camera along the x axis of the image by half of the
+// interocular distance each. Their views are not rotated, but the rays of both eyes meet at
+// the convergence distance, which therefore appears at the depth of the screen. Closer objects
+// come out of the screen. A convergence distance of about 30 times the interocular distance
+// is comfortable to look at.
+#[derive(Clone, Copy, Debug, PartialEq, new)]
+pub struct Stereo {
+    pub layout: StereoLayout,
+    pub interocular_distance: f32,
+    pub convergence_distance: f32,
+}
+
+impl Stereo {
+    // The distance between human eyes, for scenes in meters.
+    pub const DEFAULT_INTEROCULAR_DISTANCE: f32 = 0.065;
+
+    pub fn with_defaults(layout: StereoLayout, interocular_distance: Option<f32>, convergence_distance: Option<f32>) -> Stereo {
+        let interocular_distance = interocular_distance.unwrap_or(Stereo::DEFAULT_INTEROCULAR_DISTANCE);
+        Stereo::new(layout, interocular_distance, convergence_distance.unwrap_or(30.0*interocular_distance))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Integrator {
+    PathTracer,
+    GuidedPathTracer,
+    Whitted,
+    ReSTIR,
+    Gpu,
+    Wavefront,
+    FastGI,
+    // Debug views that show a property of the scene instead of the radiance
+    Normals,
+    Depth,
+    AmbientOcclusion,
+    Heatmap,
+}
+
+impl Integrator {
+    pub fn parse(name: &str) -> Option<Integrator> {
+        match name {
+            "path" => Some(Integrator::PathTracer),
+            "guided" => Some(Integrator::GuidedPathTracer),
+            "whitted" => Some(Integrator::Whitted),
+            "restir" => Some(Integrator::ReSTIR),
+            "gpu" => Some(Integrator::Gpu),
+            "wavefront" => Some(Integrator::Wavefront),
+            "fastgi" => Some(Integrator::FastGI),
+            "normals" => Some(Integrator::Normals),
+            "depth" => Some(Integrator::Depth),
+            "ao" => Some(Integrator::AmbientOcclusion),
+            "heatmap" => Some(Integrator::Heatmap),
+            _ => None,
+        }
+    }
+
+    pub fn is_debug_view(self) -> bool {
+        match self {
+            Integrator::Normals | Integrator::Depth | Integrator::AmbientOcclusion | Integrator::Heatmap => true,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Clone, Debug, new)]
@@ -17,10 +109,20 @@ pub struct WorkTile {
     pub tile_index: Vec2u,
     pub position: Vec2u,
     pub size: Vec2u,
+    pub integrator: Integrator,
+    pub max_depth: u8, // Number of bounces
+    pub generation: usize, // Tiles of an outdated generation are not rendered anymore
+    pub sample_index: usize, // Seeds the random numbers, counts the samples per pixel of the master from 1
+    pub resolution_scale: u32, // Edge length of the pixel blocks that share one sample
+    pub image_size: Vec2u, // Remote workers resize their image when it changes
+    pub features: bool, // The features of the first hit are only traced for the denoiser, the AOVs and the mattes
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub enum Transition { In, Out }
+#[derive(Clone, Debug, new)]
+pub struct FinishedTile {
+    pub work_tile: WorkTile,
+    pub cancelled: bool,
+}
 
 #[derive(Debug, Clone, new)]
 pub struct Hit<'a> {
@@ -32,21 +134,128 @@ pub struct Hit<'a> {
 }
 
 #[derive(Debug, Clone, new)]
-pub struct Meta {
+pub struct Meta<'a> {
     pixel_position: Vec2u,
     sample_index: usize,
+    guiding: Option<&'a GuidingGrid>,
+    #[new(default)]
+    channel: Option<usize>, // Color channel the path continues in after dispersion
+    #[new(default)]
+    bounced: bool, // The ray does not come from the camera (see scene::Visibility)
+    #[new(default)]
+    media: MediumStack, // Dielectrics the ray is inside of
+    #[new(default)]
+    verbose: bool, // Prints every bounce (see trace_debug_pixel)
+    #[new(default)]
+    recording: bool, // Records the segments of the path (see record_pixel_paths)
 }
 
-#[derive(Clone, Copy, Debug)]
-pub struct Basis(pub Vec3, pub Vec3, pub Vec3);
-
-#[derive(Clone, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ToneMapping {
     Clamp,
     Reinhard,
+    Aces,
     Exposure(f32),
 }
 
+impl ToneMapping {
+    // The exposure operator starts with a value of 1.
+    pub fn parse(name: &str) -> Option<ToneMapping> {
+        match name {
+            "clamp" => Some(ToneMapping::Clamp),
+            "reinhard" => Some(ToneMapping::Reinhard),
+            "aces" => Some(ToneMapping::Aces),
+            "exposure" => Some(ToneMapping::Exposure(1.0)),
+            _ => None,
+        }
+    }
+
+    // Cycles through the operators at runtime. The exposure operator starts with a value
+    // of 1 when it was not selected in the scene file.
+    pub fn next(self) -> ToneMapping {
+        match self {
+            ToneMapping::Clamp => ToneMapping::Reinhard,
+            ToneMapping::Reinhard => ToneMapping::Aces,
+            ToneMapping::Aces => ToneMapping::Exposure(1.0),
+            ToneMapping::Exposure(_) => ToneMapping::Clamp,
+        }
+    }
+}
+
+// Adjustments of the rendered radiance that are applied when it is tone mapped and can
+// therefore be changed at runtime without rendering the image again.
+//
+//   - The exposure is given in stops (EV) on top of the ISO, i.e. +1 doubles the radiance.
+//   - The white balance is the color temperature in Kelvin of the light that appears white,
+//     like in a camera. 6500 K is neutral and a lower value removes the cast of warm light.
+//   - The saturation blends between the luminance (0) and the color (1), or beyond.
+#[derive(Clone, Copy, Debug, PartialEq, new)]
+pub struct ColorGrading {
+    pub exposure: f32,
+    pub white_balance: f32,
+    pub saturation: f32,
+}
+
+pub const NEUTRAL_WHITE_BALANCE: f32 = 6500.0;
+pub const MIN_WHITE_BALANCE: f32 = 2000.0;
+pub const MAX_WHITE_BALANCE: f32 = 12000.0;
+
+impl Default for ColorGrading {
+    fn default() -> Self {
+        ColorGrading::new(0.0, NEUTRAL_WHITE_BALANCE, 1.0)
+    }
+}
+
+// The color of a black body with the temperature in Kelvin, with the maximum channel at one.
+// Only meant for the white balance between 1000 K and 40000 K.
+// Source: Tanner Helland, "How to Convert Temperature (K) to RGB", 2012
+// https://tannerhelland.com/2012/09/18/convert-temperature-rgb-algorithm-code.html
+fn black_body_color(kelvin: f32) -> Vec3 {
+    let t = kelvin/100.0;
+    let red = if t <= 66.0 { 255.0 } else { 329.698727446*f32::powf(t - 60.0, -0.1332047592) };
+    let green = if t <= 66.0 { 99.4708025861*f32::ln(t) - 161.1195681661 } else { 288.1221695283*f32::powf(t - 60.0, -0.0755148492) };
+    let blue = if t >= 66.0 { 255.0 } else if t <= 19.0 { 0.0 } else { 138.5177312231*f32::ln(t - 10.0) - 305.0447927307 };
+    // The approximation is fitted to sRGB encoded values.
+    srgb_to_linear(saturate_vec3(Vec3::new(red, green, blue)/255.0))
+}
+
+impl ColorGrading {
+    // The factors of the color channels that make light of the white balance white while
+    // keeping the neutral temperature unchanged. They are normalized to the luminance so that
+    // the brightness of the image stays the same.
+    fn white_balance_gains(&self) -> Vec3 {
+        let kelvin = f32::min(f32::max(self.white_balance, MIN_WHITE_BALANCE), MAX_WHITE_BALANCE);
+        let white = black_body_color(kelvin);
+        let neutral = black_body_color(NEUTRAL_WHITE_BALANCE);
+        let gains = Vec3::new(neutral.x/white.x, neutral.y/white.y, neutral.z/white.z);
+        gains/luminance(gains)
+    }
+}
+
+// Thin lens in front of the projection plane. The aperture is the radius of the lens and the
+// focus distance is measured from the position of the camera along its direction of view, in
+// the units of the scene.
+#[derive(Clone, Copy, Debug, PartialEq, new)]
+pub struct DepthOfField {
+    pub aperture: f32,
+    pub focus_distance: f32,
+}
+
+// The part of the camera that determines the primary rays, e.g. for sending the camera to
+// the remote workers. The tone mapping and the color grading only change how the radiance is
+// displayed.
+#[derive(Clone, Copy, Debug, new)]
+pub struct CameraView {
+    pub position: Vec3,
+    pub plane_origin: Vec3,
+    pub plane_u: Vec3,
+    pub plane_v: Vec3,
+    pub width: f32,
+    pub height: f32,
+    pub z_near: f32,
+    pub depth_of_field: Option<DepthOfField>,
+}
+
 #[derive(Clone, Debug, new)]
 struct CameraPlane {
     origin: Vec3,
@@ -63,6 +272,8 @@ pub struct Camera {
     z_near: f32,
     tone_mapping: ToneMapping,
     iso: f32,
+    depth_of_field: Option<DepthOfField>, // A pinhole camera without it
+    color_grading: ColorGrading,
 }
 
 impl Camera {
@@ -75,6 +286,8 @@ impl Camera {
             z_near: z_near,
             tone_mapping: tone_mapping,
             iso: iso,
+            depth_of_field: None,
+            color_grading: ColorGrading::default(),
         }
     }
 
@@ -95,34 +308,240 @@ impl Camera {
         CameraPlane::new(origin, u, v)
     }
 
-    #[allow(dead_code)]
+    // Returns the origin and the spanning vectors of the projection plane.
+    pub fn projection_plane(&self) -> (Vec3, Vec3, Vec3) {
+        let ref plane = self.projection_plane;
+        (plane.origin, plane.u, plane.v)
+    }
+
+    pub fn position(&self) -> Vec3 {
+        self.position
+    }
+
+    pub fn view(&self) -> CameraView {
+        let ref plane = self.projection_plane;
+        CameraView::new(self.position, plane.origin, plane.u, plane.v, self.width, self.height, self.z_near, self.depth_of_field)
+    }
+
+    pub fn set_view(&mut self, view: CameraView) {
+        self.projection_plane = CameraPlane::new(view.plane_origin, view.plane_u, view.plane_v);
+        self.position = view.position;
+        self.width = view.width;
+        self.height = view.height;
+        self.z_near = view.z_near;
+        self.depth_of_field = view.depth_of_field;
+    }
+
+    pub fn tone_mapping(&self) -> ToneMapping {
+        self.tone_mapping
+    }
+
+    pub fn set_tone_mapping(&mut self, tone_mapping: ToneMapping) {
+        self.tone_mapping = tone_mapping;
+    }
+
+    pub fn iso(&self) -> f32 {
+        self.iso
+    }
+
+    pub fn color_grading(&self) -> ColorGrading {
+        self.color_grading
+    }
+
+    pub fn set_color_grading(&mut self, color_grading: ColorGrading) {
+        self.color_grading = color_grading;
+    }
+
+    pub fn depth_of_field(&self) -> Option<DepthOfField> {
+        self.depth_of_field
+    }
+
+    pub fn set_depth_of_field(&mut self, depth_of_field: Option<DepthOfField>) {
+        self.depth_of_field = depth_of_field;
+    }
+
+    // Focuses on the distance of the point, e.g. of the surface under the cursor, and returns
+    // the focus distance. Does nothing without depth of field.
+    pub fn focus_on(&mut self, point: Vec3) -> Option<f32> {
+        let focus_distance = (point - self.position).dot(self.forward());
+        match self.depth_of_field {
+            Some(ref mut depth_of_field) if focus_distance > 0.0 => {
+                depth_of_field.focus_distance = focus_distance;
+                Some(focus_distance)
+            },
+            _ => None,
+        }
+    }
+
+    // The vertical field of view in degrees.
+    pub fn vertical_fov(&self) -> f32 {
+        2.0*f32::atan(0.5*self.height / self.z_near)*180.0/PI
+    }
+
+    // Changes the field of view while keeping the aspect ratio of the projection plane.
+    pub fn set_vertical_fov(&mut self, vertical_fov: f32) {
+        let height = 2.0*self.z_near*f32::tan(0.5*vertical_fov*PI/180.0);
+        let factor = height / self.height;
+        let ref mut plane = self.projection_plane;
+        let center = plane.origin + 0.5*plane.u + 0.5*plane.v;
+        plane.u = factor*plane.u;
+        plane.v = factor*plane.v;
+        plane.origin = center - 0.5*plane.u - 0.5*plane.v;
+        self.width *= factor;
+        self.height = height;
+    }
+
+    // Widens or narrows the projection plane around its center, e.g. for a new aspect ratio
+    // of the image.
+    pub fn scale_width(&mut self, factor: f32) {
+        let ref mut plane = self.projection_plane;
+        let center = plane.origin + 0.5*plane.u + 0.5*plane.v;
+        plane.u = factor*plane.u;
+        plane.origin = center - 0.5*plane.u - 0.5*plane.v;
+        self.width *= factor;
+    }
+
     pub fn look_at(&mut self, position: Vec3, target: Vec3, up: Vec3) {
         self.projection_plane = Self::construct_projection_plane(position, target, up, self.width, self.height, self.z_near);
         self.position = position;
     }
 
-    fn sample(&self, backbuffer_width: u32, backbuffer_height: u32, sub_pixel_sampling: bool) -> CameraSampler {
+    // The direction of view, perpendicular to the projection plane.
+    fn forward(&self) -> Vec3 {
+        let ref plane = self.projection_plane;
+        -plane.u.cross(plane.v).normalize()
+    }
+
+    // Whether any part of the sphere is within the pyramid from the position through the
+    // projection plane. The pyramid is not cut off in the distance.
+    pub fn sees_sphere(&self, center: Vec3, radius: f32) -> bool {
+        let ref plane = self.projection_plane;
+        if (center - self.position).dot(self.forward()) < -radius {
+            return false;
+        }
+        let plane_center = plane.origin + 0.5*plane.u + 0.5*plane.v;
+        let sides = [(plane.origin, plane.u), (plane.origin, plane.v), (plane.origin + plane.u, plane.v), (plane.origin + plane.v, plane.u)];
+        sides.iter().all(|&(corner, edge)| {
+            let normal = (corner - self.position).cross(edge).normalize();
+            let inward = if (plane_center - self.position).dot(normal) < 0.0 { -normal } else { normal };
+            (center - self.position).dot(inward) >= -radius
+        })
+    }
+
+    // Moves the camera along its direction of view until the sphere around the bounds fits
+    // into the image and looks at its center. The direction of view and the field of view
+    // stay the same. Empty bounds leave the camera as it is.
+    pub fn auto_frame(&mut self, bounds: &Bounds) {
+        if bounds.is_empty() {
+            return;
+        }
+        let (center, radius) = bounds.bounding_sphere();
+        let half_angle = f32::atan(0.5*f32::min(self.width, self.height) / self.z_near);
+        let distance = radius / f32::sin(half_angle);
+        let forward = self.forward();
+        let up = self.projection_plane.v.normalize();
+        self.look_at(center - distance*forward, center, up);
+    }
+
+    // Rotates the position around the vertical axis through the center by the angle (in
+    // radians) and looks at the center, e.g. for a turntable. Straight above or below the
+    // center, the vertical axis cannot be the up vector. The up vector is then -z rotated by
+    // the angle, so that the image turns with the orbit.
+    pub fn orbit(&mut self, position: Vec3, center: Vec3, angle: f32) {
+        let offset = position - center;
+        let (sin, cos) = (f32::sin(angle), f32::cos(angle));
+        let rotated = Vec3::new(cos*offset.x + sin*offset.z, offset.y, -sin*offset.x + cos*offset.z);
+        let up = if f32::abs(rotated.normalize().y) > 0.999 { Vec3::new(-sin, 0.0, -cos) } else { Vec3::new(0.0, 1.0, 0.0) };
+        self.look_at(center + rotated, center, up);
+    }
+
+    // The ray through a point of the image given in pixels from its lower left corner.
+    pub fn pixel_ray(&self, x: f32, y: f32, image_width: u32, image_height: u32) -> Ray {
+        let ref plane = self.projection_plane;
+        let origin = plane.origin + (x / image_width as f32)*plane.u + (y / image_height as f32)*plane.v;
+        Ray::new(origin, (origin - self.position).normalize())
+    }
+
+    fn sample(&self, backbuffer_width: u32, backbuffer_height: u32, sub_pixel_sampling: bool, stereo: Option<Stereo>) -> CameraSampler {
+        // Side by side, every eye covers the whole projection plane with its half of the
+        // columns. For odd widths the right eye gets the extra column.
+        let left_width = match stereo {
+            Some(Stereo { layout: StereoLayout::SideBySide, .. }) => u32::max(1, backbuffer_width/2),
+            _ => backbuffer_width,
+        };
+        let right_width = u32::max(1, backbuffer_width.saturating_sub(left_width));
         CameraSampler {
             camera: self,
-            u: self.projection_plane.u / backbuffer_width as f32,
+            u: self.projection_plane.u / left_width as f32,
+            right_u: self.projection_plane.u / right_width as f32,
             v: self.projection_plane.v / backbuffer_height as f32,
             sub_pixel_sampling: sub_pixel_sampling,
+            stereo: stereo,
+            left_width: left_width,
         }
     }
 }
 
 struct CameraSampler<'a> {
     camera: &'a Camera,
-    u: Vec3,
+    u: Vec3, // Per column of the image, or of the left eye side by side
+    right_u: Vec3, // Per column of the right eye side by side
     v: Vec3,
     sub_pixel_sampling: bool, 
+    stereo: Option<Stereo>,
+    left_width: u32, // Columns of the left eye side by side, otherwise of the image
 }
 
 // @TODO: Make a trait when more camera types are added. Different camera models can
 //        precompute different things.
 impl<'a> CameraSampler<'a> {
-    #[allow(dead_code)]
-    fn pinhole_ray(&self, x: u32, y: u32) -> Ray {
+    // The primary ray of a pixel and the weight of the radiance along it. Only anaglyphs
+    // weight the radiance, as every sample of them is rendered for one of the eyes, which is
+    // chosen at random, and only contributes to its channels. With a depth of field, the
+    // lens is centered on the camera or on the eye.
+    fn camera_ray(&self, x: u32, y: u32) -> (Ray, Vec3) {
+        let (ray, weight) = match self.stereo {
+            None => (self.pinhole_ray(x, y, self.u), Vec3::one()),
+            Some(stereo) => match stereo.layout {
+                StereoLayout::SideBySide => {
+                    if x < self.left_width {
+                        (self.eye_ray(x, y, self.u, -0.5, &stereo), Vec3::one())
+                    } else {
+                        (self.eye_ray(x - self.left_width, y, self.right_u, 0.5, &stereo), Vec3::one())
+                    }
+                },
+                StereoLayout::Anaglyph => {
+                    if random32() < 0.5 {
+                        (self.eye_ray(x, y, self.u, -0.5, &stereo), Vec3::new(2.0, 0.0, 0.0))
+                    } else {
+                        (self.eye_ray(x, y, self.u, 0.5, &stereo), Vec3::new(0.0, 2.0, 2.0))
+                    }
+                },
+            },
+        };
+        match self.camera.depth_of_field {
+            Some(ref depth_of_field) => (self.thin_lens_ray(ray, depth_of_field), weight),
+            None => (ray, weight),
+        }
+    }
+
+    // The ray of an eye that is shifted by the given fraction of the interocular distance.
+    // It passes through the point on the convergence plane that is seen through the pixel
+    // from the camera, and starts on the projection plane like the rays of the camera.
+    fn eye_ray(&self, x: u32, y: u32, u: Vec3, shift: f32, stereo: &Stereo) -> Ray {
+        let ref plane = self.camera.projection_plane;
+        let right = plane.u.normalize();
+        let forward = -plane.u.cross(plane.v).normalize();
+
+        let center_ray = self.pinhole_ray(x, y, u);
+        let on_convergence_plane = self.camera.position + (stereo.convergence_distance / center_ray.direction.dot(forward))*center_ray.direction;
+        let eye = self.camera.position + (shift*stereo.interocular_distance)*right;
+        let direction = (on_convergence_plane - eye).normalize();
+        Ray::new(eye + (self.camera.z_near / direction.dot(forward))*direction, direction)
+    }
+
+    // The ray through the pixel, with u the step of the projection plane per column.
+    fn pinhole_ray(&self, x: u32, y: u32, u: Vec3) -> Ray {
         let origin = {
             let mut offset_u = x as f32;
             let mut offset_v = y as f32;
@@ -132,7 +551,7 @@ impl<'a> CameraSampler<'a> {
                 offset_v += random32();
             }
 
-            let du = offset_u*self.u;
+            let du = offset_u*u;
             let dv = offset_v*self.v;
 
             self.camera.projection_plane.origin + du + dv
@@ -141,65 +560,39 @@ impl<'a> CameraSampler<'a> {
         Ray::new(origin, direction)
     }
 
-    #[allow(dead_code)]
-    fn thin_lens_ray(&self, x: u32, y: u32) -> Ray {
-        let Ray{ ref origin, ref direction } = self.pinhole_ray(x, y);
-
-        // In this thin-lens model a focus distance and an aperture is defined. To 
-        // determine the ray with which to sample, the point on the focus plane is
-        // computed which would be hit by the ideal ray through the pixel. Then the
-        // circle of confusion is computed which would be produced by the apterture.
-        // A point within the circle of confusion is determined randomly as the new
-        // origin point of the ray. The direction is computed by the difference of
-        // the point of the focus plane and the point within the circle of confusion
-        // on the projection plane.
-
-        const FOCUS_DISTANCE: f32 = 10.0;
-
-        // @TODO: This results in a spherical focus "plane" which is not really ideal
-        //        Use the unnormalized direction here dividing by the distance to the
-        //        projection plane to get the distance right.
-        let point_on_focus_plane = origin + FOCUS_DISTANCE*direction;
-
-        // To sample a circle correctly (with a normal distribution), the angle and
-        // radius have to be computed as follows:
-        //
-        //   alpha = 2*PI*R*sqrt(r1)
-        //   r = R*sqrt(r2)
-        //
-        // where 
-        //   R = maximum radius (circle of confusion in this case)
-        //   r1, r2 = random normally distributed numbers in the interval [0, 1]
-        //
-        // This however is not really what we want in this case as the probability
-        // of a ray hitting the sensor at a given position is not normally distributed
-        // within the circle of confusion.
-
-        const LENS_APERTURE: f32 = 4.0;
-
-        // The factor 22 means that an apterture of 22 is perfectly sharp and that 
-        // lower apertures > 0 produce larger confusion circles.
-        let circle_of_confusion = 22.0/LENS_APERTURE;
-
-        let r1 = random32();
-        let r2 = random32();
-
-        let alpha = 2.0*PI*r1;
-        let r = circle_of_confusion*r2;
-
-        let l_u = r*f32::sin(alpha);
-        let l_v = r*f32::cos(alpha);
-
-        let lens_origin = origin + l_u*self.u + l_v*self.v;
+    // In this thin-lens model the ray through the pixel is started from a random point of
+    // the lens around its origin and aimed at the point that the ray through the pixel hits
+    // on the focus plane. Points on the focus plane are therefore sharp and everything else is
+    // blurred by the size of the lens. The ray has to start at the distance z_near in front
+    // of the camera or the eye.
+    fn thin_lens_ray(&self, ray: Ray, depth_of_field: &DepthOfField) -> Ray {
+        let Ray{ origin, direction } = ray;
+        let ref plane = self.camera.projection_plane;
+        let forward = -plane.u.cross(plane.v).normalize();
+
+        // The distance along the ray is divided by the cosine to the direction of view, so
+        // that the focus plane is flat instead of a sphere around the camera.
+        let distance_along_ray = (depth_of_field.focus_distance - self.camera.z_near) / direction.dot(forward);
+        let point_on_focus_plane = origin + distance_along_ray*direction;
+
+        // For uniformly distributed points on the lens, the radius is the square root of a
+        // uniformly distributed number.
+        let alpha = 2.0*PI*random32();
+        let r = depth_of_field.aperture*f32::sqrt(random32());
+        let lens_origin = origin + (r*f32::cos(alpha))*plane.u.normalize() + (r*f32::sin(alpha))*plane.v.normalize();
         let lens_direction = (point_on_focus_plane - lens_origin).normalize();
 
         Ray::new(lens_origin, lens_direction)
     }
 }
 
+// Matte IDs (see ObjectId::matte_id) with their summed coverage. Unused ranks have the ID zero.
+pub const MATTE_RANKS: usize = 6;
+pub type Matte = [(u32, f32); MATTE_RANKS];
+
 pub struct Backbuffer {
-    pub width: u32,
-    pub height: u32,
+    // Width and height. The size only changes between frames (see resize).
+    size: UnsafeCell<(u32, u32)>,
     // The worker threads are all processing distinct tiles within the Vec which enables
     // synchronization-free writes. At the same time the main thread should not have to
     // wait on mutexes to copy the data from the backbuffer to the window as this would
@@ -211,8 +604,34 @@ pub struct Backbuffer {
     // workers that process the respective tile. Consider that one worker needs multiple
     // references to the individual lines of the tile!
     pub num_samples: UnsafeCell<usize>,
-    pub pixels32: UnsafeCell<Vec<Pixel32>>,
+    // Linear radiance, summed over all samples. The displayed image in pixels8 is tone
+    // mapped from its average whenever a sample is added, so that the tone mapping is
+    // applied to the converged radiance instead of to every single sample.
+    pub radiance: UnsafeCell<Vec<Vec3>>,
+    // Squared luminance of the samples, summed like the radiance, for estimating the noise
+    // that remains in the pixel (see relative_error).
+    pub luminance_squares: UnsafeCell<Vec<f32>>,
     pub pixels8: UnsafeCell<Vec<Pixel8>>,
+    // Albedo, normal and distance at the first hit, summed over all samples like the
+    // radiance. They are the auxiliary features for the denoisers.
+    pub albedo: UnsafeCell<Vec<Vec3>>,
+    pub normals: UnsafeCell<Vec<Vec3>>,
+    pub depths: UnsafeCell<Vec<f32>>,
+    // Coverage of the pixel by the geometry of the scene, summed over all samples. Divided
+    // by the number of samples, it is the alpha of the pixel.
+    pub alphas: UnsafeCell<Vec<f32>>,
+    // The objects that were hit by the primary rays of the pixel with the number of samples
+    // that hit them, sorted from the most to the least. They are the mattes for selecting
+    // objects in compositing. Objects beyond MATTE_RANKS are dropped.
+    pub mattes: UnsafeCell<Vec<Matte>>,
+    // The values of the debug views are displayed as they are, without tone mapping.
+    debug_view: UnsafeCell<bool>,
+    // Number of NaN, infinite or negative samples of every pixel since its first sample. The
+    // samples themselves are dropped and the radiance is averaged over the other samples of
+    // the pixel (see mean_radiance). With check_radiance, the pixels are displayed magenta.
+    invalid: UnsafeCell<Vec<u32>>,
+    check_radiance: UnsafeCell<bool>,
+    num_invalid_samples: AtomicUsize,
 }
 // UnsafeCell does not implement Sync and therefore Backbuffer could not be passed to the
 // worker threads without this implementation.
@@ -222,252 +641,263 @@ impl Backbuffer {
     pub fn new(width: u32, height: u32) -> Backbuffer {
         let num_pixels = (width * height) as usize;
         Backbuffer {
-            width: width,
-            height: height,
+            size: UnsafeCell::new((width, height)),
             num_samples: UnsafeCell::new(1),
-            pixels32: {
-                let mut pixels = Vec::new();
-                pixels.resize(num_pixels, Pixel32(0, 0, 0));
-                UnsafeCell::new(pixels)
-            },
+            radiance: UnsafeCell::new(vec![Vec3::zero(); num_pixels]),
+            luminance_squares: UnsafeCell::new(vec![0.0; num_pixels]),
             pixels8: {
                 let mut pixels = Vec::new();
                 pixels.resize(num_pixels, Pixel8(0, 0, 0));
                 UnsafeCell::new(pixels)
             },
-
+            albedo: UnsafeCell::new(vec![Vec3::zero(); num_pixels]),
+            normals: UnsafeCell::new(vec![Vec3::zero(); num_pixels]),
+            depths: UnsafeCell::new(vec![0.0; num_pixels]),
+            alphas: UnsafeCell::new(vec![0.0; num_pixels]),
+            mattes: UnsafeCell::new(vec![[(0, 0.0); MATTE_RANKS]; num_pixels]),
+            debug_view: UnsafeCell::new(false),
+            invalid: UnsafeCell::new(vec![0; num_pixels]),
+            check_radiance: UnsafeCell::new(false),
+            num_invalid_samples: AtomicUsize::new(0),
         }
     }
 
-    pub fn clear(&self) {
-        unsafe {
-            let ref mut pixels32 = *self.pixels32.get();
-            for i in 0..pixels32.len() {
-                pixels32[i] = Pixel32(0, 0, 0);
-            }
+    pub fn width(&self) -> u32 {
+        unsafe { (*self.size.get()).0 }
+    }
 
-            let ref mut pixels8 = *self.pixels8.get();
-            for i in 0..pixels8.len() {
-                pixels8[i] = Pixel8(0, 0, 0);
-            }
+    pub fn height(&self) -> u32 {
+        unsafe { (*self.size.get()).1 }
+    }
 
+    // Reallocates all buffers for the new size, which clears them. Must only be called while
+    // no worker thread is rendering.
+    pub fn resize(&self, width: u32, height: u32) {
+        let num_pixels = (width * height) as usize;
+        unsafe {
+            *self.size.get() = (width, height);
+            *self.radiance.get() = vec![Vec3::zero(); num_pixels];
+            *self.luminance_squares.get() = vec![0.0; num_pixels];
+            *self.pixels8.get() = vec![Pixel8(0, 0, 0); num_pixels];
+            *self.albedo.get() = vec![Vec3::zero(); num_pixels];
+            *self.normals.get() = vec![Vec3::zero(); num_pixels];
+            *self.depths.get() = vec![0.0; num_pixels];
+            *self.alphas.get() = vec![0.0; num_pixels];
+            *self.mattes.get() = vec![[(0, 0.0); MATTE_RANKS]; num_pixels];
+            *self.invalid.get() = vec![0; num_pixels];
             *self.num_samples.get() = 1;
         }
     }
 
-    fn add_pixel32_unsafe(&self, x: u32, y: u32, pixel: Pixel32) {
-        let index = (y*self.width + x) as usize;
+    // Must only be called while no worker thread is rendering.
+    pub fn set_debug_view(&self, debug_view: bool) {
         unsafe {
-            let old_pixel = (*self.pixels32.get())[index].clone();
-            let new_pixel = Pixel32(
-                old_pixel.0 + pixel.0,
-                old_pixel.1 + pixel.1,
-                old_pixel.2 + pixel.2,
-            );
-            (*self.pixels32.get())[index] = new_pixel;
+            *self.debug_view.get() = debug_view;
         }
     }
 
-    fn assign_pixel8_unsafe(&self, x: u32, y: u32, iso_factor: f32) {
-        let index = (y*self.width + x) as usize;
+    // Must only be called while no worker thread is rendering.
+    pub fn set_check_radiance(&self, check_radiance: bool) {
         unsafe {
-            let num_samples = *self.num_samples.get() as u32;
-            let ref pixel32 = (*self.pixels32.get())[index];
-            (*self.pixels8.get())[index] = Pixel8(
-                f32::min(255.0, iso_factor*pixel32.0 as f32 / num_samples as f32) as u8,
-                f32::min(255.0, iso_factor*pixel32.1 as f32 / num_samples as f32) as u8,
-                f32::min(255.0, iso_factor*pixel32.2 as f32 / num_samples as f32) as u8,
-            );
+            *self.check_radiance.get() = check_radiance;
         }
     }
-}
-
-fn reflect(incoming: Vec3, normal: Vec3) -> Vec3 {
-    // Bear in mind that incoming is directed at the surface!
-    incoming - 2.0*incoming.dot(normal)*normal
-}
-
-fn refract(incoming: Vec3, normal: Vec3, n1: f32, n2: f32) -> Vec3 {
-    // Bear in mind that incoming is directed at the surface!
 
-    let v = -incoming;
-    let n = normal;
+    // Number of invalid samples since the last call. They are counted whether or not the
+    // pixels are highlighted.
+    pub fn take_invalid_samples(&self) -> usize {
+        self.num_invalid_samples.swap(0, Ordering::Relaxed)
+    }
 
-    // Pythagorean trigonometric identity: sin^2(a) + cos^2(a) = 1
-    // Since cos(a) is easy to compute, sin(a) can be computed by
-    // sin(a) = sqrt(1 - cos^2(a))
-    let cos_theta1 = v.dot(n);
+    // Converts the average radiance of a pixel into the displayed color in [0, 1].
+    pub fn display_color(&self, radiance: Vec3, camera: &Camera) -> Vec3 {
+        if unsafe { *self.debug_view.get() } {
+            saturate_vec3(radiance)
+        } else {
+            tone_map(radiance, camera)
+        }
+    }
 
-    // @TODO: Test whether this is really faster.
-    // @TODO: Implement a faster but worse sqrt.
-    // Look at this article: https://www.codeproject.com/Articles/69941/Best-Square-Root-Method-Algorithm-Function-Precisi
-    let sin_theta1 = f32::sqrt(1.0 - cos_theta1*cos_theta1);
+    // Writes the tone mapped radiance of an image with the size of the backbuffer into the
+    // pixels, like the backbuffer does with its own radiance.
+    pub fn tone_map_image(&self, image: &Vec<Vec3>, camera: &Camera, pixels: &mut Vec<Pixel8>) {
+        pixels.clear();
+        for (index, radiance) in image.iter().enumerate() {
+            let (x, y) = (index as u32 % self.width(), index as u32 / self.width());
+            pixels.push(Pixel8::from_unit_dithered(self.display_color(*radiance, camera), x, y));
+        }
+    }
 
-    // Snell's law: sin(theta_1) / sin(theta_2) = n_2 / n_1
-    // where theta_1: angle between the incoming ray and normal
-    //       theta_2: angle between the outgoing ray and -normal
-    //       n1: index of refraction for the medium above the surface
-    //       n2: index of refraction for the medium below the surface
-    let sin_theta2 = (sin_theta1*n1)/n2;
-    let cos_theta2 = f32::sqrt(1.0 - sin_theta2*sin_theta2);
-    
-    // Corresponds to the projection of 'incoming' onto the ground plane.
-    let p = incoming + cos_theta1*normal;
-    
-    -normal*cos_theta2 + p.normalize()*sin_theta2
-}
+    pub fn clear(&self) {
+        unsafe {
+            let ref mut pixels8 = *self.pixels8.get();
+            for i in 0..pixels8.len() {
+                pixels8[i] = Pixel8(0, 0, 0);
+            }
 
-fn construct_coordinate_system(normal: Vec3) -> Basis {
-    const EPS: f32 = 0.9999;
-    let other = if normal.y > EPS || normal.y < -EPS {
-        Vec3::new(1.0, 0.0, 0.0)
-    } else {
-        Vec3::new(0.0, 1.0, 0.0)
-    };
-    let y = normal;
-    let x = y.cross(other);
-    let z = y.cross(x);
-    Basis(x, y, z)
-}
+            for albedo in (*self.albedo.get()).iter_mut() {
+                *albedo = Vec3::zero();
+            }
+            for normal in (*self.normals.get()).iter_mut() {
+                *normal = Vec3::zero();
+            }
+            for depth in (*self.depths.get()).iter_mut() {
+                *depth = 0.0;
+            }
+            for alpha in (*self.alphas.get()).iter_mut() {
+                *alpha = 0.0;
+            }
+            for matte in (*self.mattes.get()).iter_mut() {
+                *matte = [(0, 0.0); MATTE_RANKS];
+            }
+            for radiance in (*self.radiance.get()).iter_mut() {
+                *radiance = Vec3::zero();
+            }
+            for luminance_square in (*self.luminance_squares.get()).iter_mut() {
+                *luminance_square = 0.0;
+            }
+            for invalid in (*self.invalid.get()).iter_mut() {
+                *invalid = 0;
+            }
 
-#[allow(dead_code)]
-fn brdf_lambert(pbr_parameters: &PBRParameters) -> Vec3 {
-    let &PBRParameters{ ref reflectivity, .. } = pbr_parameters;
-    reflectivity/PI
-}
+            *self.num_samples.get() = 1;
+        }
+    }
 
-#[allow(dead_code)]
-fn normal_distribution_ggx(normal: Vec3, half: Vec3, alpha: f32) -> f32 {
-    // The normal distribution function computes how much the microfacets of the surface
-    // contribute to a reflection whose half vector is 'half'.
-    
-    // More in-depth explanation: The microfacet theory assumes that the relevant reflection 
-    // characteristics of every surface can be modelled by an in infinite amount of
-    // infinitesimal small mirrors where the orientation and visibility of these mirrors are 
-    // only defined statistically. The normal distribution function represents the statistical
-    // orientation of the microfacets in the sense that it returns the probability of a
-    // microfacet being oriented in the 'half' direction. Alternatively this can be seen as
-    // determining the surface area of the microfacets being oriented in the half direction
-    // relativ to the surface area of the macro surface. Therefore, when the intensity of
-    // a reflection from direction d_1 to direction d_2 has to be determined, the half vector
-    // between both directions can be determined and the normal distribution function returns
-    // the strength of the reflection as those microfacets oriented in the 'half' direction
-    // contribute to exactly that reflection.
-
-    let n = normal; // normal of the surface
-    let h = half; // half vector (normal of microfacets that contribute to the reflection)
-    let a = alpha; // alpha value that is based on the roughness of the surface (e.g. roughness^2)
-
-    let a2 = a*a;
-    let n_dot_h = f32::max(n.dot(h), 0.0);
-    let n_dot_h2 = n_dot_h*n_dot_h;
-
-    let d = n_dot_h2 * (a2 - 1.0) + 1.0;
-	
-    a2 / (PI*d*d)
-}
-
-fn geometry_smith(normal: Vec3, view: Vec3, light: Vec3, k: f32) -> f32 {
-    // This function computes the probability of light being blocked either by 
-    //   1. the microfacet being shadowed by another microfacet when the light is incoming or
-    //   2. the microfacet being masked by another microfacet when the light is outgoing.
-    // Another way to lock at is would be: This function reduces the amount of reflected energy
-    // by considering the proportional surface area of microfacets that is shadowed or masked
-    // by other microfacets relativ to the surface area of the macro surface.
-
-    let n = normal; // normal of the surface
-    let v = view; // direction to the viewer
-    let l = light; // direction to the light source
-
-    let geometry_schlick_ggx = |a: Vec3| -> f32 {
-        let n_dot_a = f32::max(0.0, n.dot(a));
-        let nom = n_dot_a;
-        let denom = n_dot_a * (1.0 - k) + k;
-        nom / denom
-    };
-    geometry_schlick_ggx(l)*geometry_schlick_ggx(v)
-}
+    fn add_radiance_unsafe(&self, x: u32, y: u32, radiance: Vec3, replace: bool) {
+        let index = (y*self.width() + x) as usize;
+        unsafe {
+            if replace {
+                (*self.invalid.get())[index] = 0;
+            }
+            let ref mut radiance_sum = (*self.radiance.get())[index];
+            *radiance_sum = if replace { radiance } else { *radiance_sum + radiance };
+            let ref mut luminance_square_sum = (*self.luminance_squares.get())[index];
+            let luminance_square = luminance(radiance)*luminance(radiance);
+            *luminance_square_sum = if replace { luminance_square } else { *luminance_square_sum + luminance_square };
+        }
+    }
 
-fn fresnel_schlick(cos_theta: f32, f0: Vec3) -> Vec3 {
-    f0 + (Vec3::one() - f0)*f32::powf(1.0 - cos_theta, 5.0)
-}
+    // The number of samples of the pixel that were not dropped, out of the number of samples
+    // that were rendered for the whole image.
+    pub fn valid_samples(&self, index: usize, num_samples: usize) -> usize {
+        num_samples.saturating_sub(unsafe { (*self.invalid.get())[index] } as usize)
+    }
 
-fn brdf_cook_torrance(view: Vec3, light: Vec3, normal: Vec3, roughness: f32, f0: Vec3) -> Vec3 {
-    // Bear in mind that 'view' as well as 'light' are pointing away from the surface!
+    // The average radiance of the pixel over its valid samples. Black without any.
+    pub fn mean_radiance(&self, index: usize, num_samples: usize) -> Vec3 {
+        let radiance = unsafe { (*self.radiance.get())[index] };
+        radiance/usize::max(self.valid_samples(index, num_samples), 1) as f32
+    }
 
-    let v = view;
-    let l = light;
-    let n = normal;
+    // The standard error of the mean luminance of the pixel relative to the mean, so that the
+    // noise in dark and bright pixels is comparable. It is unknown and therefore infinite for
+    // a single sample. The number of samples is passed like for the exports, as the window
+    // already counts the frame that is being rendered.
+    pub fn relative_error(&self, x: u32, y: u32, num_samples: usize) -> f32 {
+        let index = (y*self.width() + x) as usize;
+        unsafe {
+            let num_samples = self.valid_samples(index, num_samples) as f32;
+            if num_samples < 2.0 {
+                return std::f32::INFINITY;
+            }
+            let mean = luminance((*self.radiance.get())[index])/num_samples;
+            let mean_square = (*self.luminance_squares.get())[index]/num_samples;
+            let variance = f32::max(mean_square - mean*mean, 0.0)*num_samples/(num_samples - 1.0);
+            f32::sqrt(variance/num_samples)/f32::max(mean, 0.0001)
+        }
+    }
 
-    let alpha = roughness*roughness;
-    let h = (v + l).normalize();
-    let cos_theta = f32::max(0.0, h.dot(v));
+    fn add_features_unsafe(&self, x: u32, y: u32, features: &Features, replace: bool) {
+        let index = (y*self.width() + x) as usize;
+        unsafe {
+            let ref mut albedo_sum = (*self.albedo.get())[index];
+            let ref mut normal_sum = (*self.normals.get())[index];
+            let ref mut depth_sum = (*self.depths.get())[index];
+            let ref mut alpha_sum = (*self.alphas.get())[index];
+            let ref mut matte = (*self.mattes.get())[index];
+            if replace {
+                *albedo_sum = features.albedo;
+                *normal_sum = features.normal;
+                *depth_sum = features.depth;
+                *alpha_sum = features.alpha;
+                *matte = [(0, 0.0); MATTE_RANKS];
+            } else {
+                *albedo_sum = *albedo_sum + features.albedo;
+                *normal_sum = *normal_sum + features.normal;
+                *depth_sum = *depth_sum + features.depth;
+                *alpha_sum = *alpha_sum + features.alpha;
+            }
+            add_to_matte(matte, features.matte_id);
+        }
+    }
 
-    // @TODO: There are multiple definitions for this. What's the best?
-    // let k = (alpha + 1.0)*(alpha + 1.0) / 8.0;
-    let k = alpha / 2.0; // Was/is used in the Unreal Engine 4 according to Brian Karis' blog.
+    fn mark_invalid_unsafe(&self, x: u32, y: u32) {
+        let index = (y*self.width() + x) as usize;
+        unsafe {
+            (*self.invalid.get())[index] += 1;
+        }
+        self.num_invalid_samples.fetch_add(1, Ordering::Relaxed);
+    }
 
-    // The normal distribution function is canceled out as it functions as the probability 
-    // density function for the monte carlo integration.
-    let num = fresnel_schlick(cos_theta, f0)*geometry_smith(n, v, l, k);
-    let denum = 4.0*f32::max(0.0, n.dot(v));
-    (num / denum)
+    fn assign_pixel8_unsafe(&self, x: u32, y: u32, camera: &Camera, num_samples: usize) {
+        let index = (y*self.width() + x) as usize;
+        unsafe {
+            if *self.check_radiance.get() && (*self.invalid.get())[index] > 0 {
+                (*self.pixels8.get())[index] = Pixel8(255, 0, 255);
+                return;
+            }
+            let radiance = self.mean_radiance(index, num_samples);
+            (*self.pixels8.get())[index] = Pixel8::from_unit_dithered(self.display_color(radiance, camera), x, y);
+        }
+    }
 }
 
-// @TODO: Ensure that the reflection direction is not below the horizon.
-#[allow(dead_code)]
-fn importance_sample_ggx(xi: Vec2, roughness: f32) -> Vec3 {
-    let a = roughness*roughness;
-
-    let phi = 2.0*PI*xi.x;
-    let sin_phi = f32::sin(phi);
-    let cos_phi = f32::cos(phi);
-
-    let cos_theta = f32::sqrt((1.0 - xi.y) / (xi.y*(a*a - 1.0) + 1.0));
-    let sin_theta = f32::sqrt(1.0 - cos_theta*cos_theta);
-
-    Vec3::new(sin_theta*cos_phi, cos_theta, sin_theta*sin_phi)
+// The ranks stay sorted by moving the object up past those with less coverage. As the used
+// ranks come first, the object is either found before the first unused rank or takes it.
+fn add_to_matte(matte: &mut Matte, matte_id: u32) {
+    if matte_id == 0 {
+        return;
+    }
+    let mut rank = match matte.iter().position(|&(id, _)| id == matte_id || id == 0) {
+        Some(rank) => rank,
+        None => return,
+    };
+    matte[rank] = (matte_id, matte[rank].1 + 1.0);
+    while rank > 0 && matte[rank].1 > matte[rank - 1].1 {
+        matte.swap(rank, rank - 1);
+        rank -= 1;
+    }
 }
 
 #[allow(dead_code)]
-fn importance_sample_cos(xi: Vec2) -> Vec3 {
-    let r = f32::sqrt(xi.x);
-    let theta = 2.0*PI*xi.y;
- 
-    let x = r * f32::cos(theta);
-    let z = r * f32::sin(theta);
- 
-    Vec3::new(x, f32::sqrt(f32::max(0.0, 1.0 - xi.x)), z)
+fn brdf_lambert(pbr_parameters: &PBRParameters) -> Vec3 {
+    let &PBRParameters{ ref reflectivity, .. } = pbr_parameters;
+    reflectivity/PI
 }
 
-pub fn to_basis(basis: Basis, v: Vec3) -> Vec3 {
-    let Basis(x, y, z) = basis;
-    x*v.x + y*v.y + z*v.z
+fn trace_radiance(meta: &Meta, ray: &Ray, scene: &Scene, depth: u8) -> Vec3 {
+    if !meta.verbose {
+        return trace_radiance_step(meta, ray, scene, depth);
+    }
+    println!("{:indent$}depth {}: origin = {:?}, direction = {:?}", "", depth, ray.origin, ray.direction, indent = verbose_indent(depth));
+    let radiance = trace_radiance_step(meta, ray, scene, depth);
+    let marker = if is_valid_radiance(radiance) { "" } else { " <- INVALID" };
+    println!("{:indent$}depth {}: radiance = {:?}{}", "", depth, radiance, marker, indent = verbose_indent(depth));
+    radiance
 }
 
-fn trace_radiance(meta: &Meta, ray: &Ray, scene: &Scene, depth: u8) -> Vec3 {
+fn trace_radiance_step(meta: &Meta, ray: &Ray, scene: &Scene, depth: u8) -> Vec3 {
     // @TODO: Find all the places where NANs can be generated and fix as many as it makes sense.
 
     if depth <= 0 {
         // @TODO: This is really wrong but works for now.
-        let num_light_sources = scene.emissive_spheres.len() + scene.emissive_planes.len();        
-        let r = xorshift32() as usize % num_light_sources;
-
-        let (sample_point, light_radiance) = if r < scene.emissive_spheres.len() {
-            let ref light = scene.emissive_spheres[r];
-            let radiance = match light.material {
-                Material::Emissive(radiance) => radiance,
-                _ => panic!("Non-emissive material in emissive material Vec."),
-            };
-            (light.sample(ray.origin), radiance)
-        } else {
-            let ref light = scene.emissive_planes[r];
-            let radiance = match light.material {
-                Material::Emissive(radiance) => radiance,
-                _ => panic!("Non-emissive material in emissive material Vec."),
-            };
-            (light.sample(ray.origin), radiance)
+        let num_light_sources = scene.num_lights();
+        let light = match scene.light(xorshift32() as usize % usize::max(1, num_light_sources)) {
+            Some(light) => light,
+            None => return Vec3::zero(),
         };
+        let (sample_point, light_radiance) = (light.sample(ray.origin), emitted_radiance(light.material()));
 
         let sample_point = if let Some(point) = sample_point {
             point
@@ -479,71 +909,290 @@ fn trace_radiance(meta: &Meta, ray: &Ray, scene: &Scene, depth: u8) -> Vec3 {
         let light_distance = light_ray.length();
 
         let ray = Ray::new(ray.origin, light_ray.normalize());
-        return if let Some(nearest_hit) = find_scene_hit(&ray, scene) { // @TODO: Make a function for finding any hit.
-            // @TODO: This should not be done by distance but by object id
-            if nearest_hit.parameter > light_distance - 0.000001 {
-                light_radiance
-            } else {
-                Vec3::zero()
+        // @TODO: Make a function for finding any hit.
+        let is_visible = is_light_sample_unblocked(find_visible_hit(&ray, scene, RayKind::Shadow), light, light_distance - 0.000001);
+        if meta.recording {
+            record_segment(ray.origin, sample_point, SegmentKind::Shadow(is_visible), depth);
+        }
+        return if is_visible {
+            let volume_transmittance = volume::transmittance(&ray, &scene.volumes, light_distance);
+            match scene.medium {
+                Some(ref medium) => volume_transmittance*medium.transmittance(light_distance)*light_radiance,
+                None => volume_transmittance*light_radiance,
             }
         } else {
             Vec3::zero()
         }
     }
 
-    if let Some(nearest_hit) = find_scene_hit(ray, scene) {
-        const SHIFT_AMOUNT: f32 = 0.0001; // @TODO: Find a good factor and maybe make it dependent on the slope
-        let outwards_shifted_position = ||{ nearest_hit.position + SHIFT_AMOUNT*nearest_hit.normal };
-        let inwards_shifted_position  = ||{ nearest_hit.position - SHIFT_AMOUNT*nearest_hit.normal };
+    let kind = if meta.bounced { RayKind::Bounce } else { RayKind::Camera };
+    let nearest_hit = find_visible_hit(ray, scene, kind);
+    let hit_distance = nearest_hit.as_ref().map(|hit| hit.parameter).unwrap_or(std::f32::INFINITY);
+    // All the rays that continue the path are bounces, including the scattered ones.
+    let meta = &Meta { bounced: true, ..meta.clone() };
+    if meta.verbose {
+        match nearest_hit {
+            Some(ref hit) => println!("{:indent$}hit at {} ({:?}), position = {:?}, normal = {:?}, material = {:?}",
+                "", hit.parameter, hit.transition, hit.position, hit.normal, hit.material, indent = verbose_indent(depth)),
+            None => println!("{:indent$}sky", "", indent = verbose_indent(depth)),
+        }
+    }
 
-        const R: f32 = 0.04;
+    // The heterogeneous volumes are sampled with delta tracking. When a collision is
+    // found in front of the nearest surface, the path continues from within the volume.
+    let volume_collision = volume::find_volume_collision(ray, &scene.volumes, hit_distance);
+    let interaction_distance = volume_collision.as_ref().map(|collision| collision.distance).unwrap_or(hit_distance);
+    if meta.recording {
+        if interaction_distance.is_finite() {
+            record_segment(ray.origin, ray.origin + interaction_distance*ray.direction, SegmentKind::Path, depth);
+        } else {
+            record_segment(ray.origin, ray.origin + ray.direction, SegmentKind::Sky, depth);
+        }
+    }
 
-        match nearest_hit.material {
-            Material::None => Vec3::one(),
-            Material::Emissive(ref color) => {
-                color.clone()
-            },
-            Material::Mirror => {
-                let reflection_direction = reflect(ray.direction, nearest_hit.normal);
-                let reflection_ray = Ray::new(outwards_shifted_position(), reflection_direction.normalize());
-                trace_radiance(meta, &reflection_ray, scene, depth - 1)
+    // When the scene is filled with a medium, the ray might scatter before it reaches
+    // the nearest interaction. Otherwise the radiance from the interaction is attenuated
+    // on its way through the medium.
+    let weight = if let Some(ref medium) = scene.medium {
+        match sample_medium(medium, ray, interaction_distance) {
+            MediumInteraction::Scattering(position, weight) => {
+                let xi = Vec2::new(random32(), random32());
+                let scattered_direction = sample_henyey_greenstein(ray.direction, medium.anisotropy, xi);
+                let scattered_ray = Ray::new(position, scattered_direction);
+                return weight*trace_radiance(meta, &scattered_ray, scene, depth - 1);
             },
-            Material::Translucent(ior) => {
-                // Refraction
-                const IOR_AIR: f32 = 1.0;
-                let (n1, n2) = match nearest_hit.transition {
-                    Transition::In  => (IOR_AIR, *ior),
-                    Transition::Out => (*ior, IOR_AIR),
-                };
+            MediumInteraction::Surface(weight) => weight,
+        }
+    } else {
+        Vec3::one()
+    };
 
-                let refraction_direction = refract(ray.direction, nearest_hit.normal, n1, n2);
-                let refraction_ray = Ray::new(inwards_shifted_position(), refraction_direction.normalize());
-                let l_refr = trace_radiance(meta, &refraction_ray, scene, depth - 1);
+    if let Some(collision) = volume_collision {
+        let xi = Vec2::new(random32(), random32());
+        let scattered_direction = sample_henyey_greenstein(ray.direction, collision.volume.anisotropy, xi);
+        let scattered_ray = Ray::new(collision.position, scattered_direction);
+        return weight*collision.volume.albedo*trace_radiance(meta, &scattered_ray, scene, depth - 1);
+    }
 
-                // Reflection
-                let reflection_direction = reflect(ray.direction, nearest_hit.normal);
-                let reflection_ray = Ray::new(outwards_shifted_position(), reflection_direction.normalize());
-                let l_refl = trace_radiance(meta, &reflection_ray, scene, depth - 1);
+    weight*shade_surface(meta, ray, nearest_hit, scene, depth)
+}
 
-                // Fresnel
-                let cos_theta = f32::max(0.0, nearest_hit.normal.dot(-ray.direction));
-                let fresnel = fresnel_schlick(cos_theta, Vec3::new(R, R, R));
+enum MediumInteraction {
+    Scattering(Vec3, Vec3), // position and throughput weight
+    Surface(Vec3), // throughput weight
+}
 
-                let k_refl = fresnel;
-                let k_refr = Vec3::one() - fresnel;
+fn sample_medium(medium: &Medium, ray: &Ray, hit_distance: f32) -> MediumInteraction {
+    // The free-flight distance is sampled for one randomly chosen color channel. For the
+    // estimator to stay unbiased in all channels, the pdf is the average of the pdfs of
+    // the individual channels (single-sample MIS over the channels).
+    let sigma_t = medium.sigma_t();
+    let channel = (xorshift32() % 3) as usize;
+    let distance = -f32::ln(1.0 - random32()) / sigma_t.as_array()[channel];
+
+    if distance < hit_distance {
+        let transmittance = medium.transmittance(distance);
+        let density = sigma_t*transmittance;
+        let pdf = (density.x + density.y + density.z) / 3.0;
+        if pdf <= 0.0 {
+            return MediumInteraction::Surface(Vec3::zero());
+        }
+        let position = ray.origin + distance*ray.direction;
+        MediumInteraction::Scattering(position, medium.sigma_s*transmittance / pdf)
+    } else {
+        let transmittance = medium.transmittance(hit_distance);
+        let pdf = (transmittance.x + transmittance.y + transmittance.z) / 3.0;
+        if pdf <= 0.0 {
+            return MediumInteraction::Surface(Vec3::zero());
+        }
+        MediumInteraction::Surface(transmittance / pdf)
+    }
+}
 
-                k_refl*l_refl + k_refr*l_refr
-            },
-            Material::Physically(ref pbr_parameters) => {
-                let tangent_space = construct_coordinate_system(nearest_hit.normal);
+// A path that is refracted by a dispersive material continues in a single color channel, as
+// the directions of the channels differ. The channel is chosen at random and weighted by three.
+// It is kept for the rest of the path so that the path leaves the material with the index of
+// refraction it entered with.
+fn dispersion_channel(channel: Option<usize>, dispersion: f32) -> (Option<usize>, Vec3) {
+    match channel {
+        Some(channel) => (Some(channel), Vec3::one()),
+        None if dispersion > 0.0 => {
+            let channel = (xorshift32() % 3) as usize;
+            let mut weight = [0.0; 3];
+            weight[channel] = 3.0;
+            (Some(channel), Vec3::new(weight[0], weight[1], weight[2]))
+        },
+        None => (None, Vec3::one()),
+    }
+}
 
-                let view = -ray.direction;
-                let normal = nearest_hit.normal;
+fn channel_ior(ior: f32, dispersion: f32, channel: Option<usize>) -> f32 {
+    match channel {
+        Some(channel) => cauchy_ior(ior, dispersion, channel),
+        None => ior,
+    }
+}
 
-                let PBRParameters{ reflectivity, roughness, metalness } = *pbr_parameters;
-                let f0 = mix_vec3(Vec3::new(R, R, R), reflectivity, metalness);
+// NESTED DIELECTRICS
+//
+// The dielectrics a path has entered and not left yet, for dielectrics that overlap like a
+// liquid in a glass (source: Charles M. Schmidt and Brian Budge, "Simple Nested Dielectrics
+// in Ray Traced Images", 2002). The liquid is modelled slightly larger than the inside of
+// the glass so that there is no gap of air between them, and the glass gets the higher
+// priority. The ray is inside of the medium with the highest priority, the latest one
+// among equal priorities. Surfaces of media with a lower priority than that are no real
+// boundaries and are passed without refraction, so the liquid ends where the glass begins.
+// At a real boundary, the index of refraction changes from the medium before to the one
+// after it, e.g. from glass to liquid instead of from glass to air.
+//
+// The media are identified by the address of their material, which is unique for every
+// object. Paths that enter more than MAX_NESTED_MEDIA media do not remember the innermost
+// ones and see air behind them (see validation::warnings). The path tracer, the Whitted-style
+// ray tracer, FastGI and the wavefront path tracer track the media of their paths.
+pub const MAX_NESTED_MEDIA: usize = 4;
+const IOR_AIR: f32 = 1.0;
+
+#[derive(Clone, Copy, Debug, Default, new)]
+struct NestedMedium {
+    id: usize,
+    ior: f32,
+    dispersion: f32,
+    priority: u32,
+}
 
-                let xi = Vec2::new(random32(), random32());
+impl NestedMedium {
+    fn of(material: &Material, ior: f32, dispersion: f32, priority: u32) -> NestedMedium {
+        NestedMedium::new(material as *const Material as usize, ior, dispersion, priority)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct MediumStack {
+    media: [NestedMedium; MAX_NESTED_MEDIA],
+    len: usize,
+}
+
+impl MediumStack {
+    fn current(&self) -> Option<&NestedMedium> {
+        let mut current: Option<&NestedMedium> = None;
+        for medium in self.media[..self.len].iter() {
+            if current.map(|current| medium.priority >= current.priority).unwrap_or(true) {
+                current = Some(medium);
+            }
+        }
+        current
+    }
+
+    fn current_ior(&self, channel: Option<usize>) -> f32 {
+        self.current().map(|medium| channel_ior(medium.ior, medium.dispersion, channel)).unwrap_or(IOR_AIR)
+    }
+
+    fn with(&self, medium: NestedMedium) -> MediumStack {
+        let mut result = *self;
+        if result.len < MAX_NESTED_MEDIA {
+            result.media[result.len] = medium;
+            result.len += 1;
+        }
+        result
+    }
+
+    fn without(&self, id: usize) -> MediumStack {
+        let mut result = MediumStack::default();
+        for medium in self.media[..self.len].iter().filter(|medium| medium.id != id) {
+            result = result.with(*medium);
+        }
+        result
+    }
+
+    // The stack on the other side of the surface of the medium, and whether the surface is
+    // a real boundary.
+    fn cross(&self, medium: NestedMedium, transition: Transition) -> (MediumStack, bool) {
+        let others = self.without(medium.id);
+        let is_boundary = others.current().map(|current| current.priority <= medium.priority).unwrap_or(true);
+        match transition {
+            Transition::In => (others.with(medium), is_boundary),
+            Transition::Out => (others, is_boundary),
+        }
+    }
+
+    // The stack on the other side of the surface of the medium together with the indices of
+    // refraction before and after the surface, or None when the surface is no real boundary
+    // and the ray passes straight through.
+    fn refraction(&self, medium: NestedMedium, transition: Transition, channel: Option<usize>) -> (MediumStack, Option<(f32, f32)>) {
+        let (media, is_boundary) = self.cross(medium, transition);
+        if !is_boundary {
+            return (media, None);
+        }
+        let ior = channel_ior(medium.ior, medium.dispersion, channel);
+        let iors = match transition {
+            Transition::In  => (self.without(medium.id).current_ior(channel), ior),
+            Transition::Out => (ior, media.current_ior(channel)),
+        };
+        (media, Some(iors))
+    }
+}
+
+fn shade_surface(meta: &Meta, ray: &Ray, nearest_hit: Option<Hit>, scene: &Scene, depth: u8) -> Vec3 {
+    if let Some(nearest_hit) = nearest_hit {
+        let outwards_shifted_position = ||{ offset_ray_origin(nearest_hit.position, nearest_hit.normal) };
+        let inwards_shifted_position  = ||{ offset_ray_origin(nearest_hit.position, -nearest_hit.normal) };
+
+        const R: f32 = 0.04;
+
+        match nearest_hit.material {
+            Material::None => Vec3::one(),
+            Material::Emissive(ref color) => {
+                color.clone()
+            },
+            Material::Mirror => {
+                let reflection_direction = reflect(ray.direction, nearest_hit.normal);
+                let reflection_ray = Ray::new(outwards_shifted_position(), reflection_direction.normalize());
+                trace_radiance(meta, &reflection_ray, scene, depth - 1)
+            },
+            Material::Translucent(ior, dispersion, priority) => {
+                // A surface within a medium of a higher priority is passed straight through.
+                let medium = NestedMedium::of(nearest_hit.material, *ior, *dispersion, *priority);
+                let (channel, channel_weight) = dispersion_channel(meta.channel, *dispersion);
+                let (media, iors) = meta.media.refraction(medium, nearest_hit.transition, channel);
+                let (n1, n2) = match iors {
+                    Some(iors) => iors,
+                    None => {
+                        let continued_ray = Ray::new(inwards_shifted_position(), ray.direction);
+                        return trace_radiance(&Meta { media: media, ..meta.clone() }, &continued_ray, scene, depth);
+                    },
+                };
+
+                // Refraction
+
+                let refraction_direction = refract(ray.direction, nearest_hit.normal, n1, n2);
+                let refraction_ray = Ray::new(inwards_shifted_position(), refraction_direction.normalize());
+                let refraction_meta = Meta { channel: channel, media: media, ..meta.clone() };
+                let l_refr = channel_weight*trace_radiance(&refraction_meta, &refraction_ray, scene, depth - 1);
+
+                // Reflection
+                let reflection_direction = reflect(ray.direction, nearest_hit.normal);
+                let reflection_ray = Ray::new(outwards_shifted_position(), reflection_direction.normalize());
+                let l_refl = trace_radiance(meta, &reflection_ray, scene, depth - 1);
+
+                // Fresnel
+                let cos_theta = f32::max(0.0, nearest_hit.normal.dot(-ray.direction));
+                let fresnel = fresnel_schlick(cos_theta, Vec3::new(R, R, R));
+
+                let k_refl = fresnel;
+                let k_refr = Vec3::one() - fresnel;
+
+                k_refl*l_refl + k_refr*l_refr
+            },
+            Material::Physically(ref pbr_parameters) => {
+                let tangent_space = construct_coordinate_system(nearest_hit.normal);
+
+                let view = -ray.direction;
+                let normal = nearest_hit.normal;
+
+                let PBRParameters{ reflectivity, roughness, metalness } = *pbr_parameters;
+                let f0 = mix_vec3(Vec3::new(R, R, R), reflectivity, metalness);
+
+                let xi = Vec2::new(random32(), random32());
 
                // Decide whether this reflection is specular or diffuse
                 let specular_reflection = random32() < f0.as_array()[(xorshift32() % 3) as usize];
@@ -558,6 +1207,38 @@ fn trace_radiance(meta: &Meta, ray: &Ray, scene: &Scene, depth: u8) -> Vec3 {
 
                     // The cos(theta) was canceled out as it is in the denominator of the Cook-Torrance BRDF.
                     PI*brdf_cook_torrance(view, light, normal, roughness, f0)*light_radiance
+                } else if let Some(guiding) = meta.guiding {
+                    let position = outwards_shifted_position();
+
+                    // The direction is either sampled from the cosine distribution or from the
+                    // learned distribution of the guiding grid (one-sample MIS). Cells without
+                    // training data only use the cosine distribution.
+                    let guiding_probability = if guiding.is_trained(position) { 0.5 } else { 0.0 };
+                    let light = if random32() < guiding_probability {
+                        guiding.sample(position, xi)
+                    } else {
+                        to_basis(tangent_space, importance_sample_cos(xi)).normalize()
+                    };
+
+                    let light_cos_theta = light.dot(normal);
+                    if light_cos_theta <= 0.0 {
+                        return Vec3::zero();
+                    }
+
+                    let light_ray = Ray::new(position, light);
+                    let light_radiance = trace_radiance(meta, &light_ray, scene, depth - 1);
+                    guiding.record(position, light, luminance(light_radiance));
+
+                    // This is the estimator of the unguided case reweighted by the ratio of
+                    // the cosine pdf and the pdf with which the direction was actually sampled.
+                    let pdf_cos = light_cos_theta / PI;
+                    let pdf = if guiding_probability > 0.0 {
+                        guiding_probability*guiding.pdf(position, light) + (1.0 - guiding_probability)*pdf_cos
+                    } else {
+                        pdf_cos
+                    };
+
+                    PI*brdf_lambert(pbr_parameters)*light_radiance*light_cos_theta*(pdf_cos / pdf)
                 } else {
                     let light = to_basis(tangent_space, importance_sample_cos(xi));
                     let light_ray = Ray::new(outwards_shifted_position(), light.normalize());
@@ -567,20 +1248,654 @@ fn trace_radiance(meta: &Meta, ray: &Ray, scene: &Scene, depth: u8) -> Vec3 {
                     PI*brdf_lambert(pbr_parameters)*light_radiance*light_cos_theta
                 }
             },
-        }
-    } else {
-        match &scene.sky {
-            &Sky::Constant(radiance) => radiance,
-            &Sky::HDRI(ref _path, ref option_texture) => {
-                if let Some(texture) = option_texture {
-                    let sampler = texture.sampler();
-                    sampler.sample_equirectangular(ray.direction)
-                } else {
-                    Vec3::one() // Returning white until the sky is loaded
+            Material::Custom(ref bsdf) => {
+                let xi = Vec2::new(random32(), random32());
+                match bsdf.sample(-ray.direction, nearest_hit.normal, xi) {
+                    Some((light, weight)) => {
+                        // Transmitted directions continue on the other side of the surface.
+                        let position = if light.dot(nearest_hit.normal) >= 0.0 { outwards_shifted_position() } else { inwards_shifted_position() };
+                        let light_ray = Ray::new(position, light.normalize());
+                        weight*trace_radiance(meta, &light_ray, scene, depth - 1)
+                    },
+                    None => Vec3::zero(),
                 }
             },
         }
+    } else {
+        sky_radiance(&scene.sky, ray.direction)
+    }
+}
+
+fn sky_radiance(sky: &Sky, direction: Vec3) -> Vec3 {
+    match sky {
+        &Sky::Constant(radiance) => radiance,
+        &Sky::HDRI(ref _path, ref option_texture) => {
+            if let Some(texture) = option_texture {
+                let sampler = texture.sampler();
+                sampler.sample_equirectangular(direction)
+            } else {
+                Vec3::one() // Returning white until the sky is loaded
+            }
+        },
+    }
+}
+
+fn is_light_visible(position: Vec3, light_point: Vec3, light_material: &Material, scene: &Scene) -> bool {
+    let to_light = light_point - position;
+    let light_distance = to_light.length();
+    let ray = Ray::new(position, to_light.normalize());
+    match find_visible_hit(&ray, scene, RayKind::Shadow) {
+        // The light is identified by its material because the shadow ray hits the surface
+        // of spherical lights before it reaches the sample point in their center. A miss
+        // means that the light is hidden from the shadow rays (see Visibility).
+        Some(hit) => std::ptr::eq(hit.material, light_material) || hit.parameter > light_distance*(1.0 - SHADOW_EPSILON),
+        None => true,
+    }
+}
+
+// Whether the nearest hit of a shadow ray reaches a point that was sampled on the surface of
+// the light. In contrast to is_light_visible, the light may block its own sample, e.g. one on
+// the back of a spherical light. A miss lets the light through only when it is hidden from
+// the shadow rays (see Visibility) and lights the scene without being hit.
+fn is_light_sample_unblocked(hit: Option<Hit>, light: Primitive, light_distance: f32) -> bool {
+    match hit {
+        Some(hit) => hit.parameter > light_distance,
+        None => !light.visibility().shadows,
+    }
+}
+
+fn direct_lighting_whitted(position: Vec3, normal: Vec3, scene: &Scene) -> Vec3 {
+    // Every light is approximated by a point light in its center so that the result is
+    // deterministic. The area of the light is used to get the irradiance right roughly.
+    let mut irradiance = Vec3::zero();
+
+    for light in &scene.emissive_spheres {
+        if let Material::Emissive(radiance) = light.material {
+            if !is_light_visible(position, light.origin, &light.material, scene) {
+                continue;
+            }
+            let to_light = light.origin - position;
+            let distance2 = to_light.dot(to_light);
+            let cos_theta = f32::max(0.0, normal.dot(to_light.normalize()));
+            let solid_angle = PI*light.radius*light.radius / distance2;
+            irradiance = irradiance + radiance*(solid_angle*cos_theta);
+        }
+    }
+
+    for light in &scene.emissive_planes {
+        if let Material::Emissive(radiance) = light.material {
+            let center = light.origin + 0.5*light.u + 0.5*light.v;
+            if !is_light_visible(position, center, &light.material, scene) {
+                continue;
+            }
+            let to_light = center - position;
+            let distance2 = to_light.dot(to_light);
+            let direction = to_light.normalize();
+            let cos_theta = f32::max(0.0, normal.dot(direction));
+            let cos_theta_light = f32::abs(light.normal.dot(direction));
+            let area = light.u.cross(light.v).length();
+            irradiance = irradiance + radiance*(area*cos_theta*cos_theta_light / distance2);
+        }
+    }
+
+    irradiance
+}
+
+// A deterministic ray tracer that only follows perfect reflections and refractions and
+// evaluates the direct lighting with shadow rays. The participating media are ignored.
+// It converges with the first sample and is meant as a noise-free preview.
+fn trace_whitted(ray: &Ray, scene: &Scene, depth: u8, kind: RayKind, media: MediumStack) -> Vec3 {
+    let nearest_hit = match find_visible_hit(ray, scene, kind) {
+        Some(hit) => hit,
+        None => return sky_radiance(&scene.sky, ray.direction),
+    };
+
+    let outwards_shifted_position = offset_ray_origin(nearest_hit.position, nearest_hit.normal);
+    let inwards_shifted_position  = offset_ray_origin(nearest_hit.position, -nearest_hit.normal);
+
+    let reflection = |weight: Vec3| -> Vec3 {
+        if depth <= 0 {
+            return Vec3::zero();
+        }
+        let reflection_direction = reflect(ray.direction, nearest_hit.normal).normalize();
+        let reflection_ray = Ray::new(outwards_shifted_position, reflection_direction);
+        weight*trace_whitted(&reflection_ray, scene, depth - 1, RayKind::Bounce, media)
+    };
+
+    const R: f32 = 0.04;
+
+    match nearest_hit.material {
+        Material::None => Vec3::one(),
+        Material::Emissive(ref color) => color.clone(),
+        Material::Mirror => reflection(Vec3::one()),
+        // The dispersion is ignored as the color channels would be split into separate rays.
+        Material::Translucent(ior, dispersion, priority) => {
+            let (media_after, iors) = media.refraction(NestedMedium::of(nearest_hit.material, *ior, *dispersion, *priority), nearest_hit.transition, None);
+            let (n1, n2) = match iors {
+                Some(iors) => iors,
+                None => return trace_whitted(&Ray::new(inwards_shifted_position, ray.direction), scene, depth, RayKind::Bounce, media_after),
+            };
+            if depth <= 0 {
+                return Vec3::zero();
+            }
+
+            let cos_theta = f32::max(0.0, nearest_hit.normal.dot(-ray.direction));
+            let fresnel = fresnel_schlick(cos_theta, Vec3::new(R, R, R));
+
+            let refraction_direction = refract(ray.direction, nearest_hit.normal, n1, n2).normalize();
+            let refraction_ray = Ray::new(inwards_shifted_position, refraction_direction);
+            let l_refr = trace_whitted(&refraction_ray, scene, depth - 1, RayKind::Bounce, media_after);
+
+            reflection(fresnel) + (Vec3::one() - fresnel)*l_refr
+        },
+        Material::Physically(ref pbr_parameters) => {
+            let PBRParameters{ reflectivity, roughness, metalness } = *pbr_parameters;
+            let normal = nearest_hit.normal;
+            let f0 = mix_vec3(Vec3::new(R, R, R), reflectivity, metalness);
+
+            let irradiance = direct_lighting_whitted(outwards_shifted_position, normal, scene);
+            let ambient = sky_radiance(&scene.sky, normal)*PI;
+            let diffuse = (1.0 - metalness)*brdf_lambert(pbr_parameters)*(irradiance + ambient);
+
+            // Rough surfaces cannot be represented with a single reflection ray. Fading out
+            // the reflection keeps them from looking like mirrors.
+            let cos_theta = f32::max(0.0, normal.dot(-ray.direction));
+            let specular = reflection(fresnel_schlick(cos_theta, f0)*(1.0 - roughness));
+
+            diffuse + specular
+        },
+        // The BSDF can only be sampled, which is why it is approximated by its albedo.
+        Material::Custom(ref bsdf) => {
+            let normal = nearest_hit.normal;
+            let irradiance = direct_lighting_whitted(outwards_shifted_position, normal, scene);
+            let ambient = sky_radiance(&scene.sky, normal)*PI;
+            (bsdf.albedo() / PI)*(irradiance + ambient)
+        },
+    }
+}
+
+//
+// FAST GI
+//
+// A preview of the global illumination for the first few samples, e.g. while moving the
+// camera. Every bounce continues with exactly one ray, there is no russian roulette and at
+// most FAST_GI_BOUNCES diffuse bounces are traced, while the specular ones are limited by
+// the maximum depth. The direct light comes from the lights of the Whitted tracer and the
+// last diffuse bounce adds the sky as unoccluded ambient light instead of tracing further,
+// which overestimates the light in occluded corners. The directions of the diffuse bounces
+// come from the R2 sequence with a random offset per pixel, which spreads the samples of a
+// pixel more evenly than random numbers. The media are ignored.
+//
+const FAST_GI_BOUNCES: u8 = 2;
+
+// The R2 sequence, source: Martin Roberts, "The Unreasonable Effectiveness of Quasirandom
+// Sequences", 2018
+fn r2_sample(pixel: Vec2u, sample_index: usize, dimension: u32) -> Vec2 {
+    const ALPHA: (f64, f64) = (0.7548776662466927, 0.5698402909980532);
+    let hash = hash_u32(pixel.x ^ hash_u32(pixel.y ^ hash_u32(dimension)));
+    let offset = (hash as f64 / std::u32::MAX as f64, hash_u32(hash) as f64 / std::u32::MAX as f64);
+    let n = sample_index as f64;
+    Vec2::new((offset.0 + n*ALPHA.0).fract() as f32, (offset.1 + n*ALPHA.1).fract() as f32)
+}
+
+// The emission of the lights is only added when they are seen directly or through a
+// specular bounce, as it has already been added as direct light otherwise.
+fn trace_fast_gi(meta: &Meta, ray: &Ray, scene: &Scene, depth: u8, diffuse_bounces: u8, add_emission: bool) -> Vec3 {
+    let kind = if meta.bounced { RayKind::Bounce } else { RayKind::Camera };
+    let nearest_hit = match find_visible_hit(ray, scene, kind) {
+        Some(hit) => hit,
+        None => return sky_radiance(&scene.sky, ray.direction),
+    };
+    let meta = &Meta { bounced: true, ..meta.clone() };
+    let outwards_shifted_position = offset_ray_origin(nearest_hit.position, nearest_hit.normal);
+    let inwards_shifted_position = offset_ray_origin(nearest_hit.position, -nearest_hit.normal);
+    let reflection = |add_emission: bool| -> Vec3 {
+        if depth <= 0 {
+            return Vec3::zero();
+        }
+        let reflection_ray = Ray::new(outwards_shifted_position, reflect(ray.direction, nearest_hit.normal).normalize());
+        trace_fast_gi(meta, &reflection_ray, scene, depth - 1, diffuse_bounces, add_emission)
+    };
+
+    // One cosine-distributed ray, whose pdf cancels with the cosine and the 1/PI of the
+    // diffuse BRDF. The last bounce only adds the sky.
+    let diffuse = |albedo: Vec3| -> Vec3 {
+        let normal = nearest_hit.normal;
+        let direct = direct_lighting_whitted(outwards_shifted_position, normal, scene)/PI;
+        let indirect = if depth == 0 || diffuse_bounces == 0 {
+            sky_radiance(&scene.sky, normal)
+        } else {
+            let xi = r2_sample(meta.pixel_position, meta.sample_index, diffuse_bounces as u32);
+            let direction = to_basis(construct_coordinate_system(normal), importance_sample_cos(xi)).normalize();
+            trace_fast_gi(meta, &Ray::new(outwards_shifted_position, direction), scene, depth - 1, diffuse_bounces - 1, false)
+        };
+        albedo*(direct + indirect)
+    };
+
+    const R: f32 = 0.04;
+
+    match nearest_hit.material {
+        Material::None => Vec3::one(),
+        Material::Emissive(ref color) => if add_emission { color.clone() } else { Vec3::zero() },
+        Material::Mirror => reflection(true),
+        // Either the reflection or the refraction is followed, with the probability of the
+        // Fresnel term. The dispersion is ignored.
+        Material::Translucent(ior, dispersion, priority) => {
+            let (media, iors) = meta.media.refraction(NestedMedium::of(nearest_hit.material, *ior, *dispersion, *priority), nearest_hit.transition, None);
+            let (n1, n2) = match iors {
+                Some(iors) => iors,
+                None => return trace_fast_gi(&Meta { media: media, ..meta.clone() }, &Ray::new(inwards_shifted_position, ray.direction), scene, depth, diffuse_bounces, add_emission),
+            };
+            if depth <= 0 {
+                return Vec3::zero();
+            }
+            let cos_theta = f32::max(0.0, nearest_hit.normal.dot(-ray.direction));
+            let fresnel = fresnel_schlick(cos_theta, Vec3::new(R, R, R));
+            if random32() < fresnel.x {
+                reflection(true)
+            } else {
+                let refraction_ray = Ray::new(inwards_shifted_position, refract(ray.direction, nearest_hit.normal, n1, n2).normalize());
+                trace_fast_gi(&Meta { media: media, ..meta.clone() }, &refraction_ray, scene, depth - 1, diffuse_bounces, true)
+            }
+        },
+        // The metals reflect like a mirror, with the probability of the metalness, and the
+        // roughness is ignored.
+        Material::Physically(ref pbr_parameters) => {
+            let PBRParameters{ reflectivity, metalness, .. } = *pbr_parameters;
+            if random32() < metalness {
+                reflectivity*reflection(true)
+            } else {
+                diffuse(reflectivity)
+            }
+        },
+        Material::Custom(ref bsdf) => diffuse(bsdf.albedo()),
+    }
+}
+
+//
+// DEBUG VIEWS
+//
+// Instead of the radiance, these integrators write values that help with inspecting a
+// scene in [0, 1]. The backbuffer displays them without tone mapping.
+//
+// @TODO: The scales assume scenes that are a few tens of units large.
+//
+const DEPTH_SCALE: f32 = 10.0; // Distance at which the depth view has fallen off to 1/e
+const AO_DISTANCE: f32 = 1.0; // Occluders further away do not darken the ambient occlusion
+
+// The normal of the first hit, mapped from [-1, 1] to [0, 1]. The sky is black.
+fn trace_normals(ray: &Ray, scene: &Scene) -> Vec3 {
+    match find_scene_hit(ray, scene) {
+        Some(hit) => 0.5*(hit.normal + Vec3::one()),
+        None => Vec3::zero(),
+    }
+}
+
+// Close surfaces are white and get darker exponentially with the distance. The sky is black.
+fn trace_depth(ray: &Ray, scene: &Scene) -> Vec3 {
+    match find_scene_hit(ray, scene) {
+        Some(hit) => f32::exp(-hit.parameter/DEPTH_SCALE)*Vec3::one(),
+        None => Vec3::zero(),
+    }
+}
+
+// Every sample traces one cosine-distributed ray from the first hit, which is occluded when
+// it hits anything closer than AO_DISTANCE, including the lights. The sky is white.
+fn trace_ambient_occlusion(ray: &Ray, scene: &Scene) -> Vec3 {
+    let nearest_hit = match find_scene_hit(ray, scene) {
+        Some(hit) => hit,
+        None => return Vec3::one(),
+    };
+
+    let normal = if nearest_hit.normal.dot(ray.direction) > 0.0 { -nearest_hit.normal } else { nearest_hit.normal };
+    ambient_occlusion(nearest_hit.position, normal, scene)
+}
+
+// One sample of the ambient occlusion of a point on a surface, on the side of the normal.
+pub fn ambient_occlusion(position: Vec3, normal: Vec3, scene: &Scene) -> Vec3 {
+    let xi = Vec2::new(random32(), random32());
+    let direction = to_basis(construct_coordinate_system(normal), importance_sample_cos(xi)).normalize();
+    let occlusion_ray = Ray::new(offset_ray_origin(position, normal), direction);
+    match find_scene_hit(&occlusion_ray, scene) {
+        Some(ref occluder) if occluder.parameter < AO_DISTANCE => Vec3::zero(),
+        _ => Vec3::one(),
+    }
+}
+
+// One sample of the irradiance of a point on a surface, on the side of the normal. The
+// incident radiance is traced with the path tracer from a cosine-distributed direction,
+// whose pdf cancels with the cosine up to the factor PI.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn irradiance(position: Vec3, normal: Vec3, scene: &Scene, max_depth: u8) -> Vec3 {
+    let xi = Vec2::new(random32(), random32());
+    let direction = to_basis(construct_coordinate_system(normal), importance_sample_cos(xi)).normalize();
+    let ray = Ray::new(offset_ray_origin(position, normal), direction);
+    let meta = Meta { bounced: true, ..Meta::new(Vec2u::new(0, 0), 1, None) };
+    PI*trace_radiance(&meta, &ray, scene, max_depth)
+}
+
+// The relative error of every pixel (see Backbuffer::relative_error) as a heatmap, from blue
+// for converged pixels to red for an error of MAX_RELATIVE_ERROR or more. It shows where the
+// noise remains, e.g. in the caustics, and is displayed instead of the image.
+#[cfg(not(target_arch = "wasm32"))]
+const MAX_RELATIVE_ERROR: f32 = 0.1;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn variance_heatmap(backbuffer: &Backbuffer, num_samples: usize, pixels: &mut Vec<Pixel8>) {
+    pixels.clear();
+    for y in 0..backbuffer.height() {
+        for x in 0..backbuffer.width() {
+            let t = backbuffer.relative_error(x, y, num_samples)/MAX_RELATIVE_ERROR;
+            pixels.push(Pixel8::from_unit_dithered(heat_color(t), x, y));
+        }
+    }
+}
+
+// Blue, cyan, green, yellow and red for t from 0 to 1
+fn heat_color(t: f32) -> Vec3 {
+    let t = saturatef32(t);
+    Vec3::new(
+        saturatef32(1.5 - f32::abs(4.0*t - 3.0)),
+        saturatef32(1.5 - f32::abs(4.0*t - 2.0)),
+        saturatef32(1.5 - f32::abs(4.0*t - 1.0)),
+    )
+}
+
+// The number of rays of one path of the path tracer as a false color, from blue for a single
+// ray to red for two rays per bounce. As every ray is tested against every primitive, this is
+// also the cost of the sample.
+fn trace_heatmap(meta: &Meta, ray: &Ray, scene: &Scene, depth: u8) -> Vec3 {
+    let rays_before = stats::local_rays();
+    trace_radiance(meta, ray, scene, depth);
+    let num_rays = stats::local_rays() - rays_before;
+    heat_color((num_rays as f32 - 1.0) / (2.0*(depth as f32 + 1.0) - 1.0))
+}
+
+//
+// INVALID RADIANCE
+//
+// NaNs, infinities and negative values come from divisions by pdfs that are almost zero,
+// normals that are not normalized and similar numerical problems. To track them down,
+// "--check-radiance" highlights the pixels that received them and "--debug-pixel" traces
+// a single pixel with a log of every bounce.
+//
+const NUM_DEBUG_SAMPLES: usize = 16;
+
+// The paths that print their bounces are marked in their Meta. Only the indentation of the
+// log is kept per thread.
+thread_local! {
+    static VERBOSE_MAX_DEPTH: Cell<u8> = Cell::new(0);
+}
+
+// Nested bounces are indented as the depth counts down.
+fn verbose_indent(depth: u8) -> usize {
+    2*(VERBOSE_MAX_DEPTH.with(|max_depth| max_depth.get()).saturating_sub(depth) as usize + 1)
+}
+
+pub fn is_valid_radiance(radiance: Vec3) -> bool {
+    let is_valid = |value: f32| value.is_finite() && value >= 0.0;
+    is_valid(radiance.x) && is_valid(radiance.y) && is_valid(radiance.z)
+}
+
+// Traces a few samples of one pixel and prints every bounce of the path tracer with its hit
+// and the radiance that it returns. The pixel is given from the top left corner of the
+// image. The guided path tracer, ReSTIR and the wavefront and GPU integrators need state
+// that is shared between the pixels and are traced with the path tracer instead. The other
+// integrators only print the result of every sample.
+pub fn trace_debug_pixel(scene: &Scene, integrator: Integrator, max_depth: u8, x: u32, y: u32) {
+    let (width, height) = (scene.image_settings.width as u32, scene.image_settings.height as u32);
+    if x >= width || y >= height {
+        println!("The pixel ({}, {}) is outside of the image of {}x{} pixels.", x, y, width, height);
+        return;
+    }
+    // The backbuffer starts with the bottom row.
+    let pixel = Vec2u::new(x, height - 1 - y);
+    let sampler = scene.camera.sample(width, height, scene.image_settings.sub_pixel_sampling, scene.image_settings.stereo);
+
+    VERBOSE_MAX_DEPTH.with(|verbose_max_depth| verbose_max_depth.set(max_depth));
+    let mut num_invalid = 0;
+    for sample_index in 0..NUM_DEBUG_SAMPLES {
+        println!("Sample {} of pixel ({}, {})", sample_index, x, y);
+        let (ray, eye_weight) = sampler.camera_ray(pixel.x, pixel.y);
+        let meta = Meta { verbose: true, ..Meta::new(pixel, sample_index + 1, None) };
+        let hdr_radiance = eye_weight*match integrator {
+            Integrator::Whitted => trace_whitted(&ray, scene, max_depth, RayKind::Camera, MediumStack::default()),
+            Integrator::FastGI => trace_fast_gi(&Meta::new(pixel, sample_index, None), &ray, scene, max_depth, FAST_GI_BOUNCES, true),
+            Integrator::Normals => trace_normals(&ray, scene),
+            Integrator::Depth => trace_depth(&ray, scene),
+            Integrator::AmbientOcclusion => trace_ambient_occlusion(&ray, scene),
+            Integrator::Heatmap => trace_heatmap(&meta, &ray, scene, max_depth),
+            _ => trace_radiance(&meta, &ray, scene, max_depth),
+        };
+        if !is_valid_radiance(hdr_radiance) {
+            num_invalid += 1;
+        }
+        println!("  radiance = {:?}", hdr_radiance);
+    }
+    println!("{} of {} samples of pixel ({}, {}) are invalid.", num_invalid, NUM_DEBUG_SAMPLES, x, y);
+}
+
+//
+// RECORDING PATHS
+//
+// Instead of printing the bounces like "--debug-pixel", the segments of the paths of a pixel
+// can be recorded and drawn over a wireframe of the scene (see rayview.rs). The segments of
+// the rays that leave the scene only have the direction and a length of one.
+//
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SegmentKind {
+    Path, // From the origin of the ray to the surface or the collision in a volume
+    Sky, // A ray that leaves the scene
+    Shadow(bool), // From the last bounce to the light, and whether the light is visible
+}
+
+#[derive(Clone, Copy, Debug, new)]
+pub struct RaySegment {
+    pub start: Vec3,
+    pub end: Vec3,
+    pub kind: SegmentKind,
+    pub sample_index: usize,
+    pub bounce: u8, // Zero for the primary ray of the camera
+}
+
+thread_local! {
+    static RECORDED_SEGMENTS: RefCell<Vec<RaySegment>> = RefCell::new(Vec::new());
+    static RECORDING_STATE: Cell<(usize, u8)> = Cell::new((0, 0)); // Sample index and max depth
+}
+
+fn record_segment(start: Vec3, end: Vec3, kind: SegmentKind, depth: u8) {
+    let (sample_index, max_depth) = RECORDING_STATE.with(|state| state.get());
+    let segment = RaySegment::new(start, end, kind, sample_index, max_depth.saturating_sub(depth));
+    RECORDED_SEGMENTS.with(|segments| segments.borrow_mut().push(segment));
+}
+
+// Traces a few samples of one pixel with the path tracer and returns the segments of their
+// paths. The pixel is given from the top left corner of the image, like for
+// trace_debug_pixel.
+pub fn record_pixel_paths(scene: &Scene, max_depth: u8, x: u32, y: u32) -> Vec<RaySegment> {
+    let (width, height) = (scene.image_settings.width as u32, scene.image_settings.height as u32);
+    if x >= width || y >= height {
+        return Vec::new();
+    }
+    let pixel = Vec2u::new(x, height - 1 - y);
+    let sampler = scene.camera.sample(width, height, scene.image_settings.sub_pixel_sampling, scene.image_settings.stereo);
+
+    RECORDED_SEGMENTS.with(|segments| segments.borrow_mut().clear());
+    for sample_index in 0..NUM_DEBUG_SAMPLES {
+        RECORDING_STATE.with(|state| state.set((sample_index, max_depth)));
+        let (ray, _) = sampler.camera_ray(pixel.x, pixel.y);
+        let meta = Meta { recording: true, ..Meta::new(pixel, sample_index + 1, None) };
+        trace_radiance(&meta, &ray, scene, max_depth);
+    }
+    RECORDED_SEGMENTS.with(|segments| std::mem::replace(&mut *segments.borrow_mut(), Vec::new()))
+}
+
+fn emitted_radiance(material: &Material) -> Vec3 {
+    match material {
+        &Material::Emissive(radiance) => radiance,
+        _ => panic!("Non-emissive material in emissive material Vec."),
+    }
+}
+
+// Picks one of the lights uniformly and a point uniformly on its surface. Returns the
+// sample together with its pdf with respect to surface area.
+fn sample_light_uniform(scene: &Scene) -> Option<(LightSample, f32)> {
+    let num_lights = scene.num_lights();
+    if num_lights == 0 {
+        return None;
+    }
+
+    let r = xorshift32() as usize % num_lights;
+    let (sample, area) = match scene.light(r)? {
+        Primitive::Sphere(light) => {
+            let z = 1.0 - 2.0*random32();
+            let phi = 2.0*PI*random32();
+            let s = f32::sqrt(f32::max(0.0, 1.0 - z*z));
+            let normal = Vec3::new(s*f32::cos(phi), z, s*f32::sin(phi));
+            let position = light.origin + light.radius*normal;
+            (LightSample::new(position, normal, emitted_radiance(&light.material)), 4.0*PI*light.radius*light.radius)
+        },
+        Primitive::Plane(light) => {
+            let position = light.origin + random32()*light.u + random32()*light.v;
+            (LightSample::new(position, light.normal, emitted_radiance(&light.material)), light.u.cross(light.v).length())
+        },
+    };
+
+    Some((sample, 1.0 / (num_lights as f32*area)))
+}
+
+fn brdf_evaluate(view: Vec3, light: Vec3, normal: Vec3, pbr_parameters: &PBRParameters) -> Vec3 {
+    // In contrast to brdf_cook_torrance, the normal distribution function is not canceled
+    // out here because the light direction is not sampled from it.
+    const R: f32 = 0.04;
+    let PBRParameters{ reflectivity, roughness, metalness } = *pbr_parameters;
+    let f0 = mix_vec3(Vec3::new(R, R, R), reflectivity, metalness);
+
+    let alpha = f32::max(0.01, roughness*roughness); // Perfect mirrors cannot be evaluated
+    let h = (view + light).normalize();
+    let n_dot_v = f32::max(0.0001, normal.dot(view));
+    let n_dot_l = f32::max(0.0001, normal.dot(light));
+
+    let fresnel = fresnel_schlick(f32::max(0.0, h.dot(view)), f0);
+    let d = normal_distribution_ggx(normal, h, alpha);
+    let g = geometry_smith(normal, view, light, alpha / 2.0);
+
+    let specular = d*g*fresnel / (4.0*n_dot_v*n_dot_l);
+    let diffuse = (Vec3::one() - fresnel)*(1.0 - metalness)*reflectivity / PI;
+    diffuse + specular
+}
+
+// The unshadowed contribution of a light sample to the shading point. Its luminance is the
+// target function for the resampling.
+fn restir_contribution(position: Vec3, normal: Vec3, view: Vec3, pbr_parameters: &PBRParameters, sample: &LightSample) -> Vec3 {
+    let to_light = sample.position - position;
+    let distance2 = to_light.dot(to_light);
+    if distance2 <= 0.0 {
+        return Vec3::zero();
+    }
+    let light = to_light.normalize();
+    let cos_theta = normal.dot(light);
+    let cos_theta_light = -sample.normal.dot(light);
+    if cos_theta <= 0.0 || cos_theta_light <= 0.0 {
+        return Vec3::zero();
+    }
+    brdf_evaluate(view, light, normal, pbr_parameters)*sample.radiance*(cos_theta*cos_theta_light / distance2)
+}
+
+fn is_point_visible(position: Vec3, point: Vec3, scene: &Scene) -> bool {
+    let to_point = point - position;
+    let distance = to_point.length();
+    let ray = Ray::new(position, to_point.normalize());
+    match find_visible_hit(&ray, scene, RayKind::Shadow) {
+        Some(hit) => hit.parameter > distance*(1.0 - SHADOW_EPSILON),
+        None => true,
+    }
+}
+
+// Direct lighting with reservoir-based spatiotemporal importance resampling (ReSTIR). A
+// number of light samples is resampled for every pixel according to their unshadowed
+// contribution. The selected sample is then combined with the reservoir of the same pixel
+// from the previous frame and with the reservoirs of random neighbors so that good light
+// samples spread over the image and accumulate over time. Only a single shadow ray is
+// traced per pixel. Surfaces that are not physically based fall back to the path tracer.
+fn trace_restir(meta: &Meta, ray: &Ray, scene: &Scene, reservoirs: &ReservoirBuffer, max_depth: u8) -> Vec3 {
+    const NUM_CANDIDATES: usize = 16;
+    const NUM_NEIGHBORS: usize = 3;
+    const NEIGHBOR_RADIUS: i32 = 10;
+    const MAX_TEMPORAL_CANDIDATES: f32 = 20.0*NUM_CANDIDATES as f32;
+    const MIN_NORMAL_SIMILARITY: f32 = 0.9;
+
+    let (x, y) = (meta.pixel_position.x, meta.pixel_position.y);
+
+    let nearest_hit = match find_visible_hit(ray, scene, RayKind::Camera) {
+        Some(hit) => hit,
+        None => {
+            reservoirs.store_unsafe(x, y, Reservoir::empty());
+            return sky_radiance(&scene.sky, ray.direction);
+        },
+    };
+
+    let pbr_parameters = match nearest_hit.material {
+        &Material::Physically(ref pbr_parameters) => pbr_parameters,
+        &Material::Emissive(radiance) => {
+            reservoirs.store_unsafe(x, y, Reservoir::empty());
+            return radiance;
+        },
+        _ => {
+            reservoirs.store_unsafe(x, y, Reservoir::empty());
+            return trace_radiance(meta, ray, scene, max_depth);
+        },
+    };
+
+    let position = offset_ray_origin(nearest_hit.position, nearest_hit.normal);
+    let normal = nearest_hit.normal;
+    let view = -ray.direction;
+    let target = |sample: &LightSample| luminance(restir_contribution(position, normal, view, pbr_parameters, sample));
+
+    // Initial candidates
+    let mut candidates = Reservoir::empty();
+    for _ in 0..NUM_CANDIDATES {
+        if let Some((sample, pdf)) = sample_light_uniform(scene) {
+            candidates.add(sample, target(&sample) / pdf, 1.0);
+        }
+    }
+    let candidates_target = target(&candidates.sample);
+    candidates.finalize(candidates_target);
+
+    let mut reservoir = Reservoir::empty();
+    reservoir.merge(&candidates, candidates_target);
+
+    // Temporal and spatial reuse. Reservoirs of different surfaces are rejected as their
+    // samples would bias the result too much.
+    {
+        let mut reuse = |other: Reservoir| {
+            if other.num_candidates > 0.0 && other.surface_normal.dot(normal) > MIN_NORMAL_SIMILARITY {
+                let other = other.clamped(MAX_TEMPORAL_CANDIDATES);
+                let other_target = target(&other.sample);
+                reservoir.merge(&other, other_target);
+            }
+        };
+        reuse(reservoirs.previous(x, y));
+        for _ in 0..NUM_NEIGHBORS {
+            let offset = |r: u32| (r % (2*NEIGHBOR_RADIUS as u32 + 1)) as i32 - NEIGHBOR_RADIUS;
+            let nx = i32::max(0, i32::min(reservoirs.width() as i32 - 1, x as i32 + offset(xorshift32())));
+            let ny = i32::max(0, i32::min(reservoirs.height() as i32 - 1, y as i32 + offset(xorshift32())));
+            reuse(reservoirs.previous(nx as u32, ny as u32));
+        }
     }
+
+    let final_target = target(&reservoir.sample);
+    reservoir.finalize(final_target);
+    reservoir.surface_normal = normal;
+
+    // Occluded samples are not passed on to the next frame.
+    if !is_point_visible(position, reservoir.sample.position, scene) {
+        reservoir.weight = 0.0;
+    }
+    reservoirs.store_unsafe(x, y, reservoir);
+
+    let contribution = restir_contribution(position, normal, view, pbr_parameters, &reservoir.sample);
+    contribution*reservoir.weight
 }
 
 #[allow(dead_code)]
@@ -606,42 +1921,896 @@ fn tone_map_exposure(radiance: Vec3, exposure: f32) -> Vec3 {
     )
 }
 
-fn gamma_correction(radiance: Vec3) -> Vec3 {
-    const GAMMA: f32 = 1.0/2.2;
-    Vec3::new(
-        f32::powf(radiance.x, GAMMA),
-        f32::powf(radiance.y, GAMMA),
-        f32::powf(radiance.z, GAMMA),
-    )
+// Source: Krzysztof Narkowicz, "ACES Filmic Tone Mapping Curve", 2016
+// https://knarkowicz.wordpress.com/2016/01/06/aces-filmic-tone-mapping-curve/
+fn tone_map_aces(radiance: Vec3) -> Vec3 {
+    let (a, b, c, d, e) = (2.51, 0.03, 2.43, 0.59, 0.14);
+    let curve = |x: f32| saturatef32((x*(a*x + b)) / (x*(c*x + d) + e));
+    Vec3::new(curve(radiance.x), curve(radiance.y), curve(radiance.z))
 }
 
-pub fn render(work_tile: WorkTile, backbuffer: &Arc<Backbuffer>, scene: Arc<RwLock<Scene>>) {
-    let scene = scene.read().unwrap(); // @TODO: Handle the unwrap
+// Encodes linear values in [0, 1] with the sRGB transfer function. In contrast to a plain
+// power of 1/2.2, the curve is linear close to black.
+// Source: IEC 61966-2-1
+fn linear_to_srgb(linear: Vec3) -> Vec3 {
+    let encode = |x: f32| if x <= 0.0031308 { 12.92*x } else { 1.055*f32::powf(x, 1.0/2.4) - 0.055 };
+    Vec3::new(encode(linear.x), encode(linear.y), encode(linear.z))
+}
+
+fn srgb_to_linear(srgb: Vec3) -> Vec3 {
+    let decode = |x: f32| if x <= 0.04045 { x/12.92 } else { f32::powf((x + 0.055)/1.055, 2.4) };
+    Vec3::new(decode(srgb.x), decode(srgb.y), decode(srgb.z))
+}
+
+// Converts the average radiance of a pixel into the displayed sRGB color in [0, 1]. All
+// shading happens in linear space and this is the only place where the values are encoded.
+// The ISO and the exposure of the camera scale the radiance linearly before the operator is
+// applied, together with the white balance. The saturation is adjusted afterwards so that it
+// does not push the colors out of the range of the operator. The result is clamped so that
+// bright values saturate instead of wrapping around when they are quantized.
+pub fn tone_map(hdr_radiance: Vec3, camera: &Camera) -> Vec3 {
+    let ref grading = camera.color_grading;
+    let gains = grading.white_balance_gains();
+    let scale = (camera.iso/100.0)*f32::powf(2.0, grading.exposure);
+    let exposed = scale*Vec3::new(gains.x*hdr_radiance.x, gains.y*hdr_radiance.y, gains.z*hdr_radiance.z);
+    let ldr_radiance = match camera.tone_mapping {
+        ToneMapping::Clamp => tone_map_clamp(exposed),
+        ToneMapping::Reinhard => tone_map_reinhard(exposed),
+        ToneMapping::Aces => tone_map_aces(exposed),
+        ToneMapping::Exposure(value) => tone_map_exposure(exposed, value),
+    };
+    let gray = luminance(ldr_radiance);
+    let graded = Vec3::new(gray, gray, gray) + grading.saturation*(ldr_radiance - Vec3::new(gray, gray, gray));
+
+    saturate_vec3(linear_to_srgb(saturate_vec3(graded)))
+}
 
-    let iso_factor = scene.camera.iso/100.0;
+// Adds the radiance of one sample to the pixel of the backbuffer and updates the displayed
+// color. The caller has to ensure that no other thread writes the same pixel at the same
+// time.
+//
+// The first sample replaces the pixel instead. This way the preview frames can be rendered
+// repeatedly without accumulating and stay visible until the first frame in full
+// resolution overwrites them.
+//
+// Samples with NaN, infinite or negative radiance are dropped, as a single one would spoil
+// the pixel for the rest of the accumulation, and counted for the pixel instead so that they
+// do not darken its average.
+pub fn accumulate_radiance(backbuffer: &Backbuffer, camera: &Camera, x: u32, y: u32, hdr_radiance: Vec3) {
+    let first_sample = unsafe { *backbuffer.num_samples.get() == 1 };
+    let is_valid = is_valid_radiance(hdr_radiance);
+    backbuffer.add_radiance_unsafe(x, y, if is_valid { hdr_radiance } else { Vec3::zero() }, first_sample);
+    if !is_valid {
+        backbuffer.mark_invalid_unsafe(x, y);
+    }
+    backbuffer.assign_pixel8_unsafe(x, y, camera, unsafe { *backbuffer.num_samples.get() });
+}
 
-    let sampler = scene.camera.sample(backbuffer.width, backbuffer.height, scene.image_settings.sub_pixel_sampling);
+// Tone maps the whole backbuffer again, e.g. after the operator was changed. Must only be
+// called while no worker thread is rendering.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn tone_map_backbuffer(backbuffer: &Backbuffer, camera: &Camera, num_samples: usize) {
+    for y in 0..backbuffer.height() {
+        for x in 0..backbuffer.width() {
+            backbuffer.assign_pixel8_unsafe(x, y, camera, num_samples);
+        }
+    }
+}
+
+#[derive(Clone, Debug, new)]
+pub struct Features {
+    pub albedo: Vec3,
+    pub normal: Vec3,
+    pub depth: f32, // Distance along the primary ray. Zero when the sky is visible.
+    pub alpha: f32, // One when the primary ray hits the geometry, zero for the sky.
+    pub matte_id: u32, // Of the object that was hit (see ObjectId::matte_id), zero for the sky.
+}
+
+// The features for the denoisers are taken from the first hit of the primary ray. Surfaces
+// that are not diffuse are treated as white.
+fn surface_features(ray: &Ray, scene: &Scene) -> Features {
+    let object_hit = find_visible_object_hit(ray, scene, RayKind::Camera);
+    let object = object_hit.as_ref().map(|&(id, _)| id);
+    features_from_hit(ray, &object_hit.map(|(_, hit)| hit), object, scene)
+}
+
+fn features_from_hit(ray: &Ray, hit: &Option<Hit>, object: Option<ObjectId>, scene: &Scene) -> Features {
+    let matte_id = object.map(|id| id.matte_id()).unwrap_or(0);
+    match hit {
+        &Some(ref hit) => {
+            let albedo = match hit.material {
+                &Material::Physically(ref parameters) => parameters.reflectivity,
+                &Material::Emissive(radiance) => saturate_vec3(radiance),
+                &Material::Custom(ref bsdf) => saturate_vec3(bsdf.albedo()),
+                _ => Vec3::one(),
+            };
+            Features::new(albedo, hit.normal, hit.parameter, 1.0, matte_id)
+        },
+        &None => Features::new(saturate_vec3(sky_radiance(&scene.sky, ray.direction)), Vec3::zero(), 0.0, 0.0, 0),
+    }
+}
+
+// Adds the features of one sample to the pixel of the backbuffer. The same rules as for
+// accumulate_radiance apply.
+pub fn accumulate_features(backbuffer: &Backbuffer, x: u32, y: u32, features: &Features) {
+    let first_sample = unsafe { *backbuffer.num_samples.get() == 1 };
+    backbuffer.add_features_unsafe(x, y, features, first_sample);
+}
+
+// Adds the radiance and the features of one sample to a pixel. With a transparent
+// background, the radiance of primary rays that see the sky is dropped. Light that is
+// scattered by a medium in front of the sky is lost as well. Without features, the
+// buffers of the features are left as they are.
+fn accumulate_sample(backbuffer: &Backbuffer, scene: &Scene, x: u32, y: u32, hdr_radiance: Vec3, features: Option<&Features>) {
+    let sees_sky = features.map(|features| features.alpha == 0.0).unwrap_or(false);
+    let hdr_radiance = if scene.image_settings.transparent_background && sees_sky { Vec3::zero() } else { hdr_radiance };
+    accumulate_radiance(backbuffer, &scene.camera, x, y, hdr_radiance);
+    if let Some(features) = features {
+        accumulate_features(backbuffer, x, y, features);
+    }
+}
+
+pub fn render(work_tile: WorkTile, backbuffer: &Arc<Backbuffer>, scene: Arc<RwLock<Scene>>, guiding: &GuidingGrid, reservoirs: &ReservoirBuffer) {
+    let scene = scene.read().unwrap(); // @TODO: Handle the unwrap
+
+    let sampler = scene.camera.sample(backbuffer.width(), backbuffer.height(), scene.image_settings.sub_pixel_sampling, scene.image_settings.stereo);
 
     let (x0, x1) = (work_tile.position.x, work_tile.position.x + work_tile.size.x);
     let (y0, y1) = (work_tile.position.y, work_tile.position.y + work_tile.size.y);
 
-    for y in y0..y1 {
-        for x in x0..x1 {
-            let ray = sampler.pinhole_ray(x, y);
-            let meta = Meta::new(Vec2u::new(x, y), 1);
+    if work_tile.integrator == Integrator::Wavefront {
+        render_wavefront(&work_tile, &sampler, backbuffer, &*scene);
+        return;
+    }
+
+    // With a resolution scale larger than one, only the pixel in the center of each block
+    // is traced and its radiance is copied to the whole block. The blocks at the border of
+    // the tile are cut off.
+    let scale = work_tile.resolution_scale as usize;
+    let sample_index = work_tile.sample_index;
+    // The transparent background needs the coverage of the first hit as well.
+    let needs_features = work_tile.features || scene.image_settings.transparent_background;
+
+    for y in (y0..y1).step_by(scale) {
+        for x in (x0..x1).step_by(scale) {
+            let (sample_x, sample_y) = (x + work_tile.resolution_scale/2, y + work_tile.resolution_scale/2);
+            seed_pixel(sample_x, sample_y, sample_index);
+            let (ray, eye_weight) = sampler.camera_ray(sample_x, sample_y);
+            let guiding = if work_tile.integrator == Integrator::GuidedPathTracer { Some(guiding) } else { None };
+            let meta = Meta::new(Vec2u::new(sample_x, sample_y), 1, guiding);
+
+            let hdr_radiance = eye_weight*match work_tile.integrator {
+                // The frames of the GPU integrator are rendered by the GpuRenderer instead.
+                // Should a tile reach this point anyway, the path tracer is used.
+                Integrator::PathTracer | Integrator::GuidedPathTracer | Integrator::Gpu | Integrator::Wavefront => trace_radiance(&meta, &ray, &*scene, work_tile.max_depth),
+                Integrator::Whitted => trace_whitted(&ray, &*scene, work_tile.max_depth, RayKind::Camera, MediumStack::default()),
+                Integrator::FastGI => trace_fast_gi(&Meta::new(Vec2u::new(sample_x, sample_y), sample_index, None), &ray, &*scene, work_tile.max_depth, FAST_GI_BOUNCES, true),
+                Integrator::ReSTIR => trace_restir(&meta, &ray, &*scene, reservoirs, work_tile.max_depth),
+                Integrator::Normals => trace_normals(&ray, &*scene),
+                Integrator::Depth => trace_depth(&ray, &*scene),
+                Integrator::AmbientOcclusion => trace_ambient_occlusion(&ray, &*scene),
+                Integrator::Heatmap => trace_heatmap(&meta, &ray, &*scene, work_tile.max_depth),
+            };
+            let features = if needs_features { Some(surface_features(&ray, &*scene)) } else { None };
+
+            for block_y in y..u32::min(y + work_tile.resolution_scale, y1) {
+                for block_x in x..u32::min(x + work_tile.resolution_scale, x1) {
+                    accumulate_sample(backbuffer, &*scene, block_x, block_y, hdr_radiance, features.as_ref());
+                }
+            }
+        }
+    }
+}
+
+//
+// WAVEFRONT PATH TRACER
+//
+// Computes the same estimator as trace_radiance but instead of following one path after
+// another recursively, all paths of a tile are advanced together in passes over queues:
+//
+//   generate:  primary rays for all pixels of the tile
+//   shadow:    paths that reached the maximum depth are connected to a random light
+//   intersect: the nearest hits for all remaining paths
+//   shade:     the hits either add radiance or spawn the paths of the next bounce
+//
+// Every pass runs the same code for all rays which is more coherent than the recursion and
+// maps directly to kernels on the GPU. The paths are sorted by their direction between the
+// bounces so that neighboring rays traverse the scene similarly.
+//
+// @TODO: Participating media and volumes are not supported yet.
+//
+#[derive(Clone, Debug, new)]
+struct PathState {
+    sample_index: usize,
+    ray: Ray,
+    throughput: Vec3,
+    depth: u8,
+    #[new(default)]
+    channel: Option<usize>, // See dispersion_channel
+    #[new(default)]
+    media: MediumStack, // See the nested dielectrics
+}
+
+impl PathState {
+    // The path after a bounce, in the same color channel and the same media.
+    fn next(&self, ray: Ray, throughput: Vec3, depth: u8) -> PathState {
+        PathState {
+            sample_index: self.sample_index,
+            ray: ray,
+            throughput: throughput,
+            depth: depth,
+            channel: self.channel,
+            media: self.media,
+        }
+    }
+}
+
+#[derive(Clone, Debug, new)]
+struct ShadowRay {
+    sample_index: usize,
+    light_index: usize,
+    ray: Ray,
+    light_distance: f32,
+    contribution: Vec3,
+}
+
+fn direction_octant(direction: Vec3) -> u8 {
+    (if direction.x < 0.0 { 1 } else { 0 }) |
+    (if direction.y < 0.0 { 2 } else { 0 }) |
+    (if direction.z < 0.0 { 4 } else { 0 })
+}
+
+// Connects a path at the maximum depth to a random point on a random light just like
+// trace_radiance does.
+fn generate_shadow_ray(path: &PathState, scene: &Scene) -> Option<ShadowRay> {
+    let num_light_sources = scene.num_lights();
+    if num_light_sources == 0 {
+        return None;
+    }
+    let light_index = xorshift32() as usize % num_light_sources;
+    let light = scene.light(light_index)?;
+    let (sample_point, light_material) = (light.sample(path.ray.origin)?, light.material());
+
+    let light_ray = sample_point - path.ray.origin;
+    let light_distance = light_ray.length();
+    let contribution = path.throughput*emitted_radiance(light_material);
+    Some(ShadowRay::new(path.sample_index, light_index, Ray::new(path.ray.origin, light_ray.normalize()), light_distance, contribution))
+}
+
+fn shade_wavefront(path: &PathState, hit: &Option<Hit>, scene: &Scene, radiance: &mut Vec3, next_paths: &mut Vec<PathState>) {
+    const R: f32 = 0.04;
 
-            let hdr_radiance = trace_radiance(&meta, &ray, &*scene, 4);
-            let ldr_radiance = match scene.camera.tone_mapping {
-                ToneMapping::Clamp => tone_map_clamp(hdr_radiance),
-                ToneMapping::Reinhard => tone_map_reinhard(hdr_radiance),
-                ToneMapping::Exposure(value) => tone_map_exposure(hdr_radiance, value),
+    let ray = &path.ray;
+    let hit = match hit {
+        &Some(ref hit) => hit,
+        &None => {
+            *radiance = *radiance + path.throughput*sky_radiance(&scene.sky, ray.direction);
+            return;
+        },
+    };
+    let outwards_shifted_position = offset_ray_origin(hit.position, hit.normal);
+    let inwards_shifted_position = offset_ray_origin(hit.position, -hit.normal);
+    let depth = path.depth - 1;
+
+    match hit.material {
+        &Material::None => *radiance = *radiance + path.throughput,
+        &Material::Emissive(color) => *radiance = *radiance + path.throughput*color,
+        &Material::Mirror => {
+            let reflection_ray = Ray::new(outwards_shifted_position, reflect(ray.direction, hit.normal).normalize());
+            next_paths.push(path.next(reflection_ray, path.throughput, depth));
+        },
+        &Material::Translucent(ior, dispersion, priority) => {
+            // Both the reflection and the refraction are followed as separate paths. A surface
+            // within a medium of a higher priority is passed straight through.
+            let (channel, channel_weight) = dispersion_channel(path.channel, dispersion);
+            let (media, iors) = path.media.refraction(NestedMedium::of(hit.material, ior, dispersion, priority), hit.transition, channel);
+            let (n1, n2) = match iors {
+                Some(iors) => iors,
+                None => {
+                    let continued_ray = Ray::new(inwards_shifted_position, ray.direction);
+                    next_paths.push(PathState { media: media, ..path.next(continued_ray, path.throughput, path.depth) });
+                    return;
+                },
             };
 
-            let gamma_corrected = gamma_correction(ldr_radiance);
-            let color = Pixel32::from_unit(gamma_corrected);
+            let cos_theta = f32::max(0.0, hit.normal.dot(-ray.direction));
+            let fresnel = fresnel_schlick(cos_theta, Vec3::new(R, R, R));
+
+            let refraction_ray = Ray::new(inwards_shifted_position, refract(ray.direction, hit.normal, n1, n2).normalize());
+            next_paths.push(PathState { channel: channel, media: media, ..path.next(refraction_ray, path.throughput*channel_weight*(Vec3::one() - fresnel), depth) });
+
+            let reflection_ray = Ray::new(outwards_shifted_position, reflect(ray.direction, hit.normal).normalize());
+            next_paths.push(path.next(reflection_ray, path.throughput*fresnel, depth));
+        },
+        &Material::Physically(ref pbr_parameters) => {
+            let tangent_space = construct_coordinate_system(hit.normal);
+            let view = -ray.direction;
+            let normal = hit.normal;
+
+            let PBRParameters{ reflectivity, roughness, metalness } = *pbr_parameters;
+            let f0 = mix_vec3(Vec3::new(R, R, R), reflectivity, metalness);
+
+            let xi = Vec2::new(random32(), random32());
+
+            let specular_reflection = random32() < f0.as_array()[(xorshift32() % 3) as usize];
+            let (light, weight) = if specular_reflection {
+                let h = to_basis(tangent_space, importance_sample_ggx(xi, roughness));
+                let light = reflect(ray.direction, h);
+                (light, PI*brdf_cook_torrance(view, light, normal, roughness, f0))
+            } else {
+                let light = to_basis(tangent_space, importance_sample_cos(xi));
+                (light, PI*brdf_lambert(pbr_parameters)*light.dot(normal))
+            };
+            let light_ray = Ray::new(outwards_shifted_position, light.normalize());
+            next_paths.push(path.next(light_ray, path.throughput*weight, depth));
+        },
+        &Material::Custom(ref bsdf) => {
+            let xi = Vec2::new(random32(), random32());
+            if let Some((light, weight)) = bsdf.sample(-ray.direction, hit.normal, xi) {
+                let position = if light.dot(hit.normal) >= 0.0 { outwards_shifted_position } else { inwards_shifted_position };
+                let light_ray = Ray::new(position, light.normalize());
+                next_paths.push(path.next(light_ray, path.throughput*weight, depth));
+            }
+        },
+    }
+}
+
+fn render_wavefront(work_tile: &WorkTile, sampler: &CameraSampler, backbuffer: &Backbuffer, scene: &Scene) {
+    let (x0, x1) = (work_tile.position.x, work_tile.position.x + work_tile.size.x);
+    let (y0, y1) = (work_tile.position.y, work_tile.position.y + work_tile.size.y);
+    let scale = work_tile.resolution_scale;
+    let needs_features = work_tile.features || scene.image_settings.transparent_background;
+
+    // GENERATE
+    //
+    // The passes draw the random numbers of all paths of the tile from one stream, which is
+    // seeded from the tile instead of the pixels. The tiles are the same in every render.
+    seed_pixel(x0, y0, work_tile.sample_index);
+    let mut samples = Vec::new();
+    for y in (y0..y1).step_by(scale as usize) {
+        for x in (x0..x1).step_by(scale as usize) {
+            samples.push(Vec2u::new(x, y));
+        }
+    }
+    let mut paths: Vec<PathState> = samples.iter().enumerate().map(|(sample_index, block)| {
+        let (ray, eye_weight) = sampler.camera_ray(block.x + scale/2, block.y + scale/2);
+        PathState::new(sample_index, ray, eye_weight, work_tile.max_depth)
+    }).collect();
+    let mut radiance = vec![Vec3::zero(); samples.len()];
+    let mut features = Vec::with_capacity(samples.len());
+
+    let mut next_paths = Vec::with_capacity(paths.len());
+    let mut shadow_rays = Vec::new();
+    while !paths.is_empty() {
+        // SHADOW
+        for path in paths.iter().filter(|path| path.depth == 0) {
+            if let Some(shadow_ray) = generate_shadow_ray(path, scene) {
+                shadow_rays.push(shadow_ray);
+            }
+        }
+        for shadow_ray in shadow_rays.drain(..) {
+            let light = match scene.light(shadow_ray.light_index) {
+                Some(light) => light,
+                None => continue,
+            };
+            let visible = is_light_sample_unblocked(find_visible_hit(&shadow_ray.ray, scene, RayKind::Shadow), light, shadow_ray.light_distance - 0.000001);
+            if visible {
+                radiance[shadow_ray.sample_index] = radiance[shadow_ray.sample_index] + shadow_ray.contribution;
+            }
+        }
+        paths.retain(|path| path.depth > 0);
+
+        // INTERSECT
+        let kind = if features.is_empty() { RayKind::Camera } else { RayKind::Bounce };
+        let (objects, hits): (Vec<Option<ObjectId>>, Vec<Option<Hit>>) = paths.iter().map(|path| {
+            match find_visible_object_hit(&path.ray, scene, kind) {
+                Some((id, hit)) => (Some(id), Some(hit)),
+                None => (None, None),
+            }
+        }).unzip();
+
+        // The primary rays are the first paths in the queue and their hits give the
+        // features for the denoisers and the mattes.
+        if features.is_empty() {
+            features = paths.iter().zip(hits.iter()).zip(objects.into_iter())
+                .map(|((path, hit), object)| features_from_hit(&path.ray, hit, object, scene)).collect();
+        }
+
+        // SHADE
+        for (path, hit) in paths.iter().zip(hits.iter()) {
+            shade_wavefront(path, hit, scene, &mut radiance[path.sample_index], &mut next_paths);
+        }
+
+        next_paths.sort_by_key(|path| direction_octant(path.ray.direction));
+        std::mem::swap(&mut paths, &mut next_paths);
+        next_paths.clear();
+    }
+
+    for (sample_index, block) in samples.iter().enumerate() {
+        for y in block.y..u32::min(block.y + scale, y1) {
+            for x in block.x..u32::min(block.x + scale, x1) {
+                let features = if needs_features { Some(&features[sample_index]) } else { None };
+                accumulate_sample(backbuffer, scene, x, y, radiance[sample_index], features);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Deterministic random numbers, independent from the global generator which is shared
+    // by the tests running in parallel.
+    fn next_vec2(random: &mut XorShift32) -> Vec2 {
+        Vec2::new(random.next_f32(), random.next_f32())
+    }
+
+    const NUM_SAMPLES: usize = 100_000;
+    const NUM_BINS: usize = 10;
+    // Critical value of the chi-square distribution with NUM_BINS - 1 degrees of freedom at
+    // a significance level of 0.001.
+    const CHI_SQUARE_LIMIT: f64 = 27.88;
+
+    fn assert_unit(v: Vec3) {
+        assert!(f32::abs(v.length() - 1.0) < 1.0e-4, "{:?} is not normalized", v);
+    }
+
+    // Integrates the pdf over the bins that split [min, max] evenly with the midpoint rule.
+    fn expected_counts<F: Fn(f64) -> f64>(pdf: F, min: f64, max: f64) -> Vec<f64> {
+        const STEPS_PER_BIN: usize = 10_000;
+        let step = (max - min) / (NUM_BINS*STEPS_PER_BIN) as f64;
+        (0..NUM_BINS).map(|bin| {
+            let integral: f64 = (0..STEPS_PER_BIN).map(|i| pdf(min + ((bin*STEPS_PER_BIN + i) as f64 + 0.5)*step)*step).sum();
+            integral*NUM_SAMPLES as f64
+        }).collect()
+    }
+
+    // Compares the histogram of the values in [min, max] with the expected counts.
+    fn chi_square<I: Iterator<Item = f32>>(values: I, expected: &[f64], min: f32, max: f32) -> f64 {
+        let mut observed = vec![0usize; NUM_BINS];
+        for value in values {
+            let bin = ((value - min) / (max - min)*NUM_BINS as f32) as usize;
+            observed[usize::min(bin, NUM_BINS - 1)] += 1;
+        }
+        observed.iter().zip(expected).map(|(&o, &e)| (o as f64 - e)*(o as f64 - e) / e).sum()
+    }
+
+    #[test]
+    fn coordinate_system_is_orthonormal() {
+        let normals = [
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, -1.0, 0.0),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.6, 0.8, 0.0),
+            Vec3::new(1.0, 2.0, 3.0).normalize(),
+            Vec3::new(0.0, 0.99995, 0.01).normalize(),
+        ];
+        for &normal in normals.iter() {
+            let Basis(x, y, z) = construct_coordinate_system(normal);
+            assert_unit(x);
+            assert_unit(y);
+            assert_unit(z);
+            assert!(f32::abs(x.dot(y)) < 1.0e-4 && f32::abs(y.dot(z)) < 1.0e-4 && f32::abs(z.dot(x)) < 1.0e-4, "basis of {:?} is not orthogonal", normal);
+            assert!((y - normal).length() < 1.0e-6);
+            assert!((to_basis(construct_coordinate_system(normal), Vec3::new(0.0, 1.0, 0.0)) - normal).length() < 1.0e-4);
+        }
+    }
+
+    #[test]
+    fn cosine_sampling_matches_its_pdf() {
+        // The pdf of cos(theta) is 2*cos(theta) for a cosine-weighted hemisphere.
+        let mut random = XorShift32::new(12345);
+        let samples: Vec<Vec3> = (0..NUM_SAMPLES).map(|_| importance_sample_cos(next_vec2(&mut random))).collect();
+        for sample in samples.iter() {
+            assert_unit(*sample);
+            assert!(sample.y >= 0.0);
+        }
+        let expected = expected_counts(|cos_theta| 2.0*cos_theta, 0.0, 1.0);
+        let chi_square = chi_square(samples.iter().map(|sample| sample.y), &expected, 0.0, 1.0);
+        assert!(chi_square < CHI_SQUARE_LIMIT, "chi-square = {}", chi_square);
+    }
+
+    fn ggx_pdf(cos_theta: f64, alpha: f32) -> f64 {
+        // The half vectors are distributed with D(h)*cos(theta_h). Integrated over phi, the
+        // solid angle contributes 2*PI per unit of cos(theta).
+        let sin_theta = f64::sqrt(f64::max(0.0, 1.0 - cos_theta*cos_theta));
+        let half = Vec3::new(sin_theta as f32, cos_theta as f32, 0.0);
+        2.0*std::f64::consts::PI*normal_distribution_ggx(Vec3::new(0.0, 1.0, 0.0), half, alpha) as f64*cos_theta
+    }
+
+    #[test]
+    fn ggx_distribution_is_normalized() {
+        for &roughness in [0.3f32, 0.6, 1.0].iter() {
+            let alpha = roughness*roughness;
+            let integral: f64 = expected_counts(|cos_theta| ggx_pdf(cos_theta, alpha), 0.0, 1.0).iter().sum::<f64>() / NUM_SAMPLES as f64;
+            assert!(f64::abs(integral - 1.0) < 1.0e-3, "roughness = {}, integral = {}", roughness, integral);
+        }
+    }
+
+    #[test]
+    fn ggx_sampling_matches_its_pdf() {
+        for &roughness in [0.5f32, 1.0].iter() {
+            let mut random = XorShift32::new(54321);
+            let samples: Vec<Vec3> = (0..NUM_SAMPLES).map(|_| importance_sample_ggx(next_vec2(&mut random), roughness)).collect();
+            for sample in samples.iter() {
+                assert_unit(*sample);
+            }
+            let alpha = roughness*roughness;
+            let expected = expected_counts(|cos_theta| ggx_pdf(cos_theta, alpha), 0.0, 1.0);
+            let chi_square = chi_square(samples.iter().map(|sample| sample.y), &expected, 0.0, 1.0);
+            assert!(chi_square < CHI_SQUARE_LIMIT, "roughness = {}, chi-square = {}", roughness, chi_square);
+        }
+    }
+
+    #[test]
+    fn henyey_greenstein_sampling_matches_its_pdf() {
+        let direction = Vec3::new(1.0, 2.0, 3.0).normalize();
+        for &g in [-0.5f32, 0.0, 0.7].iter() {
+            let mut random = XorShift32::new(67890);
+            let cos_thetas: Vec<f32> = (0..NUM_SAMPLES).map(|_| {
+                let scattered = sample_henyey_greenstein(direction, g, next_vec2(&mut random));
+                assert_unit(scattered);
+                scattered.dot(direction)
+            }).collect();
+
+            // The mean cosine of the phase function is its anisotropy.
+            let mean = cos_thetas.iter().map(|&cos_theta| cos_theta as f64).sum::<f64>() / NUM_SAMPLES as f64;
+            assert!(f64::abs(mean - g as f64) < 0.01, "g = {}, mean cosine = {}", g, mean);
+
+            let g = g as f64;
+            let pdf = |cos_theta: f64| 0.5*(1.0 - g*g) / f64::powf(1.0 + g*g - 2.0*g*cos_theta, 1.5);
+            let expected = expected_counts(pdf, -1.0, 1.0);
+            let chi_square = chi_square(cos_thetas.into_iter(), &expected, -1.0, 1.0);
+            assert!(chi_square < CHI_SQUARE_LIMIT, "g = {}, chi-square = {}", g, chi_square);
+        }
+    }
+
+    #[test]
+    fn dispersion_refracts_blue_the_most() {
+        let (ior, dispersion) = (1.5, 0.01);
+        assert_eq!(cauchy_ior(ior, dispersion, 1), ior);
+        assert!(cauchy_ior(ior, dispersion, 0) < ior);
+        assert!(cauchy_ior(ior, dispersion, 2) > ior);
+
+        // The blue ray is bent further towards the normal when entering the glass.
+        let incoming = Vec3::new(1.0, -1.0, 0.0).normalize();
+        let normal = Vec3::new(0.0, 1.0, 0.0);
+        let red = refract(incoming, normal, 1.0, cauchy_ior(ior, dispersion, 0));
+        let blue = refract(incoming, normal, 1.0, cauchy_ior(ior, dispersion, 2));
+        assert!(blue.dot(-normal) > red.dot(-normal));
+
+        // Without the dispersion, the path keeps all channels.
+        assert_eq!(dispersion_channel(None, 0.0), (None, Vec3::one()));
+        let (channel, weight) = dispersion_channel(None, dispersion);
+        let channel = channel.unwrap();
+        assert_eq!(weight.as_array()[channel], 3.0);
+        assert_eq!(weight.x + weight.y + weight.z, 3.0);
+        assert_eq!(dispersion_channel(Some(channel), dispersion), (Some(channel), Vec3::one()));
+    }
+
+    #[test]
+    fn stereo_eyes_converge_at_the_convergence_distance() {
+        let camera = Camera::new(Vec3::zero(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 4.0, 2.0, 1.0, ToneMapping::Clamp, 100.0);
+        let stereo = Stereo::new(StereoLayout::SideBySide, 0.2, 6.0);
+        let sampler = camera.sample(128, 32, false, Some(stereo));
+        let on_convergence_plane = |ray: &Ray| ray.origin + ((-6.0 - ray.origin.z) / ray.direction.z)*ray.direction;
+        for &(x, y) in [(32, 16), (5, 3), (60, 30)].iter() {
+            let (left, _) = sampler.camera_ray(x, y);
+            let (right, _) = sampler.camera_ray(x + 64, y);
+            assert!(f32::abs(left.origin.z + 1.0) < 1e-5 && f32::abs(right.origin.z + 1.0) < 1e-5);
+            assert!(right.origin.x > left.origin.x);
+            assert!((on_convergence_plane(&left) - on_convergence_plane(&right)).length() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn both_eyes_cover_the_whole_plane_for_odd_widths() {
+        let camera = Camera::new(Vec3::zero(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 4.0, 2.0, 1.0, ToneMapping::Clamp, 100.0);
+        let stereo = Stereo::new(StereoLayout::SideBySide, 0.0, 6.0);
+        let sampler = camera.sample(5, 1, false, Some(stereo));
+        // Two columns for the left eye and three for the right one, each starting at the left
+        // edge of the plane.
+        for &(x, eye_x, eye_width) in [(0, 0.0, 2.0), (1, 1.0, 2.0), (2, 0.0, 3.0), (4, 2.0, 3.0)].iter() {
+            let (ray, _) = sampler.camera_ray(x, 0);
+            let expected = camera.pixel_ray(5.0*eye_x/eye_width, 0.0, 5, 1);
+            assert!((ray.direction - expected.direction).length() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn mattes_are_sorted_by_coverage() {
+        let mut matte: Matte = [(0, 0.0); MATTE_RANKS];
+        for &matte_id in [7, 3, 3, 0, 3, 7, 9].iter() {
+            add_to_matte(&mut matte, matte_id);
+        }
+        assert_eq!(&matte[..4], &[(3, 3.0), (7, 2.0), (9, 1.0), (0, 0.0)]);
+
+        // Objects beyond the last rank are dropped.
+        for matte_id in 10..20 {
+            add_to_matte(&mut matte, matte_id);
+        }
+        assert_eq!(matte[MATTE_RANKS - 1], (12, 1.0));
+    }
+
+    #[test]
+    fn relative_error_vanishes_for_constant_samples() {
+        let camera = Camera::new(Vec3::zero(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 4.0, 2.0, 1.0, ToneMapping::Clamp, 100.0);
+        let backbuffer = Backbuffer::new(2, 1);
+        for sample_index in 0..4 {
+            if sample_index > 0 {
+                unsafe {
+                    *backbuffer.num_samples.get() += 1;
+                }
+            }
+            accumulate_radiance(&backbuffer, &camera, 0, 0, Vec3::new(0.5, 0.5, 0.5));
+            accumulate_radiance(&backbuffer, &camera, 1, 0, Vec3::one()*(sample_index % 2) as f32);
+            if sample_index == 0 {
+                assert_eq!(backbuffer.relative_error(0, 0, 1), std::f32::INFINITY);
+            }
+        }
+        assert!(backbuffer.relative_error(0, 0, 4) < 1.0e-4);
+        // Two of four samples are one: the mean is 0.5 with a standard error of 1/sqrt(12).
+        assert!(f32::abs(backbuffer.relative_error(1, 0, 4) - 2.0/f32::sqrt(12.0)) < 1.0e-4);
+    }
+
+    #[test]
+    fn dropped_samples_do_not_darken_the_pixel() {
+        let camera = Camera::new(Vec3::zero(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 4.0, 2.0, 1.0, ToneMapping::Clamp, 100.0);
+        let backbuffer = Backbuffer::new(1, 1);
+        for sample_index in 0..4 {
+            if sample_index > 0 {
+                unsafe {
+                    *backbuffer.num_samples.get() += 1;
+                }
+            }
+            let radiance = if sample_index == 2 { Vec3::new(std::f32::NAN, 0.0, 0.0) } else { Vec3::new(0.5, 0.5, 0.5) };
+            accumulate_radiance(&backbuffer, &camera, 0, 0, radiance);
+        }
+        assert_eq!(backbuffer.valid_samples(0, 4), 3);
+        assert!(f32::abs(backbuffer.mean_radiance(0, 4).x - 0.5) < 1.0e-6);
+        assert!(backbuffer.relative_error(0, 0, 4) < 1.0e-4);
+    }
+
+    #[test]
+    fn orbits_over_the_pole_keep_a_valid_orientation() {
+        let mut camera = Camera::new(Vec3::zero(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 4.0, 2.0, 1.0, ToneMapping::Clamp, 100.0);
+        for &angle in [0.0, 1.0, 2.5].iter() {
+            camera.orbit(Vec3::new(0.0, 10.0, 0.0), Vec3::zero(), angle);
+            let (_, u, v) = camera.projection_plane();
+            assert!(u.cross(v).length() > 1.0, "{:?} {:?}", u, v);
+            assert!((camera.forward() - Vec3::new(0.0, -1.0, 0.0)).length() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn auto_frame_brings_the_scene_into_view() {
+        let mut camera = Camera::new(Vec3::zero(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 4.0, 2.0, 1.0, ToneMapping::Clamp, 100.0);
+        let mut bounds = Bounds::empty();
+        bounds.extend(Vec3::new(20.0, -1.0, 9.0));
+        bounds.extend(Vec3::new(24.0, 3.0, 13.0));
+        let (center, radius) = bounds.bounding_sphere();
+        assert!(!camera.sees_sphere(center, radius));
+
+        camera.auto_frame(&bounds);
+        assert!(camera.sees_sphere(center, radius));
+        assert!((camera.forward() - Vec3::new(0.0, 0.0, -1.0)).length() < 1e-5);
+        // The sphere touches the top and the bottom of the image.
+        let distance = (center - camera.position()).length();
+        assert!(f32::abs(distance*f32::sin(camera.vertical_fov()*PI/360.0) - radius) < 1e-3);
+        assert!(!camera.sees_sphere(center + Vec3::new(0.0, 3.0*radius, 0.0), 0.5*radius));
+    }
+
+    #[test]
+    fn focus_on_measures_along_the_direction_of_view() {
+        let mut camera = Camera::new(Vec3::zero(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 4.0, 2.0, 1.0, ToneMapping::Clamp, 100.0);
+        assert_eq!(camera.focus_on(Vec3::new(0.0, 0.0, -5.0)), None);
+
+        camera.set_depth_of_field(Some(DepthOfField::new(0.1, 10.0)));
+        assert_eq!(camera.focus_on(Vec3::new(3.0, 1.0, -5.0)), Some(5.0));
+        assert_eq!(camera.depth_of_field(), Some(DepthOfField::new(0.1, 5.0)));
+        // Points behind the camera keep the focus.
+        assert_eq!(camera.focus_on(Vec3::new(0.0, 0.0, 2.0)), None);
+        assert_eq!(camera.depth_of_field().unwrap().focus_distance, 5.0);
+    }
+
+    #[test]
+    fn color_grading_is_neutral_by_default() {
+        let mut camera = Camera::new(Vec3::zero(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 4.0, 2.0, 1.0, ToneMapping::Reinhard, 100.0);
+        let radiance = Vec3::new(0.2, 0.5, 0.1);
+        let gains = camera.color_grading().white_balance_gains();
+        assert!((gains - Vec3::one()).length() < 1e-5);
+        let neutral = tone_map(radiance, &camera);
+
+        // One stop more exposure is the same as twice the ISO.
+        camera.set_color_grading(ColorGrading::new(1.0, NEUTRAL_WHITE_BALANCE, 1.0));
+        let exposed = tone_map(radiance, &camera);
+        let iso_camera = Camera::new(Vec3::zero(), Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0), 4.0, 2.0, 1.0, ToneMapping::Reinhard, 200.0);
+        assert!((exposed - tone_map(radiance, &iso_camera)).length() < 1e-5);
+        assert!(exposed.y > neutral.y);
+
+        // Without saturation the channels are equal.
+        camera.set_color_grading(ColorGrading::new(0.0, NEUTRAL_WHITE_BALANCE, 0.0));
+        let gray = tone_map(radiance, &camera);
+        assert!(f32::abs(gray.x - gray.y) < 1e-5 && f32::abs(gray.y - gray.z) < 1e-5);
+
+        // Balancing for warm light makes the image cooler.
+        camera.set_color_grading(ColorGrading::new(0.0, 3200.0, 1.0));
+        let balanced = tone_map(radiance, &camera);
+        assert!(balanced.z > neutral.z && balanced.x < neutral.x);
+    }
+
+    fn render_tiles(scene: &Arc<RwLock<Scene>>, reverse: bool) -> Vec<Vec3> {
+        use tiles;
+        let backbuffer = Arc::new(Backbuffer::new(16, 16));
+        let guiding = GuidingGrid::new();
+        let reservoirs = ReservoirBuffer::new(16, 16);
+        for sample_index in 0..2 {
+            if sample_index > 0 {
+                unsafe {
+                    *backbuffer.num_samples.get() += 1;
+                }
+            }
+            let num_samples = unsafe { *backbuffer.num_samples.get() };
+            let mut work_tiles = tiles::work_tiles(tiles::TileOrder::Scanline, 16, 16, None, 8, Integrator::PathTracer, 4, 0, num_samples, 1, true);
+            if reverse {
+                work_tiles.reverse();
+            }
+            for work_tile in work_tiles {
+                render(work_tile, &backbuffer, scene.clone(), &guiding, &reservoirs);
+            }
+        }
+        unsafe { (*backbuffer.radiance.get()).clone() }
+    }
+
+    #[test]
+    fn renders_do_not_depend_on_the_thread_or_the_order_of_the_tiles() {
+        use scene::Sphere;
+        use builder;
+        let mut scene = Scene::default();
+        scene.set_resolution(16, 16);
+        scene.add_sphere(Sphere::new(Vec3::zero(), 3.0, builder::diffuse(Vec3::new(0.8, 0.5, 0.2))));
+        let scene = Arc::new(RwLock::new(scene));
+
+        let forward = render_tiles(&scene, false);
+        let scene2 = scene.clone();
+        let backward = std::thread::spawn(move || render_tiles(&scene2, true)).join().unwrap();
+        assert!(forward == backward);
+        // The sphere is in view, so the image is not just the constant sky.
+        assert!(forward.iter().any(|radiance| radiance.x != forward[0].x));
+    }
 
-            backbuffer.add_pixel32_unsafe(x, y, color);
-            backbuffer.assign_pixel8_unsafe(x, y, iso_factor);
+    #[test]
+    fn recorded_paths_start_at_the_camera_and_hit_the_sphere() {
+        use scene::Sphere;
+        use builder;
+        let mut scene = Scene::default();
+        scene.set_resolution(16, 16);
+        scene.add_sphere(Sphere::new(Vec3::zero(), 3.0, builder::diffuse(Vec3::new(0.8, 0.5, 0.2))));
+
+        let segments = record_pixel_paths(&scene, 4, 8, 8);
+        let primary: Vec<&RaySegment> = segments.iter().filter(|segment| segment.bounce == 0).collect();
+        assert_eq!(primary.len(), NUM_DEBUG_SAMPLES);
+        for segment in primary {
+            assert_eq!(segment.kind, SegmentKind::Path);
+            assert!(f32::abs(segment.end.length() - 3.0) < 1e-3, "{:?} is not on the sphere", segment.end);
         }
+        assert!(RECORDED_SEGMENTS.with(|segments| segments.borrow().is_empty()));
+        assert!(record_pixel_paths(&scene, 4, 16, 0).is_empty());
+    }
+
+    #[test]
+    fn fast_gi_of_a_diffuse_sphere_under_a_white_sky_is_its_albedo() {
+        use scene::Sphere;
+        use builder;
+        // The sphere is convex, so every bounce leaves into the sky.
+        let albedo = Vec3::new(0.8, 0.5, 0.2);
+        let mut scene = Scene::default();
+        scene.add_sphere(Sphere::new(Vec3::zero(), 3.0, builder::diffuse(albedo)));
+        let ray = Ray::new(Vec3::new(0.0, 0.0, 10.0), Vec3::new(0.0, 0.0, -1.0));
+        for sample_index in 1..8 {
+            let radiance = trace_fast_gi(&Meta::new(Vec2u::new(3, 5), sample_index, None), &ray, &scene, 4, FAST_GI_BOUNCES, true);
+            assert!((radiance - albedo).length() < 1e-4, "{:?} != {:?}", radiance, albedo);
+        }
+    }
+
+    #[test]
+    fn liquid_in_a_glass_is_refracted_at_the_boundaries_of_the_higher_priority() {
+        let glass = NestedMedium::new(1, 1.5, 0.0, 1);
+        let liquid = NestedMedium::new(2, 1.33, 0.0, 0);
+
+        // From the air into the glass and on into the liquid, whose surface inside of the
+        // glass is passed.
+        let (in_glass, is_boundary) = MediumStack::default().cross(glass, Transition::In);
+        assert!(is_boundary);
+        assert_eq!(in_glass.current_ior(None), 1.5);
+        let (in_both, is_boundary) = in_glass.cross(liquid, Transition::In);
+        assert!(!is_boundary);
+        assert_eq!(in_both.current_ior(None), 1.5);
+        let (in_liquid, is_boundary) = in_both.cross(glass, Transition::Out);
+        assert!(is_boundary);
+        assert_eq!(in_liquid.current_ior(None), 1.33);
+
+        // Back through the glass into the air.
+        let (in_both, is_boundary) = in_liquid.cross(glass, Transition::In);
+        assert!(is_boundary);
+        assert_eq!(in_both.current_ior(None), 1.5);
+        let (in_glass, is_boundary) = in_both.cross(liquid, Transition::Out);
+        assert!(!is_boundary);
+        let (in_air, is_boundary) = in_glass.cross(glass, Transition::Out);
+        assert!(is_boundary);
+        assert_eq!(in_air.current_ior(None), IOR_AIR);
+    }
+
+    #[test]
+    fn the_glass_refracts_against_the_liquid_instead_of_the_air() {
+        let glass = NestedMedium::new(1, 1.5, 0.0, 1);
+        let liquid = NestedMedium::new(2, 1.33, 0.0, 0);
+        let (in_glass, iors) = MediumStack::default().refraction(glass, Transition::In, None);
+        assert_eq!(iors, Some((IOR_AIR, 1.5)));
+        let (in_both, iors) = in_glass.refraction(liquid, Transition::In, None);
+        assert_eq!(iors, None);
+        let (in_liquid, iors) = in_both.refraction(glass, Transition::Out, None);
+        assert_eq!(iors, Some((1.5, 1.33)));
+        let (_, iors) = in_liquid.refraction(glass, Transition::In, None);
+        assert_eq!(iors, Some((1.33, 1.5)));
+    }
+
+    #[test]
+    fn whitted_passes_the_surfaces_of_a_liquid_inside_of_the_glass() {
+        use scene::Sphere;
+        let mut scene = Scene::default();
+        scene.add_sphere(Sphere::new(Vec3::zero(), 1.0, Material::Translucent(1.5, 0.0, 1)));
+        scene.add_sphere(Sphere::new(Vec3::new(0.5, 0.0, -4.0), 1.0, Material::Emissive(Vec3::new(4.0, 2.0, 1.0))));
+        let rays: Vec<Ray> = [0.0, 0.2, 0.4, 0.6].iter()
+            .map(|&x| Ray::new(Vec3::new(x, 0.1, 10.0), Vec3::new(0.0, 0.0, -1.0)))
+            .collect();
+        let glass: Vec<Vec3> = rays.iter().map(|ray| trace_whitted(ray, &scene, 8, RayKind::Camera, MediumStack::default())).collect();
+
+        // The liquid has the lower priority and is completely inside of the glass, so none of
+        // its surfaces is a boundary.
+        scene.add_sphere(Sphere::new(Vec3::zero(), 0.5, Material::Translucent(1.33, 0.0, 0)));
+        for (ray, glass) in rays.iter().zip(glass.iter()) {
+            let with_liquid = trace_whitted(ray, &scene, 8, RayKind::Camera, MediumStack::default());
+            assert!((with_liquid - *glass).length() < 1e-4, "{:?} != {:?}", with_liquid, glass);
+        }
+    }
+
+    #[test]
+    fn lights_hidden_from_the_shadows_do_not_block_the_other_lights() {
+        use scene::{Sphere, Visibility};
+        let mut scene = Scene::default();
+        let hidden = Visibility { shadows: false, ..Visibility::default() };
+        scene.add_sphere(Sphere { visibility: hidden, ..Sphere::new(Vec3::new(0.0, 2.0, 0.0), 0.5, Material::Emissive(Vec3::one())) });
+        scene.add_sphere(Sphere::new(Vec3::new(0.0, 4.0, 0.0), 0.5, Material::Emissive(Vec3::one())));
+
+        // The shadow rays pass the hidden light, also the ones that are connected to it.
+        {
+            let (near, far) = (&scene.emissive_spheres[0], &scene.emissive_spheres[1]);
+            assert!(is_light_visible(Vec3::zero(), near.origin, &near.material, &scene));
+            assert!(is_light_visible(Vec3::zero(), far.origin, &far.material, &scene));
+        }
+
+        scene.emissive_spheres[0].visibility = Visibility::default();
+        let (near, far) = (&scene.emissive_spheres[0], &scene.emissive_spheres[1]);
+        assert!(is_light_visible(Vec3::zero(), near.origin, &near.material, &scene));
+        assert!(!is_light_visible(Vec3::zero(), far.origin, &far.material, &scene));
+    }
+
+    #[test]
+    fn spherical_lights_block_the_samples_on_their_back() {
+        use scene::{Sphere, Visibility};
+        let mut scene = Scene::default();
+        scene.add_sphere(Sphere::new(Vec3::new(0.0, 2.0, 0.0), 0.5, Material::Emissive(Vec3::one())));
+        let shadow_ray = |point: Vec3, scene: &Scene| {
+            let ray = Ray::new(Vec3::zero(), point.normalize());
+            is_light_sample_unblocked(find_visible_hit(&ray, scene, RayKind::Shadow), scene.light(0).unwrap(), point.length()*(1.0 - SHADOW_EPSILON))
+        };
+        assert!(shadow_ray(Vec3::new(0.0, 1.5, 0.0), &scene));
+        assert!(!shadow_ray(Vec3::new(0.0, 2.5, 0.0), &scene));
+
+        // A hidden light is not hit and lights the scene from all of its samples.
+        scene.emissive_spheres[0].visibility = Visibility { shadows: false, ..Visibility::default() };
+        assert!(shadow_ray(Vec3::new(0.0, 2.5, 0.0), &scene));
     }
 }
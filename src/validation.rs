@@ -0,0 +1,251 @@
+use std::fmt;
+
+use common::*;
+
+use scene::{Scene, Material, ObjectId, Transform};
+use tracer::MAX_NESTED_MEDIA;
+
+// Checks a loaded scene for values that cannot be rendered, e.g. spheres without a radius,
+// planes whose edges are parallel or animations of objects that do not exist. Such scenes
+// would otherwise render NaNs or panic in the middle of a frame. All problems are collected
+// so that they can be fixed at once.
+
+// The part of the scene a problem was found in. The parser uses it to find the position in
+// the scene file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Subject {
+    Image,
+    Camera,
+    Medium,
+    Object(ObjectId),
+    Animation,
+}
+
+// Names the part like the scene file, e.g. "sphere 0", for the formats without positions.
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Subject::Image => write!(f, "image"),
+            Subject::Camera => write!(f, "camera"),
+            Subject::Medium => write!(f, "medium"),
+            Subject::Object(id) => write!(f, "{}", id),
+            Subject::Animation => write!(f, "animation"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, new)]
+pub struct Problem {
+    pub subject: Subject,
+    pub message: String,
+}
+
+fn is_finite(v: Vec3) -> bool {
+    v.x.is_finite() && v.y.is_finite() && v.z.is_finite()
+}
+
+fn is_non_negative(v: Vec3) -> bool {
+    v.x >= 0.0 && v.y >= 0.0 && v.z >= 0.0
+}
+
+fn check_material(material: &Material) -> Option<String> {
+    match *material {
+        Material::None | Material::Mirror => None,
+        Material::Emissive(radiance) => {
+            if !is_finite(radiance) || !is_non_negative(radiance) {
+                Some(format!("The radiance {:?} has to be finite and not negative.", radiance))
+            } else {
+                None
+            }
+        },
+        Material::Translucent(ior, dispersion, _) => {
+            if !(ior > 0.0) || !ior.is_finite() {
+                Some(format!("The index of refraction {} has to be larger than zero.", ior))
+            } else if !(dispersion >= 0.0) || !dispersion.is_finite() {
+                Some(format!("The dispersion {} has to be finite and not negative.", dispersion))
+            } else if !(cauchy_ior(ior, dispersion, 0) > 0.0) {
+                Some(format!("The dispersion {} is too large for the index of refraction {}, which becomes negative for red.", dispersion, ior))
+            } else {
+                None
+            }
+        },
+        Material::Physically(ref p) => {
+            if !is_finite(p.reflectivity) || !is_non_negative(p.reflectivity) {
+                Some(format!("The reflectivity {:?} has to be finite and not negative.", p.reflectivity))
+            } else if !(p.roughness >= 0.0 && p.roughness <= 1.0) {
+                Some(format!("The roughness {} has to be between 0 and 1.", p.roughness))
+            } else if !(p.metalness >= 0.0 && p.metalness <= 1.0) {
+                Some(format!("The metalness {} has to be between 0 and 1.", p.metalness))
+            } else {
+                None
+            }
+        },
+        // The parameters are checked by the factory of the material.
+        Material::Custom(ref bsdf) => {
+            let albedo = bsdf.albedo();
+            if !is_finite(albedo) || !is_non_negative(albedo) {
+                Some(format!("The albedo {:?} of the custom material has to be finite and not negative.", albedo))
+            } else {
+                None
+            }
+        },
+    }
+}
+
+fn check_transform(transform: &Transform) -> Option<String> {
+    if !is_finite(transform.translation) || !is_finite(transform.rotation_axis) || !transform.rotation_angle.is_finite() || !transform.scale.is_finite() {
+        Some(String::from("The transform contains a value that is not a number or infinite."))
+    } else if !(transform.scale > 0.0) {
+        Some(format!("The scale {} of the transform has to be larger than zero.", transform.scale))
+    } else if transform.rotation_angle != 0.0 && transform.rotation_axis.length() == 0.0 {
+        Some(String::from("The rotation axis of the transform must not be zero."))
+    } else {
+        None
+    }
+}
+
+fn object_exists(scene: &Scene, id: ObjectId) -> bool {
+    match id {
+        ObjectId::Sphere(index) => index < scene.spheres.len(),
+        ObjectId::Plane(index) => index < scene.planes.len(),
+        ObjectId::Volume(index) => index < scene.volumes.len(),
+        ObjectId::EmissiveSphere(index) => index < scene.emissive_spheres.len(),
+        ObjectId::EmissivePlane(index) => index < scene.emissive_planes.len(),
+        ObjectId::Node(index) => index < scene.nodes.len(),
+        ObjectId::Custom(index) => index < scene.custom_primitives.len(),
+    }
+}
+
+pub fn validate(scene: &Scene) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    {
+        let mut report = |subject: Subject, message: String| problems.push(Problem::new(subject, message));
+
+        // IMAGE AND CAMERA
+        if scene.image_settings.width == 0 || scene.image_settings.height == 0 {
+            report(Subject::Image, format!("The image has to be at least one pixel wide and high but is {}x{}.", scene.image_settings.width, scene.image_settings.height));
+        }
+        if let Some(stereo) = scene.image_settings.stereo {
+            if !(stereo.interocular_distance >= 0.0) || !stereo.interocular_distance.is_finite() {
+                report(Subject::Image, format!("The interocular distance {} has to be finite and not negative.", stereo.interocular_distance));
+            }
+            if !(stereo.convergence_distance > 0.0) || !stereo.convergence_distance.is_finite() {
+                report(Subject::Image, format!("The convergence distance {} has to be finite and larger than zero.", stereo.convergence_distance));
+            }
+        }
+        let (plane_origin, plane_u, plane_v) = scene.camera.projection_plane();
+        if !is_finite(plane_origin) || !is_finite(plane_u) || !is_finite(plane_v) || plane_u.cross(plane_v).length() == 0.0 {
+            report(Subject::Camera, String::from("The camera has no valid orientation. The position and the target must differ, the up vector must not point into the direction of view and the size has to be larger than zero."));
+        }
+        if let Some(depth_of_field) = scene.camera.depth_of_field() {
+            if !(depth_of_field.aperture >= 0.0) || !depth_of_field.aperture.is_finite() {
+                report(Subject::Camera, format!("The aperture {} has to be finite and not negative.", depth_of_field.aperture));
+            }
+            if !(depth_of_field.focus_distance > 0.0) || !depth_of_field.focus_distance.is_finite() {
+                report(Subject::Camera, format!("The focus distance {} has to be finite and larger than zero.", depth_of_field.focus_distance));
+            }
+        }
+
+        // MEDIUM
+        if let Some(ref medium) = scene.medium {
+            if !is_finite(medium.sigma_a) || !is_finite(medium.sigma_s) || !is_non_negative(medium.sigma_a) || !is_non_negative(medium.sigma_s) {
+                report(Subject::Medium, String::from("The coefficients of the medium have to be finite and not negative."));
+            }
+            if !(medium.anisotropy > -1.0 && medium.anisotropy < 1.0) {
+                report(Subject::Medium, format!("The anisotropy {} of the medium has to be between -1 and 1 (exclusive).", medium.anisotropy));
+            }
+        }
+
+        // OBJECTS
+        let spheres = scene.spheres.iter().enumerate().map(|(index, sphere)| (ObjectId::Sphere(index), sphere))
+            .chain(scene.emissive_spheres.iter().enumerate().map(|(index, sphere)| (ObjectId::EmissiveSphere(index), sphere)));
+        for (id, sphere) in spheres {
+            if !is_finite(sphere.origin) {
+                report(Subject::Object(id), String::from("The origin of the sphere is not a number or infinite."));
+            }
+            if !(sphere.radius > 0.0) || !sphere.radius.is_finite() {
+                report(Subject::Object(id), format!("The radius {} of the sphere has to be larger than zero.", sphere.radius));
+            }
+            if let Some(message) = check_material(&sphere.material) {
+                report(Subject::Object(id), message);
+            }
+        }
+
+        let planes = scene.planes.iter().enumerate().map(|(index, plane)| (ObjectId::Plane(index), plane))
+            .chain(scene.emissive_planes.iter().enumerate().map(|(index, plane)| (ObjectId::EmissivePlane(index), plane)));
+        for (id, plane) in planes {
+            if !is_finite(plane.origin) || !is_finite(plane.u) || !is_finite(plane.v) {
+                report(Subject::Object(id), String::from("The plane contains a value that is not a number or infinite."));
+            } else if plane.u.cross(plane.v).length() == 0.0 {
+                report(Subject::Object(id), String::from("The plane is degenerate. Its edges u and v must not be zero or parallel."));
+            }
+            if let Some(message) = check_material(&plane.material) {
+                report(Subject::Object(id), message);
+            }
+        }
+
+        for (index, volume) in scene.volumes.iter().enumerate() {
+            let id = ObjectId::Volume(index);
+            if !is_finite(volume.min) || !is_finite(volume.max) {
+                report(Subject::Object(id), String::from("The bounds of the volume are not a number or infinite."));
+            } else if !(volume.min.x < volume.max.x && volume.min.y < volume.max.y && volume.min.z < volume.max.z) {
+                report(Subject::Object(id), format!("The minimum {:?} of the volume has to be less than the maximum {:?} in every dimension.", volume.min, volume.max));
+            }
+            if !(volume.density_scale >= 0.0) || !volume.density_scale.is_finite() {
+                report(Subject::Object(id), format!("The density scale {} of the volume must not be negative.", volume.density_scale));
+            }
+            if !(volume.anisotropy > -1.0 && volume.anisotropy < 1.0) {
+                report(Subject::Object(id), format!("The anisotropy {} of the volume has to be between -1 and 1 (exclusive).", volume.anisotropy));
+            }
+        }
+
+        for (index, custom) in scene.custom_primitives.iter().enumerate() {
+            if let Some(message) = check_material(&custom.material) {
+                report(Subject::Object(ObjectId::Custom(index)), message);
+            }
+        }
+
+        for (index, node) in scene.nodes.iter().enumerate() {
+            if let Some(message) = check_transform(&node.transform) {
+                report(Subject::Object(ObjectId::Node(index)), message);
+            }
+        }
+
+        // ANIMATION
+        if let Some(ref animation) = scene.animation {
+            for keyframe in animation.keyframes() {
+                for &(id, ref transform) in keyframe.transforms.iter() {
+                    if !object_exists(scene, id) {
+                        report(Subject::Animation, format!("The key of frame {} animates {} which does not exist.", keyframe.frame, id));
+                    } else if let ObjectId::Custom(_) = id {
+                        report(Subject::Animation, format!("The key of frame {} animates {} but custom primitives cannot be animated.", keyframe.frame, id));
+                    } else if let Some(message) = check_transform(transform) {
+                        report(Subject::Animation, format!("The key of frame {} for {}: {}", keyframe.frame, id, message));
+                    }
+                }
+            }
+        }
+    }
+    problems
+}
+
+// Finds what can be rendered but not as the scene describes it. The parser logs these as
+// warnings instead of rejecting the scene.
+pub fn warnings(scene: &Scene) -> Vec<Problem> {
+    let mut warnings = Vec::new();
+
+    // The paths only remember MAX_NESTED_MEDIA dielectrics at a time (see the nested
+    // dielectrics in tracer.rs). Dielectric spheres that overlap more of the others can be
+    // entered one after the other, and the innermost ones are then seen as air.
+    let is_dielectric = |material: &Material| match *material { Material::Translucent(..) => true, _ => false };
+    let dielectrics: Vec<_> = scene.spheres.iter().enumerate().filter(|&(_, sphere)| is_dielectric(&sphere.material)).collect();
+    for &(index, sphere) in dielectrics.iter() {
+        let num_overlapping = dielectrics.iter()
+            .filter(|&&(_, other)| (other.origin - sphere.origin).length() < other.radius + sphere.radius)
+            .count();
+        if num_overlapping > MAX_NESTED_MEDIA {
+            warnings.push(Problem::new(Subject::Object(ObjectId::Sphere(index)), format!("The dielectric overlaps {} others, but a path is only inside of {} dielectrics at a time. The innermost ones are seen as air.", num_overlapping - 1, MAX_NESTED_MEDIA)));
+        }
+    }
+    warnings
+}
@@ -0,0 +1,330 @@
+use std;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use common::*;
+
+// The memory that all density grids together may take, set with "--memory-budget". Every
+// grid takes its values from what is left of the budget. Generated grids that would be
+// larger get the highest resolution that still fits, so that a scene with huge volumes
+// renders with less detail instead of running out of memory. Unlimited by default.
+//
+// Every scene has its own budget, which is counted on the thread that parses it. A scene
+// that is reloaded is parsed while the old one is still rendered, so the grids of the old
+// scene must not count against the new one.
+//
+// @TODO: The budget only covers the density grids. The backbuffer, the guiding grid, the
+// reservoirs and the sky textures are not limited yet.
+static GRID_MEMORY_BUDGET: AtomicUsize = AtomicUsize::new(std::usize::MAX);
+
+thread_local! {
+    static GRID_MEMORY_LEFT: Cell<usize> = Cell::new(GRID_MEMORY_BUDGET.load(Ordering::Relaxed));
+}
+
+pub fn set_memory_budget(bytes: usize) {
+    GRID_MEMORY_BUDGET.store(bytes, Ordering::Relaxed);
+    reset_memory_budget();
+}
+
+// Gives the whole budget to the grids that are created next on this thread, i.e. those of
+// the scene that is parsed next.
+pub fn reset_memory_budget() {
+    GRID_MEMORY_LEFT.with(|left| left.set(GRID_MEMORY_BUDGET.load(Ordering::Relaxed)));
+}
+
+fn memory_left() -> usize {
+    GRID_MEMORY_LEFT.with(|left| left.get())
+}
+
+// Subtracts the bytes of a new grid from the budget. Grids of the minimum resolution are
+// created even when nothing is left.
+fn take_memory(bytes: usize) {
+    GRID_MEMORY_LEFT.with(|left| left.set(left.get().saturating_sub(bytes)));
+}
+
+// The highest resolution of a grid whose values fit into the bytes, but at least two.
+fn max_resolution(budget_bytes: usize) -> usize {
+    let num_values = budget_bytes / std::mem::size_of::<f32>();
+    let mut resolution = f64::cbrt(num_values as f64) as usize + 1;
+    while resolution > 2 && resolution.saturating_mul(resolution).saturating_mul(resolution) > num_values {
+        resolution -= 1;
+    }
+    usize::max(resolution, 2)
+}
+
+// A regular grid of density values that covers the unit cube. Lookups between the
+// grid points are interpolated trilinearly.
+#[derive(Clone, Debug)]
+pub struct DensityGrid {
+    pub resolution: usize,
+    pub values: Vec<f32>,
+    pub max_value: f32, // Needed as the majorant for delta tracking
+}
+
+impl DensityGrid {
+    pub fn new(resolution: usize, values: Vec<f32>) -> Self {
+        assert!(resolution >= 2);
+        assert!(values.len() == resolution*resolution*resolution);
+        let max_value = values.iter().cloned().fold(0.0, f32::max);
+        take_memory(values.len()*std::mem::size_of::<f32>());
+        DensityGrid {
+            resolution: resolution,
+            values: values,
+            max_value: max_value,
+        }
+    }
+
+    // Generates a cloud-like density field from fractal value noise. The density fades
+    // out towards the boundary of the unit cube so that the box of the volume is not
+    // visible in the render.
+    pub fn noise(resolution: usize, frequency: f32) -> Self {
+        let max_resolution = max_resolution(memory_left());
+        if resolution > max_resolution {
+            warn!("The density grid with the resolution {} exceeds what is left of the memory budget and is generated with {} instead.", resolution, max_resolution);
+        }
+        let resolution = usize::min(resolution, max_resolution);
+        let mut values = Vec::with_capacity(resolution*resolution*resolution);
+        let step = 1.0 / (resolution - 1) as f32;
+        for z in 0..resolution {
+            for y in 0..resolution {
+                for x in 0..resolution {
+                    let p = Vec3::new(x as f32*step, y as f32*step, z as f32*step);
+                    let noise = fractal_value_noise(frequency*p, 4);
+                    let falloff = saturatef32(1.0 - 2.0*(p - Vec3::new(0.5, 0.5, 0.5)).length());
+                    values.push(saturatef32(2.5*(noise - 0.4))*falloff);
+                }
+            }
+        }
+        DensityGrid::new(resolution, values)
+    }
+
+    fn value(&self, x: usize, y: usize, z: usize) -> f32 {
+        let r = self.resolution;
+        self.values[(z*r + y)*r + x]
+    }
+
+    // Expects coordinates within the unit cube.
+    pub fn lookup(&self, p: Vec3) -> f32 {
+        let max_index = (self.resolution - 1) as f32;
+        let gx = clampf32(0.0, max_index, p.x*max_index);
+        let gy = clampf32(0.0, max_index, p.y*max_index);
+        let gz = clampf32(0.0, max_index, p.z*max_index);
+
+        // The upper index is clamped so that lookups exactly on the boundary stay in the grid.
+        let (x0, y0, z0) = (gx as usize, gy as usize, gz as usize);
+        let x1 = usize::min(x0 + 1, self.resolution - 1);
+        let y1 = usize::min(y0 + 1, self.resolution - 1);
+        let z1 = usize::min(z0 + 1, self.resolution - 1);
+        let (fx, fy, fz) = (gx - x0 as f32, gy - y0 as f32, gz - z0 as f32);
+
+        let lerp = |a: f32, b: f32, t: f32| (1.0 - t)*a + t*b;
+        let c00 = lerp(self.value(x0, y0, z0), self.value(x1, y0, z0), fx);
+        let c10 = lerp(self.value(x0, y1, z0), self.value(x1, y1, z0), fx);
+        let c01 = lerp(self.value(x0, y0, z1), self.value(x1, y0, z1), fx);
+        let c11 = lerp(self.value(x0, y1, z1), self.value(x1, y1, z1), fx);
+        lerp(lerp(c00, c10, fy), lerp(c01, c11, fy), fz)
+    }
+}
+
+fn hash_lattice(x: i32, y: i32, z: i32) -> f32 {
+    let mut h = (x as u32).wrapping_mul(73856093) ^ (y as u32).wrapping_mul(19349663) ^ (z as u32).wrapping_mul(83492791);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0x5bd1e995);
+    h ^= h >> 15;
+    h as f32 / std::u32::MAX as f32
+}
+
+fn value_noise(p: Vec3) -> f32 {
+    let (x0, y0, z0) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let smooth = |t: f32| t*t*(3.0 - 2.0*t);
+    let (fx, fy, fz) = (smooth(p.x - x0), smooth(p.y - y0), smooth(p.z - z0));
+    let (x0, y0, z0) = (x0 as i32, y0 as i32, z0 as i32);
+
+    let lerp = |a: f32, b: f32, t: f32| (1.0 - t)*a + t*b;
+    let c00 = lerp(hash_lattice(x0, y0    , z0    ), hash_lattice(x0 + 1, y0    , z0    ), fx);
+    let c10 = lerp(hash_lattice(x0, y0 + 1, z0    ), hash_lattice(x0 + 1, y0 + 1, z0    ), fx);
+    let c01 = lerp(hash_lattice(x0, y0    , z0 + 1), hash_lattice(x0 + 1, y0    , z0 + 1), fx);
+    let c11 = lerp(hash_lattice(x0, y0 + 1, z0 + 1), hash_lattice(x0 + 1, y0 + 1, z0 + 1), fx);
+    lerp(lerp(c00, c10, fy), lerp(c01, c11, fy), fz)
+}
+
+fn fractal_value_noise(p: Vec3, octaves: u32) -> f32 {
+    let mut sum: f32 = 0.0;
+    let mut amplitude: f32 = 0.5;
+    let mut frequency: f32 = 1.0;
+    let mut normalization: f32 = 0.0;
+    for _ in 0..octaves {
+        sum += amplitude*value_noise(frequency*p);
+        normalization += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    sum / normalization
+}
+
+// A heterogeneous medium within an axis-aligned box. The density grid is stretched
+// over the whole box and scaled by density_scale to get the extinction coefficient.
+#[derive(Clone, Debug, new)]
+pub struct Volume {
+    pub min: Vec3,
+    pub max: Vec3,
+    pub grid: DensityGrid,
+    pub density_scale: f32,
+    pub albedo: Vec3,
+    pub anisotropy: f32,
+}
+
+impl Volume {
+    fn majorant(&self) -> f32 {
+        self.density_scale*self.grid.max_value
+    }
+
+    fn extinction(&self, position: Vec3) -> f32 {
+        let extent = self.max - self.min;
+        let local = (position - self.min) / extent;
+        self.density_scale*self.grid.lookup(local)
+    }
+
+    // Returns the parametric range of the ray within the box (slab test).
+    fn intersect(&self, ray: &Ray) -> Option<(f32, f32)> {
+        let inv = Vec3::new(1.0/ray.direction.x, 1.0/ray.direction.y, 1.0/ray.direction.z);
+        let t0 = (self.min - ray.origin)*inv;
+        let t1 = (self.max - ray.origin)*inv;
+
+        let t_enter = f32::max(f32::max(f32::min(t0.x, t1.x), f32::min(t0.y, t1.y)), f32::min(t0.z, t1.z));
+        let t_exit  = f32::min(f32::min(f32::max(t0.x, t1.x), f32::max(t0.y, t1.y)), f32::max(t0.z, t1.z));
+
+        if t_exit < f32::max(t_enter, 0.0) {
+            None
+        } else {
+            Some((f32::max(t_enter, 0.0), t_exit))
+        }
+    }
+}
+
+pub struct VolumeCollision<'a> {
+    pub distance: f32,
+    pub position: Vec3,
+    pub volume: &'a Volume,
+}
+
+// Finds the nearest real collision with any of the volumes in front of max_distance with
+// delta tracking. Tentative collisions are generated with the majorant of the volume and
+// accepted with the probability of the real extinction relative to the majorant, which
+// makes the estimator unbiased without ever evaluating the transmittance explicitly.
+pub fn find_volume_collision<'a>(ray: &Ray, volumes: &'a [Volume], max_distance: f32) -> Option<VolumeCollision<'a>> {
+    let mut nearest_collision: Option<VolumeCollision> = None;
+
+    for volume in volumes {
+        let majorant = volume.majorant();
+        if majorant <= 0.0 {
+            continue;
+        }
+
+        let limit = nearest_collision.as_ref().map(|c| c.distance).unwrap_or(max_distance);
+        let (t_enter, t_exit) = match volume.intersect(ray) {
+            Some(range) => range,
+            None => continue,
+        };
+        let t_exit = f32::min(t_exit, limit);
+
+        let mut t = t_enter;
+        loop {
+            t -= f32::ln(1.0 - random32()) / majorant;
+            if t >= t_exit {
+                break;
+            }
+            let position = ray.origin + t*ray.direction;
+            if random32() < volume.extinction(position) / majorant {
+                nearest_collision = Some(VolumeCollision {
+                    distance: t,
+                    position: position,
+                    volume: volume,
+                });
+                break;
+            }
+        }
+    }
+
+    nearest_collision
+}
+
+// Estimates the transmittance along the ray up to max_distance with ratio tracking, which
+// has a lower variance than testing the visibility with delta tracking.
+pub fn transmittance(ray: &Ray, volumes: &[Volume], max_distance: f32) -> f32 {
+    let mut transmittance: f32 = 1.0;
+
+    for volume in volumes {
+        let majorant = volume.majorant();
+        if majorant <= 0.0 {
+            continue;
+        }
+
+        let (t_enter, t_exit) = match volume.intersect(ray) {
+            Some(range) => range,
+            None => continue,
+        };
+        let t_exit = f32::min(t_exit, max_distance);
+
+        let mut t = t_enter;
+        loop {
+            t -= f32::ln(1.0 - random32()) / majorant;
+            if t >= t_exit {
+                break;
+            }
+            let position = ray.origin + t*ray.direction;
+            transmittance *= 1.0 - volume.extinction(position) / majorant;
+        }
+    }
+
+    transmittance
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_box() -> Volume {
+        let grid = DensityGrid::new(2, vec![1.0; 8]);
+        Volume::new(Vec3::new(-1.0, -1.0, -1.0), Vec3::new(1.0, 1.0, 1.0), grid, 1.0, Vec3::one(), 0.0)
+    }
+
+    fn assert_range(range: Option<(f32, f32)>, t_enter: f32, t_exit: f32) {
+        let (enter, exit) = range.expect("the box is missed");
+        assert!(f32::abs(enter - t_enter) < 1.0e-4 && f32::abs(exit - t_exit) < 1.0e-4, "({}, {}) != ({}, {})", enter, exit, t_enter, t_exit);
+    }
+
+    #[test]
+    fn box_is_crossed_from_outside() {
+        let volume = unit_box();
+        assert_range(volume.intersect(&Ray::new(Vec3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0))), 4.0, 6.0);
+        assert_range(volume.intersect(&Ray::new(Vec3::new(0.5, 0.5, 5.0), Vec3::new(0.0, 0.0, -1.0))), 4.0, 6.0);
+
+        let diagonal = Vec3::new(1.0, 1.0, 1.0).normalize();
+        let range = volume.intersect(&Ray::new(Vec3::new(-2.0, -2.0, -2.0), diagonal));
+        assert_range(range, f32::sqrt(3.0), 3.0*f32::sqrt(3.0));
+    }
+
+    #[test]
+    fn box_starts_at_the_origin_of_rays_inside() {
+        let volume = unit_box();
+        assert_range(volume.intersect(&Ray::new(Vec3::zero(), Vec3::new(1.0, 0.0, 0.0))), 0.0, 1.0);
+        assert_range(volume.intersect(&Ray::new(Vec3::new(0.0, 0.5, 0.0), Vec3::new(0.0, -1.0, 0.0))), 0.0, 1.5);
+    }
+
+    #[test]
+    fn box_behind_or_beside_the_ray_is_missed() {
+        let volume = unit_box();
+        assert!(volume.intersect(&Ray::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, 1.0))).is_none());
+        assert!(volume.intersect(&Ray::new(Vec3::new(0.0, 2.0, -5.0), Vec3::new(0.0, 0.0, 1.0))).is_none());
+        assert!(volume.intersect(&Ray::new(Vec3::new(-5.0, 0.0, 0.0), Vec3::new(0.0, 0.6, 0.8))).is_none());
+    }
+
+    #[test]
+    fn resolution_fits_into_the_memory_budget() {
+        assert_eq!(max_resolution(64*64*64*4), 64);
+        assert_eq!(max_resolution(64*64*64*4 - 1), 63);
+        assert_eq!(max_resolution(0), 2);
+        assert!(max_resolution(std::usize::MAX) > 1_000_000);
+    }
+}
@@ -0,0 +1,185 @@
+// Renders into a canvas of the page when the library is built for wasm32. It takes the place
+// of the window, the headless mode and the distributed rendering, which need threads, files
+// or sockets that the browser does not have. Therefore:
+//
+//   - the scene is a demo scene or the text of a scene file that is handed over by the page,
+//   - the tiles are rendered on the main thread, as many as fit into the time budget of one
+//     frame of the page, so that the page stays responsive while the image converges,
+//   - the image is drawn into the canvas with softbuffer (see presenter.rs),
+//   - the messages are written to the console of the browser.
+//
+// The module for the page is built with wasm-bindgen:
+//
+//   cargo build --lib --release --target wasm32-unknown-unknown
+//   wasm-bindgen --target web --out-dir web/pkg target/wasm32-unknown-unknown/release/pathtracer.wasm
+//
+// and is started by web/index.html.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, RwLock};
+
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys;
+use web_sys::HtmlCanvasElement;
+use winit::event::{Event, WindowEvent};
+use winit::event_loop::{EventLoop, ControlFlow};
+use winit::platform::web::EventLoopExtWebSys;
+
+use common::*;
+use cli::Options;
+use scene::Scene;
+use parser;
+use tracer;
+use guiding;
+use restir;
+use tiles;
+use presenter::Presenter;
+use renderer::Renderer;
+use error::Error;
+
+// The canvas that Renderer::run draws into.
+pub const DEFAULT_CANVAS_ID: &str = "pathtracer";
+
+// Time of every frame of the page that is spent rendering tiles. The rest is left to the
+// browser for drawing the canvas and handling the input.
+const FRAME_BUDGET_NS: u64 = 12_000_000;
+
+pub fn log(message: &str) {
+    web_sys::console::log_1(&JsValue::from_str(message));
+}
+
+// Renders one of the demo scenes with its default settings, e.g. renderDemo("pathtracer",
+// "cornell") from JavaScript.
+#[wasm_bindgen(js_name = renderDemo)]
+pub fn render_demo(canvas_id: &str, name: &str) -> Result<(), JsValue> {
+    let renderer = Renderer::builder().demo(name).build().map_err(to_js)?;
+    start(renderer.options().clone(), canvas_id).map_err(to_js)
+}
+
+// Renders the text of a scene file in the native format, e.g. after it was fetched by the
+// page.
+#[wasm_bindgen(js_name = renderScene)]
+pub fn render_scene(canvas_id: &str, text: &str) -> Result<(), JsValue> {
+    let scene = parser::parse_scene_text(text, parser::SceneFormat::Native).map_err(Error::Scene).map_err(to_js)?;
+    start_scene(Options::default(), canvas_id, scene).map_err(to_js)
+}
+
+fn to_js(err: Error) -> JsValue {
+    JsValue::from_str(&format!("{}", err))
+}
+
+// Renders the demo scene of the options, or the default scene without one. Returns right
+// away, the rendering continues in the event loop of the page.
+pub fn start(options: Options, canvas_id: &str) -> Result<(), Error> {
+    let scene = match options.demo {
+        Some(ref name) => parser::parse_scene_text(name, parser::SceneFormat::Demo).map_err(Error::Scene)?,
+        None => Scene::default(),
+    };
+    start_scene(options, canvas_id, scene)
+}
+
+fn start_scene(options: Options, canvas_id: &str, mut scene: Scene) -> Result<(), Error> {
+    options.apply_to_scene(&mut scene);
+    let width = scene.image_settings.width as u32;
+    let height = scene.image_settings.height as u32;
+    let scene = Arc::new(RwLock::new(scene));
+
+    //
+    // SETTING UP THE CANVAS
+    //
+    // The canvas is resized to the resolution of the scene, like the window.
+    //
+    let canvas = web_sys::window()
+        .and_then(|window| window.document())
+        .and_then(|document| document.get_element_by_id(canvas_id))
+        .and_then(|element| element.dyn_into::<HtmlCanvasElement>().ok())
+        .ok_or(Error::Display(format!("There is no canvas with the id \"{}\" on the page.", canvas_id)))?;
+    let event_loop = EventLoop::new()
+        .map_err(|err| Error::Display(format!("Could not create the event loop. Error: {}", err)))?;
+    let (window, mut presenter) = Presenter::open_canvas(&event_loop, canvas, width, height)?;
+
+    //
+    // SETTING UP THE BUFFERS
+    //
+    // The GPU backend is not available in the browser.
+    //
+    let integrator = if options.integrator == tracer::Integrator::Gpu {
+        log("The GPU backend is not available in the browser. Using the path tracer instead.");
+        tracer::Integrator::PathTracer
+    } else {
+        options.integrator
+    };
+    let backbuffer = Arc::new(tracer::Backbuffer::new(width, height));
+    backbuffer.set_debug_view(integrator.is_debug_view());
+    backbuffer.set_check_radiance(options.check_radiance);
+    let guiding = guiding::GuidingGrid::new();
+    guiding.reset(&scene.read().unwrap());
+    let reservoirs = restir::ReservoirBuffer::new(width, height);
+
+    //
+    // MAIN LOOP
+    //
+    // The tiles of a frame are rendered over as many frames of the page as needed. A sample is
+    // finished when all of them are rendered, which is accounted for like in the window. The
+    // canvas shows the tiles that are finished in the current sample immediately.
+    //
+    let mut pending_tiles: VecDeque<tracer::WorkTile> = VecDeque::new();
+    let mut frame_in_flight = false;
+    let mut converged = false;
+    let render_time_start = precise_time_ns();
+    event_loop.spawn(move |event, window_target| {
+        match event {
+            Event::WindowEvent { event: WindowEvent::Resized(size), .. } => {
+                presenter.resize(size.width, size.height);
+            },
+            Event::AboutToWait => {
+                let budget_start = precise_time_ns();
+                while !converged && precise_time_ns() - budget_start < FRAME_BUDGET_NS {
+                    if let Some(work_tile) = pending_tiles.pop_front() {
+                        tracer::render(work_tile, &backbuffer, scene.clone(), &guiding, &reservoirs);
+                        continue;
+                    }
+
+                    // FINISHING THE SAMPLE
+                    if frame_in_flight {
+                        if integrator == tracer::Integrator::GuidedPathTracer {
+                            guiding.update();
+                        }
+                        if integrator == tracer::Integrator::ReSTIR {
+                            reservoirs.swap();
+                        }
+                        unsafe {
+                            *backbuffer.num_samples.get() += 1;
+                        }
+                        frame_in_flight = false;
+                    }
+
+                    // The accumulation stops when the requested number of samples per pixel
+                    // is reached.
+                    let num_samples = unsafe { *backbuffer.num_samples.get() };
+                    converged = options.samples_per_pixel.map(|spp| num_samples > spp).unwrap_or(false);
+                    if converged {
+                        log(&format!("Rendered {} samples per pixel in {:.1} s.",
+                            num_samples - 1, (precise_time_ns() - render_time_start) as f64 / 1_000_000_000.0));
+                    } else {
+                        pending_tiles.extend(tiles::work_tiles(options.tile_order, width, height, options.crop, options.tile_size, integrator, options.max_depth, 0, num_samples, 1, false));
+                        frame_in_flight = true;
+                    }
+                }
+
+                // WRITING THE BACKBUFFER TO THE CANVAS
+                let pixels = unsafe { &*backbuffer.pixels8.get() };
+                let size = window.inner_size();
+                if let Err(err) = presenter.present(pixels, width, height, (0.0, 0.0), (size.width as f64, size.height as f64), None) {
+                    log(&format!("{}", err));
+                }
+
+                // The page only wakes the loop up again for input once the image is converged.
+                window_target.set_control_flow(if converged { ControlFlow::Wait } else { ControlFlow::Poll });
+            },
+            _ => (),
+        }
+    });
+    Ok(())
+}
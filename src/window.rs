@@ -1,12 +1,12 @@
 use std;
 use std::time::Duration;
-use std::fs;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, RwLock, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::channel;
 
-use time;
-use glutin;
-use glium;
+use winit::event::{Event, WindowEvent, KeyEvent, ElementState, MouseButton, MouseScrollDelta};
+use winit::event_loop::{EventLoop, ControlFlow};
+use winit::keyboard::{KeyCode, PhysicalKey, ModifiersState};
 use notify;
 use notify::Watcher;
 
@@ -16,51 +16,66 @@ use content;
 use scene;
 use tracer;
 use parser;
+use guiding;
+use restir;
+use tiles;
+use progress::Progress;
+use gpu;
+use denoise;
+use postprocess;
+use rayview;
+use network;
+use stats;
+use export;
+use cli;
+use overlay;
+use presenter::Presenter;
+use error::Error;
 
-#[link(name = "opengl32")]
-extern "C" {
-    // glium only provides functionality of the 'modern' OpenGL APIs. glDrawPixels is deprecated
-    // and therefore not available. However, to date it is commonly still implemented and can be
-    // used without much worries.
-    fn glDrawPixels(width: u32, height: u32, format: i32, component_type: i32, data: *const u8);
-}
+pub fn start(options: cli::Options) {
+    let scene_file_path = options.scene_path.clone();
 
-fn load_scene(filename: &str) -> Result<scene::Scene, String> {
-    match fs::read_to_string(filename) {
-        Ok(content) => match parser::parse_scene(&*content) {
-            Ok(scene) => Ok(scene),
-            Err(err) => Err(format!("Could not parse the file \"{}\". Error: \"{:?}\".", filename, err)), // @TODO: Make the ParseError printable.
-        },
-        Err(_) => Err(format!("Could not read the file \"{}\".", filename)),
-    }
-}
+    // The tile order only affects the order in which the image appears and can also be
+    // changed at runtime.
+    let mut tile_order = options.tile_order;
 
-pub fn start() {
-    //
-    // READING THE COMMAND LINE ARGUMENTS
-    //
-    // All parameters of the image as well as the rendering process should be specified
-    // within the scene file. 
-    //
-    let scene_file_path = match {
-        const ARG: &'static str = "--path=";
-        std::env::args()
-            .find(|arg| arg.starts_with(ARG))
-            .map(|arg| String::from(arg.split_at(ARG.len()).1))
-    } {
-        Some(path) => path,
-        None => String::from("scenes/sample/sample.scene"), // Let's try to load this one when no other was provided via the arguments.
-    };
+    // After the scene was changed, the image is rendered at a reduced resolution for a
+    // moment so that the interaction stays smooth on slow machines. A scale of one disables
+    // the preview.
+    let preview_scale = options.preview_scale;
+
+    // The image is saved to this path when the window is closed. Paths ending with ".exr"
+    // are saved as OpenEXR with the linear radiance.
+    let output_path = options.output_path.clone();
+    let exr_precision = options.exr_precision;
+    let exr_aovs = options.exr_aovs;
+    let exr_mattes = options.exr_mattes;
 
     //
     // TRYING TO LOAD THE SCENE FOR THE FIRST TIME
     //
-    let scene = Arc::new(RwLock::new(load_scene(&*scene_file_path)
-        .unwrap_or_else(|err| {
+    // The remote workers parse the scene from the source. When the default scene is used,
+    // they fall back to it as well. The source of a demo scene is its name.
+    //
+    let scene_format = if options.demo.is_some() { parser::SceneFormat::Demo } else { parser::SceneFormat::from_path(&scene_file_path) };
+    let loaded_scene = match options.demo {
+        Some(ref name) => parser::parse_scene_text(name, parser::SceneFormat::Demo).map_err(Error::Scene).map(|scene| (scene, name.clone())),
+        None => parser::load_scene(&*scene_file_path),
+    };
+    let (mut scene, scene_source) = match loaded_scene {
+        Ok((scene, source)) => (scene, source),
+        Err(err) => {
             println!("{}", err);
             println!("Using the default scene.");
-            scene::Scene::default()
-        })));
+            (scene::Scene::default(), String::new())
+        },
+    };
+    options.apply_to_scene(&mut scene);
+    if options.print_stats {
+        stats::print_scene_stats(&scene);
+    }
+    let scene = Arc::new(RwLock::new(scene));
+    let scene_source = Arc::new(RwLock::new(network::SceneSource::new(0, scene_format, scene_source)));
     
     let width = scene.read().unwrap().image_settings.width as u32;
     let height = scene.read().unwrap().image_settings.height as u32;
@@ -68,52 +83,179 @@ pub fn start() {
     //
     // SETTING UP THE HOT-RELOADING OF THE SCENE FILE
     //
-    // @TODO: This should not be done with panics. Not being able to load the a file
-    //        from disk is something that can happen in normal use.
+    // The directory of the scene file is watched instead of the file itself. Many editors
+    // save by writing a temporary file and renaming it, which replaces the watched file and
+    // would end the watch after the first save. This also allows the scene file to be
+    // created after the window was opened. Without a watcher, the window works as before
+    // but does not reload the scene. Demo scenes have no file and are never reloaded.
     //
     let (sender, receiver) = channel();
-    let mut watcher: notify::RecommendedWatcher = match notify::Watcher::new(sender, Duration::from_millis(100)) {
-        Ok(watcher) => watcher,
-        Err(_) => panic!("Could not create the watcher for the scene file."),
+    let scene_file_name = std::path::Path::new(&scene_file_path).file_name().map(|name| name.to_os_string());
+    let scene_directory = match std::path::Path::new(&scene_file_path).parent() {
+        Some(parent) if parent != std::path::Path::new("") => parent.to_path_buf(),
+        _ => std::path::PathBuf::from("."),
     };
-    match watcher.watch(&*scene_file_path, notify::RecursiveMode::NonRecursive) {
-        Ok(_) => (),
-        Err(_) => panic!("Could not start the watcher for the scene file."),
+    let _watcher: Option<notify::RecommendedWatcher> = if options.demo.is_some() {
+        None
+    } else {
+        match notify::RecommendedWatcher::new(sender, Duration::from_millis(100)) {
+            Ok(mut watcher) => {
+                if let Err(err) = watcher.watch(&scene_directory, notify::RecursiveMode::NonRecursive) {
+                    println!("Could not watch the directory \"{}\". The scene is not reloaded. Error: {:?}", scene_directory.display(), err);
+                }
+                Some(watcher)
+            },
+            Err(err) => {
+                println!("Could not create the watcher for the scene file. The scene is not reloaded. Error: {:?}", err);
+                None
+            },
+        }
     };
 
     //
     // SETTING UP WINDOW AND EVENT LOOP
     //
-    // @TODO: The backbuffer should resize when the window is resized. This is
-    //        not implemented right not but also questionable as the resolution
-    //        of the image should be specified by the scene file. Maybe it's
-    //        best to have an internal resolution with which to export images and
-    //        a resolution for displaying the image on the window.
+    // The window opens with the resolution of the scene in physical pixels, so that every
+    // pixel of the image covers one pixel of the screen also on HiDPI displays. When it is
+    // resized, the image is rendered in the new resolution (see RESIZING THE IMAGE). The
+    // presenter draws the image into the window (see presenter.rs).
     //
-    let mut events_loop = glium::glutin::EventsLoop::new();
-    let window = glium::glutin::WindowBuilder::new()
-        .with_dimensions(glium::glutin::dpi::LogicalSize::new(width as f64, height as f64))
-        .with_title("Pathtracer");
-    let display = glium::Display::new(window, glium::glutin::ContextBuilder::new(), &events_loop).unwrap();
+    let event_loop = match EventLoop::new() {
+        Ok(event_loop) => event_loop,
+        Err(err) => {
+            println!("{}", Error::Display(format!("Could not create the event loop. Error: {}", err)));
+            return;
+        },
+    };
+    let (window, mut presenter) = match Presenter::open(&event_loop, width, height) {
+        Ok(window_and_presenter) => window_and_presenter,
+        Err(err) => {
+            println!("{}", err);
+            return;
+        },
+    };
 
     //
-    // SETTING UP THE BACKBUFFER
+    // SETTING UP THE OVERLAY
+    //
+    // The overlay for editing the parameters is toggled with Tab. It is only available when
+    // compiled with the "overlay" feature.
     //
-    // @TODO: Implement resizing. View comment on the window stuff.
+    // The camera is sent to the remote workers with every tile. The materials are not, so
+    // they cannot be edited while serving remote workers.
+    //
+    let mut overlay = presenter.create_overlay();
+    let mut show_overlay = false;
+    let mut picked_object = None;
+
+    //
+    // SETTING UP THE BACKBUFFER
     //
     let backbuffer = Arc::new(tracer::Backbuffer::new(width, height));
 
+    //
+    // SETTING UP THE PATH GUIDING
+    //
+    // The guiding grid learns the distribution of the incident radiance while the guided
+    // path tracer is running. Its sampling distribution is rebuilt after every frame.
+    //
+    let guiding = Arc::new(guiding::GuidingGrid::new());
+    guiding.reset(&scene.read().unwrap());
+
+    //
+    // SETTING UP THE RESERVOIRS FOR RESTIR
+    //
+    let reservoirs = Arc::new(restir::ReservoirBuffer::new(width, height));
+
+    //
+    // SETTING UP THE GPU BACKEND
+    //
+    // The GPU renders whole frames, each as a single work item on one of the worker
+    // threads, so that the window stays responsive while the GPU is busy. It is only
+    // available when compiled with the "gpu" feature.
+    //
+    let gpu_renderer = gpu::GpuRenderer::new();
+    let gpu_available = gpu_renderer.is_some();
+    let gpu_renderer = Mutex::new(gpu_renderer);
+
     //
     // STARTING THE WORKER POOL
     //
-    let worker_pool = {
-        const NUM_WORKER_THREADS: usize = 8;
+    // The worker threads render the tiles in the background and report every finished
+    // tile to the main thread over a channel. The main thread never blocks on the workers
+    // so that the window stays responsive even when a frame takes a long time. When the
+    // generation of a queued tile is outdated, e.g. because the scene was reloaded, the
+    // tile is skipped which cancels the rest of the frame.
+    //
+    let generation = Arc::new(AtomicUsize::new(0));
+    let (tile_sender, tile_receiver) = channel();
+    let remote_tile_sender = tile_sender.clone();
+    let worker_pool = Arc::new({
         let backbuffer2 = backbuffer.clone();
         let scene2 = scene.clone();
-        worker::WorkerPool::new(NUM_WORKER_THREADS, Box::new(move |work_tile| {
-            tracer::render(work_tile, &backbuffer2, scene2.clone());
+        let guiding2 = guiding.clone();
+        let reservoirs2 = reservoirs.clone();
+        let generation2 = generation.clone();
+        let tile_sender = Mutex::new(tile_sender);
+        worker::WorkerPool::new(options.num_threads, Box::new(move |work_tile: tracer::WorkTile| {
+            let cancelled = work_tile.generation != generation2.load(Ordering::SeqCst);
+            if !cancelled {
+                let tile_time_start = precise_time_ns();
+                if work_tile.integrator == tracer::Integrator::Gpu {
+                    let mut gpu_renderer = gpu_renderer.lock().expect("Could not aquire the lock for the GPU renderer.");
+                    if let Some(ref mut gpu_renderer) = *gpu_renderer {
+                        gpu_renderer.render_frame(&scene2.read().unwrap(), &backbuffer2, work_tile.max_depth); // @TODO: Handle the unwrap
+                    }
+                } else {
+                    tracer::render(work_tile.clone(), &backbuffer2, scene2.clone(), &guiding2, &reservoirs2);
+                }
+                stats::flush_tile(precise_time_ns() - tile_time_start);
+            }
+            let tile_sender = tile_sender.lock().expect("Could not aquire the lock for the tile sender.");
+            tile_sender.send(tracer::FinishedTile::new(work_tile, cancelled)).expect("Could not send the finished tile to the main thread.");
         }))
-    };
+    });
+
+    //
+    // ACCEPTING REMOTE WORKERS
+    //
+    // Remote workers take the tiles from the same queue as the worker threads. Therefore
+    // the main loop does not distinguish between local and remote tiles.
+    //
+    if let Some(ref address) = options.serve_address {
+        network::serve(&address, network::Server {
+            worker_pool: worker_pool.clone(),
+            tile_sender: remote_tile_sender,
+            backbuffer: backbuffer.clone(),
+            scene: scene.clone(),
+            scene_source: scene_source.clone(),
+            generation: generation.clone(),
+        });
+    }
+
+    //
+    // SETTING UP THE DENOISER
+    //
+    // The denoised image is computed after every frame while the denoiser is enabled and
+    // shown instead of the backbuffer. Its linear radiance is kept for the post-processing.
+    //
+    let mut denoiser = denoise::Denoiser::new(options.denoiser);
+    let mut denoised_image: Vec<Vec3> = Vec::new();
+    let mut denoised_pixels: Vec<Pixel8> = Vec::new();
+    let mut show_denoised = options.denoise;
+    // The features of the first hit are only traced once they are needed. Enabling the
+    // denoiser later restarts the accumulation so that the features cover all samples.
+    let mut gather_features = show_denoised || exr_aovs || exr_mattes;
+    let mut variance_pixels: Vec<Pixel8> = Vec::new();
+    let mut show_variance = false;
+    // The post-processing is computed the same way and only at the full resolution. It is
+    // applied to the denoised image while that is shown.
+    let post_processing = options.post_processing;
+    let mut post_processed_pixels: Vec<Pixel8> = Vec::new();
+    let mut show_post_processed = post_processing.is_enabled();
+    // The recorded paths of a pixel are drawn once and shown over everything else.
+    let mut ray_view_pixels: Vec<Pixel8> = Vec::new();
+    let mut show_ray_view = false;
 
     //
     // SETTING UP CONTENT MANAGEMENT
@@ -123,108 +265,838 @@ pub fn start() {
     //
     // MAIN LOOP
     //
-    let mut running = true;
-    while running {
-        let frame_time_start = time::precise_time_ns();
-
-        // ASSIGNING THE HDRI TEXTURE FOR THE SKY
-        {
-            let mut scene = scene.write().expect("Could not get writing access to the scene for updating the sky texture.");
-            if let scene::Sky::HDRI(ref path, ref mut option_texture) = scene.sky {
-                if let None = option_texture {
-                    *option_texture = match content.get_hdri_texture(path) {
-                        Some(hdri_texture) => {
-                            backbuffer.clear();
-                            Some(hdri_texture)
-                        },
-                        None => None,
-                    };
+    // The integrator can be switched at runtime with the number keys. The Whitted-style
+    // ray tracer is noise-free and therefore useful as a preview while editing a scene.
+    // The debug views are selected like integrators, and pressing the key of the active
+    // view again returns to the integrator that was used before it.
+    //
+    // Everything that modifies the backbuffer, the guiding grid or the reservoirs as a
+    // whole is only done between two frames while no tile is being rendered. Changes during
+    // a frame only request a restart of the accumulation.
+    //
+    // Preview frames are not accumulated. Every preview frame replaces the previous one
+    // until the first frame in full resolution is rendered.
+    //
+    const PREVIEW_DURATION_NS: u64 = 500_000_000;
+    let mut integrator = if options.integrator == tracer::Integrator::Gpu && !gpu_available {
+        println!("The GPU backend is not available. Using the path tracer instead.");
+        tracer::Integrator::PathTracer
+    } else {
+        options.integrator
+    };
+    let mut beauty_integrator = if integrator.is_debug_view() { tracer::Integrator::PathTracer } else { integrator };
+    backbuffer.set_debug_view(integrator.is_debug_view());
+    backbuffer.set_check_radiance(options.check_radiance);
+    let mut num_pending_tiles = 0;
+    let mut num_frame_tiles = 0;
+    let mut progress = Progress::new(options.samples_per_pixel);
+    let mut frame_in_flight = false;
+    let mut frame_resolution_scale = 1;
+    let mut last_restart_time = precise_time_ns();
+    let mut restart_requested = false;
+    let mut max_depth = options.max_depth;
+    let mut samples_per_pixel = options.samples_per_pixel;
+    let mut frame_time_start = precise_time_ns();
+    let mut screenshot_requested = false;
+    let mut exr_requested = false;
+    let mut tone_mapping_changed = false;
+    let mut requested_size = None;
+    let mut paused = false;
+    let mut cursor_position = None;
+    let mut crop = options.crop;
+    let mut crop_start = None; // Corner of the rectangle that is being dragged
+    let mut image_view = ImageView::new();
+    let mut pan_start = None; // Cursor position while dragging with the middle mouse button
+    //
+    // The event loop calls back for every event of the window. When all pending events were
+    // handled, the next step of the rendering is done and the window is redrawn.
+    //
+    let mut modifiers = ModifiersState::empty();
+    let result = event_loop.run(|event, window_target| {
+        window_target.set_control_flow(ControlFlow::Poll);
+        match event {
+            // HANDLE THE EVENTS PROVIDED BY THE WINDOW
+            //
+            // The overlay sees the events first. Clicks on the overlay do not pick objects.
+            Event::WindowEvent { event, .. } => {
+                let mut overlay_uses_mouse = false;
+                if let (true, Some(overlay)) = (show_overlay, overlay.as_mut()) {
+                    overlay_uses_mouse = overlay.handle_event(&event);
                 }
-            }
-        }
+                match event {
+                    WindowEvent::CloseRequested => window_target.exit(),
+                    WindowEvent::CursorMoved { position, .. } => {
+                        if let Some((x, y)) = pan_start {
+                            image_view.pan(position.x - x, position.y - y);
+                            pan_start = Some((position.x, position.y));
+                        }
+                        cursor_position = Some(position);
+                    },
+                    // The scroll wheel zooms around the cursor and dragging with the middle
+                    // mouse button pans the image.
+                    WindowEvent::MouseWheel { delta, .. } => {
+                        if let (Some(position), false) = (cursor_position, overlay_uses_mouse) {
+                            let lines = match delta {
+                                MouseScrollDelta::LineDelta(_, y) => y as f64,
+                                MouseScrollDelta::PixelDelta(position) => position.y / 20.0,
+                            };
+                            image_view.zoom_at(position.x, position.y, f64::powf(2.0, lines/2.0));
+                        }
+                    },
+                    WindowEvent::MouseInput { state: ElementState::Pressed, button: MouseButton::Middle, .. } => {
+                        if !overlay_uses_mouse {
+                            pan_start = cursor_position.map(|position| (position.x, position.y));
+                        }
+                    },
+                    WindowEvent::MouseInput { state: ElementState::Released, button: MouseButton::Middle, .. } => {
+                        pan_start = None;
+                    },
+                    WindowEvent::MouseInput { state: ElementState::Pressed, button: MouseButton::Left, .. } => {
+                        if let (Some(position), false) = (cursor_position, overlay_uses_mouse) {
+                            let (x, y) = image_view.to_image(position.x, position.y);
+                            picked_object = log_picked_object(&scene.read().unwrap(), &backbuffer, x, y); // @TODO: Handle the unwrap
 
-        // RELOADING THE SCENE WHEN THE FILE WAS UPDATED
-        match receiver.try_recv() {
-            Ok(event) => {
-                if let notify::DebouncedEvent::Write(_) = event {
-                    let mut scene = scene.write().unwrap(); // @TODO: Handle the unwrap
-                    match load_scene(&*scene_file_path) {
-                        Ok(loaded_scene) => {
-                            *scene = loaded_scene;
-                            backbuffer.clear();
+                            // With depth of field the click also pulls the focus to the picked surface.
+                            if let Some(focus_distance) = focus_on_pixel(&mut scene.write().unwrap(), &backbuffer, x, y) {
+                                info!("    focus_distance = {}", focus_distance);
+                                restart_requested = true;
+                            }
+                        }
+                    },
+                    // Dragging with the right mouse button selects the crop region and a
+                    // click without dragging renders the whole image again.
+                    WindowEvent::MouseInput { state: ElementState::Pressed, button: MouseButton::Right, .. } => {
+                        if !overlay_uses_mouse {
+                            crop_start = cursor_position;
+                        }
+                    },
+                    WindowEvent::MouseInput { state: ElementState::Released, button: MouseButton::Right, .. } => {
+                        if let (Some(start), Some(end)) = (crop_start.take(), cursor_position) {
+                            const MIN_CROP_SIZE: u32 = 4;
+                            let (x0, y0) = image_view.to_image(start.x, start.y);
+                            let (x1, y1) = image_view.to_image(end.x, end.y);
+                            let region = tiles::Region::from_corners(f64::max(0.0, x0) as u32, f64::max(0.0, y0) as u32, f64::max(0.0, x1) as u32, f64::max(0.0, y1) as u32);
+                            crop = if region.width >= MIN_CROP_SIZE && region.height >= MIN_CROP_SIZE { Some(region) } else { None };
+                            info!("crop = {:?}", crop);
+                            restart_requested = true;
+                        }
+                    },
+                    // The size is given in physical pixels. The surface of the window has to
+                    // be resized explicitly. A minimized window has no size and keeps the image.
+                    WindowEvent::Resized(size) => {
+                        presenter.resize(size.width, size.height);
+                        let (width, height) = (size.width, size.height);
+                        if width > 0 && height > 0 && (width, height) != (backbuffer.width(), backbuffer.height()) {
+                            requested_size = Some((width, height));
+                            restart_requested = true;
+                        }
+                    },
+                    WindowEvent::ModifiersChanged(new_modifiers) => modifiers = new_modifiers.state(),
+                    // The keys are identified by their position so that they do not depend on
+                    // the keyboard layout.
+                    WindowEvent::KeyboardInput { event: KeyEvent { physical_key: PhysicalKey::Code(key), state: ElementState::Pressed, .. }, .. } => {
+                        let selected_integrator = match key {
+                            KeyCode::Digit1 if modifiers.shift_key() => Some(tracer::Integrator::FastGI),
+                            KeyCode::Digit1 => Some(tracer::Integrator::PathTracer),
+                            KeyCode::Digit2 => Some(tracer::Integrator::Whitted),
+                            KeyCode::Digit3 => Some(tracer::Integrator::GuidedPathTracer),
+                            KeyCode::Digit4 => Some(tracer::Integrator::ReSTIR),
+                            KeyCode::Digit5 if gpu_available => Some(tracer::Integrator::Gpu),
+                            KeyCode::Digit5 => {
+                                println!("The GPU backend is not available. Compile with \"--features gpu\" and make sure a GPU is present.");
+                                None
+                            },
+                            KeyCode::Digit6 => Some(tracer::Integrator::Wavefront),
+                            KeyCode::Digit7 => Some(tracer::Integrator::Normals),
+                            KeyCode::Digit8 => Some(tracer::Integrator::Depth),
+                            KeyCode::Digit9 => Some(tracer::Integrator::AmbientOcclusion),
+                            KeyCode::Digit0 => Some(tracer::Integrator::Heatmap),
+                            _ => None,
+                        };
+                        let selected_integrator = match selected_integrator {
+                            Some(view) if view == integrator && view.is_debug_view() => Some(beauty_integrator),
+                            selected_integrator => selected_integrator,
+                        };
+                        if key == KeyCode::F12 {
+                            if modifiers.shift_key() {
+                                exr_requested = true;
+                            } else {
+                                screenshot_requested = true;
+                            }
+                        }
+                        if key == KeyCode::KeyD {
+                            show_denoised = !show_denoised;
+                            if show_denoised && !gather_features {
+                                gather_features = true;
+                                restart_requested = true;
+                            }
+                            if !show_denoised {
+                                denoised_image.clear();
+                                if show_post_processed && !post_processed_pixels.is_empty() {
+                                    post_process(&backbuffer, &scene.read().unwrap().camera, &post_processing, finished_samples(&backbuffer), show_denoised, &denoised_image, &mut post_processed_pixels); // @TODO: Handle the unwrap
+                                }
+                            }
+                            info!("show_denoised = {}", show_denoised);
+                        }
+                        // The heatmap is updated with every sample, like the denoised image.
+                        if key == KeyCode::KeyV {
+                            show_variance = !show_variance;
+                            if show_variance {
+                                tracer::variance_heatmap(&backbuffer, finished_samples(&backbuffer), &mut variance_pixels);
+                            }
+                            info!("show_variance = {}", show_variance);
+                        }
+                        // The camera keeps its direction and moves until the whole scene is in view.
+                        if key == KeyCode::KeyF {
+                            let mut scene = scene.write().unwrap(); // @TODO: Handle the unwrap
+                            scene.auto_frame();
+                            restart_requested = true;
+                            info!("camera = {:?}", scene.camera.position());
+                        }
+                        if key == KeyCode::KeyB {
+                            if post_processing.is_enabled() {
+                                show_post_processed = !show_post_processed;
+                                if show_post_processed {
+                                    post_process(&backbuffer, &scene.read().unwrap().camera, &post_processing, finished_samples(&backbuffer), show_denoised, &denoised_image, &mut post_processed_pixels); // @TODO: Handle the unwrap
+                                }
+                                info!("show_post_processed = {}", show_post_processed);
+                            } else {
+                                println!("There is no post-processing. Start with \"--bloom\", \"--lens-flare\" or \"--vignette\".");
+                            }
+                        }
+                        if key == KeyCode::KeyM {
+                            let mut scene = scene.write().unwrap(); // @TODO: Handle the unwrap
+                            let tone_mapping = scene.camera.tone_mapping().next();
+                            scene.camera.set_tone_mapping(tone_mapping);
+                            tone_mapping_changed = true;
+                            info!("tone_mapping = {:?}", tone_mapping);
+                        }
+                        // The color grading is increased with the keys and decreased with Shift.
+                        // Like the tone mapping, it does not restart the accumulation.
+                        if key == KeyCode::KeyE || key == KeyCode::KeyW || key == KeyCode::KeyS || key == KeyCode::KeyG {
+                            const EXPOSURE_STEP: f32 = 0.5; // EV
+                            const WHITE_BALANCE_STEP: f32 = 500.0; // K
+                            const SATURATION_STEP: f32 = 0.1;
+                            let mut scene = scene.write().unwrap(); // @TODO: Handle the unwrap
+                            let mut grading = scene.camera.color_grading();
+                            let sign = if modifiers.shift_key() { -1.0 } else { 1.0 };
+                            match key {
+                                KeyCode::KeyE => grading.exposure += sign*EXPOSURE_STEP,
+                                KeyCode::KeyW => grading.white_balance = f32::min(f32::max(grading.white_balance + sign*WHITE_BALANCE_STEP, tracer::MIN_WHITE_BALANCE), tracer::MAX_WHITE_BALANCE),
+                                KeyCode::KeyS => grading.saturation = f32::max(grading.saturation + sign*SATURATION_STEP, 0.0),
+                                _ => grading = tracer::ColorGrading::default(),
+                            }
+                            scene.camera.set_color_grading(grading);
+                            tone_mapping_changed = true;
+                            info!("exposure = {:+.1} EV, white_balance = {} K, saturation = {:.1}", grading.exposure, grading.white_balance, grading.saturation);
+                        }
+                        // The pixel under the cursor is traced with a log of every bounce.
+                        if key == KeyCode::KeyP {
+                            if let Some(position) = cursor_position {
+                                let (x, y) = image_view.to_image(position.x, position.y);
+                                if x >= 0.0 && y >= 0.0 {
+                                    tracer::trace_debug_pixel(&scene.read().unwrap(), integrator, max_depth, x as u32, y as u32); // @TODO: Handle the unwrap
+                                }
+                            }
+                        }
+                        // The paths of the pixel under the cursor are drawn over a top and side
+                        // view of the scene. Pressing the key again goes back to the image.
+                        if key == KeyCode::KeyL {
+                            if show_ray_view {
+                                show_ray_view = false;
+                            } else if let Some(position) = cursor_position {
+                                let (x, y) = image_view.to_image(position.x, position.y);
+                                if x >= 0.0 && y >= 0.0 {
+                                    let scene = scene.read().unwrap(); // @TODO: Handle the unwrap
+                                    let segments = tracer::record_pixel_paths(&scene, max_depth, x as u32, y as u32);
+                                    if !segments.is_empty() {
+                                        ray_view_pixels = rayview::draw(&scene, &segments, backbuffer.width(), backbuffer.height());
+                                        show_ray_view = true;
+                                        println!("Recorded {} segments of the paths of pixel ({}, {}).", segments.len(), x as u32, y as u32);
+                                    }
+                                }
+                            }
+                            info!("show_ray_view = {}", show_ray_view);
+                        }
+                        if key == KeyCode::Space {
+                            paused = !paused;
+                            info!("paused = {}", paused);
+                        }
+                        if key == KeyCode::KeyR {
+                            restart_requested = true;
+                            if paused {
+                                paused = false;
+                                info!("paused = {}", paused);
+                            }
+                        }
+                        if key == KeyCode::Home {
+                            image_view = ImageView::new();
+                        }
+                        if key == KeyCode::Tab {
+                            if overlay.is_some() {
+                                show_overlay = !show_overlay;
+                            } else {
+                                println!("The overlay is not available. Compile with \"--features overlay\".");
+                            }
+                        }
+                        if key == KeyCode::KeyT {
+                            tile_order = tile_order.next();
+                            info!("tile_order = {:?}", tile_order);
+                        }
+                        if let Some(selected_integrator) = selected_integrator {
+                            if selected_integrator != integrator {
+                                if !integrator.is_debug_view() {
+                                    beauty_integrator = integrator;
+                                }
+                                integrator = selected_integrator;
+                                info!("integrator = {:?}", integrator);
+                                restart_requested = true;
+                            }
+                        }
+                    },
+                    _ => (),
+                }
+            },
+            Event::AboutToWait => {
+                // ASSIGNING THE HDRI TEXTURE FOR THE SKY
+                //
+                // The write lock is only taken when the texture is actually missing as it has to
+                // wait for the workers that are currently reading the scene.
+                let missing_hdri_texture = match scene.read().expect("Could not get reading access to the scene for checking the sky texture.").sky {
+                    scene::Sky::HDRI(ref path, None) => Some(path.clone()),
+                    _ => None,
+                };
+                // A texture that cannot be loaded is replaced by a black sky so that the
+                // error is only reported once.
+                if let Some(path) = missing_hdri_texture {
+                    match content.get_hdri_texture(&path) {
+                        Ok(Some(hdri_texture)) => {
+                            let mut scene = scene.write().expect("Could not get writing access to the scene for updating the sky texture.");
+                            if let scene::Sky::HDRI(_, ref mut option_texture) = scene.sky {
+                                *option_texture = Some(hdri_texture);
+                            }
+                            restart_requested = true;
                         },
+                        Ok(None) => (),
                         Err(err) => {
-                            println!("Could not load the scene. Error: {:?}", err);
+                            println!("{} Using a black sky.", err);
+                            scene.write().expect("Could not get writing access to the scene for updating the sky texture.").sky = scene::Sky::Constant(Vec3::zero());
+                            restart_requested = true;
                         },
+                    }
+                }
+
+                // RELOADING THE SCENE WHEN THE FILE WAS UPDATED
+                match receiver.try_recv() {
+                    Ok(event) => {
+                        // Events of the other files in the directory are ignored.
+                        let changed_path = match event {
+                            notify::DebouncedEvent::Write(path) => Some(path),
+                            notify::DebouncedEvent::Create(path) => Some(path),
+                            notify::DebouncedEvent::Rename(_, path) => Some(path),
+                            _ => None,
+                        };
+                        let scene_file_changed = changed_path
+                            .map(|path| path.file_name().map(|name| name.to_os_string()) == scene_file_name)
+                            .unwrap_or(false);
+                        if scene_file_changed {
+                            match parser::load_scene(&*scene_file_path) {
+                                Ok((mut loaded_scene, source)) => {
+                                    options.apply_to_scene(&mut loaded_scene);
+                                    if options.print_stats {
+                                        stats::print_scene_stats(&loaded_scene);
+                                    }
+                                    loaded_scene.set_resolution(backbuffer.width() as usize, backbuffer.height() as usize);
+                                    let mut scene = scene.write().unwrap(); // @TODO: Handle the unwrap
+                                    // The color grading is only set at runtime and survives the reload.
+                                    loaded_scene.camera.set_color_grading(scene.camera.color_grading());
+                                    *scene = loaded_scene;
+                                    let mut scene_source = scene_source.write().unwrap(); // @TODO: Handle the unwrap
+                                    scene_source.version += 1;
+                                    scene_source.text = source;
+                                    restart_requested = true;
+                                },
+                                Err(err) => {
+                                    println!("{}", err);
+                                },
+                            };
+                        }
+                    },
+                    Err(_) => (), // @TODO: If disconnected panic
+                }
+
+                // CANCELLING THE CURRENT FRAME
+                if restart_requested {
+                    generation.fetch_add(1, Ordering::SeqCst);
+                }
+
+                // COLLECTING THE FINISHED TILES
+                //
+                // Waiting for the first tile with a timeout limits the rate with which the window
+                // is redrawn without making the loop unresponsive.
+                if num_pending_tiles > 0 {
+                    if let Ok(finished_tile) = tile_receiver.recv_timeout(Duration::from_millis(16)) {
+                        trace_finished_tile(&finished_tile);
+                        num_pending_tiles -= 1;
+                    }
+                    while let Ok(finished_tile) = tile_receiver.try_recv() {
+                        trace_finished_tile(&finished_tile);
+                        num_pending_tiles -= 1;
+                    }
+                    if frame_resolution_scale == 1 && !restart_requested {
+                        progress.set_frame_tiles(num_frame_tiles - num_pending_tiles, num_frame_tiles);
+                        progress.print(false);
+                    }
+                }
+
+                // STARTING THE NEXT FRAME
+                //
+                // Only one work item per tile should be active at a time. Therefore the next frame
+                // is only started when all tiles of the previous one have been reported back.
+                if num_pending_tiles == 0 {
+                    // The displayed image is tone mapped again with the same number of samples it
+                    // was accumulated with. Therefore this has to happen before the accounting of
+                    // the frame.
+                    if tone_mapping_changed {
+                        tracer::tone_map_backbuffer(&backbuffer, &scene.read().unwrap().camera, finished_samples(&backbuffer));
+                        if show_post_processed && !post_processed_pixels.is_empty() {
+                            post_process(&backbuffer, &scene.read().unwrap().camera, &post_processing, finished_samples(&backbuffer), show_denoised, &denoised_image, &mut post_processed_pixels); // @TODO: Handle the unwrap
+                        }
+                        tone_mapping_changed = false;
+                    }
+                    // RESIZING THE IMAGE
+                    //
+                    // The buffers are reallocated in the size of the window and the camera is
+                    // adjusted to its aspect ratio. The size is taken from the tiles by remote workers.
+                    if let Some((width, height)) = requested_size.take() {
+                        crop = crop.map(|crop| crop.resized(backbuffer.width(), backbuffer.height(), width, height));
+                        backbuffer.resize(width, height);
+                        reservoirs.resize(width, height);
+                        scene.write().unwrap().set_resolution(width as usize, height as usize); // @TODO: Handle the unwrap
+                        info!("resolution = {}x{}", width, height);
+                    }
+                    if restart_requested {
+                        backbuffer.clear();
+                        backbuffer.set_debug_view(integrator.is_debug_view());
+                        guiding.reset(&scene.read().unwrap());
+                        reservoirs.clear();
+                        denoised_image.clear();
+                        denoised_pixels.clear();
+                        variance_pixels.clear();
+                        post_processed_pixels.clear();
+                        restart_requested = false;
+                        last_restart_time = precise_time_ns();
+                        progress.restart();
+                    } else if frame_in_flight && frame_resolution_scale == 1 {
+                        let guiding_time_start = precise_time_ns();
+                        if integrator == tracer::Integrator::GuidedPathTracer {
+                            guiding.update();
+                        }
+                        let guiding_time = precise_time_ns() - guiding_time_start;
+                        if integrator == tracer::Integrator::ReSTIR {
+                            reservoirs.swap();
+                        }
+                        let denoise_time_start = precise_time_ns();
+                        if show_denoised {
+                            denoised_image = denoiser.denoise(&backbuffer);
+                            backbuffer.tone_map_image(&denoised_image, &scene.read().unwrap().camera, &mut denoised_pixels); // @TODO: Handle the unwrap
+                        }
+                        // The frame has finished but is not counted yet.
+                        if show_variance {
+                            tracer::variance_heatmap(&backbuffer, unsafe { *backbuffer.num_samples.get() }, &mut variance_pixels);
+                        }
+                        if show_post_processed {
+                            post_process(&backbuffer, &scene.read().unwrap().camera, &post_processing, unsafe { *backbuffer.num_samples.get() }, show_denoised, &denoised_image, &mut post_processed_pixels); // @TODO: Handle the unwrap
+                        }
+                        let denoise_time = precise_time_ns() - denoise_time_start;
+                        unsafe {
+                            *backbuffer.num_samples.get() += 1;
+                        }
+
+                        // MEASURING THE FRAME TIME
+                        let frame_time_end = precise_time_ns();
+                        let frame_time = frame_time_end - frame_time_start;
+
+                        // SHOWING THE STATISTICS IN THE WINDOW TITLE
+                        //
+                        // The progress shows the elapsed time, the samples per pixel, the estimated
+                        // remaining time and the average rays per second since the last restart.
+                        // The tile time is summed over all worker threads and therefore shows how much
+                        // of the frame time was spent rendering when compared to the frame time times
+                        // the number of threads.
+                        let frame_stats = stats::take_frame_stats();
+                        debug!("Frame {}: {:.1} ms in total, {:.1} ms guiding, {:.1} ms denoising, {} rays in {} tiles",
+                            unsafe { *backbuffer.num_samples.get() }, frame_time as f64 / 1_000_000.0,
+                            guiding_time as f64 / 1_000_000.0, denoise_time as f64 / 1_000_000.0, frame_stats.rays, frame_stats.tiles);
+                        progress.finish_sample(frame_time, frame_stats.rays);
+                        progress.print(true);
+                        let num_invalid_samples = backbuffer.take_invalid_samples();
+                        if num_invalid_samples > 0 {
+                            progress.finish();
+                            println!("Dropped {} samples with NaN, infinite or negative radiance.", num_invalid_samples);
+                        }
+                        window.set_title(&format!(
+                            "Pathtracer | {:?} | {} | {:.1} ms/frame | {:.1} M tests/frame | {:.1} ms/tile | denoise {:.1} ms",
+                            integrator,
+                            progress.summary(),
+                            frame_time as f64 / 1_000_000.0,
+                            frame_stats.intersection_tests as f64 / 1_000_000.0,
+                            if frame_stats.tiles > 0 { frame_stats.tile_time_ns as f64 / frame_stats.tiles as f64 / 1_000_000.0 } else { 0.0 },
+                            denoise_time as f64 / 1_000_000.0,
+                        ));
+                    }
+                    frame_time_start = precise_time_ns();
+                    stats::take_frame_stats(); // Discarding the statistics of restarted and preview frames
+
+                    frame_resolution_scale = if frame_time_start - last_restart_time < PREVIEW_DURATION_NS {
+                        preview_scale
+                    } else {
+                        1
+                    };
+
+                    // The accumulation stops when the requested number of samples per pixel is
+                    // reached and continues after the next restart.
+                    let num_samples = unsafe { *backbuffer.num_samples.get() };
+                    let converged = samples_per_pixel.map(|spp| num_samples > spp).unwrap_or(false);
+
+                    // While paused, the frame that was running is finished but no new one is started.
+                    if converged || paused {
+                        frame_in_flight = false;
+                        std::thread::sleep(Duration::from_millis(16));
+                    } else if integrator == tracer::Integrator::Gpu {
+                        // RENDERING THE FRAME ON THE GPU
+                        //
+                        // The whole image is a single work item that is reported back like a tile.
+                        let (width, height) = (backbuffer.width(), backbuffer.height());
+                        let current_generation = generation.load(Ordering::SeqCst);
+                        worker_pool.process(tracer::WorkTile::new(Vec2u::new(0, 0), Vec2u::new(0, 0), Vec2u::new(width, height), integrator, max_depth, current_generation, num_samples, 1, Vec2u::new(width, height), false));
+                        num_pending_tiles = 1;
+                        num_frame_tiles = 1;
+                        frame_in_flight = true;
+                    } else {
+                        // GENERATING WORK ITEMS FOR THE WORKER THREADS TO DO THE PATH TRACING
+                        let current_generation = generation.load(Ordering::SeqCst);
+                        for work_tile in tiles::work_tiles(tile_order, backbuffer.width(), backbuffer.height(), crop, options.tile_size, integrator, max_depth, current_generation, num_samples, frame_resolution_scale, gather_features) {
+                            worker_pool.process(work_tile);
+                            num_pending_tiles += 1;
+                        }
+                        num_frame_tiles = num_pending_tiles;
+                        frame_in_flight = true;
+                    }
+                }
+
+                // WRITING THE BACKBUFFER TO THE WINDOW
+                //
+                // The tiles that are finished in the current frame are visible immediately while
+                // the others still show the result of the previous frame.
+                //
+                // The image is drawn with the zoom and the pan of the view and the overlay on top.
+                // The parameters of the overlay are read from the scene on every frame so that
+                // they follow reloads and the hotkeys. Only the edited ones are written back.
+                let mut parameters = match (show_overlay, overlay.is_some()) {
+                    (true, true) => {
+                        let scene = scene.read().unwrap(); // @TODO: Handle the unwrap
+                        Some(overlay::Parameters {
+                            vertical_fov: scene.camera.vertical_fov(),
+                            exposure: match scene.camera.tone_mapping() {
+                                tracer::ToneMapping::Exposure(exposure) => Some(exposure),
+                                _ => None,
+                            },
+                            color_grading: scene.camera.color_grading(),
+                            max_depth: max_depth,
+                            samples_per_pixel: samples_per_pixel,
+                            material: picked_object.and_then(|id| scene.material(id).map(|material| (format!("{}", id), material.clone()))),
+                        })
+                    },
+                    _ => None,
+                };
+                let changes = {
+                    let pixels = displayed_pixels(&backbuffer, show_denoised, &denoised_pixels, show_variance, &variance_pixels, show_post_processed, &post_processed_pixels, show_ray_view, &ray_view_pixels);
+                    let top_left = image_view.to_window(0.0, 0.0);
+                    let bottom_right = image_view.to_window(backbuffer.width() as f64, backbuffer.height() as f64);
+                    let overlay_and_parameters = match (overlay.as_mut(), parameters.as_mut()) {
+                        (Some(overlay), Some(parameters)) => Some((overlay, parameters)),
+                        _ => None,
                     };
+                    match presenter.present(pixels, backbuffer.width(), backbuffer.height(), top_left, bottom_right, overlay_and_parameters) {
+                        Ok(changes) => changes,
+                        Err(err) => {
+                            println!("{}", err);
+                            overlay::Changes::default()
+                        },
+                    }
+                };
+
+                // APPLYING THE EDITS OF THE OVERLAY
+                if let Some(parameters) = parameters {
+                    if changes.camera || changes.exposure || changes.color_grading || changes.material {
+                        let mut scene = scene.write().unwrap(); // @TODO: Handle the unwrap
+                        if changes.camera {
+                            scene.camera.set_vertical_fov(parameters.vertical_fov);
+                        }
+                        if let (true, Some(exposure)) = (changes.exposure, parameters.exposure) {
+                            scene.camera.set_tone_mapping(tracer::ToneMapping::Exposure(exposure));
+                        }
+                        if changes.color_grading {
+                            scene.camera.set_color_grading(parameters.color_grading);
+                        }
+                        if let (true, Some(id), Some((_, material))) = (changes.material, picked_object, parameters.material) {
+                            if options.serve_address.is_some() {
+                                warn!("The material is not changed as the remote workers would still render the old one.");
+                            } else {
+                                scene.set_material(id, material);
+                            }
+                        }
+                    }
+                    max_depth = parameters.max_depth;
+                    samples_per_pixel = parameters.samples_per_pixel;
+                    progress.set_target_samples(samples_per_pixel);
+                    restart_requested |= changes.camera || changes.max_depth || (changes.material && options.serve_address.is_none());
+                    tone_mapping_changed |= changes.exposure || changes.color_grading;
+                }
+
+                // SAVING A SCREENSHOT
+                if screenshot_requested {
+                    save_displayed_image(&export::timestamped_filename("render", "png"), &backbuffer, &scene.read().unwrap(),
+                        displayed_pixels(&backbuffer, show_denoised, &denoised_pixels, show_variance, &variance_pixels, show_post_processed, &post_processed_pixels, show_ray_view, &ray_view_pixels),
+                        &render_metadata(&backbuffer, &scene.read().unwrap(), &scene_source.read().unwrap(), integrator, max_depth, options.seed, last_restart_time, if show_post_processed { Some(post_processing) } else { None }, options.tile_size));
+                    screenshot_requested = false;
+                }
+                if exr_requested {
+                    save_exr(&export::timestamped_filename("render", "exr"), &backbuffer, &scene.read().unwrap(), exr_precision, exr_aovs, exr_mattes,
+                        &render_metadata(&backbuffer, &scene.read().unwrap(), &scene_source.read().unwrap(), integrator, max_depth, options.seed, last_restart_time, if show_post_processed { Some(post_processing) } else { None }, options.tile_size));
+                    exr_requested = false;
                 }
             },
-            Err(_) => (), // @TODO: If disconnected panic
+            _ => (),
         }
+    });
+    if let Err(err) = result {
+        println!("The event loop failed. Error: {}", err);
+    }
 
-        // GENERATING WORK ITEMS FOR THE WORKER THREADS TO DO THE PATH TRACING
-        for _ in 0..1 {
-            const TILE_SIZE: u32 = 32;
-            let tile_size = Vec2u::new(TILE_SIZE, TILE_SIZE);
-            let num_tiles_x = (backbuffer.width + TILE_SIZE - 1) / TILE_SIZE;
-            let num_tiles_y = (backbuffer.height + TILE_SIZE - 1) / TILE_SIZE;
-            for y in 0..num_tiles_y {
-                for x in 0..num_tiles_x {
-                    let tile_index = Vec2u::new(x, y);
-                    let tile_position = Vec2u::new(x*TILE_SIZE, y*TILE_SIZE);
-                    let work_tile = tracer::WorkTile::new(tile_index, tile_position, tile_size);
-                    worker_pool.process(work_tile);
-                }
-            }
+    // Letting the workers skip the remaining tiles
+    generation.fetch_add(1, Ordering::SeqCst);
+
+    if let Some(path) = output_path {
+        let metadata = render_metadata(&backbuffer, &scene.read().unwrap(), &scene_source.read().unwrap(), integrator, max_depth, options.seed, last_restart_time, if show_post_processed { Some(post_processing) } else { None }, options.tile_size);
+        if path.ends_with(".exr") {
+            save_exr(&path, &backbuffer, &scene.read().unwrap(), exr_precision, exr_aovs, exr_mattes, &metadata);
+        } else {
+            save_displayed_image(&path, &backbuffer, &scene.read().unwrap(),
+                displayed_pixels(&backbuffer, show_denoised, &denoised_pixels, show_variance, &variance_pixels, show_post_processed, &post_processed_pixels, show_ray_view, &ray_view_pixels), &metadata);
         }
+    }
+}
 
-        //
-        // WAITING UNTIL THE WORKER POOL HAS FINISHED THE PATH TRACING
-        //
-        // There are two reasons for waiting on the path tracing result:
-        //   1. All pixels should be written to the backbuffer before it is
-        //      displayed on the window. This however is not really important
-        //      considering the amount of noise. This would only be noticeable
-        //      when the camera changes every frame which could result in 
-        //      complete tiles not being ready.
-        //   2. There must be a mechanism that ensures that the work items for
-        //      the tiles don't pile up in the queue for the worker threads.
-        //      Only one work item per tile should be active at a time. Waiting
-        //      for all tiles to be ready ensures this.
-        //
-        worker_pool.wait();
-        assert!(worker_pool.queue_len() == 0);
-
-        // WRITING THE BACKBUFFER TO THE WINDOW
-        let target = display.draw();
-        unsafe {
-            // Constants from gl.h
-            const GL_RGB: i32 = 0x1907;
-            const GL_UNSIGNED_BYTE: i32 = 0x1401;
-
-            let raw = &(*backbuffer.pixels8.get())[0].0 as *const u8;
-            glDrawPixels(backbuffer.width,
-                         backbuffer.height,
-                         GL_RGB,
-                         GL_UNSIGNED_BYTE,
-                         raw);
-            *backbuffer.num_samples.get() += 1;
-        };
-        target.finish().unwrap();
+// Zoom and pan of the displayed image, e.g. for inspecting single pixels. This only changes how
+// the backbuffer is drawn and not the camera. All positions are in physical pixels from the
+// upper left corner like the cursor. Without zoom and pan, the image covers the window.
+#[derive(Clone, Copy, Debug)]
+struct ImageView {
+    zoom: f64, // Pixels of the window per pixel of the image
+    offset: (f64, f64), // Position of the upper left corner of the image in the window
+}
 
-        // HANDLE THE EVENTS PROVIDED BY THE WINDOW
-        events_loop.poll_events(|ev| {
-            match ev {
-                glutin::Event::WindowEvent { event, .. } => match event {
-                    glutin::WindowEvent::CloseRequested => running = false,
-                    _ => (),
-                },
-                _ => (),
-            }
-        });
+impl ImageView {
+    fn new() -> Self {
+        ImageView {
+            zoom: 1.0,
+            offset: (0.0, 0.0),
+        }
+    }
+
+    // Zooms by the factor while the pixel under the cursor stays in place.
+    fn zoom_at(&mut self, cursor_x: f64, cursor_y: f64, factor: f64) {
+        const MIN_ZOOM: f64 = 0.125;
+        const MAX_ZOOM: f64 = 64.0;
+        let zoom = f64::max(MIN_ZOOM, f64::min(MAX_ZOOM, self.zoom*factor));
+        let (x, y) = self.to_image(cursor_x, cursor_y);
+        self.offset = (cursor_x - zoom*x, cursor_y - zoom*y);
+        self.zoom = zoom;
+    }
+
+    fn pan(&mut self, dx: f64, dy: f64) {
+        self.offset = (self.offset.0 + dx, self.offset.1 + dy);
+    }
+
+    fn to_image(&self, x: f64, y: f64) -> (f64, f64) {
+        ((x - self.offset.0)/self.zoom, (y - self.offset.1)/self.zoom)
+    }
+
+    fn to_window(&self, x: f64, y: f64) -> (f64, f64) {
+        (self.offset.0 + self.zoom*x, self.offset.1 + self.zoom*y)
+    }
+}
+
+// Logs what is visible at a position of the image, given in pixels from its upper left
+// corner, for debugging the scene. Returns the object for the overlay.
+fn log_picked_object(scene: &scene::Scene, backbuffer: &tracer::Backbuffer, image_x: f64, image_y: f64) -> Option<scene::ObjectId> {
+    // The backbuffer starts with the bottom row.
+    let (width, height) = (backbuffer.width(), backbuffer.height());
+    let (x, y) = (image_x as f32, height as f32 - image_y as f32);
+    if x < 0.0 || y < 0.0 || x >= width as f32 || y >= height as f32 {
+        return None;
+    }
+    let ray = scene.camera.pixel_ray(x, y, width, height);
+    match scene::find_object_hit(&ray, scene) {
+        Some((id, hit)) => {
+            let node = scene.nodes.iter().position(|node| node.objects.contains(&id));
+            info!("Picked {}{} at pixel ({}, {})", id, node.map(|node| format!(" in node {}", node)).unwrap_or(String::new()), x as u32, y as u32);
+            info!("    distance = {}", hit.parameter);
+            info!("    position = {:?}", hit.position);
+            info!("    normal = {:?}", hit.normal);
+            info!("    material = {:?}", hit.material);
+            Some(id)
+        },
+        None => {
+            info!("Picked the sky at pixel ({}, {})", x as u32, y as u32);
+            None
+        },
+    }
+}
+
+// Focuses the camera on the surface at a position of the image like log_picked_object.
+// Returns the new focus distance, or None without depth of field or for the sky.
+fn focus_on_pixel(scene: &mut scene::Scene, backbuffer: &tracer::Backbuffer, image_x: f64, image_y: f64) -> Option<f32> {
+    scene.camera.depth_of_field()?;
+    let (width, height) = (backbuffer.width(), backbuffer.height());
+    let (x, y) = (image_x as f32, height as f32 - image_y as f32);
+    if x < 0.0 || y < 0.0 || x >= width as f32 || y >= height as f32 {
+        return None;
+    }
+    let ray = scene.camera.pixel_ray(x, y, width, height);
+    let position = scene::find_scene_hit(&ray, scene)?.position;
+    scene.camera.focus_on(position)
+}
+
+fn trace_finished_tile(finished_tile: &tracer::FinishedTile) {
+    trace!("Finished the tile at {:?}{}.", finished_tile.work_tile.position, if finished_tile.cancelled { ", which was skipped as it is outdated" } else { "" });
+}
+
+// The backbuffer already counts the frame that is being rendered, which is incremented once
+// the previous frame has finished. The tiles of that frame that are done are not counted.
+fn finished_samples(backbuffer: &tracer::Backbuffer) -> usize {
+    unsafe { *backbuffer.num_samples.get() }.saturating_sub(1)
+}
+
+// The render time starts with the last restart of the accumulation. The settings are the ones
+// that are in effect in the window, e.g. the resolution after resizing it and the tone mapping
+// of the overlay.
+fn render_metadata(backbuffer: &tracer::Backbuffer, scene: &scene::Scene, scene_source: &network::SceneSource, integrator: tracer::Integrator, max_depth: u8, seed: Option<u32>, last_restart_time: u64, post_processing: Option<postprocess::PostProcessing>, tile_size: u32) -> export::Metadata {
+    export::Metadata::new(finished_samples(backbuffer), seed.unwrap_or(DEFAULT_SEED), export::scene_hash(&scene_source.text),
+        integrator, max_depth, (precise_time_ns() - last_restart_time) as f64 / 1_000_000_000.0,
+        backbuffer.width(), backbuffer.height(), scene.image_settings.stereo, scene.camera.tone_mapping(), post_processing, tile_size)
+}
+
+fn save_exr(path: &str, backbuffer: &tracer::Backbuffer, scene: &scene::Scene, precision: export::ExrPrecision, aovs: bool, mattes: bool, metadata: &export::Metadata) {
+    let result = export::save_exr(path, backbuffer, finished_samples(backbuffer), precision, aovs, if mattes { Some(scene) } else { None }, scene.image_settings.transparent_background, Some(metadata))
+        .and_then(|()| if mattes { export::save_matte_manifest(path, scene) } else { Ok(()) });
+    match result {
+        Ok(()) => println!("Saved the radiance to \"{}\".", path),
+        Err(err) => println!("{}", err),
+    }
+}
+
+// The recorded paths take precedence over the heatmap of the variance, which takes
+// precedence over the denoised image and then the post-processed one. They are only shown
+// once they have been computed for the current size of the backbuffer.
+fn displayed_pixels<'a>(backbuffer: &'a tracer::Backbuffer, show_denoised: bool, denoised_pixels: &'a Vec<Pixel8>, show_variance: bool, variance_pixels: &'a Vec<Pixel8>, show_post_processed: bool, post_processed_pixels: &'a Vec<Pixel8>, show_ray_view: bool, ray_view_pixels: &'a Vec<Pixel8>) -> &'a Vec<Pixel8> {
+    let pixels = unsafe { &*backbuffer.pixels8.get() };
+    if show_ray_view && ray_view_pixels.len() == pixels.len() {
+        ray_view_pixels
+    } else if show_variance && variance_pixels.len() == pixels.len() {
+        variance_pixels
+    } else if show_post_processed && post_processed_pixels.len() == pixels.len() {
+        post_processed_pixels
+    } else if show_denoised && denoised_pixels.len() == pixels.len() {
+        denoised_pixels
+    } else {
+        pixels
+    }
+}
 
-        // MEASURING THE FRAME TIME
-        let frame_time_end = time::precise_time_ns();
-        println!("frame_time = {} ms", (frame_time_end - frame_time_start) as f64 / 1_000_000.0);
+// The post-processing is applied to the denoised image while it is shown, so that the image is
+// denoised first and then post-processed. The denoised image is from the last finished frame.
+fn post_process(backbuffer: &tracer::Backbuffer, camera: &tracer::Camera, settings: &postprocess::PostProcessing, num_samples: usize, show_denoised: bool, denoised_image: &Vec<Vec3>, pixels: &mut Vec<Pixel8>) {
+    if show_denoised && denoised_image.len() == (backbuffer.width()*backbuffer.height()) as usize {
+        postprocess::apply_to_image(backbuffer, camera, settings, denoised_image.clone(), pixels);
+    } else {
+        postprocess::apply(backbuffer, camera, settings, num_samples, pixels);
+    }
+}
+
+// Saves what is currently shown in the window. Tiles that are being rendered while saving
+// can be from the previous frame. With a transparent background, the image is saved with
+// alpha instead.
+fn save_displayed_image(path: &str, backbuffer: &tracer::Backbuffer, scene: &scene::Scene, pixels: &Vec<Pixel8>, metadata: &export::Metadata) {
+    let result = if scene.image_settings.transparent_background {
+        export::save_png_rgba(path, backbuffer, &scene.camera, finished_samples(backbuffer), Some(metadata))
+    } else {
+        export::save_png(path, backbuffer.width(), backbuffer.height(), pixels, Some(metadata))
+    };
+    match result {
+        Ok(()) => println!("Saved the image to \"{}\".", path),
+        Err(err) => println!("{}", err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std;
+    use std::sync::{Arc, RwLock};
+
+    use common::*;
+    use scene::{Scene, Sphere};
+    use tracer::{self, Backbuffer, Integrator};
+    use builder;
+    use guiding::GuidingGrid;
+    use restir::ReservoirBuffer;
+    use tiles;
+    use export;
+    use super::finished_samples;
+
+    fn read_file(path: &std::path::Path) -> Vec<u8> {
+        use std::io::Read;
+        let mut bytes = Vec::new();
+        std::fs::File::open(path).unwrap().read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    // The window counts the next frame as soon as one has finished, while the headless
+    // renderer only counts the frames that were rendered. The exports must not differ.
+    #[test]
+    fn exports_after_one_frame_match_the_headless_renderer() {
+        let mut scene = Scene::default();
+        scene.set_resolution(16, 16);
+        scene.image_settings.transparent_background = true;
+        scene.add_sphere(Sphere::new(Vec3::zero(), 3.0, builder::diffuse(Vec3::new(0.8, 0.5, 0.2))));
+        let scene = Arc::new(RwLock::new(scene));
+
+        let backbuffer = Arc::new(Backbuffer::new(16, 16));
+        let guiding = GuidingGrid::new();
+        let reservoirs = ReservoirBuffer::new(16, 16);
+        for work_tile in tiles::work_tiles(tiles::TileOrder::Scanline, 16, 16, None, 8, Integrator::PathTracer, 4, 0, 1, 1, true) {
+            tracer::render(work_tile, &backbuffer, scene.clone(), &guiding, &reservoirs);
+        }
+
+        let directory = std::env::temp_dir();
+        let save = |name: &str, num_samples: usize| {
+            let (png_path, exr_path) = (directory.join(format!("{}.png", name)), directory.join(format!("{}.exr", name)));
+            export::save_png_rgba(png_path.to_str().unwrap(), &backbuffer, &scene.read().unwrap().camera, num_samples, None).unwrap();
+            export::save_exr(exr_path.to_str().unwrap(), &backbuffer, num_samples, export::ExrPrecision::Full, true, Some(&*scene.read().unwrap()), true, None).unwrap();
+            let files = (read_file(&png_path), read_file(&exr_path));
+            std::fs::remove_file(png_path).unwrap();
+            std::fs::remove_file(exr_path).unwrap();
+            files
+        };
+
+        let headless = save("pathtracer_headless_frame", unsafe { *backbuffer.num_samples.get() });
+        unsafe {
+            *backbuffer.num_samples.get() += 1;
+        }
+        assert_eq!(finished_samples(&backbuffer), 1);
+        let window = save("pathtracer_window_frame", finished_samples(&backbuffer));
+        assert!(headless == window);
     }
 }
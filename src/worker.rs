@@ -21,6 +21,7 @@ impl<D: Send> WorkerPool<D> {
         let processor = Arc::new(processor);
         let num_waiting_workers = Arc::new((Mutex::new(0), Condvar::new()));
 
+        debug!("Starting {} worker threads.", num_workers);
         let workers = (0..num_workers).map(|i| {
             let work_queue2 = work_queue.clone();
             let processor2 = processor.clone();
@@ -92,6 +93,16 @@ impl<D: Send> WorkerPool<D> {
         self.work_queue.0.lock().expect("Could not aquire the mutex to get the queue length.").len()
     }
 
+    // Removes the first job for which the predicate holds from the queue. This allows other
+    // consumers, e.g. remote workers, to take their work from the same queue.
+    pub fn take_matching<P: Fn(&D) -> bool>(&self, predicate: P) -> Option<D> {
+        let mut queue = self.work_queue.0.lock().expect("Could not aquire the mutex of the queue for taking.");
+        match queue.iter().position(|job_data| predicate(job_data)) {
+            Some(index) => queue.remove(index),
+            None => None,
+        }
+    }
+
     pub fn process(&self, job_data: D) {
         let (ref queue, ref condvar) = *self.work_queue;
         let mut queue = queue.lock().expect("Could not aquire the mutex of the queue for inserting.");